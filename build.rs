@@ -0,0 +1,52 @@
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Use a vendored protoc, so building doesn't need one installed.
+    // SAFETY: build scripts are single threaded.
+    unsafe { std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?) };
+    tonic_build::compile_protos("proto/events.proto")?;
+    emit_build_info()?;
+    Ok(())
+}
+
+/// Passes the commit, build time and enabled features to the crate, for `GET /version`.
+fn emit_build_info() -> Result<(), Box<dyn std::error::Error>> {
+    let git_sha = git(&["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_SHA={git_sha}");
+    // Rebuild when a commit is checked out or made, so the commit isn't stale.
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        println!("cargo:rerun-if-changed=.git/{head_ref}");
+    }
+
+    // Reproducible builds set the time explicitly.
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let build_timestamp = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(seconds) => seconds.parse::<u64>()? * 1000,
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+    };
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={build_timestamp}");
+
+    let mut features: Vec<_> = std::env::vars()
+        .filter_map(|(name, _)| {
+            let feature = name.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=ENABLED_FEATURES={}", features.join(","));
+    Ok(())
+}
+
+/// Runs a git command and returns its output, `None` if it fails, e.g. outside a checkout.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string())
+}
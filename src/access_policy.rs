@@ -0,0 +1,358 @@
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::analytics::FieldPath;
+
+/// Role of the requests without a known API key.
+pub const ANONYMOUS_ROLE: &str = "anonymous";
+
+/// Replaces the values of masked fields.
+const REDACTED: &str = "[redacted]";
+
+/// Which payload fields each role may see, as declared in the access policy file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    /// Role of each API key.
+    #[serde(default)]
+    api_keys: BTreeMap<String, String>,
+
+    /// Role of the members of each identity provider group, for users logging in with OpenID
+    /// Connect.
+    #[serde(default)]
+    groups: BTreeMap<String, String>,
+
+    /// Identity of the clients authenticating with a certificate, by the certificate's subject
+    /// common name or SHA-256 fingerprint in hex.
+    #[serde(default)]
+    client_certificates: BTreeMap<String, ClientIdentity>,
+
+    #[serde(default)]
+    roles: BTreeMap<String, RoleFile>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoleFile {
+    /// Payload fields redacted in responses, like `payload.email`.
+    #[serde(default)]
+    masked_fields: Vec<String>,
+
+    /// Whether the role may use the admin APIs when login is enabled.
+    #[serde(default)]
+    admin: bool,
+
+    /// What the role's API keys may do with events when API keys are required.
+    /// Roles without scopes may do everything.
+    scopes: Option<BTreeSet<ApiKeyScope>>,
+}
+
+/// What an API key may do with events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, strum::AsRefStr)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ApiKeyScope {
+    /// Storing and validating events.
+    Ingest,
+
+    /// Querying events.
+    Read,
+
+    /// Deleting events.
+    Delete,
+}
+
+/// Who a client certificate stands for. Its requests are served as if they carried the API key
+/// and producer id in their headers.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClientIdentity {
+    pub api_key: Option<String>,
+    pub producer: Option<String>,
+}
+
+/// Payload fields redacted for a role. Masking a field masks everything nested in it, too.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MaskedFields(Vec<Vec<String>>);
+
+/// Resolves the role of requests from their API key, and the payload fields hidden from each
+/// role. Roles without masked fields see full payloads.
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    api_keys: BTreeMap<String, String>,
+    groups: BTreeMap<String, String>,
+    client_certificates: BTreeMap<String, ClientIdentity>,
+    masks: BTreeMap<String, Arc<MaskedFields>>,
+    admin_roles: BTreeSet<String>,
+    scopes: BTreeMap<String, BTreeSet<ApiKeyScope>>,
+}
+
+impl AccessPolicy {
+    /// Loads the policy from a TOML, or if the extension says so, a YAML file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read access policy {}", path.display()))?;
+        let file: PolicyFile = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => serde_yaml::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            _ => toml::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+        };
+        Self::from_file(file)
+    }
+
+    fn from_file(file: PolicyFile) -> Result<Self> {
+        let mut masks = BTreeMap::new();
+        let mut admin_roles = BTreeSet::new();
+        let mut scopes = BTreeMap::new();
+        for (role, role_file) in file.roles {
+            if role_file.admin {
+                admin_roles.insert(role.clone());
+            }
+            if let Some(role_scopes) = role_file.scopes {
+                scopes.insert(role.clone(), role_scopes);
+            }
+            let fields = role_file
+                .masked_fields
+                .iter()
+                .map(|field| match field.parse() {
+                    Ok(FieldPath::Payload(path)) => Ok(path),
+                    _ => Err(anyhow!("Invalid masked field of role '{role}': '{field}'")),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if !fields.is_empty() {
+                masks.insert(role, Arc::new(MaskedFields(fields)));
+            }
+        }
+        for (name, identity) in &file.client_certificates {
+            let values = [&identity.api_key, &identity.producer];
+            // They are put into request headers.
+            if values
+                .into_iter()
+                .flatten()
+                .any(|value| value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_graphic()))
+            {
+                return Err(anyhow!("Invalid identity of client certificate '{name}'"));
+            }
+        }
+        Ok(Self {
+            api_keys: file.api_keys,
+            groups: file.groups,
+            client_certificates: file.client_certificates,
+            masks,
+            admin_roles,
+            scopes,
+        })
+    }
+
+    /// Returns the role of a request's API key, the anonymous role if it's missing or unknown.
+    pub fn role(&self, api_key: Option<&str>) -> &str {
+        api_key
+            .and_then(|api_key| self.api_keys.get(api_key))
+            .map_or(ANONYMOUS_ROLE, String::as_str)
+    }
+
+    /// Whether the API key is assigned a role.
+    pub fn is_known_key(&self, api_key: &str) -> bool {
+        self.api_keys.contains_key(api_key)
+    }
+
+    /// Returns the roles of an identity provider user from their groups.
+    pub fn group_roles<'a>(&'a self, groups: &'a [String]) -> impl Iterator<Item = &'a str> {
+        groups
+            .iter()
+            .filter_map(|group| self.groups.get(group))
+            .map(String::as_str)
+    }
+
+    /// Returns the identity of a client certificate, looked up by its fingerprint first.
+    pub fn client_identity(
+        &self,
+        common_name: Option<&str>,
+        fingerprint: &str,
+    ) -> Option<&ClientIdentity> {
+        self.client_certificates
+            .get(fingerprint)
+            .or_else(|| self.client_certificates.get(common_name?))
+    }
+
+    /// Whether the role may use the admin APIs when login is enabled.
+    pub fn is_admin(&self, role: &str) -> bool {
+        self.admin_roles.contains(role)
+    }
+
+    /// Whether the role's API keys have the scope.
+    pub fn has_scope(&self, role: &str, scope: ApiKeyScope) -> bool {
+        self.scopes
+            .get(role)
+            .is_none_or(|scopes| scopes.contains(&scope))
+    }
+
+    /// Returns the fields masked for the role, `None` if it sees full payloads.
+    pub fn masked_fields(&self, role: &str) -> Option<Arc<MaskedFields>> {
+        self.masks.get(role).cloned()
+    }
+}
+
+impl MaskedFields {
+    /// Whether the field reveals a masked field, by being one, or containing or being in one.
+    pub fn reveals(&self, field: &FieldPath) -> bool {
+        let FieldPath::Payload(path) = field else {
+            return false;
+        };
+        self.0
+            .iter()
+            .any(|masked| masked.starts_with(path) || path.starts_with(masked))
+    }
+
+    /// Redacts the masked payload fields of every event in a JSON document. Events are the
+    /// objects with both an `event_type` and a `payload`.
+    pub fn redact(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Array(values) => {
+                values.iter_mut().for_each(|value| self.redact(value))
+            }
+            serde_json::Value::Object(object) => {
+                if object.contains_key("event_type")
+                    && let Some(payload) = object.get_mut("payload")
+                {
+                    self.redact_payload(payload);
+                }
+                object.values_mut().for_each(|value| self.redact(value));
+            }
+            _ => {}
+        }
+    }
+
+    /// Redacts the masked fields of one event's payload.
+    pub fn redact_payload(&self, payload: &mut serde_json::Value) {
+        for path in &self.0 {
+            redact_path(payload, path);
+        }
+    }
+}
+
+fn redact_path(value: &mut serde_json::Value, path: &[String]) {
+    let Some((key, rest)) = path.split_first() else {
+        return;
+    };
+    let Some(field) = value.get_mut(key) else {
+        return;
+    };
+    if rest.is_empty() {
+        *field = REDACTED.into();
+    } else {
+        redact_path(field, rest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> AccessPolicy {
+        let file = toml::from_str(
+            r#"
+            [api_keys]
+            secret = "analyst"
+            root = "admin"
+            ingest = "producer"
+
+            [groups]
+            tracker-admins = "admin"
+            data-team = "analyst"
+
+            [client_certificates.ingest-worker]
+            api_key = "secret"
+            producer = "worker"
+
+            [roles.admin]
+            admin = true
+
+            [roles.producer]
+            scopes = ["ingest"]
+
+            [roles.analyst]
+            masked_fields = ["payload.email", "payload.card.number"]
+
+            [roles.anonymous]
+            masked_fields = ["payload.email"]
+            "#,
+        )
+        .unwrap();
+        AccessPolicy::from_file(file).unwrap()
+    }
+
+    #[test]
+    fn test_roles() {
+        let policy = policy();
+        assert_eq!(policy.role(Some("secret")), "analyst");
+        assert_eq!(policy.role(Some("guess")), ANONYMOUS_ROLE);
+        assert_eq!(policy.role(None), ANONYMOUS_ROLE);
+        assert_eq!(policy.masked_fields("admin"), None);
+        assert!(policy.is_admin("admin"));
+        assert!(!policy.is_admin("analyst"));
+        assert!(policy.is_known_key("ingest"));
+        assert!(!policy.is_known_key("guess"));
+        assert!(policy.has_scope("producer", ApiKeyScope::Ingest));
+        assert!(!policy.has_scope("producer", ApiKeyScope::Read));
+        assert!(!policy.has_scope("producer", ApiKeyScope::Delete));
+        assert!(policy.has_scope("analyst", ApiKeyScope::Read));
+        let groups = ["staff".to_string(), "data-team".to_string()];
+        assert_eq!(policy.group_roles(&groups).collect::<Vec<_>>(), ["analyst"]);
+        let identity = policy
+            .client_identity(Some("ingest-worker"), "ab12")
+            .unwrap();
+        assert_eq!(identity.api_key.as_deref(), Some("secret"));
+        assert_eq!(policy.client_identity(Some("laptop"), "ab12"), None);
+
+        let masked = policy.masked_fields("analyst").unwrap();
+        let reveals = |field: &str| masked.reveals(&field.parse().unwrap());
+        assert!(reveals("payload.card"));
+        assert!(reveals("payload.email.domain"));
+        assert!(!reveals("payload.card.expiry"));
+        assert!(!reveals("event_type"));
+
+        let file = PolicyFile {
+            roles: BTreeMap::from([(
+                "analyst".to_string(),
+                RoleFile {
+                    masked_fields: vec!["event_type".to_string()],
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        assert!(AccessPolicy::from_file(file).is_err());
+    }
+
+    #[test]
+    fn test_redact() {
+        let masked = policy().masked_fields("analyst").unwrap();
+        let mut page = serde_json::json!({
+            "events": [{
+                "event_id": "1",
+                "event_type": "payment",
+                "payload": {
+                    "email": "a@example.com",
+                    "card": {"number": "4111", "expiry": "12/30"},
+                },
+            }],
+            "next_cursor": null,
+        });
+        masked.redact(&mut page);
+        assert_eq!(
+            page["events"][0]["payload"],
+            serde_json::json!({"email": REDACTED, "card": {"number": REDACTED, "expiry": "12/30"}})
+        );
+
+        let mut not_an_event = serde_json::json!({"payload": {"email": "a@example.com"}});
+        masked.redact(&mut not_an_event);
+        assert_eq!(not_an_event["payload"]["email"], "a@example.com");
+    }
+}
@@ -0,0 +1,836 @@
+use serde::Serialize;
+use std::{collections::BTreeMap, str::FromStr};
+use tdigest::TDigest;
+
+use crate::event::{Event, Timestamp};
+
+/// Centroids kept by the sketches percentiles are estimated from. More are more accurate.
+const DIGEST_SIZE: usize = 100;
+
+/// Values buffered before they are merged into a sketch, as merging is costly.
+const DIGEST_BUFFER_SIZE: usize = 1024;
+
+/// A field of an event: `event_type`, `correlation_id`, or a payload field like
+/// `payload.country`. Nested payload fields are joined with dots, like `payload.customer.id`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldPath {
+    EventType,
+    CorrelationId,
+    Payload(Vec<String>),
+}
+
+impl FromStr for FieldPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "event_type" => Ok(FieldPath::EventType),
+            "correlation_id" => Ok(FieldPath::CorrelationId),
+            _ => match s.strip_prefix("payload.") {
+                Some(path) if path.split('.').all(|key| !key.is_empty()) => Ok(FieldPath::Payload(
+                    path.split('.').map(str::to_string).collect(),
+                )),
+                _ => Err(format!("Invalid field: '{s}'")),
+            },
+        }
+    }
+}
+
+impl FieldPath {
+    /// Returns the value of the field, or null if the event doesn't have it.
+    pub fn get(&self, event: &Event) -> serde_json::Value {
+        match self {
+            FieldPath::EventType => event.event_type.clone().into(),
+            FieldPath::CorrelationId => event.correlation_id.clone().into(),
+            FieldPath::Payload(path) => path
+                .iter()
+                .try_fold(&event.payload, |value, key| value.get(key))
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// An aggregate function computed for each group.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    /// Number of events.
+    Count,
+
+    /// Sum of a numeric field.
+    Sum,
+
+    /// Average of a numeric field, over the events that have it.
+    Avg,
+
+    /// Estimated percentile of a numeric field, like `p95`. Holds the percentile, e.g. `95.0`.
+    Percentile(f64),
+
+    /// Smallest value of a numeric field.
+    Min,
+
+    /// Largest value of a numeric field.
+    Max,
+
+    /// The whole event with the earliest timestamp.
+    First,
+
+    /// The whole event with the latest timestamp.
+    Last,
+
+    /// Increase of a cumulative numeric field, like a byte counter, over a time bucket.
+    Delta,
+
+    /// Increase of a cumulative numeric field per second over a time bucket.
+    Rate,
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "count" => Ok(Aggregation::Count),
+            "sum" => Ok(Aggregation::Sum),
+            "avg" => Ok(Aggregation::Avg),
+            "min" => Ok(Aggregation::Min),
+            "max" => Ok(Aggregation::Max),
+            "first" => Ok(Aggregation::First),
+            "last" => Ok(Aggregation::Last),
+            "delta" => Ok(Aggregation::Delta),
+            "rate" => Ok(Aggregation::Rate),
+            _ => {
+                let percentile = s
+                    .strip_prefix('p')
+                    .and_then(|percentile| percentile.parse::<f64>().ok())
+                    .filter(|percentile| (0.0..=100.0).contains(percentile))
+                    .ok_or_else(|| format!("Unknown aggregation: '{s}'"))?;
+                Ok(Aggregation::Percentile(percentile))
+            }
+        }
+    }
+}
+
+impl Aggregation {
+    fn name(&self) -> String {
+        match self {
+            Aggregation::Count => "count".to_string(),
+            Aggregation::Sum => "sum".to_string(),
+            Aggregation::Avg => "avg".to_string(),
+            Aggregation::Percentile(percentile) => format!("p{percentile}"),
+            Aggregation::Min => "min".to_string(),
+            Aggregation::Max => "max".to_string(),
+            Aggregation::First => "first".to_string(),
+            Aggregation::Last => "last".to_string(),
+            Aggregation::Delta => "delta".to_string(),
+            Aggregation::Rate => "rate".to_string(),
+        }
+    }
+
+    /// Whether the aggregation is computed over a numeric field.
+    fn needs_field(&self) -> bool {
+        !matches!(
+            self,
+            Aggregation::Count | Aggregation::First | Aggregation::Last
+        )
+    }
+}
+
+/// Groups events by a field and aggregates each group.
+#[derive(Debug, Clone)]
+pub struct AggregateQuery {
+    /// Events are grouped by the value of this field. All events are one group if not set.
+    pub group_by: Option<FieldPath>,
+
+    /// Numeric field aggregated by the functions other than `count`, `first` and `last`.
+    pub field: Option<FieldPath>,
+
+    pub aggregations: Vec<Aggregation>,
+
+    /// Groups are split into time buckets of this many seconds, if set.
+    pub bucket: Option<Timestamp>,
+}
+
+impl AggregateQuery {
+    /// Parses a query from request parameters. Aggregations are comma separated.
+    pub fn parse(
+        group_by: Option<&str>,
+        aggregations: &str,
+        field: Option<&str>,
+        bucket: Option<Timestamp>,
+    ) -> Result<Self, String> {
+        let aggregations = aggregations
+            .split(',')
+            .map(|aggregation| aggregation.trim().parse())
+            .collect::<Result<Vec<Aggregation>, _>>()?;
+        let field: Option<FieldPath> = field.map(str::parse).transpose()?;
+        if field.is_none()
+            && let Some(aggregation) = aggregations
+                .iter()
+                .find(|aggregation| aggregation.needs_field())
+        {
+            return Err(format!(
+                "Aggregation '{}' requires a field",
+                aggregation.name()
+            ));
+        }
+        if bucket == Some(0) {
+            return Err("Bucket must be at least a second".to_string());
+        }
+        if bucket.is_none()
+            && let Some(aggregation) = aggregations
+                .iter()
+                .find(|aggregation| matches!(aggregation, Aggregation::Delta | Aggregation::Rate))
+        {
+            return Err(format!(
+                "Aggregation '{}' requires a bucket",
+                aggregation.name()
+            ));
+        }
+        Ok(Self {
+            group_by: group_by.map(str::parse).transpose()?,
+            field,
+            aggregations,
+            bucket,
+        })
+    }
+
+    /// Whether the query only counts events by type, which storage can do on its indexes.
+    pub fn counts_by_type(&self) -> bool {
+        self.group_by == Some(FieldPath::EventType)
+            && self.aggregations == [Aggregation::Count]
+            && self.bucket.is_none()
+    }
+}
+
+/// Aggregates of the events with the same value of the grouping field, in the same bucket.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AggregateGroup {
+    /// Value of the grouping field, null for events without it.
+    key: serde_json::Value,
+
+    /// Start of the time bucket, if the query has buckets.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bucket: Option<Timestamp>,
+
+    /// Result of each aggregation by its name.
+    #[serde(flatten)]
+    values: BTreeMap<String, serde_json::Value>,
+
+    /// Number of events in the group, which privacy thresholds apply to.
+    #[serde(skip)]
+    count: u64,
+}
+
+/// Running totals of a group, so events don't have to be kept in memory.
+#[derive(Default)]
+struct Accumulator {
+    count: u64,
+    sum: f64,
+
+    /// Number of events with a numeric field.
+    numeric_count: u64,
+
+    min: Option<f64>,
+    max: Option<f64>,
+
+    /// Timestamp and value of the field in the earliest and the latest event.
+    first_value: Option<(Timestamp, f64)>,
+    last_value: Option<(Timestamp, f64)>,
+
+    /// Increase of the field over the bucket and its rate, set once all buckets are known.
+    delta: Option<f64>,
+    rate: Option<f64>,
+
+    /// Sketch of the field's distribution, if percentiles are requested.
+    sketch: Option<Sketch>,
+
+    /// Whether the first and last events are kept, as copying them isn't free.
+    keep_events: bool,
+    first: Option<Event>,
+    last: Option<Event>,
+}
+
+/// A t-digest sketch, estimating percentiles in constant memory.
+struct Sketch {
+    digest: TDigest,
+    buffer: Vec<f64>,
+}
+
+impl Sketch {
+    fn add(&mut self, value: f64) {
+        self.buffer.push(value);
+        if self.buffer.len() >= DIGEST_BUFFER_SIZE {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.buffer.is_empty() {
+            self.digest = self.digest.merge_unsorted(std::mem::take(&mut self.buffer));
+        }
+    }
+}
+
+impl Accumulator {
+    fn new(query: &AggregateQuery) -> Self {
+        let requested = |f: fn(&Aggregation) -> bool| query.aggregations.iter().any(f);
+        let percentiles =
+            requested(|aggregation| matches!(aggregation, Aggregation::Percentile(_)));
+        Self {
+            sketch: percentiles.then(|| Sketch {
+                digest: TDigest::new_with_size(DIGEST_SIZE),
+                buffer: vec![],
+            }),
+            keep_events: requested(|aggregation| {
+                matches!(aggregation, Aggregation::First | Aggregation::Last)
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, event: &Event, field: Option<&FieldPath>) {
+        // A coalesced event stands for `count` identical events.
+        let count = event.count.unwrap_or(1);
+        self.count += count;
+        if let Some(value) = field.and_then(|field| field.get(event).as_f64()) {
+            self.sum += value * count as f64;
+            self.numeric_count += count;
+            self.min = Some(self.min.map_or(value, |min| min.min(value)));
+            self.max = Some(self.max.map_or(value, |max| max.max(value)));
+            let sample = (event.timestamp, value);
+            if self
+                .first_value
+                .is_none_or(|(first, _)| event.timestamp < first)
+            {
+                self.first_value = Some(sample);
+            }
+            if self
+                .last_value
+                .is_none_or(|(last, _)| event.timestamp >= last)
+            {
+                self.last_value = Some(sample);
+            }
+            if let Some(sketch) = &mut self.sketch {
+                for _ in 0..count {
+                    sketch.add(value);
+                }
+            }
+        }
+        if self.keep_events {
+            // Of events with the same timestamp, the first and the last stored one are kept.
+            if self
+                .first
+                .as_ref()
+                .is_none_or(|first| event.timestamp < first.timestamp)
+            {
+                self.first = Some(event.clone());
+            }
+            if self
+                .last
+                .as_ref()
+                .is_none_or(|last| event.timestamp >= last.timestamp)
+            {
+                self.last = Some(event.clone());
+            }
+        }
+    }
+
+    /// Merges the buffered values into the sketch, before results are read.
+    fn flush(&mut self) {
+        if let Some(sketch) = &mut self.sketch {
+            sketch.flush();
+        }
+    }
+
+    fn result(&self, aggregation: Aggregation) -> serde_json::Value {
+        match aggregation {
+            Aggregation::Count => self.count.into(),
+            Aggregation::Sum => self.sum.into(),
+            Aggregation::First => event_value(self.first.as_ref()),
+            Aggregation::Last => event_value(self.last.as_ref()),
+            _ if self.numeric_count == 0 => serde_json::Value::Null,
+            Aggregation::Avg => (self.sum / self.numeric_count as f64).into(),
+            Aggregation::Min => self.min.into(),
+            Aggregation::Max => self.max.into(),
+            Aggregation::Delta => self.delta.into(),
+            Aggregation::Rate => self.rate.into(),
+            Aggregation::Percentile(percentile) => match &self.sketch {
+                Some(sketch) => sketch.digest.estimate_quantile(percentile / 100.0).into(),
+                None => serde_json::Value::Null,
+            },
+        }
+    }
+}
+
+fn event_value(event: Option<&Event>) -> serde_json::Value {
+    serde_json::to_value(event).expect("Events always serialize")
+}
+
+/// Aggregates events in a single pass, as they are added. Only the running totals of each
+/// group are kept, so events can be fed in pages of any size.
+pub struct Aggregator<'a> {
+    query: &'a AggregateQuery,
+
+    /// Keyed by the JSON text of the group key, as JSON values aren't ordered.
+    groups: BTreeMap<(Option<Timestamp>, String), (serde_json::Value, Accumulator)>,
+}
+
+impl<'a> Aggregator<'a> {
+    pub fn new(query: &'a AggregateQuery) -> Self {
+        Self {
+            query,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, event: &Event) {
+        let query = self.query;
+        let key = match &query.group_by {
+            Some(group_by) => group_by.get(event),
+            None => serde_json::Value::Null,
+        };
+        let bucket = query.bucket.map(|bucket| event.timestamp / bucket * bucket);
+        self.groups
+            .entry((bucket, key.to_string()))
+            .or_insert_with(|| (key, Accumulator::new(query)))
+            .1
+            .add(event, query.field.as_ref());
+    }
+
+    /// Returns the groups, ordered by their bucket and key.
+    pub fn finish(self) -> Vec<AggregateGroup> {
+        let query = self.query;
+        // Bucket and last field value of the previous bucket of each group, for deltas.
+        let mut previous: BTreeMap<String, (Timestamp, f64)> = BTreeMap::new();
+        self.groups
+            .into_iter()
+            .map(|((bucket, key_text), (key, mut accumulator))| {
+                accumulator.flush();
+                if let (Some(bucket), Some((_, last))) = (bucket, accumulator.last_value) {
+                    let since = previous.insert(key_text, (bucket, last));
+                    (accumulator.delta, accumulator.rate) =
+                        delta_and_rate(accumulator.first_value, since, bucket, last, query.bucket);
+                }
+                AggregateGroup {
+                    key,
+                    bucket,
+                    values: query
+                        .aggregations
+                        .iter()
+                        .map(|aggregation| (aggregation.name(), accumulator.result(*aggregation)))
+                        .collect(),
+                    count: accumulator.count,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Aggregates events in a single pass. Groups are ordered by their bucket and key.
+#[cfg(test)]
+pub fn aggregate(
+    events: impl IntoIterator<Item = Event>,
+    query: &AggregateQuery,
+) -> Vec<AggregateGroup> {
+    let mut aggregator = Aggregator::new(query);
+    for event in events {
+        aggregator.add(&event);
+    }
+    aggregator.finish()
+}
+
+/// Returns the groups of a query counting events by type, from the number of events of each
+/// type, like `aggregate` would.
+pub fn type_count_groups(counts: impl IntoIterator<Item = (String, u64)>) -> Vec<AggregateGroup> {
+    counts
+        .into_iter()
+        .map(|(event_type, count)| AggregateGroup {
+            key: event_type.into(),
+            bucket: None,
+            values: BTreeMap::from([(Aggregation::Count.name(), count.into())]),
+            count,
+        })
+        .collect()
+}
+
+/// Makes aggregates safer to share with less trusted consumers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrivacyConfig {
+    /// Groups of fewer events are left out, so no group singles out a few events.
+    pub min_group_size: u64,
+
+    /// Privacy budget of the noise added to counts. Smaller is more private but less accurate.
+    /// Counts are exact if not set.
+    pub epsilon: Option<f64>,
+}
+
+impl PrivacyConfig {
+    /// Checks that a query doesn't return values of single events, which thresholds and noise
+    /// don't protect.
+    pub fn check(&self, query: &AggregateQuery) -> Result<(), String> {
+        match query.aggregations.iter().find(|aggregation| {
+            matches!(
+                aggregation,
+                Aggregation::First | Aggregation::Last | Aggregation::Min | Aggregation::Max
+            )
+        }) {
+            Some(aggregation) => Err(format!(
+                "Aggregation '{}' isn't allowed in privacy mode",
+                aggregation.name()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Leaves out the groups with fewer events than the minimum, and adds Laplace noise of
+    /// scale `1 / epsilon` to the counts, as one event changes a count by at most one.
+    pub fn apply(&self, groups: Vec<AggregateGroup>) -> Vec<AggregateGroup> {
+        let count = Aggregation::Count.name();
+        groups
+            .into_iter()
+            .filter(|group| group.count >= self.min_group_size)
+            .map(|mut group| {
+                if let Some(epsilon) = self.epsilon
+                    && let Some(value) = group.values.get_mut(&count)
+                {
+                    let noisy = (group.count as f64 + laplace(1.0 / epsilon)).round();
+                    *value = serde_json::json!(noisy.max(0.0) as u64);
+                }
+                group
+            })
+            .collect()
+    }
+}
+
+/// Samples Laplace noise centered on zero, as the difference of two exponential variables.
+fn laplace(scale: f64) -> f64 {
+    let exponential = || -(1.0 - rand::random::<f64>()).ln();
+    scale * (exponential() - exponential())
+}
+
+/// Computes the increase of a cumulative field in a bucket, and its rate per second.
+///
+/// The increase is counted from the last value in the previous bucket of the group, or from
+/// the first value in the bucket if there is none. A decrease means the counter was reset, so
+/// the increase is the last value itself.
+fn delta_and_rate(
+    first: Option<(Timestamp, f64)>,
+    previous: Option<(Timestamp, f64)>,
+    bucket: Timestamp,
+    last: f64,
+    bucket_length: Option<Timestamp>,
+) -> (Option<f64>, Option<f64>) {
+    let (since, base) = match (previous, first) {
+        (Some((previous_bucket, previous_last)), _) => (previous_bucket, previous_last),
+        (None, Some((_, first))) => (bucket, first),
+        (None, None) => return (None, None),
+    };
+    let delta = if last < base { last } else { last - base };
+    // Skipped buckets without events are part of the interval.
+    let seconds = (bucket - since).max(bucket_length.unwrap_or(1));
+    (Some(delta), Some(delta / seconds as f64))
+}
+
+/// Aggregates of a group in two time ranges, like this week and last week.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct GroupComparison {
+    key: serde_json::Value,
+
+    /// Start of the time bucket in the current range, if the query has buckets.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bucket: Option<Timestamp>,
+
+    /// Results in the current range.
+    current: BTreeMap<String, serde_json::Value>,
+
+    /// Results in the same bucket of the previous range.
+    previous: BTreeMap<String, serde_json::Value>,
+
+    /// Percentage change of each numeric result, null if the previous result is zero or missing.
+    change: BTreeMap<String, Option<f64>>,
+}
+
+/// Pairs the groups of two ranges with the same key and bucket. The previous range's events
+/// must have been shifted into the current range before aggregating, so their buckets align.
+pub fn compare(
+    current: Vec<AggregateGroup>,
+    previous: Vec<AggregateGroup>,
+) -> Vec<GroupComparison> {
+    type Results = BTreeMap<String, serde_json::Value>;
+    let mut pairs: BTreeMap<(Option<Timestamp>, String), (serde_json::Value, [Results; 2])> =
+        BTreeMap::new();
+    for (index, groups) in [current, previous].into_iter().enumerate() {
+        for group in groups {
+            let (_, results) = pairs
+                .entry((group.bucket, group.key.to_string()))
+                .or_insert_with(|| (group.key, Default::default()));
+            results[index] = group.values;
+        }
+    }
+    pairs
+        .into_iter()
+        .map(|((bucket, _), (key, [current, previous]))| {
+            let change = current
+                .iter()
+                .filter_map(|(name, value)| {
+                    let value = value.as_f64()?;
+                    let change = previous
+                        .get(name)
+                        .and_then(|previous| previous.as_f64())
+                        .filter(|previous| *previous != 0.0)
+                        .map(|previous| (value - previous) / previous.abs() * 100.0);
+                    Some((name.clone(), change))
+                })
+                .collect();
+            GroupComparison {
+                key,
+                bucket,
+                current,
+                previous,
+                change,
+            }
+        })
+        .collect()
+}
+
+/// A time interval without events.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Gap {
+    /// Timestamp of the last event before the gap, or the start of the range.
+    start: Timestamp,
+
+    /// Timestamp of the first event after the gap, or the end of the range.
+    end: Timestamp,
+
+    duration: Timestamp,
+}
+
+/// Finds the intervals longer than `min_gap` seconds without events, e.g. outages in
+/// heartbeats. The range bounds count as events, so gaps at the edges of the range are found,
+/// too.
+pub fn find_gaps(
+    mut timestamps: Vec<Timestamp>,
+    start: Option<Timestamp>,
+    end: Option<Timestamp>,
+    min_gap: Timestamp,
+) -> Vec<Gap> {
+    timestamps.extend(start);
+    timestamps.extend(end);
+    timestamps.sort_unstable();
+    timestamps
+        .windows(2)
+        .filter(|pair| pair[1] - pair[0] > min_gap)
+        .map(|pair| Gap {
+            start: pair[0],
+            end: pair[1],
+            duration: pair[1] - pair[0],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate() {
+        let purchase = |country: &str, amount: serde_json::Value| Event {
+            event_type: "purchase".to_string(),
+            payload: serde_json::json!({"country": country, "amount": amount}),
+            ..Default::default()
+        };
+        let events = vec![
+            purchase("HU", 10.into()),
+            purchase("HU", 20.into()),
+            purchase("DE", "n/a".into()),
+        ];
+        let query = AggregateQuery::parse(
+            Some("payload.country"),
+            "count,sum,avg",
+            Some("payload.amount"),
+            None,
+        )
+        .unwrap();
+
+        let groups = aggregate(events, &query);
+        assert_eq!(
+            serde_json::to_value(groups).unwrap(),
+            serde_json::json!([
+                {"key": "DE", "count": 1, "sum": 0.0, "avg": null},
+                {"key": "HU", "count": 2, "sum": 30.0, "avg": 15.0},
+            ])
+        );
+
+        assert!(AggregateQuery::parse(None, "sum", None, None).is_err());
+        assert!(AggregateQuery::parse(Some("country"), "count", None, None).is_err());
+        assert!(AggregateQuery::parse(None, "median", None, None).is_err());
+        assert!(AggregateQuery::parse(None, "p101", Some("payload.amount"), None).is_err());
+    }
+
+    #[test]
+    fn test_min_max_first_last() {
+        let config_change = |timestamp, version: u64| Event {
+            event_type: "config_change".to_string(),
+            timestamp,
+            payload: serde_json::json!({"version": version}),
+            ..Default::default()
+        };
+        let events = vec![
+            config_change(20, 3),
+            config_change(10, 7),
+            config_change(30, 5),
+        ];
+        let query =
+            AggregateQuery::parse(None, "min,max,first,last", Some("payload.version"), None)
+                .unwrap();
+
+        let groups = aggregate(events.clone(), &query);
+        assert_eq!(groups[0].values["min"], 3.0);
+        assert_eq!(groups[0].values["max"], 7.0);
+        assert_eq!(
+            groups[0].values["first"],
+            serde_json::to_value(&events[1]).unwrap()
+        );
+        assert_eq!(
+            groups[0].values["last"],
+            serde_json::to_value(&events[2]).unwrap()
+        );
+        assert!(AggregateQuery::parse(None, "last", None, None).is_ok());
+        assert!(AggregateQuery::parse(None, "max", None, None).is_err());
+    }
+
+    #[test]
+    fn test_delta_and_rate() {
+        let bytes = |timestamp, total: u64| Event {
+            event_type: "traffic".to_string(),
+            timestamp,
+            payload: serde_json::json!({"bytes": total}),
+            ..Default::default()
+        };
+        // The counter is reset between 25 and 35.
+        let events = vec![bytes(0, 100), bytes(5, 200), bytes(15, 700), bytes(25, 900)];
+        let events = events.into_iter().chain([bytes(35, 50)]);
+        let query =
+            AggregateQuery::parse(None, "delta,rate", Some("payload.bytes"), Some(10)).unwrap();
+
+        let groups = aggregate(events, &query);
+        let deltas: Vec<_> = groups
+            .iter()
+            .map(|group| (group.bucket, group.values["delta"].as_f64().unwrap()))
+            .collect();
+        assert_eq!(
+            deltas,
+            [
+                (Some(0), 100.0),
+                (Some(10), 500.0),
+                (Some(20), 200.0),
+                (Some(30), 50.0)
+            ]
+        );
+        assert_eq!(groups[1].values["rate"], 50.0);
+        assert!(AggregateQuery::parse(None, "rate", Some("payload.bytes"), None).is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        let visit = |timestamp, page: &str| Event {
+            event_type: "visit".to_string(),
+            timestamp,
+            payload: serde_json::json!({"page": page}),
+            ..Default::default()
+        };
+        let query = AggregateQuery::parse(Some("payload.page"), "count", None, None).unwrap();
+        let current = aggregate(vec![visit(10, "a"), visit(11, "a"), visit(12, "b")], &query);
+        let previous = aggregate(vec![visit(1, "a"), visit(2, "c")], &query);
+
+        let comparison = compare(current, previous);
+        assert_eq!(
+            serde_json::to_value(comparison).unwrap(),
+            serde_json::json!([
+                {
+                    "key": "a",
+                    "current": {"count": 2},
+                    "previous": {"count": 1},
+                    "change": {"count": 100.0},
+                },
+                {"key": "b", "current": {"count": 1}, "previous": {}, "change": {"count": null}},
+                {"key": "c", "current": {}, "previous": {"count": 1}, "change": {}},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_privacy() {
+        let visit = |page: &str| Event {
+            event_type: "visit".to_string(),
+            payload: serde_json::json!({"page": page}),
+            ..Default::default()
+        };
+        let events: Vec<_> = ["a", "a", "a", "b"].into_iter().map(visit).collect();
+        let query = AggregateQuery::parse(Some("payload.page"), "count", None, None).unwrap();
+        let exact = PrivacyConfig {
+            min_group_size: 2,
+            epsilon: None,
+        };
+        assert_eq!(
+            serde_json::to_value(exact.apply(aggregate(events.clone(), &query))).unwrap(),
+            serde_json::json!([{"key": "a", "count": 3}])
+        );
+
+        let noisy = PrivacyConfig {
+            epsilon: Some(1.0),
+            ..exact
+        };
+        let counts: Vec<f64> = (0..1000)
+            .flat_map(|_| noisy.apply(aggregate(events.clone(), &query)))
+            .map(|group| group.values["count"].as_f64().unwrap())
+            .collect();
+        let mean = counts.iter().sum::<f64>() / counts.len() as f64;
+        assert!(counts.iter().any(|count| *count != 3.0));
+        assert!((mean - 3.0).abs() < 0.5, "mean is {mean}");
+
+        let last = AggregateQuery::parse(None, "count,last", None, None).unwrap();
+        assert!(exact.check(&query).is_ok());
+        assert!(exact.check(&last).is_err());
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let events = (0..2000).map(|n| Event {
+            event_type: "request".to_string(),
+            timestamp: n,
+            payload: serde_json::json!({"duration_ms": n % 1000}),
+            ..Default::default()
+        });
+        let query = AggregateQuery::parse(None, "p50,p99", Some("payload.duration_ms"), Some(1000))
+            .unwrap();
+
+        let groups = aggregate(events, &query);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[1].bucket, Some(1000));
+        let p50 = groups[1].values["p50"].as_f64().unwrap();
+        let p99 = groups[1].values["p99"].as_f64().unwrap();
+        assert!((p50 - 500.0).abs() < 20.0, "p50 is {p50}");
+        assert!((p99 - 990.0).abs() < 20.0, "p99 is {p99}");
+    }
+
+    #[test]
+    fn test_find_gaps() {
+        let gap = |start, end| Gap {
+            start,
+            end,
+            duration: end - start,
+        };
+        let heartbeats = vec![130, 100, 110, 120, 200];
+        assert_eq!(
+            find_gaps(heartbeats.clone(), None, None, 30),
+            vec![gap(130, 200)]
+        );
+        assert_eq!(
+            find_gaps(heartbeats, Some(0), Some(300), 30),
+            vec![gap(0, 100), gap(130, 200), gap(200, 300)]
+        );
+        assert_eq!(find_gaps(vec![], Some(0), Some(10), 5), vec![gap(0, 10)]);
+    }
+}
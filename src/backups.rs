@@ -0,0 +1,198 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::{
+    event_snapshots::{EventSnapshotConfig, EventSnapshotError, EventSnapshots},
+    metrics, sentry,
+    snapshot_file::SnapshotFormat,
+    storage::Storage,
+};
+
+/// Prefix of the backup directory names, followed by the Unix time of the backup in millis.
+const BACKUP_PREFIX: &str = "backup-";
+
+/// Configures periodic backups of the event store.
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+    /// Local directory the backups are written to.
+    pub dir: PathBuf,
+
+    /// How often a backup is taken.
+    pub interval: Duration,
+
+    /// Number of most recent backups to keep.
+    pub keep_last: usize,
+
+    /// Backups older than this are deleted, except the most recent one.
+    pub max_age: Option<Duration>,
+
+    /// Compression and encryption of the backup files.
+    pub format: SnapshotFormat,
+}
+
+/// Takes a backup of the store and prunes old backups. Returns the directory of the backup.
+///
+/// Each backup is a directory holding a single full event snapshot, so it can be restored by
+/// starting the server with `EVENT_SNAPSHOT_DIR` pointing at it.
+pub async fn back_up(
+    store: &(dyn Storage + Send + Sync),
+    config: &BackupConfig,
+) -> Result<PathBuf, EventSnapshotError> {
+    let mut millis = now_millis();
+    let mut dir = config.dir.join(format!("{BACKUP_PREFIX}{millis}"));
+    while tokio::fs::try_exists(&dir).await? {
+        millis += 1;
+        dir = config.dir.join(format!("{BACKUP_PREFIX}{millis}"));
+    }
+
+    let snapshots = EventSnapshots::new(EventSnapshotConfig {
+        dir: dir.clone(),
+        interval: config.interval,
+        max_chain_length: 1,
+        format: config.format.clone(),
+    });
+    let link = snapshots.take(store, true).await?;
+    info!("Backed up {} events to {}", link.events, dir.display());
+
+    prune(config).await?;
+    Ok(dir)
+}
+
+/// Deletes backups according to the retention policy.
+async fn prune(config: &BackupConfig) -> Result<(), EventSnapshotError> {
+    let backups = list_backups(&config.dir).await?;
+    let now = now_millis();
+    let max_age_millis = config.max_age.map(|max_age| max_age.as_millis() as u64);
+    // Backups are listed newest first.
+    for (index, (millis, path)) in backups.into_iter().enumerate() {
+        let expired = max_age_millis.is_some_and(|max_age| now.saturating_sub(millis) > max_age);
+        if index >= config.keep_last.max(1) || (index > 0 && expired) {
+            info!("Deleting old backup {}", path.display());
+            tokio::fs::remove_dir_all(&path).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the backups in a directory with their Unix times in millis, newest first.
+async fn list_backups(dir: &Path) -> Result<Vec<(u64, PathBuf)>, EventSnapshotError> {
+    let mut backups = vec![];
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(backups),
+        Err(error) => return Err(error.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let millis = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(BACKUP_PREFIX))
+            .and_then(|millis| millis.parse().ok());
+        if let Some(millis) = millis {
+            backups.push((millis, entry.path()));
+        }
+    }
+    backups.sort_by(|a, b| b.cmp(a));
+    Ok(backups)
+}
+
+/// Takes backups periodically in the background, and reports the age of the last successful
+/// one in the `backup_age_seconds` metric so monitoring can alert when backups stop.
+pub fn spawn(
+    store: Arc<dyn Storage + Send + Sync + 'static>,
+    config: BackupConfig,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    tokio::spawn(async move {
+        let mut last_backup = match list_backups(&config.dir).await {
+            Ok(backups) => backups.first().map(|(millis, _)| *millis),
+            Err(error) => {
+                warn!("Failed to list backups: {error}");
+                None
+            }
+        };
+        let mut interval = tokio::time::interval(config.interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.wait_for(|shutting_down| *shutting_down) => break,
+            }
+            match back_up(store.as_ref(), &config).await {
+                Ok(_) => {
+                    last_backup = Some(now_millis());
+                    metrics::increment("backups_total", &[("outcome", "ok")]);
+                }
+                Err(error) => {
+                    error!("Backup failed: {error}");
+                    sentry::capture_task_failure("backups", &error);
+                    metrics::increment("backups_total", &[("outcome", "failed")]);
+                }
+            }
+            if let Some(last_backup) = last_backup {
+                let age = now_millis().saturating_sub(last_backup) as f64 / 1000.0;
+                metrics::set_gauge("backup_age_seconds", &[], age);
+            }
+        }
+    });
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        event::Event,
+        storage::{EventFilter, InMemoryStorage},
+    };
+
+    #[tokio::test]
+    async fn test_backups() {
+        let dir = std::env::temp_dir().join(format!("backups-{}", std::process::id()));
+        let config = BackupConfig {
+            dir: dir.clone(),
+            interval: Duration::from_secs(3600),
+            keep_last: 2,
+            max_age: None,
+            format: SnapshotFormat::default(),
+        };
+        let store = InMemoryStorage::new();
+        let event = Event {
+            event_type: "test".to_string(),
+            ..Default::default()
+        };
+        store.store(event).await.unwrap();
+
+        for _ in 0..3 {
+            back_up(&store, &config).await.unwrap();
+        }
+        let backups = list_backups(&dir).await.unwrap();
+        assert_eq!(backups.len(), 2);
+
+        let restored = InMemoryStorage::new();
+        let snapshots = EventSnapshots::new(EventSnapshotConfig {
+            dir: backups[0].1.clone(),
+            interval: config.interval,
+            max_chain_length: 1,
+            format: config.format,
+        });
+        assert_eq!(snapshots.restore(&restored).await.unwrap(), 1);
+        let all = EventFilter::default();
+        assert_eq!(
+            restored.scan(&all, None).await.unwrap(),
+            store.scan(&all, None).await.unwrap()
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,397 @@
+use ahash::{AHashMap, AHashSet};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::event::{Event, Timestamp};
+
+/// Event type of the events reporting anomalous clients.
+pub const ANOMALY_EVENT_TYPE: &str = "client_anomaly";
+
+/// Clients tracked before the idle ones are forgotten.
+const MAX_CLIENTS: usize = 10_000;
+
+/// Windows a client may be idle for before it can be forgotten.
+const IDLE_WINDOWS: u32 = 10;
+
+/// Fewest events in a window to judge its event types and payloads on.
+const MIN_SAMPLE: u64 = 20;
+
+/// Weight of the latest window in the baselines.
+const SMOOTHING: f64 = 0.3;
+
+/// Configures the detection of clients whose ingest traffic stops looking like their own, eg.
+/// because their API key was stolen.
+#[derive(Debug, Clone)]
+pub struct FingerprintConfig {
+    /// Length of the windows the traffic of a client is summarized in.
+    pub window: Duration,
+
+    /// Windows of a client learned before it can be flagged.
+    pub warm_up: u32,
+
+    /// How many times its usual number of events a client must send in a window to be flagged.
+    pub rate_factor: f64,
+
+    /// Fewest events in a window to flag its rate, so quiet clients aren't flagged for a few
+    /// more events than usual.
+    pub min_events: u64,
+
+    /// Share of a window's events of types the client never sent before to be flagged.
+    pub new_types_share: f64,
+
+    /// Change of the average payload entropy in bits per byte to be flagged, eg. when random or
+    /// encrypted blobs replace the usual payloads.
+    pub entropy_shift: f64,
+}
+
+impl Default for FingerprintConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60),
+            warm_up: 5,
+            rate_factor: 5.0,
+            min_events: 100,
+            new_types_share: 0.5,
+            entropy_shift: 1.0,
+        }
+    }
+}
+
+/// What differed in an anomalous window from the client's baseline.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, strum::AsRefStr)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum AnomalyReason {
+    /// Far more events than usual.
+    Rate,
+
+    /// Mostly events of types the client never sent before.
+    EventTypes,
+
+    /// Payloads of a different entropy than usual.
+    Entropy,
+}
+
+/// A window of a client's traffic that differed from its baseline.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Anomaly {
+    pub client: String,
+    pub reasons: Vec<AnomalyReason>,
+
+    /// Events of the window, and the usual number per window.
+    pub events: u64,
+    pub baseline_events: f64,
+
+    /// Share of the window's events of types the client never sent before.
+    pub new_types_share: f64,
+
+    /// Average entropy of the window's payloads in bits per byte, and the usual one.
+    pub entropy: f64,
+    pub baseline_entropy: f64,
+}
+
+impl Anomaly {
+    /// Returns the event reporting the anomaly, correlated by the client.
+    pub fn to_event(&self, timestamp: Timestamp) -> Event {
+        Event {
+            event_type: ANOMALY_EVENT_TYPE.to_string(),
+            timestamp,
+            payload: serde_json::to_value(self).unwrap_or_default(),
+            correlation_id: Some(self.client.clone()),
+            count: None,
+            dedup_id: None,
+        }
+    }
+}
+
+/// The ingest pattern of a client, as reported by `GET /admin/clients`.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ClientFingerprint {
+    pub client: String,
+
+    /// Windows learned into the baseline.
+    pub windows: u32,
+
+    /// Usual number of events per window.
+    pub baseline_events: f64,
+
+    /// Usual average entropy of payloads in bits per byte.
+    pub baseline_entropy: f64,
+
+    /// Event types the client sent, sorted.
+    pub event_types: Vec<String>,
+
+    /// Events of the current window.
+    pub events: u64,
+
+    /// Windows flagged as anomalous since startup.
+    pub anomalies: u64,
+}
+
+/// The traffic of a client in a window.
+#[derive(Default)]
+struct Window {
+    events: u64,
+    event_types: AHashMap<String, u64>,
+    entropy_sum: f64,
+}
+
+impl Window {
+    fn record(&mut self, event: &Event) {
+        self.events += 1;
+        *self
+            .event_types
+            .entry(event.event_type.clone())
+            .or_default() += 1;
+        self.entropy_sum += entropy(&event.payload);
+    }
+}
+
+struct Profile {
+    started: Instant,
+    current: Window,
+    windows: u32,
+    baseline_events: f64,
+    baseline_entropy: f64,
+    event_types: AHashSet<String>,
+    anomalies: u64,
+}
+
+/// Learns the ingest pattern of each client, by its API key or address: its number of events,
+/// the types of its events, and the entropy of its payloads. Windows that differ from the
+/// pattern are reported as anomalies.
+pub struct ClientFingerprints {
+    config: FingerprintConfig,
+    clients: Mutex<AHashMap<String, Profile>>,
+}
+
+impl ClientFingerprints {
+    pub fn new(config: FingerprintConfig) -> Self {
+        Self {
+            config,
+            clients: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    /// Records events sent by a client. The first request after a window of the client is over
+    /// closes it: the window is compared to the client's baseline, then learned into it. Returns
+    /// the anomaly if the window differed.
+    ///
+    /// Anomalous windows are learned too, so a lasting change, like a producer scaled up, stops
+    /// being flagged after a few windows.
+    pub fn observe<'a>(
+        &self,
+        client: &str,
+        events: impl IntoIterator<Item = &'a Event>,
+        now: Instant,
+    ) -> Option<Anomaly> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(client) {
+            let idle = self.config.window * IDLE_WINDOWS;
+            clients.retain(|_, profile| now.duration_since(profile.started) < idle);
+        }
+        let profile = clients
+            .entry(client.to_string())
+            .or_insert_with(|| Profile {
+                started: now,
+                current: Window::default(),
+                windows: 0,
+                baseline_events: 0.0,
+                baseline_entropy: 0.0,
+                event_types: AHashSet::new(),
+                anomalies: 0,
+            });
+        let mut anomaly = None;
+        if now.duration_since(profile.started) >= self.config.window {
+            anomaly = self.close_window(client, profile);
+            profile.started = now;
+        }
+        for event in events {
+            profile.current.record(event);
+        }
+        anomaly
+    }
+
+    /// Returns the ingest patterns of the tracked clients, sorted by client.
+    pub fn fingerprints(&self) -> Vec<ClientFingerprint> {
+        let clients = self.clients.lock().unwrap();
+        let mut fingerprints: Vec<_> = clients
+            .iter()
+            .map(|(client, profile)| {
+                let mut event_types: Vec<_> = profile.event_types.iter().cloned().collect();
+                event_types.sort();
+                ClientFingerprint {
+                    client: client.clone(),
+                    windows: profile.windows,
+                    baseline_events: profile.baseline_events,
+                    baseline_entropy: profile.baseline_entropy,
+                    event_types,
+                    events: profile.current.events,
+                    anomalies: profile.anomalies,
+                }
+            })
+            .collect();
+        fingerprints.sort_by(|a, b| a.client.cmp(&b.client));
+        fingerprints
+    }
+
+    fn close_window(&self, client: &str, profile: &mut Profile) -> Option<Anomaly> {
+        let window = std::mem::take(&mut profile.current);
+        if window.events == 0 {
+            return None;
+        }
+        let events = window.events as f64;
+        let entropy = window.entropy_sum / events;
+        let new_types: u64 = window
+            .event_types
+            .iter()
+            .filter(|(event_type, _)| !profile.event_types.contains(*event_type))
+            .map(|(_, count)| count)
+            .sum();
+        let new_types_share = new_types as f64 / events;
+
+        let mut reasons = vec![];
+        if profile.windows >= self.config.warm_up {
+            if window.events >= self.config.min_events
+                && events > profile.baseline_events * self.config.rate_factor
+            {
+                reasons.push(AnomalyReason::Rate);
+            }
+            if window.events >= MIN_SAMPLE {
+                if new_types_share > self.config.new_types_share {
+                    reasons.push(AnomalyReason::EventTypes);
+                }
+                if (entropy - profile.baseline_entropy).abs() > self.config.entropy_shift {
+                    reasons.push(AnomalyReason::Entropy);
+                }
+            }
+        }
+        let anomaly = (!reasons.is_empty()).then(|| Anomaly {
+            client: client.to_string(),
+            reasons,
+            events: window.events,
+            baseline_events: profile.baseline_events,
+            new_types_share,
+            entropy,
+            baseline_entropy: profile.baseline_entropy,
+        });
+        if anomaly.is_some() {
+            profile.anomalies += 1;
+        }
+
+        if profile.windows == 0 {
+            profile.baseline_events = events;
+            profile.baseline_entropy = entropy;
+        } else {
+            profile.baseline_events += SMOOTHING * (events - profile.baseline_events);
+            profile.baseline_entropy += SMOOTHING * (entropy - profile.baseline_entropy);
+        }
+        profile.windows = profile.windows.saturating_add(1);
+        profile.event_types.extend(window.event_types.into_keys());
+        anomaly
+    }
+}
+
+/// Returns the Shannon entropy of a serialized payload in bits per byte, from 0 for a single
+/// repeated byte up to 8 for random bytes.
+fn entropy(payload: &Value) -> f64 {
+    let bytes = serde_json::to_vec(payload).unwrap_or_default();
+    if bytes.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0u32; 256];
+    for byte in &bytes {
+        counts[*byte as usize] += 1;
+    }
+    let len = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event(event_type: &str, payload: Value) -> Event {
+        Event {
+            event_type: event_type.to_string(),
+            timestamp: 1,
+            payload,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_client_fingerprints() {
+        let fingerprints = ClientFingerprints::new(FingerprintConfig {
+            window: Duration::from_secs(60),
+            warm_up: 2,
+            rate_factor: 3.0,
+            min_events: 30,
+            ..Default::default()
+        });
+        let page_views: Vec<_> = (0..20)
+            .map(|n| event("page_view", json!({"page": format!("/products/{n}")})))
+            .collect();
+        let start = Instant::now();
+        let window = |n: u32| start + Duration::from_secs(60) * n;
+
+        // Learns the usual traffic without flagging it.
+        for n in 0..3 {
+            let anomaly = fingerprints.observe("key:shop", &page_views, window(n));
+            assert_eq!(anomaly, None);
+        }
+        let fingerprint = &fingerprints.fingerprints()[0];
+        assert_eq!(fingerprint.windows, 2);
+        assert_eq!(fingerprint.baseline_events, 20.0);
+        assert_eq!(fingerprint.event_types, vec!["page_view"]);
+
+        // A burst of unusual events with random-looking payloads.
+        let blobs: Vec<_> = (0..100u32)
+            .map(|n| {
+                let blob: String = (0..64)
+                    .map(|i| char::from(b'!' + ((n * 31 + i * 17) % 90) as u8))
+                    .collect();
+                event("export", json!(blob))
+            })
+            .collect();
+        assert_eq!(fingerprints.observe("key:shop", &blobs, window(3)), None);
+        let anomaly = fingerprints.observe("key:shop", &page_views, window(4));
+        let anomaly = anomaly.unwrap();
+        assert_eq!(
+            anomaly.reasons,
+            vec![
+                AnomalyReason::Rate,
+                AnomalyReason::EventTypes,
+                AnomalyReason::Entropy
+            ]
+        );
+        assert_eq!(anomaly.events, 100);
+        assert_eq!(anomaly.new_types_share, 1.0);
+        assert_eq!(
+            anomaly.to_event(1).correlation_id.as_deref(),
+            Some("key:shop")
+        );
+
+        // Other clients are learned on their own.
+        assert_eq!(fingerprints.observe("ip:10.0.0.1", &blobs, window(5)), None);
+        assert_eq!(fingerprints.fingerprints()[1].anomalies, 1);
+    }
+
+    #[test]
+    fn test_entropy() {
+        assert_eq!(entropy(&json!(1111)), 0.0);
+        assert_eq!(entropy(&json!(1234)), 2.0);
+    }
+}
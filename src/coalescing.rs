@@ -0,0 +1,118 @@
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::event::{Event, EventId, Timestamp};
+
+/// Number of tracked bursts above which expired ones are pruned.
+const MAX_TRACKED_BURSTS: usize = 10_000;
+
+/// Merges bursts of identical events of a type into a single event with a `count`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct CoalescingRule {
+    pub event_type: String,
+
+    /// Events with the same payload within this many seconds of the first event of a burst are
+    /// merged into it.
+    pub window: Timestamp,
+}
+
+/// A stored event that identical events are merged into.
+struct Burst {
+    event_id: EventId,
+    first_timestamp: Timestamp,
+}
+
+/// Bursts by event type and serialized payload.
+#[derive(Default)]
+pub struct Bursts {
+    bursts: AHashMap<(String, String), Burst>,
+}
+
+impl Bursts {
+    /// Returns the stored event the given event should be merged into, if there is one.
+    pub fn find(&self, event: &Event, window: Timestamp) -> Option<EventId> {
+        let burst = self.bursts.get(&burst_key(event))?;
+        (event.timestamp.abs_diff(burst.first_timestamp) <= window).then_some(burst.event_id)
+    }
+
+    /// Starts a new burst with a stored event.
+    pub fn start(&mut self, event: &Event, event_id: EventId, window: Timestamp) {
+        if self.bursts.len() >= MAX_TRACKED_BURSTS {
+            self.bursts
+                .retain(|_, burst| event.timestamp.abs_diff(burst.first_timestamp) <= window);
+        }
+        let burst = Burst {
+            event_id,
+            first_timestamp: event.timestamp,
+        };
+        self.bursts.insert(burst_key(event), burst);
+    }
+}
+
+/// Coalescing rules and the bursts currently being merged.
+pub struct Coalescer {
+    rules: std::sync::RwLock<Vec<CoalescingRule>>,
+    bursts: Mutex<Bursts>,
+}
+
+impl Coalescer {
+    pub fn new(rules: Vec<CoalescingRule>) -> Self {
+        Self {
+            rules: std::sync::RwLock::new(rules),
+            bursts: Mutex::new(Bursts::default()),
+        }
+    }
+
+    pub fn rules(&self) -> Vec<CoalescingRule> {
+        self.rules.read().unwrap().clone()
+    }
+
+    pub fn set_rules(&self, rules: Vec<CoalescingRule>) {
+        *self.rules.write().unwrap() = rules;
+    }
+
+    /// Returns the coalescing window of an event type, if it has a rule.
+    pub fn window(&self, event_type: &str) -> Option<Timestamp> {
+        let rules = self.rules.read().unwrap();
+        let rule = rules.iter().find(|rule| rule.event_type == event_type)?;
+        Some(rule.window)
+    }
+
+    /// Locks the bursts. Ingest holds the lock while storing so that concurrent identical
+    /// events end up in the same burst.
+    pub async fn lock(&self) -> MutexGuard<'_, Bursts> {
+        self.bursts.lock().await
+    }
+}
+
+fn burst_key(event: &Event) -> (String, String) {
+    (event.event_type.clone(), event.payload.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bursts() {
+        let mut bursts = Bursts::default();
+        let heartbeat = |timestamp| Event {
+            event_type: "heartbeat".to_string(),
+            timestamp,
+            payload: serde_json::json!({ "host": "a" }),
+            ..Default::default()
+        };
+
+        assert_eq!(bursts.find(&heartbeat(1), 10), None);
+        bursts.start(&heartbeat(1), 42, 10);
+        assert_eq!(bursts.find(&heartbeat(5), 10), Some(42));
+        assert_eq!(bursts.find(&heartbeat(20), 10), None);
+
+        let other_host = Event {
+            payload: serde_json::json!({ "host": "b" }),
+            ..heartbeat(5)
+        };
+        assert_eq!(bursts.find(&other_host, 10), None);
+    }
+}
@@ -0,0 +1,701 @@
+use anyhow::{Context, Result, anyhow};
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    analytics::PrivacyConfig,
+    backups::BackupConfig,
+    client_fingerprints::FingerprintConfig,
+    coalescing::CoalescingRule,
+    crash_reports::CrashReportConfig,
+    dedup::DedupConfig,
+    discovery::DiscoveryConfig,
+    event_policy::EventTypePolicy,
+    event_snapshots::EventSnapshotConfig,
+    exports::ExportConfig,
+    health::HealthConfig,
+    hooks::{CountingHook, IngestHooks},
+    id_generator::IdStrategy,
+    oidc::OidcConfig,
+    payload_limits::PayloadLimits,
+    producers::ProducerConfig,
+    projections::{ProjectionDefinition, SnapshotConfig},
+    receipts::SigningKeys,
+    retention::RetentionConfig,
+    rollup::RollupConfig,
+    scripts::ScriptRule,
+    secrets::Secrets,
+    sentry::SentryConfig,
+    server::{
+        CompressionConfig, ConnectionConfig, IpNetwork, JwtConfig, JwtKey, OverflowPolicy,
+        QueryConcurrencyConfig, RateLimitConfig, SmoothingConfig, TlsConfig,
+    },
+    shadow::ShadowConfig,
+    snapshot_file::SnapshotFormat,
+    state_machine::StateMachineDefinition,
+    storage::{PostgresPoolConfig, ReplicaConfig, StorageBackend, WriteBatchConfig},
+};
+
+/// Server configuration.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    /// State machines interpreting events as entity state transitions.
+    pub state_machines: Vec<StateMachineDefinition>,
+
+    /// Read models folded from filtered event streams.
+    pub projections: Vec<ProjectionDefinition>,
+
+    /// Snapshots of projection states, so rebuilds don't have to replay every event.
+    pub projection_snapshots: SnapshotConfig,
+
+    /// TOML or YAML file declaring resources, loaded at startup and on reload.
+    pub resources_file: Option<PathBuf>,
+
+    /// Mirrors a share of the ingested events into a shadow backend.
+    pub shadow: Option<ShadowConfig>,
+
+    /// Where events are stored.
+    pub storage: StorageBackend,
+
+    /// Read replica of the storage, asked too when the storage is slow to answer a read.
+    pub replica: Option<ReplicaConfig>,
+
+    /// Write-ahead log of the in-memory storage, so events survive restarts.
+    pub wal_file: Option<PathBuf>,
+
+    /// How concurrent writes to database backends are grouped.
+    pub write_batch: WriteBatchConfig,
+
+    /// Connection pool of the PostgreSQL backend.
+    pub postgres_pool: PostgresPoolConfig,
+
+    /// PEM file of the CAs the PostgreSQL server's certificate must be signed by, to connect
+    /// over TLS.
+    pub postgres_ca_file: Option<PathBuf>,
+
+    /// How storage backends generate event ids.
+    pub id_strategy: IdStrategy,
+
+    /// Event types whose bursts of identical events are merged at ingest.
+    pub coalescing: Vec<CoalescingRule>,
+
+    /// Scripts raising alerts and deriving new events from stored events.
+    pub scripts: Vec<ScriptRule>,
+
+    /// Downsamples old raw events into aggregates.
+    pub rollup: Option<RollupConfig>,
+
+    /// Deletes events older than their TTL.
+    pub retention: Option<RetentionConfig>,
+
+    /// Self-checks of the storage backend.
+    pub health: HealthConfig,
+
+    /// Persists the event store as a chain of incremental snapshots, restored at startup.
+    pub event_snapshots: Option<EventSnapshotConfig>,
+
+    /// Periodic backups of the event store.
+    pub backups: Option<BackupConfig>,
+
+    /// Crash reports written when the server panics.
+    pub crash_reports: Option<CrashReportConfig>,
+
+    /// Sends server errors, panics and background task failures to Sentry.
+    pub sentry: Option<SentryConfig>,
+
+    /// Limits concurrent queries per API key.
+    pub query_concurrency: Option<QueryConcurrencyConfig>,
+
+    /// Limits the rate of ingest requests per API key.
+    pub ingest_rate_limit: Option<RateLimitConfig>,
+
+    /// Suppresses small groups and adds noise to counts in aggregates.
+    pub privacy: Option<PrivacyConfig>,
+
+    /// Object store destination of query result exports.
+    pub exports: Option<ExportConfig>,
+
+    /// File keeping the requests accepted during maintenance mode until they are applied.
+    pub outbox_file: Option<PathBuf>,
+
+    /// Audit trail of the legal holds, restored on startup.
+    pub legal_holds_file: Option<PathBuf>,
+
+    /// TOML or YAML file assigning roles to API keys and masking payload fields per role.
+    pub access_policy_file: Option<PathBuf>,
+
+    /// Rejects `/events` requests without an API key of the access policy, or whose role lacks
+    /// the scope of the request.
+    pub require_api_key: bool,
+
+    /// HTTP and TCP tuning of client connections.
+    pub connection: ConnectionConfig,
+
+    /// Compresses large responses. Not compressed if not set.
+    pub compression: Option<CompressionConfig>,
+
+    /// Port of the gRPC API, on the same address as the REST API. Not served if not set.
+    pub grpc_port: Option<u16>,
+
+    /// Proxies whose `Forwarded` and `X-Forwarded-For` headers are believed.
+    pub trusted_proxies: Vec<IpNetwork>,
+
+    /// Registers the instance with service discovery while it runs.
+    pub discovery: Option<DiscoveryConfig>,
+
+    /// Event types accepted at ingest.
+    pub event_policy: EventTypePolicy,
+
+    /// Bounds on the size and nesting of ingested payloads.
+    pub payload_limits: PayloadLimits,
+
+    /// Side effects run after events are stored.
+    pub hooks: IngestHooks,
+
+    /// Directory of WebAssembly plugins filtering and transforming events at ingest.
+    pub plugin_dir: Option<PathBuf>,
+
+    /// When producers identifying themselves are reported as silent.
+    pub producers: ProducerConfig,
+
+    /// Flags clients whose ingest traffic differs from their usual one. Not tracked if not set.
+    pub client_fingerprints: Option<FingerprintConfig>,
+
+    /// How long retries of an event are detected by its dedup id.
+    pub dedup: DedupConfig,
+
+    /// Signs the receipts of accepted events, so producers can prove later they were accepted.
+    pub receipt_key: Option<SigningKeys>,
+
+    /// Signs query tokens, so they stay valid across restarts and instances.
+    pub query_token_key: Option<SigningKeys>,
+
+    /// OpenID Connect login protecting the admin APIs.
+    pub oidc: Option<OidcConfig>,
+
+    /// JWT bearer tokens required for the `/events` routes.
+    pub jwt: Option<JwtConfig>,
+
+    /// Secrets referenced by environment variables, refreshed to pick up rotated keys.
+    pub secrets: Arc<Secrets>,
+}
+
+impl ServerConfig {
+    /// Creates the configuration from environment variables. Secret variables may reference
+    /// secrets, resolved from `secrets`.
+    pub fn from_env(secrets: Arc<Secrets>) -> Result<Self> {
+        let storage = match secrets.var("STORAGE") {
+            Ok(backend) => backend.parse().map_err(|error: String| anyhow!(error))?,
+            Err(_) => StorageBackend::default(),
+        };
+        let default_batch = WriteBatchConfig::default();
+        let write_batch = WriteBatchConfig {
+            max_delay: match std::env::var("WRITE_BATCH_MAX_DELAY") {
+                Ok(seconds) => Duration::try_from_secs_f64(
+                    seconds.parse().context("Invalid WRITE_BATCH_MAX_DELAY")?,
+                )
+                .context("Invalid WRITE_BATCH_MAX_DELAY")?,
+                Err(_) => default_batch.max_delay,
+            },
+            max_batch: match std::env::var("WRITE_BATCH_MAX_SIZE") {
+                Ok(size) => size.parse().context("Invalid WRITE_BATCH_MAX_SIZE")?,
+                Err(_) => default_batch.max_batch,
+            },
+        };
+        let default_pool = PostgresPoolConfig::default();
+        let postgres_pool = PostgresPoolConfig {
+            max_size: match std::env::var("POSTGRES_POOL_SIZE") {
+                Ok(size) => size.parse().context("Invalid POSTGRES_POOL_SIZE")?,
+                Err(_) => default_pool.max_size,
+            },
+            wait_timeout: match std::env::var("POSTGRES_POOL_TIMEOUT") {
+                Ok(seconds) => Duration::try_from_secs_f64(
+                    seconds.parse().context("Invalid POSTGRES_POOL_TIMEOUT")?,
+                )
+                .context("Invalid POSTGRES_POOL_TIMEOUT")?,
+                Err(_) => default_pool.wait_timeout,
+            },
+        };
+        let replica = match secrets.var("STORAGE_REPLICA") {
+            Ok(backend) => Some(ReplicaConfig {
+                storage: match backend.parse().map_err(|error: String| anyhow!(error))? {
+                    StorageBackend::Memory => bail!("STORAGE_REPLICA can't be in memory"),
+                    storage => storage,
+                },
+                hedge_after: match std::env::var("HEDGE_AFTER") {
+                    Ok(seconds) => {
+                        Duration::try_from_secs_f64(seconds.parse().context("Invalid HEDGE_AFTER")?)
+                            .context("Invalid HEDGE_AFTER")?
+                    }
+                    Err(_) => Duration::from_millis(50),
+                },
+            }),
+            Err(_) => None,
+        };
+        let id_strategy = match std::env::var("ID_STRATEGY") {
+            Ok(strategy) => strategy.parse().map_err(|error: String| anyhow!(error))?,
+            Err(_) => IdStrategy::default(),
+        };
+        let rollup = match std::env::var("ROLLUP_MAX_AGE") {
+            Ok(max_age) => Some(RollupConfig {
+                max_age: max_age.parse().context("Invalid ROLLUP_MAX_AGE")?,
+                bucket: match std::env::var("ROLLUP_BUCKET") {
+                    Ok(bucket) => bucket.parse().context("Invalid ROLLUP_BUCKET")?,
+                    Err(_) => 3600,
+                },
+                sum_fields: std::env::var("ROLLUP_SUM_FIELDS")
+                    .map(|fields| {
+                        fields
+                            .split(',')
+                            .map(|field| field.trim().to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                interval: Duration::from_secs(60),
+            }),
+            Err(_) => None,
+        };
+        let retention_ttl = match std::env::var("RETENTION_TTL") {
+            Ok(ttl) => Some(ttl.parse().context("Invalid RETENTION_TTL")?),
+            Err(_) => None,
+        };
+        let ttl_by_type: BTreeMap<_, _> = match std::env::var("RETENTION_TTL_BY_TYPE") {
+            Ok(ttls) => ttls
+                .split(',')
+                .map(|entry| {
+                    let (event_type, ttl) = entry
+                        .split_once('=')
+                        .context("Invalid RETENTION_TTL_BY_TYPE, expected type=seconds")?;
+                    let ttl = ttl
+                        .trim()
+                        .parse()
+                        .context("Invalid RETENTION_TTL_BY_TYPE")?;
+                    Ok((event_type.trim().to_string(), ttl))
+                })
+                .collect::<Result<_>>()?,
+            Err(_) => BTreeMap::new(),
+        };
+        // Always on, as test events expire even without a retention policy for real events.
+        let retention = Some(RetentionConfig {
+            ttl: retention_ttl,
+            ttl_by_type,
+            test_ttl: match std::env::var("TEST_EVENT_TTL") {
+                Ok(ttl) => ttl.parse().context("Invalid TEST_EVENT_TTL")?,
+                Err(_) => 3600,
+            },
+            interval: Duration::from_secs(match std::env::var("RETENTION_INTERVAL") {
+                Ok(seconds) => seconds.parse().context("Invalid RETENTION_INTERVAL")?,
+                Err(_) => 60,
+            }),
+        });
+        let privacy = match std::env::var("PRIVACY_MIN_GROUP_SIZE") {
+            Ok(size) => Some(PrivacyConfig {
+                min_group_size: size.parse().context("Invalid PRIVACY_MIN_GROUP_SIZE")?,
+                epsilon: match std::env::var("PRIVACY_EPSILON") {
+                    Ok(epsilon) => Some(
+                        epsilon
+                            .parse::<f64>()
+                            .ok()
+                            .filter(|epsilon| *epsilon > 0.0)
+                            .context("Invalid PRIVACY_EPSILON, expected a positive number")?,
+                    ),
+                    Err(_) => None,
+                },
+            }),
+            Err(_) => None,
+        };
+        let mut snapshot_format = SnapshotFormat::default();
+        if let Ok(keys) = secrets.var("SNAPSHOT_ENCRYPTION_KEY") {
+            snapshot_format
+                .parse_encryption_keys(&keys)
+                .map_err(|error| anyhow!(error))?;
+        }
+        let projection_snapshots = SnapshotConfig {
+            format: snapshot_format,
+            ..Default::default()
+        };
+        let event_snapshots = match std::env::var_os("EVENT_SNAPSHOT_DIR") {
+            Some(dir) => Some(EventSnapshotConfig {
+                dir: PathBuf::from(dir),
+                interval: match std::env::var("EVENT_SNAPSHOT_INTERVAL") {
+                    Ok(seconds) => Duration::from_secs(
+                        seconds.parse().context("Invalid EVENT_SNAPSHOT_INTERVAL")?,
+                    ),
+                    Err(_) => Duration::from_secs(300),
+                },
+                max_chain_length: 24,
+                format: projection_snapshots.format.clone(),
+            }),
+            None => None,
+        };
+        let backups = match std::env::var_os("BACKUP_DIR") {
+            Some(dir) => Some(BackupConfig {
+                dir: PathBuf::from(dir),
+                interval: Duration::from_secs(match std::env::var("BACKUP_INTERVAL") {
+                    Ok(seconds) => seconds.parse().context("Invalid BACKUP_INTERVAL")?,
+                    Err(_) => 3600,
+                }),
+                keep_last: match std::env::var("BACKUP_KEEP") {
+                    Ok(keep) => keep.parse().context("Invalid BACKUP_KEEP")?,
+                    Err(_) => 7,
+                },
+                max_age: match std::env::var("BACKUP_MAX_AGE") {
+                    Ok(seconds) => Some(Duration::from_secs(
+                        seconds.parse().context("Invalid BACKUP_MAX_AGE")?,
+                    )),
+                    Err(_) => None,
+                },
+                format: projection_snapshots.format.clone(),
+            }),
+            None => None,
+        };
+        let crash_reports = std::env::var_os("CRASH_REPORT_DIR").map(|dir| CrashReportConfig {
+            dir: PathBuf::from(dir),
+        });
+        let sentry = match std::env::var("SENTRY_DSN") {
+            Ok(dsn) => Some(SentryConfig {
+                dsn: dsn.parse().map_err(|error: String| anyhow!(error))?,
+                environment: std::env::var("SENTRY_ENVIRONMENT").ok(),
+            }),
+            Err(_) => None,
+        };
+        let defaults = ConnectionConfig::default();
+        let connection = ConnectionConfig {
+            address: SocketAddr::new(
+                match std::env::var("BIND_ADDRESS") {
+                    Ok(address) => address.parse().context("Invalid BIND_ADDRESS")?,
+                    Err(_) => defaults.address.ip(),
+                },
+                match std::env::var("PORT") {
+                    Ok(port) => port.parse().context("Invalid PORT")?,
+                    Err(_) => defaults.address.port(),
+                },
+            ),
+            http2: match std::env::var("HTTP2") {
+                Ok(enabled) => enabled.parse().context("Invalid HTTP2")?,
+                Err(_) => defaults.http2,
+            },
+            http1_keep_alive: match std::env::var("HTTP1_KEEP_ALIVE") {
+                Ok(enabled) => enabled.parse().context("Invalid HTTP1_KEEP_ALIVE")?,
+                Err(_) => defaults.http1_keep_alive,
+            },
+            http2_keep_alive_interval: match std::env::var("HTTP2_KEEP_ALIVE_INTERVAL") {
+                Ok(seconds) => Some(Duration::from_secs(
+                    seconds
+                        .parse()
+                        .context("Invalid HTTP2_KEEP_ALIVE_INTERVAL")?,
+                )),
+                Err(_) => defaults.http2_keep_alive_interval,
+            },
+            http2_keep_alive_timeout: match std::env::var("HTTP2_KEEP_ALIVE_TIMEOUT") {
+                Ok(seconds) => Duration::from_secs(
+                    seconds
+                        .parse()
+                        .context("Invalid HTTP2_KEEP_ALIVE_TIMEOUT")?,
+                ),
+                Err(_) => defaults.http2_keep_alive_timeout,
+            },
+            http2_max_concurrent_streams: match std::env::var("HTTP2_MAX_CONCURRENT_STREAMS") {
+                Ok(streams) => Some(
+                    streams
+                        .parse()
+                        .context("Invalid HTTP2_MAX_CONCURRENT_STREAMS")?,
+                ),
+                Err(_) => defaults.http2_max_concurrent_streams,
+            },
+            tcp_nodelay: match std::env::var("TCP_NODELAY") {
+                Ok(enabled) => enabled.parse().context("Invalid TCP_NODELAY")?,
+                Err(_) => defaults.tcp_nodelay,
+            },
+            proxy_protocol: match std::env::var("PROXY_PROTOCOL") {
+                Ok(enabled) => enabled.parse().context("Invalid PROXY_PROTOCOL")?,
+                Err(_) => defaults.proxy_protocol,
+            },
+            tls: match std::env::var_os("TLS_CERT_FILE") {
+                Some(cert_file) => Some(TlsConfig {
+                    cert_file: PathBuf::from(cert_file),
+                    key_file: std::env::var_os("TLS_KEY_FILE")
+                        .map(PathBuf::from)
+                        .context("TLS_KEY_FILE must be set for TLS")?,
+                    client_ca_file: std::env::var_os("TLS_CLIENT_CA_FILE").map(PathBuf::from),
+                    port: match std::env::var("TLS_PORT") {
+                        Ok(port) => Some(port.parse().context("Invalid TLS_PORT")?),
+                        Err(_) => None,
+                    },
+                }),
+                None => None,
+            },
+            shutdown_timeout: match std::env::var("SHUTDOWN_TIMEOUT") {
+                Ok(seconds) => {
+                    Duration::from_secs(seconds.parse().context("Invalid SHUTDOWN_TIMEOUT")?)
+                }
+                Err(_) => defaults.shutdown_timeout,
+            },
+        };
+        let trusted_proxies = match std::env::var("TRUSTED_PROXIES") {
+            Ok(networks) => networks
+                .split(',')
+                .map(|network| {
+                    network
+                        .trim()
+                        .parse()
+                        .map_err(|error: String| anyhow!(error))
+                })
+                .collect::<Result<_>>()?,
+            Err(_) => vec![],
+        };
+        let ingest_rate_limit = match std::env::var("INGEST_RATE_LIMIT") {
+            Ok(rate) => {
+                let rate: f64 = rate
+                    .parse()
+                    .ok()
+                    .filter(|rate: &f64| *rate > 0.0)
+                    .context("Invalid INGEST_RATE_LIMIT, expected a positive number")?;
+                Some(RateLimitConfig {
+                    rate,
+                    burst: match std::env::var("INGEST_RATE_BURST") {
+                        Ok(burst) => burst
+                            .parse::<f64>()
+                            .ok()
+                            .filter(|burst| *burst >= 1.0)
+                            .context("Invalid INGEST_RATE_BURST, expected at least 1")?,
+                        Err(_) => rate.max(1.0),
+                    },
+                    smoothing: match std::env::var("INGEST_RATE_QUEUE") {
+                        Ok(max_queued) => Some(SmoothingConfig {
+                            max_queued: max_queued.parse().context("Invalid INGEST_RATE_QUEUE")?,
+                            max_wait: match std::env::var("INGEST_RATE_QUEUE_TIMEOUT") {
+                                Ok(seconds) => Duration::try_from_secs_f64(
+                                    seconds
+                                        .parse()
+                                        .context("Invalid INGEST_RATE_QUEUE_TIMEOUT")?,
+                                )
+                                .context("Invalid INGEST_RATE_QUEUE_TIMEOUT")?,
+                                Err(_) => Duration::from_secs(10),
+                            },
+                            overflow: match std::env::var("INGEST_RATE_OVERFLOW") {
+                                Ok(policy) => {
+                                    policy.parse().map_err(|error: String| anyhow!(error))?
+                                }
+                                Err(_) => OverflowPolicy::default(),
+                            },
+                        }),
+                        Err(_) => None,
+                    },
+                })
+            }
+            Err(_) => None,
+        };
+        let query_concurrency = match std::env::var("QUERY_CONCURRENCY_PER_KEY") {
+            Ok(max_per_key) => Some(QueryConcurrencyConfig {
+                max_per_key: max_per_key
+                    .parse()
+                    .context("Invalid QUERY_CONCURRENCY_PER_KEY")?,
+                queue_timeout: match std::env::var("QUERY_QUEUE_TIMEOUT") {
+                    Ok(seconds) => Duration::try_from_secs_f64(
+                        seconds.parse().context("Invalid QUERY_QUEUE_TIMEOUT")?,
+                    )
+                    .context("Invalid QUERY_QUEUE_TIMEOUT")?,
+                    Err(_) => Duration::from_secs(5),
+                },
+            }),
+            Err(_) => None,
+        };
+        let discovery = match std::env::var("DISCOVERY_BACKEND") {
+            Ok(backend) => Some(DiscoveryConfig {
+                backend: backend.parse().map_err(|error: String| anyhow!(error))?,
+                service_name: std::env::var("SERVICE_NAME")
+                    .unwrap_or_else(|_| "cside-event-tracker".to_string()),
+                service_id: std::env::var("SERVICE_ID").ok(),
+                address: std::env::var("SERVICE_ADDRESS")
+                    .context("SERVICE_ADDRESS must be set for service discovery")?,
+                tags: std::env::var("SERVICE_TAGS")
+                    .map(|tags| tags.split(',').map(|tag| tag.trim().to_string()).collect())
+                    .unwrap_or_default(),
+                ttl: Duration::from_secs(60),
+            }),
+            Err(_) => None,
+        };
+        let jwt_key = match (
+            secrets.var("JWT_SECRET"),
+            std::env::var_os("JWT_PUBLIC_KEY_FILE"),
+        ) {
+            (Ok(_), Some(_)) => {
+                return Err(anyhow!(
+                    "Only one of JWT_SECRET and JWT_PUBLIC_KEY_FILE can be set"
+                ));
+            }
+            (Ok(secret), None) => Some(JwtKey::Hs256(secret)),
+            (Err(_), Some(path)) => Some(JwtKey::Rs256(PathBuf::from(path))),
+            (Err(_), None) => None,
+        };
+        let jwt = jwt_key.map(|key| JwtConfig {
+            key,
+            issuer: std::env::var("JWT_ISSUER").ok(),
+            audience: std::env::var("JWT_AUDIENCE").ok(),
+            roles_claim: std::env::var("JWT_ROLES_CLAIM").unwrap_or_else(|_| "roles".to_string()),
+        });
+        let oidc = match std::env::var("OIDC_ISSUER_URL") {
+            Ok(issuer_url) => Some(OidcConfig {
+                issuer_url,
+                client_id: std::env::var("OIDC_CLIENT_ID")
+                    .context("OIDC_CLIENT_ID must be set for login")?,
+                client_secret: secrets
+                    .var("OIDC_CLIENT_SECRET")
+                    .context("OIDC_CLIENT_SECRET must be set for login")?,
+                redirect_url: std::env::var("OIDC_REDIRECT_URL")
+                    .context("OIDC_REDIRECT_URL must be set for login")?,
+                groups_claim: std::env::var("OIDC_GROUPS_CLAIM")
+                    .unwrap_or_else(|_| "groups".to_string()),
+                session_ttl: match std::env::var("OIDC_SESSION_TTL") {
+                    Ok(seconds) => {
+                        Duration::from_secs(seconds.parse().context("Invalid OIDC_SESSION_TTL")?)
+                    }
+                    Err(_) => Duration::from_secs(3600),
+                },
+                session_key: match secrets.var("OIDC_SESSION_KEY") {
+                    Ok(key) => Some(key.parse().map_err(|error: String| anyhow!(error))?),
+                    Err(_) => None,
+                },
+            }),
+            Err(_) => None,
+        };
+        let producers = ProducerConfig {
+            silence_timeout: match std::env::var("PRODUCER_SILENCE_TIMEOUT") {
+                Ok(seconds) => Duration::try_from_secs_f64(
+                    seconds
+                        .parse()
+                        .context("Invalid PRODUCER_SILENCE_TIMEOUT")?,
+                )
+                .context("Invalid PRODUCER_SILENCE_TIMEOUT")?,
+                Err(_) => ProducerConfig::default().silence_timeout,
+            },
+        };
+        let client_fingerprints = match std::env::var("ANOMALY_WINDOW") {
+            Ok(seconds) => {
+                let defaults = FingerprintConfig::default();
+                Some(FingerprintConfig {
+                    window: Duration::from_secs(seconds.parse().context("Invalid ANOMALY_WINDOW")?),
+                    warm_up: match std::env::var("ANOMALY_WARM_UP") {
+                        Ok(windows) => windows.parse().context("Invalid ANOMALY_WARM_UP")?,
+                        Err(_) => defaults.warm_up,
+                    },
+                    rate_factor: match std::env::var("ANOMALY_RATE_FACTOR") {
+                        Ok(factor) => factor.parse().context("Invalid ANOMALY_RATE_FACTOR")?,
+                        Err(_) => defaults.rate_factor,
+                    },
+                    min_events: match std::env::var("ANOMALY_MIN_EVENTS") {
+                        Ok(events) => events.parse().context("Invalid ANOMALY_MIN_EVENTS")?,
+                        Err(_) => defaults.min_events,
+                    },
+                    ..defaults
+                })
+            }
+            Err(_) => None,
+        };
+        let dedup = DedupConfig {
+            window: match std::env::var("DEDUP_WINDOW") {
+                Ok(seconds) => {
+                    Duration::from_secs(seconds.parse().context("Invalid DEDUP_WINDOW")?)
+                }
+                Err(_) => DedupConfig::default().window,
+            },
+        };
+        let patterns = |name| {
+            std::env::var(name)
+                .map(|patterns| {
+                    patterns
+                        .split(',')
+                        .map(|pattern| pattern.trim().to_string())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+        let event_policy = EventTypePolicy {
+            allow: patterns("EVENT_TYPE_ALLOW"),
+            deny: patterns("EVENT_TYPE_DENY"),
+        };
+        let payload_limits = PayloadLimits {
+            max_bytes: match std::env::var("MAX_PAYLOAD_BYTES") {
+                Ok(bytes) => bytes.parse().context("Invalid MAX_PAYLOAD_BYTES")?,
+                Err(_) => PayloadLimits::default().max_bytes,
+            },
+            max_depth: match std::env::var("MAX_PAYLOAD_DEPTH") {
+                Ok(depth) => depth.parse().context("Invalid MAX_PAYLOAD_DEPTH")?,
+                Err(_) => PayloadLimits::default().max_depth,
+            },
+        };
+        let mut hooks = IngestHooks::default();
+        for pattern in patterns("EVENT_COUNTERS") {
+            hooks.register(Some(&pattern), Arc::new(CountingHook));
+        }
+        Ok(Self {
+            resources_file: std::env::var_os("RESOURCES_FILE").map(PathBuf::from),
+            shadow: std::env::var("SHADOW_PERCENTAGE")
+                .ok()
+                .and_then(|percentage| percentage.parse().ok())
+                .map(|percentage| ShadowConfig { percentage }),
+            storage,
+            replica,
+            wal_file: std::env::var_os("WAL_FILE").map(PathBuf::from),
+            write_batch,
+            postgres_pool,
+            postgres_ca_file: std::env::var_os("POSTGRES_CA_FILE").map(PathBuf::from),
+            id_strategy,
+            rollup,
+            retention,
+            projection_snapshots,
+            event_snapshots,
+            backups,
+            crash_reports,
+            sentry,
+            privacy,
+            exports: std::env::var("EXPORT_DESTINATION")
+                .ok()
+                .map(|destination| ExportConfig { destination }),
+            outbox_file: std::env::var_os("OUTBOX_FILE").map(PathBuf::from),
+            legal_holds_file: std::env::var_os("LEGAL_HOLDS_FILE").map(PathBuf::from),
+            access_policy_file: std::env::var_os("ACCESS_POLICY_FILE").map(PathBuf::from),
+            require_api_key: match std::env::var("REQUIRE_API_KEY") {
+                Ok(enabled) => enabled.parse().context("Invalid REQUIRE_API_KEY")?,
+                Err(_) => false,
+            },
+            connection,
+            compression: match std::env::var("COMPRESSION_MIN_SIZE") {
+                Ok(value) if value == "off" => None,
+                Ok(min_size) => Some(CompressionConfig {
+                    min_size: min_size
+                        .parse()
+                        .context("Invalid COMPRESSION_MIN_SIZE, expected bytes up to 65535")?,
+                }),
+                Err(_) => Some(CompressionConfig::default()),
+            },
+            grpc_port: match std::env::var("GRPC_PORT") {
+                Ok(port) => Some(port.parse().context("Invalid GRPC_PORT")?),
+                Err(_) => None,
+            },
+            trusted_proxies,
+            query_concurrency,
+            ingest_rate_limit,
+            discovery,
+            event_policy,
+            payload_limits,
+            hooks,
+            plugin_dir: std::env::var_os("PLUGIN_DIR").map(PathBuf::from),
+            producers,
+            client_fingerprints,
+            dedup,
+            oidc,
+            jwt,
+            receipt_key: match secrets.var("RECEIPT_KEY") {
+                Ok(key) => Some(key.parse().map_err(|error: String| anyhow!(error))?),
+                Err(_) => None,
+            },
+            query_token_key: match secrets.var("QUERY_TOKEN_KEY") {
+                Ok(key) => Some(key.parse().map_err(|error: String| anyhow!(error))?),
+                Err(_) => None,
+            },
+            secrets,
+            ..Default::default()
+        })
+    }
+}
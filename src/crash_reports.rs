@@ -0,0 +1,452 @@
+use serde::Serialize;
+use std::{
+    backtrace::Backtrace,
+    collections::{BTreeMap, VecDeque},
+    panic::PanicHookInfo,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, OnceLock, TryLockError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::{
+    health::{Health, HealthStatus},
+    metrics, sentry,
+    storage::{PoolStats, Storage},
+};
+
+/// Prefix of the crash report file names, followed by the Unix time of the panic in millis.
+const REPORT_PREFIX: &str = "crash-";
+
+/// Suffix replacing `.json` once a report was sent to Sentry.
+const REPORTED_SUFFIX: &str = ".reported.json";
+
+/// Number of finished requests kept for the crash reports.
+const MAX_BREADCRUMBS: usize = 50;
+
+/// Configures the crash reports written when the server panics.
+#[derive(Debug, Clone)]
+pub struct CrashReportConfig {
+    /// Directory the reports are written to.
+    pub dir: PathBuf,
+}
+
+/// A request the server was handling before a panic.
+#[derive(Debug, Clone, Serialize)]
+struct Breadcrumb {
+    /// Unix time in millis when the request arrived.
+    started_at: u64,
+
+    method: String,
+    path: String,
+
+    /// Status of the response, if it was sent.
+    status: Option<u16>,
+
+    /// Time taken to respond, if the request finished.
+    duration_ms: Option<f64>,
+}
+
+/// Writes a crash report with the requests in flight, the recently finished requests, storage
+/// statistics and build info when any thread panics, so a rare panic can be investigated with
+/// more than its backtrace.
+pub struct CrashReporter {
+    config: CrashReportConfig,
+    store: Arc<dyn Storage + Send + Sync + 'static>,
+    health: Arc<Health>,
+    next_request: AtomicU64,
+    in_flight: Mutex<BTreeMap<u64, Breadcrumb>>,
+    finished: Mutex<VecDeque<Breadcrumb>>,
+
+    /// Queues the written reports for sending to Sentry, if configured.
+    reports: OnceLock<mpsc::UnboundedSender<PathBuf>>,
+}
+
+/// Tracks a request until it finishes or is cancelled.
+pub struct RequestGuard<'a> {
+    reporter: &'a CrashReporter,
+    id: u64,
+    started: Instant,
+    status: Option<u16>,
+}
+
+#[derive(Serialize)]
+struct CrashReport {
+    timestamp: u64,
+    message: String,
+    location: Option<String>,
+    thread: Option<String>,
+    backtrace: String,
+    build: BuildInfo,
+    in_flight_requests: Option<Vec<Breadcrumb>>,
+    recent_requests: Option<Vec<Breadcrumb>>,
+    storage: StorageStats,
+    metrics: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BuildInfo {
+    version: &'static str,
+    profile: &'static str,
+    os: &'static str,
+    arch: &'static str,
+}
+
+#[derive(Serialize)]
+struct StorageStats {
+    pool: Option<PoolStats>,
+    health: Option<HealthStatus>,
+}
+
+impl CrashReporter {
+    pub fn new(
+        config: CrashReportConfig,
+        store: Arc<dyn Storage + Send + Sync + 'static>,
+        health: Arc<Health>,
+    ) -> Self {
+        Self {
+            config,
+            store,
+            health,
+            next_request: AtomicU64::new(0),
+            in_flight: Mutex::new(BTreeMap::new()),
+            finished: Mutex::new(VecDeque::new()),
+            reports: OnceLock::new(),
+        }
+    }
+
+    /// Records a request as in flight until the returned guard is dropped.
+    pub fn start_request(&self, method: &str, path: &str) -> RequestGuard<'_> {
+        let id = self.next_request.fetch_add(1, Ordering::Relaxed);
+        let breadcrumb = Breadcrumb {
+            started_at: now_millis(),
+            method: method.to_string(),
+            path: path.to_string(),
+            status: None,
+            duration_ms: None,
+        };
+        self.in_flight.lock().unwrap().insert(id, breadcrumb);
+        RequestGuard {
+            reporter: self,
+            id,
+            started: Instant::now(),
+            status: None,
+        }
+    }
+
+    /// Writes a crash report on every panic, then runs the previous panic hook. If Sentry is
+    /// set up, the reports are sent to it, including the ones left by earlier runs.
+    pub fn install(self: Arc<Self>) {
+        if sentry::is_enabled() {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let _ = self.reports.set(sender);
+            self.clone().spawn_uploads(receiver);
+        }
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            match self.write_report(info) {
+                Ok(path) => {
+                    eprintln!("Crash report written to {}", path.display());
+                    if let Some(reports) = self.reports.get() {
+                        let _ = reports.send(path);
+                    }
+                }
+                Err(error) => eprintln!("Failed to write a crash report: {error}"),
+            }
+            previous_hook(info);
+        }));
+    }
+
+    /// Writes the report of a panic, and returns its path.
+    fn write_report(&self, info: &PanicHookInfo) -> std::io::Result<PathBuf> {
+        let timestamp = now_millis();
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match info.payload().downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "Box<dyn Any>".to_string(),
+            },
+        };
+        let report = CrashReport {
+            timestamp,
+            message,
+            location: info
+                .location()
+                .map(|location| format!("{}:{}", location.file(), location.line())),
+            thread: std::thread::current().name().map(str::to_string),
+            backtrace: Backtrace::force_capture().to_string(),
+            build: BuildInfo {
+                version: env!("CARGO_PKG_VERSION"),
+                profile: if cfg!(debug_assertions) {
+                    "debug"
+                } else {
+                    "release"
+                },
+                os: std::env::consts::OS,
+                arch: std::env::consts::ARCH,
+            },
+            // The panicking thread may hold any lock, so nothing is waited for.
+            in_flight_requests: try_snapshot(&self.in_flight, |requests| {
+                requests.values().cloned().collect()
+            }),
+            recent_requests: try_snapshot(&self.finished, |requests| {
+                requests.iter().cloned().collect()
+            }),
+            storage: StorageStats {
+                pool: self.store.pool_stats(),
+                health: self.health.try_status(),
+            },
+            metrics: metrics::try_render(),
+        };
+
+        std::fs::create_dir_all(&self.config.dir)?;
+        let mut path = self
+            .config
+            .dir
+            .join(format!("{REPORT_PREFIX}{timestamp}.json"));
+        let mut suffix = 1;
+        while path.exists() {
+            path = self
+                .config
+                .dir
+                .join(format!("{REPORT_PREFIX}{timestamp}-{suffix}.json"));
+            suffix += 1;
+        }
+        std::fs::write(&path, serde_json::to_vec_pretty(&report)?)?;
+        Ok(path)
+    }
+
+    /// Sends the unsent reports in the directory to Sentry, then the ones written from now on.
+    fn spawn_uploads(self: Arc<Self>, mut receiver: mpsc::UnboundedReceiver<PathBuf>) {
+        tokio::spawn(async move {
+            let pending = match unsent_reports(&self.config.dir) {
+                Ok(pending) => pending,
+                Err(error) => {
+                    warn!("Failed to list the crash reports: {error}");
+                    Vec::new()
+                }
+            };
+            if !pending.is_empty() {
+                info!("Sending {} crash reports to Sentry", pending.len());
+            }
+            for path in pending {
+                upload(&path).await;
+            }
+            while let Some(path) = receiver.recv().await {
+                upload(&path).await;
+            }
+        });
+    }
+}
+
+impl RequestGuard<'_> {
+    /// Records the status of the response.
+    pub fn finish(&mut self, status: u16) {
+        self.status = Some(status);
+    }
+}
+
+impl Drop for RequestGuard<'_> {
+    fn drop(&mut self) {
+        let Some(mut breadcrumb) = self.reporter.in_flight.lock().unwrap().remove(&self.id) else {
+            return;
+        };
+        breadcrumb.status = self.status;
+        breadcrumb.duration_ms = Some(self.started.elapsed().as_secs_f64() * 1000.0);
+        let mut finished = self.reporter.finished.lock().unwrap();
+        if finished.len() == MAX_BREADCRUMBS {
+            finished.pop_front();
+        }
+        finished.push_back(breadcrumb);
+    }
+}
+
+/// Copies the contents of a mutex without waiting, even if a panic poisoned it.
+fn try_snapshot<T, R>(mutex: &Mutex<T>, copy: impl FnOnce(&T) -> R) -> Option<R> {
+    match mutex.try_lock() {
+        Ok(value) => Some(copy(&value)),
+        Err(TryLockError::Poisoned(poisoned)) => Some(copy(&poisoned.into_inner())),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+/// Returns the reports in the directory that weren't sent to Sentry yet, oldest first.
+fn unsent_reports(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut reports = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if name.starts_with(REPORT_PREFIX)
+            && name.ends_with(".json")
+            && !name.ends_with(REPORTED_SUFFIX)
+        {
+            reports.push(path);
+        }
+    }
+    reports.sort();
+    Ok(reports)
+}
+
+/// Sends a report to Sentry as a fatal event, and marks it as sent.
+async fn upload(path: &Path) {
+    let result = async {
+        let report: serde_json::Value = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+        sentry::send(sentry_event(&report)).await?;
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        let reported = path.with_file_name(name.replace(".json", REPORTED_SUFFIX));
+        tokio::fs::rename(path, reported).await?;
+        anyhow::Ok(())
+    }
+    .await;
+    match result {
+        Ok(()) => metrics::increment("crash_reports_sent_total", &[]),
+        Err(error) => {
+            metrics::increment("crash_reports_send_failures_total", &[]);
+            error!("Failed to send {} to Sentry: {error}", path.display());
+        }
+    }
+}
+
+/// Converts a crash report to a Sentry event, with the finished requests as breadcrumbs.
+fn sentry_event(report: &serde_json::Value) -> serde_json::Value {
+    let breadcrumbs: Vec<_> = report["recent_requests"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|request| {
+            serde_json::json!({
+                "timestamp": request["started_at"].as_f64().unwrap_or_default() / 1000.0,
+                "type": "http",
+                "category": "request",
+                "data": {
+                    "method": request["method"],
+                    "url": request["path"],
+                    "status_code": request["status"],
+                },
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "timestamp": report["timestamp"].as_f64().unwrap_or_default() / 1000.0,
+        "level": "fatal",
+        "exception": {
+            "values": [{
+                "type": "panic",
+                "value": report["message"],
+                "mechanism": {"type": "panic", "handled": false},
+            }],
+        },
+        "breadcrumbs": {"values": breadcrumbs},
+        "extra": {
+            "location": report["location"],
+            "thread": report["thread"],
+            "backtrace": report["backtrace"],
+            "in_flight_requests": report["in_flight_requests"],
+            "storage": report["storage"],
+        },
+    })
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{health::HealthConfig, storage::InMemoryStorage};
+
+    #[test]
+    fn test_breadcrumbs() {
+        let store: Arc<dyn Storage + Send + Sync> = Arc::new(InMemoryStorage::new());
+        let health = Arc::new(Health::new(store.clone(), HealthConfig::default()));
+        let config = CrashReportConfig {
+            dir: std::env::temp_dir().join("crash-reports"),
+        };
+        let reporter = CrashReporter::new(config, store, health);
+
+        let mut finished = reporter.start_request("POST", "/events");
+        let cancelled = reporter.start_request("GET", "/events/stream");
+        let in_flight = reporter.start_request("GET", "/events");
+        finished.finish(200);
+        drop(finished);
+        drop(cancelled);
+        let in_flight_paths: Vec<_> = reporter
+            .in_flight
+            .lock()
+            .unwrap()
+            .values()
+            .map(|request| request.path.clone())
+            .collect();
+        assert_eq!(in_flight_paths, ["/events"]);
+        let recent = try_snapshot(&reporter.finished, |requests| requests.clone()).unwrap();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].status, Some(200));
+        assert_eq!(recent[1].path, "/events/stream");
+        assert_eq!(recent[1].status, None);
+        drop(in_flight);
+
+        for _ in 0..MAX_BREADCRUMBS {
+            reporter.start_request("GET", "/");
+        }
+        assert_eq!(reporter.finished.lock().unwrap().len(), MAX_BREADCRUMBS);
+        assert!(reporter.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unsent_reports() {
+        let dir = std::env::temp_dir().join(format!("crash-reports-unsent-{}", std::process::id()));
+        assert!(unsent_reports(&dir).unwrap().is_empty());
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "crash-2.json",
+            "crash-1.json",
+            "crash-0.reported.json",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.join(name), "{}").unwrap();
+        }
+        assert_eq!(
+            unsent_reports(&dir).unwrap(),
+            [dir.join("crash-1.json"), dir.join("crash-2.json")]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sentry_event() {
+        let report = serde_json::json!({
+            "timestamp": 1_700_000_000_500u64,
+            "message": "index out of bounds",
+            "recent_requests": [{
+                "started_at": 1_700_000_000_000u64,
+                "method": "GET",
+                "path": "/events",
+                "status": 200,
+            }],
+        });
+        let event = sentry_event(&report);
+        assert_eq!(event["level"], "fatal");
+        assert_eq!(event["timestamp"], 1_700_000_000.5);
+        assert_eq!(
+            event["exception"]["values"][0]["value"],
+            "index out of bounds"
+        );
+        assert_eq!(event["breadcrumbs"]["values"][0]["data"]["url"], "/events");
+    }
+}
@@ -0,0 +1,91 @@
+use ahash::AHashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::event::EventId;
+
+/// Number of remembered dedup ids above which expired ones are pruned.
+const MAX_TRACKED_IDS: usize = 100_000;
+
+/// Configures the detection of events retried by their producers.
+#[derive(Debug, Clone)]
+pub struct DedupConfig {
+    /// Events repeating the dedup id of an event stored within this long are acknowledged
+    /// without being stored again.
+    pub window: Duration,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// The stored events of the dedup ids seen within the window.
+pub struct DedupIds {
+    window: Duration,
+    ids: AHashMap<String, (EventId, Instant)>,
+}
+
+impl DedupIds {
+    /// Returns the stored event with the dedup id, if it was stored within the window.
+    pub fn find(&self, dedup_id: &str, now: Instant) -> Option<EventId> {
+        let (event_id, stored) = self.ids.get(dedup_id)?;
+        (now.duration_since(*stored) < self.window).then_some(*event_id)
+    }
+
+    /// Remembers the stored event of a dedup id.
+    pub fn insert(&mut self, dedup_id: String, event_id: EventId, now: Instant) {
+        if self.ids.len() >= MAX_TRACKED_IDS {
+            let window = self.window;
+            self.ids
+                .retain(|_, (_, stored)| now.duration_since(*stored) < window);
+        }
+        self.ids.insert(dedup_id, (event_id, now));
+    }
+}
+
+/// The dedup index: stored events by the dedup id their producer gave them, so retries of an
+/// event are acknowledged with the first one instead of being stored twice. Kept in memory, so
+/// retries across a restart aren't detected.
+pub struct DedupIndex {
+    ids: Mutex<DedupIds>,
+}
+
+impl DedupIndex {
+    pub fn new(config: DedupConfig) -> Self {
+        Self {
+            ids: Mutex::new(DedupIds {
+                window: config.window,
+                ids: AHashMap::new(),
+            }),
+        }
+    }
+
+    /// Locks the dedup ids. Ingest holds the lock while storing so that concurrent retries of
+    /// an event are stored once.
+    pub async fn lock(&self) -> MutexGuard<'_, DedupIds> {
+        self.ids.lock().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dedup_index() {
+        let index = DedupIndex::new(DedupConfig {
+            window: Duration::from_secs(60),
+        });
+        let now = Instant::now();
+        let mut ids = index.lock().await;
+        assert_eq!(ids.find("order-1", now), None);
+        ids.insert("order-1".to_string(), 7, now);
+        assert_eq!(ids.find("order-1", now + Duration::from_secs(59)), Some(7));
+        assert_eq!(ids.find("order-1", now + Duration::from_secs(60)), None);
+        assert_eq!(ids.find("order-2", now), None);
+    }
+}
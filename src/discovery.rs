@@ -0,0 +1,268 @@
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde_json::json;
+use std::{str::FromStr, time::Duration};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Prefix of the etcd keys instances are registered under, followed by the service name and
+/// the instance id.
+const ETCD_PREFIX: &str = "/services";
+
+/// Service registry the server registers itself in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiscoveryBackend {
+    /// Consul agent HTTP API, e.g. `http://127.0.0.1:8500`.
+    Consul { url: String },
+
+    /// etcd v3 JSON gateway, e.g. `http://127.0.0.1:2379`.
+    Etcd { url: String },
+}
+
+impl FromStr for DiscoveryBackend {
+    type Err = String;
+
+    /// Parses `consul:<url>` or `etcd:<url>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, url) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid discovery backend: '{s}'"))?;
+        let url = url.trim_end_matches('/').to_string();
+        match kind {
+            "consul" => Ok(DiscoveryBackend::Consul { url }),
+            "etcd" => Ok(DiscoveryBackend::Etcd { url }),
+            _ => Err(format!("Unknown discovery backend: '{kind}'")),
+        }
+    }
+}
+
+/// Configures self-registration with service discovery.
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    pub backend: DiscoveryBackend,
+
+    /// Name other services look the tracker up by.
+    pub service_name: String,
+
+    /// Id of this instance. Defaults to the service name, address and port.
+    pub service_id: Option<String>,
+
+    /// Address other services reach this instance at.
+    pub address: String,
+
+    /// Tags of the instance, e.g. the supported API versions.
+    pub tags: Vec<String>,
+
+    /// How long a registration outlives an instance that stopped without deregistering.
+    pub ttl: Duration,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DiscoveryError {
+    #[error("Service registry request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Unexpected service registry response: {0}")]
+    InvalidResponse(String),
+}
+
+/// Registration of this instance, to be removed on shutdown.
+pub struct Registration {
+    client: reqwest::Client,
+    backend: DiscoveryBackend,
+    service_id: String,
+    key: String,
+
+    /// Keeps the etcd lease of the registration alive.
+    keepalive: Option<JoinHandle<()>>,
+}
+
+/// Registers the instance serving on the given port.
+///
+/// Consul probes `/readyz` and deregisters the instance once it stays critical for the TTL.
+/// etcd keeps the registration as long as the instance keeps its lease alive.
+pub async fn register(config: &DiscoveryConfig, port: u16) -> Result<Registration, DiscoveryError> {
+    let client = reqwest::Client::new();
+    let service_id = config
+        .service_id
+        .clone()
+        .unwrap_or_else(|| format!("{}-{}-{port}", config.service_name, config.address));
+    let health_url = format!("http://{}:{port}/readyz", config.address);
+    let key = format!("{ETCD_PREFIX}/{}/{service_id}", config.service_name);
+
+    let keepalive = match &config.backend {
+        DiscoveryBackend::Consul { url } => {
+            let service = json!({
+                "ID": service_id,
+                "Name": config.service_name,
+                "Address": config.address,
+                "Port": port,
+                "Tags": config.tags,
+                "Meta": {"version": env!("CARGO_PKG_VERSION")},
+                "Check": {
+                    "HTTP": health_url,
+                    "Interval": "10s",
+                    "DeregisterCriticalServiceAfter": format!("{}s", config.ttl.as_secs()),
+                },
+            });
+            client
+                .put(format!("{url}/v1/agent/service/register"))
+                .json(&service)
+                .send()
+                .await?
+                .error_for_status()?;
+            None
+        }
+        DiscoveryBackend::Etcd { url } => {
+            let value = json!({
+                "id": service_id,
+                "name": config.service_name,
+                "address": config.address,
+                "port": port,
+                "tags": config.tags,
+                "version": env!("CARGO_PKG_VERSION"),
+                "health": health_url,
+            })
+            .to_string();
+            let ttl = config.ttl.as_secs().max(1);
+            let mut lease = etcd_put(&client, url, &key, &value, ttl).await?;
+            let (client, url, key) = (client.clone(), url.clone(), key.clone());
+            Some(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(ttl.div_ceil(3)));
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    if let Err(error) = etcd_keepalive(&client, &url, &lease).await {
+                        // The lease may have expired, register again.
+                        warn!("Failed to keep the etcd registration alive: {error}");
+                        match etcd_put(&client, &url, &key, &value, ttl).await {
+                            Ok(new_lease) => lease = new_lease,
+                            Err(error) => warn!("Failed to register with etcd: {error}"),
+                        }
+                    }
+                }
+            }))
+        }
+    };
+    info!("Registered as '{service_id}' with service discovery");
+    Ok(Registration {
+        client,
+        backend: config.backend.clone(),
+        service_id,
+        key,
+        keepalive,
+    })
+}
+
+impl Registration {
+    /// Removes the instance from service discovery.
+    pub async fn deregister(self) -> Result<(), DiscoveryError> {
+        if let Some(keepalive) = self.keepalive {
+            keepalive.abort();
+        }
+        match &self.backend {
+            DiscoveryBackend::Consul { url } => {
+                self.client
+                    .put(format!(
+                        "{url}/v1/agent/service/deregister/{}",
+                        self.service_id
+                    ))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            DiscoveryBackend::Etcd { url } => {
+                self.client
+                    .post(format!("{url}/v3/kv/deleterange"))
+                    .json(&json!({"key": BASE64.encode(&self.key)}))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        info!("Deregistered '{}' from service discovery", self.service_id);
+        Ok(())
+    }
+}
+
+/// Stores the key under a new lease with the given TTL in seconds. Returns the lease id.
+async fn etcd_put(
+    client: &reqwest::Client,
+    url: &str,
+    key: &str,
+    value: &str,
+    ttl: u64,
+) -> Result<String, DiscoveryError> {
+    let response: serde_json::Value = client
+        .post(format!("{url}/v3/lease/grant"))
+        .json(&json!({"TTL": ttl}))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    // The gateway encodes 64-bit integers as strings.
+    let lease = match &response["ID"] {
+        serde_json::Value::String(id) => id.clone(),
+        serde_json::Value::Number(id) => id.to_string(),
+        _ => return Err(DiscoveryError::InvalidResponse(response.to_string())),
+    };
+    client
+        .post(format!("{url}/v3/kv/put"))
+        .json(&json!({
+            "key": BASE64.encode(key),
+            "value": BASE64.encode(value),
+            "lease": lease,
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(lease)
+}
+
+/// Renews the lease. Fails if it has expired.
+async fn etcd_keepalive(
+    client: &reqwest::Client,
+    url: &str,
+    lease: &str,
+) -> Result<(), DiscoveryError> {
+    let response: serde_json::Value = client
+        .post(format!("{url}/v3/lease/keepalive"))
+        .json(&json!({"ID": lease}))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    // An expired lease is renewed with no TTL.
+    let ttl = &response["result"]["TTL"];
+    if ttl.is_null() || ttl == "0" || ttl == 0 {
+        return Err(DiscoveryError::InvalidResponse(response.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_backend() {
+        assert_eq!(
+            "consul:http://127.0.0.1:8500/".parse(),
+            Ok(DiscoveryBackend::Consul {
+                url: "http://127.0.0.1:8500".to_string()
+            })
+        );
+        assert_eq!(
+            "etcd:http://etcd:2379".parse(),
+            Ok(DiscoveryBackend::Etcd {
+                url: "http://etcd:2379".to_string()
+            })
+        );
+        assert!(
+            "zookeeper:http://zk:2181"
+                .parse::<DiscoveryBackend>()
+                .is_err()
+        );
+    }
+}
@@ -1,11 +1,29 @@
 use serde::{Deserialize, Serialize};
 
+/// Unix timestamp in seconds.
 pub type Timestamp = u64;
 
+/// An internal identifier for events, assigned by the storage in insertion order.
+///
+/// 128 bits wide to fit ULIDs and UUIDs, see `IdGenerator`.
+pub type EventId = u128;
+
 /// The event type we need to store.
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
 pub struct Event {
     pub event_type: String,
     pub timestamp: Timestamp,
     pub payload: serde_json::value::Value,
+
+    /// Identifies the entity stream the event belongs to, eg. an order id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+
+    /// Number of identical events this event stands for, if it was coalesced from a burst.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+
+    /// Identifies the event for its producer, so retries of it are stored once. Not stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup_id: Option<String>,
 }
@@ -0,0 +1,93 @@
+/// Prefix of the event types reserved for test events, so teams can exercise their integration
+/// in production without polluting real data. Test events are always accepted unless denied,
+/// expire after a short TTL, and are left out of analytics unless asked for.
+pub const TEST_NAMESPACE: &str = "test.";
+
+/// Returns whether the event type is in the test namespace.
+pub fn is_test_event_type(event_type: &str) -> bool {
+    event_type.starts_with(TEST_NAMESPACE)
+}
+
+/// Decides which event types may be ingested.
+///
+/// Patterns are exact event types or globs, where `*` matches any number of characters and `?`
+/// matches a single one.
+#[derive(Debug, Clone, Default)]
+pub struct EventTypePolicy {
+    /// If not empty, only event types matching one of these patterns, or in the test namespace,
+    /// are accepted.
+    pub allow: Vec<String>,
+
+    /// Event types matching one of these patterns are rejected, even if they are allowed.
+    pub deny: Vec<String>,
+}
+
+impl EventTypePolicy {
+    pub fn accepts(&self, event_type: &str) -> bool {
+        let matches = |pattern: &String| glob_matches(pattern, event_type);
+        let allowed = self.allow.is_empty()
+            || is_test_event_type(event_type)
+            || self.allow.iter().any(matches);
+        allowed && !self.deny.iter().any(matches)
+    }
+}
+
+/// Matches text against a glob pattern with `*` and `?` wildcards.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern, and of the text it was tried at.
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, star_t)) = backtrack {
+            // Let the last `*` swallow one more character.
+            p = star + 1;
+            t = star_t + 1;
+            backtrack = Some((star, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_type_policy() {
+        assert!(glob_matches("order_*", "order_paid"));
+        assert!(glob_matches("*_paid", "order_paid"));
+        assert!(glob_matches("order_?aid", "order_paid"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("order_*", "orders"));
+        assert!(!glob_matches("order", "order_paid"));
+
+        let policy = EventTypePolicy::default();
+        assert!(policy.accepts("anything"));
+
+        let policy = EventTypePolicy {
+            allow: vec!["order_*".to_string(), "signup".to_string()],
+            deny: vec!["*_test".to_string()],
+        };
+        assert!(policy.accepts("order_paid"));
+        assert!(policy.accepts("signup"));
+        assert!(!policy.accepts("login"));
+        assert!(!policy.accepts("order_test"));
+        // The test namespace is reserved, but can still be denied.
+        assert!(policy.accepts("test.signup"));
+        let policy = EventTypePolicy {
+            deny: vec!["test.*".to_string()],
+            ..policy
+        };
+        assert!(!policy.accepts("test.signup"));
+    }
+}
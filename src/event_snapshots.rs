@@ -0,0 +1,451 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{Mutex, watch};
+use tracing::{error, info, warn};
+
+use crate::{
+    event::{Event, EventId, Timestamp},
+    metrics, sentry,
+    snapshot_file::{self, SnapshotFormat},
+    storage::{EventFilter, RetrieveError, Storage, StoreError},
+};
+
+/// Name of the file listing the snapshot chain.
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Configures snapshots of the event store.
+#[derive(Debug, Clone)]
+pub struct EventSnapshotConfig {
+    /// Directory of the snapshot chain.
+    pub dir: PathBuf,
+
+    /// How often an incremental snapshot is taken.
+    pub interval: Duration,
+
+    /// A full snapshot is taken instead of an incremental one when the chain is this long.
+    pub max_chain_length: usize,
+
+    /// Compression and encryption of the snapshot files.
+    pub format: SnapshotFormat,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EventSnapshotError {
+    #[error("Failed to retrieve events: {0:?}")]
+    Retrieve(RetrieveError),
+
+    #[error("Failed to restore events: {0:?}")]
+    Store(StoreError),
+
+    #[error("Snapshot I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid snapshot manifest: {0}")]
+    InvalidManifest(String),
+
+    #[error("No snapshot was taken at or before {0}")]
+    NoSnapshotBefore(Timestamp),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotKind {
+    /// Contains all events.
+    Full,
+
+    /// Contains the changes since the previous snapshot of the chain.
+    Delta,
+}
+
+/// A snapshot file in the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainLink {
+    pub file: String,
+    pub kind: SnapshotKind,
+
+    /// Unix timestamp of the moment the snapshot reflects.
+    pub created_at: Timestamp,
+
+    /// Number of events stored or changed.
+    pub events: u64,
+
+    /// Number of events deleted.
+    pub deleted: u64,
+}
+
+/// Lists the snapshot chain: a full snapshot followed by deltas.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    chain: Vec<ChainLink>,
+    next_sequence: u64,
+}
+
+/// Events stored or changed, and ids of events deleted since the previous snapshot.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SnapshotBody {
+    events: Vec<(EventId, Event)>,
+    deleted: Vec<EventId>,
+}
+
+struct ChainState {
+    manifest: Manifest,
+
+    /// Checksums of the events as of the last snapshot, to find changed and deleted ones.
+    checksums: BTreeMap<EventId, u32>,
+}
+
+/// Persists the event store as a chain of snapshots: a full snapshot followed by incremental
+/// ones holding only the changes since the previous snapshot.
+pub struct EventSnapshots {
+    config: EventSnapshotConfig,
+    chain: Mutex<ChainState>,
+}
+
+impl EventSnapshots {
+    pub fn new(config: EventSnapshotConfig) -> Self {
+        Self {
+            config,
+            chain: Mutex::new(ChainState {
+                manifest: Manifest::default(),
+                checksums: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Loads the snapshot chain and imports its events into the store. Returns the number of
+    /// restored events.
+    pub async fn restore(
+        &self,
+        store: &(dyn Storage + Send + Sync),
+    ) -> Result<usize, EventSnapshotError> {
+        let mut chain = self.chain.lock().await;
+        let Some(mut manifest) = self.load_manifest()? else {
+            return Ok(0);
+        };
+        let (events, valid_links) = self.replay(&manifest.chain);
+        // Later deltas continue from the last loadable snapshot.
+        manifest.chain.truncate(valid_links);
+
+        chain.checksums = checksums(events.iter());
+        chain.manifest = manifest;
+        let restored = events.len();
+        store
+            .import_events(events.into_iter().collect())
+            .await
+            .map_err(EventSnapshotError::Store)?;
+        info!("Restored {restored} events from snapshots");
+        Ok(restored)
+    }
+
+    /// Takes a snapshot of the store. It is incremental unless `full` is set, there is no
+    /// chain yet or the chain is too long.
+    pub async fn take(
+        &self,
+        store: &(dyn Storage + Send + Sync),
+        full: bool,
+    ) -> Result<ChainLink, EventSnapshotError> {
+        let mut chain = self.chain.lock().await;
+        let events = store
+            .scan(&EventFilter::default(), None)
+            .await
+            .map_err(EventSnapshotError::Retrieve)?;
+        let current = checksums(events.iter().map(|(event_id, event)| (event_id, event)));
+        let full = full
+            || chain.manifest.chain.is_empty()
+            || chain.manifest.chain.len() >= self.config.max_chain_length;
+
+        let (kind, body) = if full {
+            let body = SnapshotBody {
+                events,
+                deleted: vec![],
+            };
+            (SnapshotKind::Full, body)
+        } else {
+            let previous = &chain.checksums;
+            let body = SnapshotBody {
+                events: events
+                    .into_iter()
+                    .filter(|(event_id, _)| previous.get(event_id) != current.get(event_id))
+                    .collect(),
+                deleted: previous
+                    .keys()
+                    .filter(|event_id| !current.contains_key(event_id))
+                    .copied()
+                    .collect(),
+            };
+            (SnapshotKind::Delta, body)
+        };
+        let link = self.append(&mut chain, kind, body, now()).await?;
+        chain.checksums = current;
+        Ok(link)
+    }
+
+    /// Rewrites the chain to reflect the event store as of the given Unix timestamp, as closely
+    /// as the snapshots allow: snapshots taken later are dropped from the chain and the rest is
+    /// collapsed into a full snapshot. The previous manifest and its snapshot files are kept,
+    /// so the restore can be undone.
+    pub async fn restore_to(&self, timestamp: Timestamp) -> Result<ChainLink, EventSnapshotError> {
+        let mut chain = self.chain.lock().await;
+        let manifest = self
+            .load_manifest()?
+            .ok_or(EventSnapshotError::NoSnapshotBefore(timestamp))?;
+        let links: Vec<_> = manifest
+            .chain
+            .iter()
+            .take_while(|link| link.created_at <= timestamp)
+            .cloned()
+            .collect();
+        let (events, applied) = self.replay(&links);
+        let Some(last) = links[..applied].last() else {
+            return Err(EventSnapshotError::NoSnapshotBefore(timestamp));
+        };
+        let created_at = last.created_at;
+
+        let backup = format!("{MANIFEST_FILE}.before-restore-{}", now());
+        tokio::fs::copy(
+            self.config.dir.join(MANIFEST_FILE),
+            self.config.dir.join(&backup),
+        )
+        .await?;
+        info!("Restoring events as of {created_at}, previous manifest kept as {backup}");
+
+        chain.manifest = Manifest {
+            chain: vec![],
+            next_sequence: manifest.next_sequence,
+        };
+        chain.checksums = checksums(events.iter());
+        let body = SnapshotBody {
+            events: events.into_iter().collect(),
+            deleted: vec![],
+        };
+        self.append(&mut chain, SnapshotKind::Full, body, created_at)
+            .await
+    }
+
+    /// Collapses the chain into a single full snapshot. Returns `None` if there is no chain.
+    pub async fn collapse(&self) -> Result<Option<ChainLink>, EventSnapshotError> {
+        let mut chain = self.chain.lock().await;
+        let Some(last) = chain.manifest.chain.last() else {
+            return Ok(None);
+        };
+        let created_at = last.created_at;
+        let (events, _) = self.replay(&chain.manifest.chain);
+        let body = SnapshotBody {
+            events: events.into_iter().collect(),
+            deleted: vec![],
+        };
+        let link = self
+            .append(&mut chain, SnapshotKind::Full, body, created_at)
+            .await?;
+        Ok(Some(link))
+    }
+
+    /// Returns the snapshot chain.
+    pub async fn chain(&self) -> Vec<ChainLink> {
+        self.chain.lock().await.manifest.chain.clone()
+    }
+
+    /// Takes incremental snapshots periodically in the background.
+    pub fn spawn(
+        self: Arc<Self>,
+        store: Arc<dyn Storage + Send + Sync + 'static>,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.interval);
+            // The first tick completes immediately, right after the restore.
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.wait_for(|shutting_down| *shutting_down) => break,
+                }
+                if let Err(error) = self.take(store.as_ref(), false).await {
+                    error!("Failed to snapshot events: {error}");
+                    sentry::capture_task_failure("event_snapshots", &error);
+                }
+            }
+        });
+    }
+
+    /// Writes a snapshot file and adds it to the chain. A full snapshot replaces the chain.
+    async fn append(
+        &self,
+        chain: &mut ChainState,
+        kind: SnapshotKind,
+        body: SnapshotBody,
+        created_at: Timestamp,
+    ) -> Result<ChainLink, EventSnapshotError> {
+        let manifest = &mut chain.manifest;
+        let kind_name = match kind {
+            SnapshotKind::Full => "full",
+            SnapshotKind::Delta => "delta",
+        };
+        let file = format!("events-{:06}-{kind_name}.snap", manifest.next_sequence);
+        let json = serde_json::to_vec(&body).expect("Events always serialize");
+        snapshot_file::write(&self.config.dir.join(&file), &json, &self.config.format).await?;
+
+        let link = ChainLink {
+            file,
+            kind,
+            created_at,
+            events: body.events.len() as u64,
+            deleted: body.deleted.len() as u64,
+        };
+        let obsolete = match kind {
+            SnapshotKind::Full => std::mem::take(&mut manifest.chain),
+            SnapshotKind::Delta => vec![],
+        };
+        manifest.chain.push(link.clone());
+        manifest.next_sequence += 1;
+        let json = serde_json::to_vec_pretty(&*manifest).expect("Manifests always serialize");
+        let plain = SnapshotFormat {
+            compression: false,
+            encryption_key: None,
+            retired_keys: Vec::new(),
+        };
+        snapshot_file::write(&self.config.dir.join(MANIFEST_FILE), &json, &plain).await?;
+
+        // Only delete the replaced files once the new manifest is in place.
+        for link in obsolete {
+            if let Err(error) = tokio::fs::remove_file(self.config.dir.join(&link.file)).await {
+                warn!("Failed to delete snapshot {}: {error}", link.file);
+            }
+        }
+        info!(
+            "Took {kind_name} event snapshot {}: {} events, {} deleted",
+            link.file, link.events, link.deleted
+        );
+        Ok(link)
+    }
+
+    fn load_manifest(&self) -> Result<Option<Manifest>, EventSnapshotError> {
+        let path = self.config.dir.join(MANIFEST_FILE);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let json = snapshot_file::decode(&data, &self.config.format)
+            .map_err(|error| EventSnapshotError::InvalidManifest(error.to_string()))?;
+        let manifest = serde_json::from_slice(&json)
+            .map_err(|error| EventSnapshotError::InvalidManifest(error.to_string()))?;
+        Ok(Some(manifest))
+    }
+
+    /// Applies the snapshots of a chain in order. Stops at the first snapshot that can't be
+    /// loaded, and returns the events with the number of snapshots applied.
+    fn replay(&self, chain: &[ChainLink]) -> (BTreeMap<EventId, Event>, usize) {
+        let mut events = BTreeMap::new();
+        for (index, link) in chain.iter().enumerate() {
+            let path = self.config.dir.join(&link.file);
+            let body = snapshot_file::read(&path, &self.config.format)
+                .and_then(|json| serde_json::from_slice::<SnapshotBody>(&json).ok());
+            let Some(body) = body else {
+                warn!(
+                    "Skipping the rest of the snapshot chain from {}",
+                    path.display()
+                );
+                metrics::increment("snapshot_chain_truncations_total", &[]);
+                return (events, index);
+            };
+            for event_id in body.deleted {
+                events.remove(&event_id);
+            }
+            events.extend(body.events);
+        }
+        (events, chain.len())
+    }
+}
+
+fn checksums<'a>(events: impl Iterator<Item = (&'a EventId, &'a Event)>) -> BTreeMap<EventId, u32> {
+    events
+        .map(|(event_id, event)| {
+            let json = serde_json::to_vec(event).expect("Events always serialize");
+            (*event_id, crc32fast::hash(&json))
+        })
+        .collect()
+}
+
+fn now() -> Timestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryStorage;
+
+    #[tokio::test]
+    async fn test_incremental_snapshots() {
+        let dir = std::env::temp_dir().join(format!("event-snapshots-{}", std::process::id()));
+        let config = EventSnapshotConfig {
+            dir: dir.clone(),
+            interval: Duration::from_secs(60),
+            max_chain_length: 10,
+            format: SnapshotFormat::default(),
+        };
+        let event = |timestamp| Event {
+            event_type: "test".to_string(),
+            timestamp,
+            ..Default::default()
+        };
+
+        let store = InMemoryStorage::new();
+        let snapshots = EventSnapshots::new(config.clone());
+        let first = store.store(event(1)).await.unwrap();
+        let second = store.store(event(2)).await.unwrap();
+        let link = snapshots.take(&store, false).await.unwrap();
+        assert_eq!(link.kind, SnapshotKind::Full);
+
+        store.store(event(3)).await.unwrap();
+        store.increment_count(first, 1).await.unwrap();
+        store.replace_events(&[second], vec![]).await.unwrap();
+        let link = snapshots.take(&store, false).await.unwrap();
+        assert_eq!(
+            (link.kind, link.events, link.deleted),
+            (SnapshotKind::Delta, 2, 1)
+        );
+
+        let all = EventFilter::default();
+        let expected = store.scan(&all, None).await.unwrap();
+        let restored = InMemoryStorage::new();
+        let restored_snapshots = EventSnapshots::new(config.clone());
+        assert_eq!(restored_snapshots.restore(&restored).await.unwrap(), 2);
+        assert_eq!(restored.scan(&all, None).await.unwrap(), expected);
+        assert!(restored.store(event(4)).await.unwrap() > expected[1].0);
+
+        snapshots.collapse().await.unwrap();
+        assert_eq!(snapshots.chain().await.len(), 1);
+        let restored = InMemoryStorage::new();
+        EventSnapshots::new(config.clone())
+            .restore(&restored)
+            .await
+            .unwrap();
+        assert_eq!(restored.scan(&all, None).await.unwrap(), expected);
+
+        let snapshots = EventSnapshots::new(config.clone());
+        assert!(matches!(
+            snapshots.restore_to(0).await,
+            Err(EventSnapshotError::NoSnapshotBefore(0))
+        ));
+        let link = snapshots.restore_to(u64::MAX).await.unwrap();
+        assert_eq!((link.kind, link.events), (SnapshotKind::Full, 2));
+        let restored = InMemoryStorage::new();
+        EventSnapshots::new(config)
+            .restore(&restored)
+            .await
+            .unwrap();
+        assert_eq!(restored.scan(&all, None).await.unwrap(), expected);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
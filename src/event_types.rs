@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+
+/// Human documentation of an event type, so the tracker doubles as an event catalog.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct EventTypeMeta {
+    #[serde(default)]
+    pub description: String,
+
+    /// Teams or people responsible for producing the events.
+    #[serde(default)]
+    pub owners: Vec<String>,
+
+    /// Example payloads.
+    #[serde(default)]
+    pub examples: Vec<serde_json::Value>,
+}
+
+/// Documentation of a single event type, as declared in resources.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct EventTypeDoc {
+    pub event_type: String,
+
+    #[serde(flatten)]
+    pub meta: EventTypeMeta,
+}
+
+/// The documented event types.
+pub struct EventTypeCatalog {
+    docs: RwLock<Vec<EventTypeDoc>>,
+}
+
+impl EventTypeCatalog {
+    pub fn new(docs: Vec<EventTypeDoc>) -> Self {
+        Self {
+            docs: RwLock::new(docs),
+        }
+    }
+
+    pub fn docs(&self) -> Vec<EventTypeDoc> {
+        self.docs.read().unwrap().clone()
+    }
+
+    pub fn set_docs(&self, docs: Vec<EventTypeDoc>) {
+        *self.docs.write().unwrap() = docs;
+    }
+
+    pub fn get(&self, event_type: &str) -> Option<EventTypeMeta> {
+        let docs = self.docs.read().unwrap();
+        let doc = docs.iter().find(|doc| doc.event_type == event_type)?;
+        Some(doc.meta.clone())
+    }
+
+    /// Documents an event type, replacing its previous documentation.
+    pub fn set(&self, event_type: &str, meta: EventTypeMeta) {
+        let mut docs = self.docs.write().unwrap();
+        match docs.iter_mut().find(|doc| doc.event_type == event_type) {
+            Some(doc) => doc.meta = meta,
+            None => docs.push(EventTypeDoc {
+                event_type: event_type.to_string(),
+                meta,
+            }),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    num::NonZeroU64,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{
+    event::Timestamp,
+    exports::{ExportConfig, ExportFormat, ExportRequest, export},
+    jobs::Jobs,
+    metrics,
+    storage::{EventFilter, Storage},
+};
+
+/// How often schedules are checked for a finished period.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Exports the events of each finished period, like a daily dump of yesterday's events.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ExportSchedule {
+    pub name: String,
+
+    /// Length of the exported periods in seconds, like 86400 for daily exports. Periods are
+    /// aligned to multiples of it since the epoch, so daily periods start at midnight UTC.
+    pub every: NonZeroU64,
+
+    /// Event types exported into separate objects. All events go into one object if empty.
+    #[serde(default)]
+    pub event_types: Vec<String>,
+
+    #[serde(default)]
+    pub format: ExportFormat,
+
+    /// URL the outcome of each run is posted to.
+    pub notify_url: Option<String>,
+}
+
+/// Outcome of a scheduled export run, posted to the notification URL.
+#[derive(Debug, Serialize)]
+struct RunNotification<'a> {
+    schedule: &'a str,
+    start: Timestamp,
+    end: Timestamp,
+    succeeded: bool,
+
+    /// Exports written by a successful run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exports: Option<&'a serde_json::Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Runs export schedules as background jobs.
+pub struct ExportScheduler {
+    schedules: RwLock<Vec<ExportSchedule>>,
+
+    /// End of the last period exported by each schedule.
+    exported_until: Mutex<AHashMap<String, Timestamp>>,
+
+    client: reqwest::Client,
+}
+
+impl ExportScheduler {
+    pub fn new(schedules: Vec<ExportSchedule>) -> Self {
+        Self {
+            schedules: RwLock::new(schedules),
+            exported_until: Mutex::new(AHashMap::new()),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn schedules(&self) -> Vec<ExportSchedule> {
+        self.schedules.read().unwrap().clone()
+    }
+
+    pub fn set_schedules(&self, schedules: Vec<ExportSchedule>) {
+        *self.schedules.write().unwrap() = schedules;
+    }
+
+    /// Checks the schedules every minute, as long as the process runs.
+    pub fn spawn(
+        self: Arc<Self>,
+        store: Arc<dyn Storage + Send + Sync + 'static>,
+        config: ExportConfig,
+        jobs: Arc<Jobs>,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.wait_for(|shutting_down| *shutting_down) => break,
+                }
+                self.run_due(&store, &config, &jobs, now());
+            }
+        });
+    }
+
+    /// Starts an export job for each schedule whose latest finished period wasn't exported.
+    ///
+    /// Exported periods aren't persisted, so the latest period is exported again after a
+    /// restart, overwriting the same objects.
+    pub fn run_due(
+        self: &Arc<Self>,
+        store: &Arc<dyn Storage + Send + Sync + 'static>,
+        config: &ExportConfig,
+        jobs: &Arc<Jobs>,
+        now: Timestamp,
+    ) {
+        for schedule in self.schedules() {
+            let end = now / schedule.every.get() * schedule.every.get();
+            let start = end.saturating_sub(schedule.every.get());
+            {
+                let mut exported_until = self.exported_until.lock().unwrap();
+                let until = exported_until.entry(schedule.name.clone()).or_default();
+                if *until >= end {
+                    continue;
+                }
+                *until = end;
+            }
+
+            info!(
+                "Running export schedule '{}' for {start}..{end}",
+                schedule.name
+            );
+            let scheduler = self.clone();
+            let store = store.clone();
+            let config = config.clone();
+            jobs.start("scheduled_export", async move {
+                let outcome = run(store.as_ref(), &config, &schedule, start, end).await;
+                scheduler.notify(&schedule, start, end, &outcome).await;
+                outcome
+            });
+        }
+    }
+
+    /// Reports the outcome of a run in the metrics and to the notification URL.
+    async fn notify(
+        &self,
+        schedule: &ExportSchedule,
+        start: Timestamp,
+        end: Timestamp,
+        outcome: &anyhow::Result<serde_json::Value>,
+    ) {
+        let label = if outcome.is_ok() { "ok" } else { "failed" };
+        let labels = [("schedule", schedule.name.as_str()), ("outcome", label)];
+        metrics::increment("scheduled_exports_total", &labels);
+
+        let Some(url) = &schedule.notify_url else {
+            return;
+        };
+        let notification = RunNotification {
+            schedule: &schedule.name,
+            start,
+            end,
+            succeeded: outcome.is_ok(),
+            exports: outcome.as_ref().ok(),
+            error: outcome.as_ref().err().map(|error| format!("{error:#}")),
+        };
+        let response = self.client.post(url).json(&notification).send().await;
+        if let Err(error) = response.and_then(|response| response.error_for_status()) {
+            warn!(
+                "Failed to notify {url} of export schedule '{}': {error}",
+                schedule.name
+            );
+        }
+    }
+}
+
+/// Exports the events of a period, returning the written exports.
+async fn run(
+    store: &(dyn Storage + Send + Sync),
+    config: &ExportConfig,
+    schedule: &ExportSchedule,
+    start: Timestamp,
+    end: Timestamp,
+) -> anyhow::Result<serde_json::Value> {
+    let event_types = match schedule.event_types.is_empty() {
+        true => vec![None],
+        false => schedule.event_types.iter().map(Some).collect(),
+    };
+    let mut results = vec![];
+    for event_type in event_types {
+        let extension = schedule.format.extension();
+        let object = event_type.map_or("all", String::as_str);
+        let request = ExportRequest {
+            filter: EventFilter {
+                event_type: event_type.cloned(),
+                start: Some(start),
+                end: Some(end - 1),
+                ..Default::default()
+            },
+            format: schedule.format,
+            name: Some(format!("{}/{object}/{start}.{extension}", schedule.name)),
+        };
+        results.push(export(store, config, &request).await?);
+    }
+    Ok(serde_json::to_value(results)?)
+}
+
+fn now() -> Timestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{event::Event, jobs::JobState, storage::InMemoryStorage};
+
+    #[tokio::test]
+    async fn test_run_due() {
+        let store: Arc<dyn Storage + Send + Sync> = Arc::new(InMemoryStorage::new());
+        for (event_type, timestamp) in [("login", 50), ("login", 150), ("signup", 160)] {
+            let event = Event {
+                event_type: event_type.to_string(),
+                timestamp,
+                ..Default::default()
+            };
+            store.store(event).await.unwrap();
+        }
+        let dir = std::env::temp_dir().join(format!("export-schedules-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = ExportConfig {
+            destination: format!("file://{}", dir.display()),
+        };
+        let schedule = ExportSchedule {
+            name: "hourly".to_string(),
+            every: NonZeroU64::new(100).unwrap(),
+            event_types: vec!["login".to_string(), "signup".to_string()],
+            format: ExportFormat::Ndjson,
+            notify_url: None,
+        };
+        let scheduler = Arc::new(ExportScheduler::new(vec![schedule]));
+        let jobs = Arc::new(Jobs::default());
+
+        scheduler.run_due(&store, &config, &jobs, 250);
+        // The period was already exported.
+        scheduler.run_due(&store, &config, &jobs, 299);
+        assert_eq!(jobs.list().len(), 1);
+        while jobs.list().iter().any(|job| job.state == JobState::Running) {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(jobs.list()[0].state, JobState::Succeeded);
+        let logins = std::fs::read_to_string(dir.join("hourly/login/100.ndjson")).unwrap();
+        assert_eq!(logins.lines().count(), 1);
+        assert!(dir.join("hourly/signup/100.ndjson").exists());
+
+        scheduler.run_due(&store, &config, &jobs, 300);
+        assert_eq!(jobs.list().len(), 2);
+        while jobs.list().iter().any(|job| job.state == JobState::Running) {
+            tokio::task::yield_now().await;
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
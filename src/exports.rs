@@ -0,0 +1,440 @@
+use arrow_array::{ArrayRef, RecordBatch, StringArray, UInt64Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use object_store::{GetOptions, GetResult, ObjectStore, WriteMultipart, path::Path};
+use parquet::arrow::ArrowWriter;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    ops::Range,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{Instrument, Span, field, info, info_span};
+
+use crate::{
+    event::{Event, EventId},
+    storage::{EventFilter, RetrieveError, Storage},
+};
+
+/// Events encoded and uploaded at a time. Events are read from the storage page by page until
+/// a chunk is full, so only a chunk of events is in memory at a time, and Parquet exports get a
+/// row group per chunk.
+const CHUNK_EVENTS: usize = 10_000;
+
+/// Parts uploaded concurrently.
+const MAX_CONCURRENT_PARTS: usize = 8;
+
+/// Configures where exports are written.
+#[derive(Debug, Clone)]
+pub struct ExportConfig {
+    /// Object store URL exports are written under, like `s3://bucket/exports`,
+    /// `gs://bucket/exports` or `file:///var/exports`. Credentials are taken from the
+    /// environment, e.g. `AWS_ACCESS_KEY_ID` or `GOOGLE_SERVICE_ACCOUNT`.
+    pub destination: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One JSON event per line.
+    #[default]
+    Ndjson,
+
+    /// Columns of event id, type, timestamp, correlation id, count, and payload as JSON text.
+    Parquet,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 2] = [ExportFormat::Ndjson, ExportFormat::Parquet];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+/// Events to export and how.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct ExportRequest {
+    #[serde(default)]
+    pub filter: EventFilter,
+
+    #[serde(default)]
+    pub format: ExportFormat,
+
+    /// Object name under the destination, may contain `/`. Defaults to
+    /// `export-<unix millis>.<format>`.
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportResult {
+    /// URL of the written object.
+    pub location: String,
+
+    /// Name of the written object under the destination.
+    pub object: String,
+
+    pub format: ExportFormat,
+    pub events: u64,
+    pub bytes: u64,
+
+    /// Hex SHA-256 hash of the object, to verify downloads.
+    pub sha256: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    #[error("Invalid export destination '{0}'")]
+    Destination(String),
+
+    #[error("Failed to retrieve events: {0:?}")]
+    Retrieve(RetrieveError),
+
+    #[error("Failed to encode events: {0}")]
+    Encode(String),
+
+    #[error("Failed to write export: {0}")]
+    Write(#[from] object_store::Error),
+}
+
+/// An exported event, with its id.
+#[derive(Serialize)]
+struct ExportedEvent<'a> {
+    event_id: String,
+
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Writes the events matching the filter to the destination, ordered by timestamp, as a
+/// multipart upload.
+pub async fn export(
+    store: &(dyn Storage + Send + Sync),
+    config: &ExportConfig,
+    request: &ExportRequest,
+) -> Result<ExportResult, ExportError> {
+    let name = request.name.clone().unwrap_or_else(|| {
+        let extension = request.format.extension();
+        format!("export-{}.{extension}", now_millis())
+    });
+    let (object_store, path) = open(config, &name)?;
+
+    let span = object_span("put_multipart", &path);
+    let upload = object_store
+        .put_multipart(&path)
+        .instrument(span.clone())
+        .await?;
+    let mut writer = WriteMultipart::new(upload);
+    let mut written = Written::default();
+    if let Err(error) = write_events(store, request, &mut writer, &mut written, &span).await {
+        writer.abort().instrument(span).await?;
+        return Err(error);
+    }
+    writer.finish().instrument(span.clone()).await?;
+    span.record("object_store.bytes", written.bytes);
+
+    let location = format!("{}/{name}", config.destination.trim_end_matches('/'));
+    info!("Exported {} events to {location}", written.events);
+    Ok(ExportResult {
+        location,
+        object: name,
+        format: request.format,
+        events: written.events,
+        bytes: written.bytes,
+        sha256: format!("{:x}", written.hasher.finalize()),
+    })
+}
+
+/// What has been uploaded of an export so far.
+#[derive(Default)]
+struct Written {
+    events: u64,
+    bytes: u64,
+    hasher: Sha256,
+}
+
+/// Reads the matching events page by page, and encodes and uploads them a chunk at a time.
+async fn write_events(
+    store: &(dyn Storage + Send + Sync),
+    request: &ExportRequest,
+    writer: &mut WriteMultipart,
+    written: &mut Written,
+    span: &Span,
+) -> Result<(), ExportError> {
+    let filter = &request.filter;
+    let mut encoder = Encoder::new(request.format)?;
+    let mut chunk = Vec::with_capacity(CHUNK_EVENTS);
+    let mut cursor = None;
+    loop {
+        let page = store
+            .get_events_page(
+                filter.event_type.as_deref(),
+                filter.start,
+                filter.end,
+                cursor,
+                CHUNK_EVENTS,
+            )
+            .await
+            .map_err(ExportError::Retrieve)?;
+        // Pages are only filtered by type and time range.
+        chunk.extend(
+            page.events
+                .into_iter()
+                .filter(|(_, event)| filter.matches(event)),
+        );
+        cursor = page.next_cursor;
+        if !chunk.is_empty() && (chunk.len() >= CHUNK_EVENTS || cursor.is_none()) {
+            let data = encoder.encode(&chunk)?;
+            written.events += chunk.len() as u64;
+            chunk.clear();
+            upload(writer, &data, written, span).await?;
+        }
+        if cursor.is_none() {
+            break;
+        }
+    }
+    let data = encoder.finish()?;
+    upload(writer, &data, written, span).await
+}
+
+/// Uploads encoded data, waiting while too many parts are in flight.
+async fn upload(
+    writer: &mut WriteMultipart,
+    data: &[u8],
+    written: &mut Written,
+    span: &Span,
+) -> Result<(), ExportError> {
+    written.bytes += data.len() as u64;
+    written.hasher.update(data);
+    writer
+        .wait_for_capacity(MAX_CONCURRENT_PARTS)
+        .instrument(span.clone())
+        .await?;
+    writer.write(data);
+    Ok(())
+}
+
+/// Reads a byte range of a written export, or the whole of it.
+pub async fn read_export(
+    config: &ExportConfig,
+    object: &str,
+    range: Option<Range<usize>>,
+) -> Result<GetResult, ExportError> {
+    let (object_store, path) = open(config, object)?;
+    let options = GetOptions {
+        range: range.map(Into::into),
+        ..Default::default()
+    };
+    let span = object_span("get", &path);
+    let result = object_store
+        .get_opts(&path, options)
+        .instrument(span.clone())
+        .await?;
+    span.record("object_store.bytes", result.range.len());
+    Ok(result)
+}
+
+/// Creates the span of an object store operation, recording the bytes transferred in
+/// `object_store.bytes`.
+fn object_span(operation: &'static str, path: &Path) -> Span {
+    info_span!(
+        "object_store",
+        object_store.operation = operation,
+        object_store.path = %path,
+        object_store.bytes = field::Empty,
+    )
+}
+
+/// Connects to the destination, returning the path of the named object under it.
+fn open(config: &ExportConfig, name: &str) -> Result<(Box<dyn ObjectStore>, Path), ExportError> {
+    let invalid_destination = || ExportError::Destination(config.destination.clone());
+    let url = config
+        .destination
+        .parse()
+        .map_err(|_| invalid_destination())?;
+    let (object_store, prefix) =
+        object_store::parse_url_opts(&url, std::env::vars()).map_err(|_| invalid_destination())?;
+    let name = Path::from(name);
+    Ok((object_store, prefix.parts().chain(name.parts()).collect()))
+}
+
+fn encode_ndjson(events: &[(EventId, Event)]) -> Vec<u8> {
+    let mut data = vec![];
+    for (event_id, event) in events {
+        let exported = ExportedEvent {
+            event_id: event_id.to_string(),
+            event,
+        };
+        serde_json::to_writer(&mut data, &exported).expect("Events always serialize");
+        data.push(b'\n');
+    }
+    data
+}
+
+/// Encodes events in the export format chunk by chunk, returning the bytes to upload.
+enum Encoder {
+    Ndjson,
+
+    /// Writes a row group per chunk into its buffer, which is emptied after each chunk.
+    Parquet(ArrowWriter<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(format: ExportFormat) -> Result<Self, ExportError> {
+        match format {
+            ExportFormat::Ndjson => Ok(Encoder::Ndjson),
+            ExportFormat::Parquet => ArrowWriter::try_new(vec![], parquet_schema(), None)
+                .map(Encoder::Parquet)
+                .map_err(|e| encode_error(&e)),
+        }
+    }
+
+    fn encode(&mut self, events: &[(EventId, Event)]) -> Result<Vec<u8>, ExportError> {
+        match self {
+            Encoder::Ndjson => Ok(encode_ndjson(events)),
+            Encoder::Parquet(writer) => {
+                writer
+                    .write(&record_batch(events)?)
+                    .map_err(|e| encode_error(&e))?;
+                writer.flush().map_err(|e| encode_error(&e))?;
+                Ok(std::mem::take(writer.inner_mut()))
+            }
+        }
+    }
+
+    /// Returns the bytes left to upload after the last chunk, like the Parquet footer.
+    fn finish(self) -> Result<Vec<u8>, ExportError> {
+        match self {
+            Encoder::Ndjson => Ok(vec![]),
+            Encoder::Parquet(mut writer) => {
+                writer.finish().map_err(|e| encode_error(&e))?;
+                Ok(std::mem::take(writer.inner_mut()))
+            }
+        }
+    }
+}
+
+fn encode_error(error: &dyn std::error::Error) -> ExportError {
+    ExportError::Encode(error.to_string())
+}
+
+fn parquet_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        // Ids may not fit 64 bits.
+        Field::new("event_id", DataType::Utf8, false),
+        Field::new("event_type", DataType::Utf8, false),
+        Field::new("timestamp", DataType::UInt64, false),
+        Field::new("correlation_id", DataType::Utf8, true),
+        Field::new("count", DataType::UInt64, true),
+        Field::new("payload", DataType::Utf8, false),
+    ]))
+}
+
+fn record_batch(chunk: &[(EventId, Event)]) -> Result<RecordBatch, ExportError> {
+    let events = chunk.iter().map(|(_, event)| event);
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(
+            chunk.iter().map(|(event_id, _)| event_id.to_string()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            events.clone().map(|event| event.event_type.as_str()),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            events.clone().map(|event| event.timestamp),
+        )),
+        Arc::new(StringArray::from_iter(
+            events.clone().map(|event| event.correlation_id.as_deref()),
+        )),
+        Arc::new(UInt64Array::from_iter(
+            events.clone().map(|event| event.count),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            events.map(|event| event.payload.to_string()),
+        )),
+    ];
+    RecordBatch::try_new(parquet_schema(), columns).map_err(|e| encode_error(&e))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryStorage;
+    use axum::body::Bytes;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    #[tokio::test]
+    async fn test_export() {
+        let store = InMemoryStorage::new();
+        // More events than fit a page of the storage.
+        for event_type in ["signup", "login", "login", "signup", "signup", "signup"] {
+            let event = Event {
+                event_type: event_type.to_string(),
+                payload: serde_json::json!({"user_id": 1}),
+                ..Default::default()
+            };
+            store.store(event).await.unwrap();
+        }
+        let dir = std::env::temp_dir().join(format!("exports-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = ExportConfig {
+            destination: format!("file://{}", dir.display()),
+        };
+        let request = ExportRequest {
+            filter: EventFilter {
+                event_type: Some("login".to_string()),
+                ..Default::default()
+            },
+            name: Some("logins.ndjson".to_string()),
+            ..Default::default()
+        };
+
+        let result = export(&store, &config, &request).await.unwrap();
+        assert_eq!(result.events, 2);
+        let content = std::fs::read_to_string(dir.join("logins.ndjson")).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event_type"], "login");
+        assert_eq!(lines[0]["event_id"], "2");
+        assert_eq!(result.sha256, format!("{:x}", Sha256::digest(&content)));
+        let part = read_export(&config, "logins.ndjson", Some(5..10))
+            .await
+            .unwrap();
+        assert_eq!(part.bytes().await.unwrap(), content.as_bytes()[5..10]);
+
+        let request = ExportRequest {
+            format: ExportFormat::Parquet,
+            name: Some("all.parquet".to_string()),
+            ..Default::default()
+        };
+        let result = export(&store, &config, &request).await.unwrap();
+        assert_eq!(result.events, 6);
+        let content = std::fs::read(dir.join("all.parquet")).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(content))
+            .unwrap()
+            .build()
+            .unwrap();
+        let rows: usize = reader.map(|batch| batch.unwrap().num_rows()).sum();
+        assert_eq!(rows, 6);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
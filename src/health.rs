@@ -0,0 +1,175 @@
+use serde::Serialize;
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::{event::Event, metrics, storage::Storage};
+
+/// Event type of the synthetic events written by probes. They are deleted right after.
+const PROBE_EVENT_TYPE: &str = "__health_probe";
+
+/// Configures the storage backend self-checks.
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// How often the backend is probed.
+    pub interval: Duration,
+
+    /// Probes slower than this count as failed.
+    pub max_latency: Duration,
+
+    /// Number of consecutive failed probes after which the backend is considered degraded.
+    pub failure_threshold: u32,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            max_latency: Duration::from_millis(500),
+            failure_threshold: 3,
+        }
+    }
+}
+
+/// Outcome of the latest probes.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HealthStatus {
+    pub degraded: bool,
+    pub consecutive_failures: u32,
+    pub last_latency_ms: Option<f64>,
+    pub last_error: Option<String>,
+}
+
+/// Periodically writes, reads and deletes a synthetic event in the storage backend to detect
+/// when it degrades. Optional features are switched off while it is degraded.
+pub struct Health {
+    store: Arc<dyn Storage + Send + Sync + 'static>,
+    config: HealthConfig,
+    status: RwLock<HealthStatus>,
+}
+
+impl Health {
+    pub fn new(store: Arc<dyn Storage + Send + Sync + 'static>, config: HealthConfig) -> Self {
+        Self {
+            store,
+            config,
+            status: RwLock::new(HealthStatus::default()),
+        }
+    }
+
+    pub fn status(&self) -> HealthStatus {
+        self.status.read().unwrap().clone()
+    }
+
+    /// Returns the status unless it's being updated, e.g. by a panicking thread.
+    pub fn try_status(&self) -> Option<HealthStatus> {
+        self.status.try_read().ok().map(|status| status.clone())
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.status.read().unwrap().degraded
+    }
+
+    /// Runs a single probe and updates the status.
+    pub async fn probe(&self) {
+        let started = Instant::now();
+        let result = self.run_probe().await;
+        let latency = started.elapsed();
+        let result = result.and_then(|()| {
+            if latency > self.config.max_latency {
+                Err(format!("Probe took {latency:?}"))
+            } else {
+                Ok(())
+            }
+        });
+
+        let outcome = if result.is_ok() { "ok" } else { "failed" };
+        metrics::increment("storage_probes_total", &[("outcome", outcome)]);
+        metrics::set_gauge("storage_probe_seconds", &[], latency.as_secs_f64());
+
+        let mut status = self.status.write().unwrap();
+        status.last_latency_ms = Some(latency.as_secs_f64() * 1000.0);
+        match result {
+            Ok(()) => {
+                status.consecutive_failures = 0;
+                status.last_error = None;
+            }
+            Err(error) => {
+                warn!("Storage probe failed: {error}");
+                status.consecutive_failures += 1;
+                status.last_error = Some(error);
+            }
+        }
+        status.degraded = status.consecutive_failures >= self.config.failure_threshold;
+        let degraded = if status.degraded { 1.0 } else { 0.0 };
+        metrics::set_gauge("storage_degraded", &[], degraded);
+    }
+
+    async fn run_probe(&self) -> Result<(), String> {
+        let event = Event {
+            event_type: PROBE_EVENT_TYPE.to_string(),
+            ..Default::default()
+        };
+        let event_id = self
+            .store
+            .store(event)
+            .await
+            .map_err(|error| format!("Write failed: {error:?}"))?;
+        let count = self
+            .store
+            .count_events(Some(PROBE_EVENT_TYPE), None, None)
+            .await
+            .map_err(|error| format!("Read failed: {error:?}"))?;
+        self.store
+            .replace_events(&[event_id], vec![])
+            .await
+            .map_err(|error| format!("Delete failed: {error:?}"))?;
+        if count == 0 {
+            return Err("Written event not found".to_string());
+        }
+        Ok(())
+    }
+
+    /// Probes the backend periodically in the background.
+    pub fn spawn(self: Arc<Self>, mut shutdown: watch::Receiver<bool>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.interval);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.wait_for(|shutting_down| *shutting_down) => break,
+                }
+                self.probe().await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryStorage;
+
+    #[tokio::test]
+    async fn test_probe() {
+        let store = Arc::new(InMemoryStorage::new());
+        let config = HealthConfig {
+            max_latency: Duration::ZERO,
+            failure_threshold: 2,
+            ..Default::default()
+        };
+        let health = Health::new(store.clone(), config);
+
+        // Every probe is too slow with a zero latency budget.
+        health.probe().await;
+        assert!(!health.is_degraded());
+        health.probe().await;
+        assert!(health.is_degraded());
+
+        // Probe events don't stay in the storage.
+        assert_eq!(store.count_events(None, None, None).await.unwrap(), 0);
+    }
+}
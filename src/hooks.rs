@@ -0,0 +1,178 @@
+use std::{fmt, sync::Arc, time::Duration};
+use tracing::warn;
+
+use crate::{
+    event::{Event, EventId},
+    event_policy::glob_matches,
+    metrics,
+};
+
+/// Hooks taking longer than this are abandoned, so they can't stall ingest.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Side effect run after an event was stored, like invalidating a cache.
+#[async_trait::async_trait]
+pub trait IngestHook: Send + Sync {
+    /// Name of the hook in logs and metrics.
+    fn name(&self) -> &str;
+
+    /// Called with each stored event the hook is registered for.
+    async fn after_store(&self, event_id: EventId, event: &Event) -> anyhow::Result<()>;
+}
+
+/// Hooks registered for all event types or for event type patterns.
+///
+/// For each stored event, the matching hooks run one after another in registration order, and
+/// the request returns after they ran. A hook failing, panicking or timing out is logged and
+/// counted in the `ingest_hook_runs_total` metric, but doesn't affect the request or other
+/// hooks. Events merged into an earlier one by coalescing don't trigger hooks.
+#[derive(Clone, Default)]
+pub struct IngestHooks {
+    /// Hooks with the event type pattern they are registered for, if any.
+    hooks: Vec<(Option<String>, Arc<dyn IngestHook>)>,
+}
+
+impl IngestHooks {
+    /// Registers a hook for events whose type matches the pattern, or for all events. Patterns
+    /// are exact event types or globs, like in the event type policy.
+    pub fn register(&mut self, event_type: Option<&str>, hook: Arc<dyn IngestHook>) {
+        self.hooks.push((event_type.map(str::to_string), hook));
+    }
+
+    /// Returns whether any hook would run for the event.
+    pub fn matches(&self, event: &Event) -> bool {
+        self.matching(event).next().is_some()
+    }
+
+    /// Runs the matching hooks for a stored event.
+    pub async fn run(&self, event_id: EventId, event: Event) {
+        let event = Arc::new(event);
+        for hook in self.matching(&event) {
+            let name = hook.name().to_string();
+            let started = std::time::Instant::now();
+            let task = tokio::spawn({
+                let (hook, event) = (hook.clone(), event.clone());
+                async move { hook.after_store(event_id, &event).await }
+            });
+            // Running the hook in its own task contains its panics.
+            let outcome = match tokio::time::timeout(HOOK_TIMEOUT, task).await {
+                Ok(Ok(Ok(()))) => "ok",
+                Ok(Ok(Err(error))) => {
+                    warn!("Ingest hook '{name}' failed for event {event_id}: {error:#}");
+                    "failed"
+                }
+                Ok(Err(error)) => {
+                    warn!("Ingest hook '{name}' panicked for event {event_id}: {error}");
+                    "panicked"
+                }
+                Err(_) => {
+                    warn!("Ingest hook '{name}' timed out for event {event_id}");
+                    "timed_out"
+                }
+            };
+            metrics::increment(
+                "ingest_hook_runs_total",
+                &[("hook", name.as_str()), ("outcome", outcome)],
+            );
+            metrics::add(
+                "ingest_hook_seconds_total",
+                &[("hook", name.as_str())],
+                started.elapsed().as_secs_f64(),
+            );
+        }
+    }
+
+    fn matching(&self, event: &Event) -> impl Iterator<Item = &Arc<dyn IngestHook>> {
+        self.hooks
+            .iter()
+            .filter(|(pattern, _)| {
+                pattern
+                    .as_ref()
+                    .is_none_or(|pattern| glob_matches(pattern, &event.event_type))
+            })
+            .map(|(_, hook)| hook)
+    }
+}
+
+impl fmt::Debug for IngestHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.hooks
+                    .iter()
+                    .map(|(pattern, hook)| (pattern, hook.name())),
+            )
+            .finish()
+    }
+}
+
+/// Counts stored events by type in the `events_stored_total` metric.
+pub struct CountingHook;
+
+#[async_trait::async_trait]
+impl IngestHook for CountingHook {
+    fn name(&self) -> &str {
+        "counting"
+    }
+
+    async fn after_store(&self, _event_id: EventId, event: &Event) -> anyhow::Result<()> {
+        metrics::increment(
+            "events_stored_total",
+            &[("event_type", event.event_type.as_str())],
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records the ids of the events it was called with, and fails for odd ids.
+    struct RecordingHook {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<(&'static str, EventId)>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl IngestHook for RecordingHook {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn after_store(&self, event_id: EventId, _event: &Event) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push((self.name, event_id));
+            anyhow::ensure!(event_id.is_multiple_of(2), "odd event id");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ingest_hooks() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let hook = |name| {
+            Arc::new(RecordingHook {
+                name,
+                calls: calls.clone(),
+            })
+        };
+        let mut hooks = IngestHooks::default();
+        hooks.register(Some("order_*"), hook("orders"));
+        hooks.register(None, hook("all"));
+        let event = |event_type: &str| Event {
+            event_type: event_type.to_string(),
+            ..Default::default()
+        };
+
+        assert!(hooks.matches(&event("signup")));
+        hooks.run(1, event("order_paid")).await;
+        hooks.run(2, event("signup")).await;
+
+        // A failing hook doesn't stop the next one.
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [("orders", 1), ("all", 1), ("all", 2)]
+        );
+    }
+}
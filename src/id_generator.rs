@@ -0,0 +1,216 @@
+use std::{
+    str::FromStr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::event::EventId;
+
+/// Generates event ids.
+///
+/// Ids must be strictly increasing within a process: storages rely on them to scan events in
+/// insertion order.
+pub trait IdGenerator {
+    fn generate(&self) -> EventId;
+
+    /// Makes sure later ids are larger than the given one, eg. after restoring events.
+    fn observe(&self, event_id: EventId);
+}
+
+/// Id generation strategies selectable at startup.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IdStrategy {
+    /// 1, 2, 3, ... Only unique within a single node.
+    #[default]
+    Sequential,
+
+    /// 48-bit millisecond timestamp followed by 80 random bits.
+    Ulid,
+
+    /// 41-bit millisecond timestamp, 10-bit node id and 12-bit sequence number.
+    Snowflake { node_id: u16 },
+
+    /// UUID version 7: millisecond timestamp and random bits.
+    UuidV7,
+}
+
+impl FromStr for IdStrategy {
+    type Err = String;
+
+    /// Parses `sequential`, `ulid`, `uuidv7` or `snowflake:<node id>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(IdStrategy::Sequential),
+            "ulid" => Ok(IdStrategy::Ulid),
+            "uuidv7" => Ok(IdStrategy::UuidV7),
+            _ => {
+                let node_id = s
+                    .strip_prefix("snowflake:")
+                    .ok_or_else(|| format!("Unknown id strategy: '{s}'"))?;
+                let node_id = node_id
+                    .parse()
+                    .ok()
+                    .filter(|node_id| *node_id < 1 << SNOWFLAKE_NODE_BITS)
+                    .ok_or_else(|| format!("Invalid snowflake node id: '{node_id}'"))?;
+                Ok(IdStrategy::Snowflake { node_id })
+            }
+        }
+    }
+}
+
+/// Creates the id generator of a strategy.
+pub fn make_id_generator(strategy: IdStrategy) -> Box<dyn IdGenerator + Send + Sync> {
+    match strategy {
+        IdStrategy::Sequential => Box::new(SequentialIdGenerator::default()),
+        IdStrategy::Ulid => Box::new(Monotonic::new(ulid)),
+        IdStrategy::Snowflake { node_id } => Box::new(SnowflakeIdGenerator::new(node_id)),
+        IdStrategy::UuidV7 => Box::new(Monotonic::new(uuid_v7)),
+    }
+}
+
+#[derive(Default)]
+pub struct SequentialIdGenerator {
+    last: AtomicU64,
+}
+
+impl IdGenerator for SequentialIdGenerator {
+    fn generate(&self) -> EventId {
+        (self.last.fetch_add(1, Ordering::Relaxed) + 1) as EventId
+    }
+
+    fn observe(&self, event_id: EventId) {
+        self.last.fetch_max(event_id as u64, Ordering::Relaxed);
+    }
+}
+
+/// Makes a time-based id generator strictly increasing: if the clock goes backwards or two ids
+/// are generated in the same millisecond with a smaller random part, the previous id plus one
+/// is used instead.
+struct Monotonic<F> {
+    generate: F,
+    last: Mutex<EventId>,
+}
+
+impl<F: Fn() -> EventId> Monotonic<F> {
+    fn new(generate: F) -> Self {
+        Self {
+            generate,
+            last: Mutex::new(0),
+        }
+    }
+}
+
+impl<F: Fn() -> EventId> IdGenerator for Monotonic<F> {
+    fn generate(&self) -> EventId {
+        let mut last = self.last.lock().unwrap();
+        *last = (self.generate)().max(*last + 1);
+        *last
+    }
+
+    fn observe(&self, event_id: EventId) {
+        let mut last = self.last.lock().unwrap();
+        *last = event_id.max(*last);
+    }
+}
+
+fn ulid() -> EventId {
+    let random: u128 = rand::random();
+    ((now_millis() as u128) << 80) | (random & ((1 << 80) - 1))
+}
+
+fn uuid_v7() -> EventId {
+    let random: u128 = rand::random();
+    let rand_a = (random >> 64) & 0xfff;
+    let rand_b = random & ((1 << 62) - 1);
+    ((now_millis() as u128) << 80) | (0x7 << 76) | (rand_a << 64) | (0b10 << 62) | rand_b
+}
+
+const SNOWFLAKE_NODE_BITS: u32 = 10;
+const SNOWFLAKE_SEQUENCE_BITS: u32 = 12;
+
+/// Twitter's snowflake epoch, 2010-11-04.
+const SNOWFLAKE_EPOCH_MILLIS: u64 = 1_288_834_974_657;
+
+pub struct SnowflakeIdGenerator {
+    node_id: u64,
+
+    /// Millisecond timestamp and sequence number of the last id.
+    last: Mutex<(u64, u64)>,
+}
+
+impl SnowflakeIdGenerator {
+    pub fn new(node_id: u16) -> Self {
+        Self {
+            node_id: node_id as u64 & ((1 << SNOWFLAKE_NODE_BITS) - 1),
+            last: Mutex::new((0, 0)),
+        }
+    }
+}
+
+impl IdGenerator for SnowflakeIdGenerator {
+    fn generate(&self) -> EventId {
+        let mut last = self.last.lock().unwrap();
+        let (last_millis, last_sequence) = *last;
+        let millis = now_millis().saturating_sub(SNOWFLAKE_EPOCH_MILLIS);
+        // When the sequence of the current millisecond is exhausted or the clock went backwards,
+        // borrow from the next millisecond.
+        *last = if millis > last_millis {
+            (millis, 0)
+        } else if last_sequence + 1 < 1 << SNOWFLAKE_SEQUENCE_BITS {
+            (last_millis, last_sequence + 1)
+        } else {
+            (last_millis + 1, 0)
+        };
+        let (millis, sequence) = *last;
+        ((millis << (SNOWFLAKE_NODE_BITS + SNOWFLAKE_SEQUENCE_BITS))
+            | (self.node_id << SNOWFLAKE_SEQUENCE_BITS)
+            | sequence) as EventId
+    }
+
+    fn observe(&self, event_id: EventId) {
+        // Ids of other nodes may have a larger node id in the same millisecond, so continue
+        // from the next millisecond.
+        let millis = (event_id >> (SNOWFLAKE_NODE_BITS + SNOWFLAKE_SEQUENCE_BITS)) as u64;
+        let mut last = self.last.lock().unwrap();
+        *last = (millis + 1, 0).max(*last);
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_are_increasing() {
+        for strategy in ["sequential", "ulid", "uuidv7", "snowflake:7"] {
+            let generator = make_id_generator(strategy.parse().unwrap());
+            let ids: Vec<_> = (0..10_000).map(|_| generator.generate()).collect();
+            assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "{strategy}");
+        }
+
+        let snowflake = SnowflakeIdGenerator::new(7).generate();
+        assert_eq!((snowflake >> SNOWFLAKE_SEQUENCE_BITS) & 0x3ff, 7);
+
+        let uuid = make_id_generator(IdStrategy::UuidV7).generate();
+        assert_eq!((uuid >> 76) & 0xf, 7);
+
+        assert!("snowflake:1024".parse::<IdStrategy>().is_err());
+
+        for strategy in ["sequential", "ulid", "uuidv7", "snowflake:7"] {
+            let generator = make_id_generator(strategy.parse().unwrap());
+            let restored = generator.generate() + (1 << 40);
+            generator.observe(restored);
+            assert!(generator.generate() > restored, "{strategy}");
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use ahash::AHashMap;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    event::{Event, Timestamp},
+    metrics,
+};
+
+/// Latest lags kept per event type, so percentiles reflect the current state of producers.
+const WINDOW: usize = 1000;
+
+/// Percentile gauges of a type are refreshed every this many events.
+const METRICS_EVERY: u64 = 100;
+
+/// Ingest lag percentiles of an event type, in seconds.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LagReport {
+    pub event_type: String,
+
+    /// Number of events the percentiles are computed from, the latest ones of the type.
+    pub events: usize,
+
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+/// Completeness signal of an event type: all events up to the watermark have been received.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Watermark {
+    pub event_type: String,
+
+    /// Timestamp up to which the events of the type are complete.
+    pub watermark: Timestamp,
+
+    /// Events received since startup with a timestamp already behind the watermark.
+    pub late_events: u64,
+}
+
+#[derive(Default)]
+struct TypeLags {
+    /// Latest lags, oldest first.
+    lags: VecDeque<f64>,
+
+    /// Events received since startup.
+    received: u64,
+
+    /// Latest published watermark, it never goes back.
+    watermark: Timestamp,
+
+    late_events: u64,
+}
+
+impl TypeLags {
+    /// Advances the watermark. An event is received at most the largest recent lag after its
+    /// timestamp, so all events older than that should have arrived by now.
+    fn advance_watermark(&mut self, event_type: &str) -> Timestamp {
+        let max_lag = self.lags.iter().copied().fold(0.0, f64::max);
+        let watermark = (now() - max_lag).max(0.0).floor() as Timestamp;
+        self.watermark = self.watermark.max(watermark);
+        let labels = [("event_type", event_type)];
+        metrics::set_gauge("ingest_watermark_timestamp", &labels, self.watermark as f64);
+        self.watermark
+    }
+}
+
+/// Tracks how far behind producers are: the time between an event's timestamp and when it
+/// was received. A growing lag tells that a producer's buffer is backing up.
+#[derive(Default)]
+pub struct IngestLag {
+    types: Mutex<AHashMap<String, TypeLags>>,
+}
+
+impl IngestLag {
+    /// Records the lag of a received event. Events timestamped in the future, eg. by a skewed
+    /// clock, count as no lag.
+    pub fn record(&self, event: &Event) {
+        let lag = (now() - event.timestamp as f64).max(0.0);
+        let mut types = self.types.lock().unwrap();
+        let lags = types.entry(event.event_type.clone()).or_default();
+        let labels = [("event_type", event.event_type.as_str())];
+        if lags.received > 0 && event.timestamp <= lags.watermark {
+            lags.late_events += 1;
+            metrics::increment("ingest_late_events_total", &labels);
+        }
+        if lags.lags.len() == WINDOW {
+            lags.lags.pop_front();
+        }
+        lags.lags.push_back(lag);
+        lags.received += 1;
+
+        metrics::increment("ingest_lag_events_total", &labels);
+        metrics::add("ingest_lag_seconds_total", &labels, lag);
+        if lags.received % METRICS_EVERY == 1 {
+            set_gauges(&report(&event.event_type, lags));
+            lags.advance_watermark(&event.event_type);
+        }
+    }
+
+    /// Returns the watermark of each event type seen since startup, ordered by event type.
+    ///
+    /// The watermark is estimated from the lags of the latest events, so a producer that
+    /// stops sending while it buffers events isn't accounted for.
+    pub fn watermarks(&self) -> Vec<Watermark> {
+        let mut types = self.types.lock().unwrap();
+        let mut watermarks: Vec<_> = types
+            .iter_mut()
+            .map(|(event_type, lags)| Watermark {
+                event_type: event_type.clone(),
+                watermark: lags.advance_watermark(event_type),
+                late_events: lags.late_events,
+            })
+            .collect();
+        watermarks.sort_by(|a, b| a.event_type.cmp(&b.event_type));
+        watermarks
+    }
+
+    /// Returns the lag percentiles of each event type, ordered by event type.
+    pub fn reports(&self) -> Vec<LagReport> {
+        let types = self.types.lock().unwrap();
+        let mut reports: Vec<_> = types
+            .iter()
+            .map(|(event_type, lags)| report(event_type, lags))
+            .collect();
+        reports.sort_by(|a, b| a.event_type.cmp(&b.event_type));
+        for report in &reports {
+            set_gauges(report);
+        }
+        reports
+    }
+}
+
+fn report(event_type: &str, lags: &TypeLags) -> LagReport {
+    let mut sorted: Vec<_> = lags.lags.iter().copied().collect();
+    sorted.sort_by(f64::total_cmp);
+    // Nearest-rank percentile.
+    let percentile = |p: f64| {
+        let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+        sorted.get(rank.saturating_sub(1)).copied().unwrap_or(0.0)
+    };
+    LagReport {
+        event_type: event_type.to_string(),
+        events: sorted.len(),
+        p50: percentile(50.0),
+        p90: percentile(90.0),
+        p99: percentile(99.0),
+        max: sorted.last().copied().unwrap_or(0.0),
+    }
+}
+
+fn set_gauges(report: &LagReport) {
+    for (quantile, value) in [
+        ("0.5", report.p50),
+        ("0.9", report.p90),
+        ("0.99", report.p99),
+        ("1", report.max),
+    ] {
+        let labels = [
+            ("event_type", report.event_type.as_str()),
+            ("quantile", quantile),
+        ];
+        metrics::set_gauge("ingest_lag_seconds", &labels, value);
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ingest_lag() {
+        let lag = IngestLag::default();
+        let now = now() as u64;
+        for seconds in 1..=100 {
+            let event = Event {
+                event_type: "click".to_string(),
+                timestamp: now - seconds * 10,
+                ..Default::default()
+            };
+            lag.record(&event);
+        }
+        let future = Event {
+            event_type: "signup".to_string(),
+            timestamp: now + 3600,
+            ..Default::default()
+        };
+        lag.record(&future);
+
+        let reports = lag.reports();
+        assert_eq!(reports.len(), 2);
+        let clicks = &reports[0];
+        assert_eq!(clicks.events, 100);
+        // Lags are whole tens of seconds plus the fraction of the current second.
+        assert!((500.0..502.0).contains(&clicks.p50));
+        assert!((990.0..992.0).contains(&clicks.p99));
+        assert!((1000.0..1002.0).contains(&clicks.max));
+        assert_eq!(reports[1].max, 0.0);
+    }
+
+    #[test]
+    fn test_watermarks() {
+        let lag = IngestLag::default();
+        let now = now() as u64;
+        let click = |timestamp| Event {
+            event_type: "click".to_string(),
+            timestamp,
+            ..Default::default()
+        };
+        lag.record(&click(now - 60));
+        lag.record(&click(now - 10));
+
+        // The largest lag is a minute, so events older than that are complete.
+        let watermarks = lag.watermarks();
+        assert_eq!(watermarks.len(), 1);
+        assert!((now - 61..=now - 59).contains(&watermarks[0].watermark));
+        assert_eq!(watermarks[0].late_events, 0);
+
+        // Events behind the watermark are late, and their larger lag doesn't move it back.
+        lag.record(&click(now - 120));
+        lag.record(&click(now - 120));
+        let watermarks = lag.watermarks();
+        assert!(watermarks[0].watermark >= now - 61);
+        assert_eq!(watermarks[0].late_events, 2);
+    }
+}
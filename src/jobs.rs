@@ -0,0 +1,157 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+use crate::{event::Timestamp, metrics, sentry};
+
+/// Finished jobs kept for polling, older ones are forgotten.
+const MAX_FINISHED_JOBS: usize = 1000;
+
+pub type JobId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A background job started through the API, like an export.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: JobId,
+    pub kind: &'static str,
+    pub state: JobState,
+    pub started_at: Timestamp,
+    pub finished_at: Option<Timestamp>,
+
+    /// Outcome of a successful job, depending on its kind.
+    pub result: Option<serde_json::Value>,
+
+    pub error: Option<String>,
+}
+
+/// Runs long operations in the background and keeps their state, so clients can poll for
+/// completion instead of holding a request open.
+#[derive(Default)]
+pub struct Jobs {
+    last_id: AtomicU64,
+    jobs: Mutex<BTreeMap<JobId, Job>>,
+}
+
+impl Jobs {
+    /// Starts a job in the background and returns it in the running state.
+    pub fn start<F>(self: &Arc<Self>, kind: &'static str, job: F) -> Job
+    where
+        F: Future<Output = anyhow::Result<serde_json::Value>> + Send + 'static,
+    {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let running = Job {
+            id,
+            kind,
+            state: JobState::Running,
+            started_at: now(),
+            finished_at: None,
+            result: None,
+            error: None,
+        };
+        self.jobs.lock().unwrap().insert(id, running.clone());
+        info!("Started {kind} job {id}");
+
+        let jobs = self.clone();
+        tokio::spawn(async move {
+            // Running the job in its own task contains its panics.
+            let outcome = match tokio::spawn(job).await {
+                Ok(outcome) => outcome,
+                Err(error) => Err(anyhow::anyhow!("Job panicked: {error}")),
+            };
+            jobs.finish(id, outcome);
+        });
+        running
+    }
+
+    pub fn get(&self, id: JobId) -> Option<Job> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Returns the jobs, newest first.
+    pub fn list(&self) -> Vec<Job> {
+        self.jobs.lock().unwrap().values().rev().cloned().collect()
+    }
+
+    fn finish(&self, id: JobId, outcome: anyhow::Result<serde_json::Value>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(&id) else {
+            return;
+        };
+        job.finished_at = Some(now());
+        match outcome {
+            Ok(result) => {
+                info!("{} job {id} succeeded", job.kind);
+                job.state = JobState::Succeeded;
+                job.result = Some(result);
+            }
+            Err(error) => {
+                warn!("{} job {id} failed: {error:#}", job.kind);
+                sentry::capture_task_failure(job.kind, &format!("{error:#}"));
+                job.state = JobState::Failed;
+                job.error = Some(format!("{error:#}"));
+            }
+        }
+        let outcome = match job.state {
+            JobState::Succeeded => "ok",
+            _ => "failed",
+        };
+        metrics::increment("jobs_total", &[("kind", job.kind), ("outcome", outcome)]);
+
+        let finished: Vec<_> = jobs
+            .iter()
+            .filter(|(_, job)| job.state != JobState::Running)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in finished
+            .iter()
+            .take(finished.len().saturating_sub(MAX_FINISHED_JOBS))
+        {
+            jobs.remove(id);
+        }
+    }
+}
+
+fn now() -> Timestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_jobs() {
+        let jobs = Arc::new(Jobs::default());
+        let ok = jobs.start("test", async { Ok(serde_json::json!({"events": 3})) });
+        let failed = jobs.start("test", async { anyhow::bail!("Destination unreachable") });
+        assert_eq!(ok.state, JobState::Running);
+
+        while jobs.list().iter().any(|job| job.state == JobState::Running) {
+            tokio::task::yield_now().await;
+        }
+        let ok = jobs.get(ok.id).unwrap();
+        assert_eq!(ok.state, JobState::Succeeded);
+        assert_eq!(ok.result, Some(serde_json::json!({"events": 3})));
+        let failed = jobs.get(failed.id).unwrap();
+        assert_eq!(failed.state, JobState::Failed);
+        assert_eq!(failed.error.as_deref(), Some("Destination unreachable"));
+    }
+}
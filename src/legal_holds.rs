@@ -0,0 +1,277 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use tracing::{info, warn};
+
+use crate::event::{Event, Timestamp};
+
+/// Events a legal hold applies to. Omitted fields match any event.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct HoldFilter {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_type: Option<String>,
+
+    /// Inclusive timestamp range of the held events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<Timestamp>,
+}
+
+impl HoldFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        self.event_type
+            .as_ref()
+            .is_none_or(|event_type| *event_type == event.event_type)
+            && self.start.is_none_or(|start| event.timestamp >= start)
+            && self.end.is_none_or(|end| event.timestamp <= end)
+    }
+
+    /// Whether any event matching the other filter could match this one, too.
+    pub fn overlaps(&self, other: &HoldFilter) -> bool {
+        let types_overlap = match (&self.event_type, &other.event_type) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        let start = self.start.unwrap_or(0).max(other.start.unwrap_or(0));
+        let end = self.end.unwrap_or(Timestamp::MAX);
+        types_overlap && start <= end.min(other.end.unwrap_or(Timestamp::MAX))
+    }
+}
+
+/// Exempts the matching events from deletion, retention and rollup until released.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LegalHold {
+    pub id: u64,
+
+    #[serde(flatten)]
+    pub filter: HoldFilter,
+
+    /// Why the events are held, eg. a case number.
+    pub reason: String,
+
+    /// Unix timestamp in seconds of when the hold was placed.
+    pub placed_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HoldAction {
+    Placed,
+    Released,
+}
+
+/// A change of the legal holds, as recorded in the audit trail.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HoldAuditEntry {
+    pub action: HoldAction,
+    pub hold: LegalHold,
+
+    /// Unix timestamp in seconds of the change.
+    pub at: u64,
+}
+
+/// Legal holds on events, exempting them from deletion, retention and rollup until they are
+/// released.
+///
+/// Every change is recorded in an audit trail. The trail is appended to a JSON lines file if
+/// one is configured, and the holds are restored from it on startup, so they survive a
+/// restart.
+pub struct LegalHolds {
+    state: Mutex<HoldsState>,
+}
+
+struct HoldsState {
+    file: Option<PathBuf>,
+    holds: BTreeMap<u64, LegalHold>,
+    audit: Vec<HoldAuditEntry>,
+    next_id: u64,
+}
+
+impl LegalHolds {
+    /// Opens the audit trail, restoring the holds placed and not released in it.
+    pub fn open(file: Option<PathBuf>) -> Result<Self> {
+        let mut state = HoldsState {
+            file,
+            holds: BTreeMap::new(),
+            audit: vec![],
+            next_id: 1,
+        };
+        if let Some(path) = &state.file
+            && path.exists()
+        {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read legal holds {}", path.display()))?;
+            for line in content.lines().filter(|line| !line.is_empty()) {
+                match serde_json::from_str(line) {
+                    Ok(entry) => state.apply(entry),
+                    // A torn write can only affect the last line.
+                    Err(error) => warn!("Ignoring invalid legal hold audit entry: {error}"),
+                }
+            }
+            info!("Restored {} legal holds", state.holds.len());
+        }
+        Ok(Self {
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Places a hold on the events matching the filter.
+    pub async fn place(&self, filter: HoldFilter, reason: String) -> std::io::Result<LegalHold> {
+        let mut state = self.state.lock().await;
+        let hold = LegalHold {
+            id: state.next_id,
+            filter,
+            reason,
+            placed_at: now(),
+        };
+        state.record(HoldAction::Placed, hold.clone()).await?;
+        info!("Placed legal hold {}: {:?}", hold.id, hold.filter);
+        Ok(hold)
+    }
+
+    /// Releases a hold, returning it, or `None` if there is no such hold.
+    pub async fn release(&self, id: u64) -> std::io::Result<Option<LegalHold>> {
+        let mut state = self.state.lock().await;
+        let Some(hold) = state.holds.get(&id).cloned() else {
+            return Ok(None);
+        };
+        state.record(HoldAction::Released, hold.clone()).await?;
+        info!("Released legal hold {id}");
+        Ok(Some(hold))
+    }
+
+    /// Returns the holds in force, ordered by id.
+    pub async fn holds(&self) -> Vec<LegalHold> {
+        self.state.lock().await.holds.values().cloned().collect()
+    }
+
+    /// Returns the changes of the holds, oldest first.
+    pub async fn audit_trail(&self) -> Vec<HoldAuditEntry> {
+        self.state.lock().await.audit.clone()
+    }
+
+    /// Returns the first hold in force on any event the filter could match.
+    pub async fn overlapping(&self, filter: &HoldFilter) -> Option<LegalHold> {
+        let state = self.state.lock().await;
+        state
+            .holds
+            .values()
+            .find(|hold| hold.filter.overlaps(filter))
+            .cloned()
+    }
+}
+
+impl HoldsState {
+    /// Records a change in the audit trail, making it durable before applying it if there is
+    /// a file.
+    async fn record(&mut self, action: HoldAction, hold: LegalHold) -> std::io::Result<()> {
+        let entry = HoldAuditEntry {
+            action,
+            hold,
+            at: now(),
+        };
+        if let Some(path) = &self.file {
+            let mut line = serde_json::to_vec(&entry).expect("Legal holds always serialize");
+            line.push(b'\n');
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(&line).await?;
+            file.sync_data().await?;
+        }
+        self.apply(entry);
+        Ok(())
+    }
+
+    fn apply(&mut self, entry: HoldAuditEntry) {
+        match entry.action {
+            HoldAction::Placed => {
+                self.next_id = self.next_id.max(entry.hold.id + 1);
+                self.holds.insert(entry.hold.id, entry.hold.clone());
+            }
+            HoldAction::Released => {
+                self.holds.remove(&entry.hold.id);
+            }
+        }
+        self.audit.push(entry);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hold_filters() {
+        let hold = HoldFilter {
+            event_type: Some("payment".to_string()),
+            start: Some(10),
+            end: Some(20),
+        };
+        let event = |event_type: &str, timestamp| Event {
+            event_type: event_type.to_string(),
+            timestamp,
+            ..Default::default()
+        };
+        assert!(hold.matches(&event("payment", 10)));
+        assert!(!hold.matches(&event("payment", 21)));
+        assert!(!hold.matches(&event("click", 15)));
+
+        let range = |start, end| HoldFilter {
+            start,
+            end,
+            ..Default::default()
+        };
+        assert!(hold.overlaps(&range(Some(20), None)));
+        assert!(hold.overlaps(&range(None, None)));
+        assert!(!hold.overlaps(&range(None, Some(9))));
+        let clicks = HoldFilter {
+            event_type: Some("click".to_string()),
+            ..Default::default()
+        };
+        assert!(!hold.overlaps(&clicks));
+    }
+
+    #[tokio::test]
+    async fn test_legal_holds_file() {
+        let path = std::env::temp_dir().join(format!("holds-{}.jsonl", std::process::id()));
+        let holds = LegalHolds::open(Some(path.clone())).unwrap();
+        let first = holds
+            .place(HoldFilter::default(), "case 1".to_string())
+            .await
+            .unwrap();
+        let second = holds
+            .place(HoldFilter::default(), "case 2".to_string())
+            .await
+            .unwrap();
+        assert_eq!(holds.release(first.id).await.unwrap(), Some(first.clone()));
+        assert_eq!(holds.release(first.id).await.unwrap(), None);
+
+        // Holds and their audit trail survive a restart, and ids aren't reused.
+        let holds = LegalHolds::open(Some(path.clone())).unwrap();
+        assert_eq!(holds.holds().await, [second]);
+        assert_eq!(holds.audit_trail().await.len(), 3);
+        let third = holds
+            .place(HoldFilter::default(), "case 3".to_string())
+            .await
+            .unwrap();
+        assert_eq!(third.id, 3);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{
+    fmt::{
+        FmtContext, FormatEvent, FormattedFields,
+        format::{JsonFields, Writer},
+        time::{FormatTime, SystemTime},
+    },
+    registry::LookupSpan,
+};
+
+/// Writes each event as a single line JSON object, so log pipelines can index the fields
+/// without parsing text.
+///
+/// The fields of the event and of every span it happened in are flattened into the object,
+/// with the innermost value winning if a name repeats, e.g. the `request_id`, `trace_id`,
+/// `client_ip` and `tenant` of the request span, and the `event_type` of ingest handlers. The
+/// `timestamp`, `level`, `target` and `message` fields are always present.
+pub struct JsonLogFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonLogFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        context: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = Map::new();
+        for span in context
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            let extensions = span.extensions();
+            let Some(span_fields) = extensions.get::<FormattedFields<JsonFields>>() else {
+                continue;
+            };
+            // Spans without fields are formatted as an empty string.
+            if let Ok(span_fields) = serde_json::from_str::<Map<String, Value>>(span_fields) {
+                fields.extend(span_fields);
+            }
+        }
+        event.record(&mut FieldVisitor(&mut fields));
+
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let metadata = event.metadata();
+        fields.insert("timestamp".to_string(), Value::String(timestamp));
+        fields.insert(
+            "level".to_string(),
+            Value::String(metadata.level().as_str().to_string()),
+        );
+        fields.insert(
+            "target".to_string(),
+            Value::String(metadata.target().to_string()),
+        );
+        fields
+            .entry("message")
+            .or_insert_with(|| Value::String(String::new()));
+        writeln!(writer, "{}", Value::Object(fields))
+    }
+}
+
+/// Collects the fields of an event into a JSON object.
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl FieldVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        // Events bridged from the `log` crate carry their metadata as fields.
+        if !field.name().starts_with("log.") {
+            self.0.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, Value::String(format!("{value:?}")));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+    use tracing::info_span;
+    use tracing_subscriber::fmt::MakeWriter;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn test_json_log_format() {
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .fmt_fields(JsonFields::new())
+            .event_format(JsonLogFormat)
+            .with_writer(buffer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let request = info_span!("request", request_id = "checkout-42", tenant = "team-a");
+            let _request = request.enter();
+            let handler = info_span!("post_event", event_type = "click", tenant = "team-b");
+            let _handler = handler.enter();
+            tracing::warn!(events = 3, "Stored events");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["message"], "Stored events");
+        assert_eq!(line["events"], 3);
+        assert_eq!(line["request_id"], "checkout-42");
+        assert_eq!(line["event_type"], "click");
+        assert_eq!(line["tenant"], "team-b");
+        assert!(
+            line["timestamp"]
+                .as_str()
+                .is_some_and(|time| !time.is_empty())
+        );
+        assert_eq!(output.lines().count(), 1);
+    }
+}
@@ -1,17 +1,120 @@
+mod access_policy;
+mod analytics;
+mod backups;
+mod client_fingerprints;
+mod coalescing;
+mod config;
+mod crash_reports;
+mod dedup;
+mod discovery;
 mod event;
+mod event_policy;
+mod event_snapshots;
+mod event_types;
+mod export_schedules;
+mod exports;
+mod health;
+mod hooks;
+mod id_generator;
+mod ingest_lag;
+mod jobs;
+mod legal_holds;
+mod log_format;
+mod merkle_log;
+mod metrics;
+mod oidc;
+mod outbox;
+mod payload_limits;
+mod payload_samples;
+mod plugins;
+mod producers;
+mod projections;
+mod query_tokens;
+mod receipts;
+mod resources;
+mod retention;
+mod rollup;
+mod schema_drift;
+mod schema_registry;
+mod scripts;
+mod secrets;
+mod sentry;
 mod server;
+mod shadow;
+mod snapshot_file;
+mod state_machine;
 mod storage;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::{net::IpAddr, sync::Arc};
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::fmt;
 use tracing_subscriber::layer::SubscriberExt as _;
 use tracing_subscriber::prelude::*;
 
+/// A simple event tracking service. Options override the environment variables.
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// Where events are stored: `memory`, `sqlite:<path>` or a `postgres://` URL. Overrides
+    /// `STORAGE`.
+    #[arg(long)]
+    storage: Option<storage::StorageBackend>,
+
+    /// Port to listen on. Overrides `PORT`.
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Address to listen on. Overrides `BIND_ADDRESS`.
+    #[arg(long)]
+    bind: Option<IpAddr>,
+
+    /// Rewrites the event snapshot chain to the store as of the given moment and exits,
+    /// instead of serving.
+    #[arg(long, value_name = "UNIX TIMESTAMP")]
+    restore_to: Option<event::Timestamp>,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
     set_up_tracing()?;
-    server::serve().await?;
+    let secrets = secrets::Secrets::from_env()
+        .await
+        .context("Failed to load secrets")?;
+    let mut config = config::ServerConfig::from_env(Arc::new(secrets))?;
+    if let Some(storage) = args.storage {
+        config.storage = storage;
+    }
+    if let Some(port) = args.port {
+        config.connection.address.set_port(port);
+    }
+    if let Some(bind) = args.bind {
+        config.connection.address.set_ip(bind);
+    }
+    match args.restore_to {
+        Some(timestamp) => restore_to(config, timestamp).await?,
+        None => server::serve(config).await?,
+    }
+    Ok(())
+}
+
+/// Rewrites the event snapshot chain to reflect the store as of the given moment. The server
+/// restores it on the next start.
+async fn restore_to(config: config::ServerConfig, timestamp: event::Timestamp) -> Result<()> {
+    let snapshot_config = config
+        .event_snapshots
+        .context("EVENT_SNAPSHOT_DIR must be set to restore events")?;
+    let link = event_snapshots::EventSnapshots::new(snapshot_config)
+        .restore_to(timestamp)
+        .await?;
+    tracing::info!(
+        "Restored {} events as of {} into {}",
+        link.events,
+        link.created_at,
+        link.file
+    );
     Ok(())
 }
 
@@ -21,9 +124,24 @@ fn set_up_tracing() -> Result<()> {
     #[cfg(not(windows))]
     let with_color = true;
 
+    // Logs are written as text by default, or as JSON lines for log pipelines.
+    let json = match std::env::var("LOG_FORMAT") {
+        Ok(format) => match format.as_str() {
+            "text" => false,
+            "json" => true,
+            _ => anyhow::bail!("Invalid LOG_FORMAT {format:?}, expected `text` or `json`"),
+        },
+        Err(_) => false,
+    };
+
     // let crate_filter =
     //     tracing_subscriber::filter::filter_fn(|metadata| metadata.target().starts_with("bitang"));
-    let fmt_layer = fmt::layer().with_ansi(with_color).with_target(false);
+    let fmt_layer = (!json).then(|| fmt::layer().with_ansi(with_color).with_target(false));
+    let json_layer = json.then(|| {
+        fmt::layer()
+            .fmt_fields(fmt::format::JsonFields::new())
+            .event_format(log_format::JsonLogFormat)
+    });
     let filter_layer = EnvFilter::try_from_default_env().or_else(|_| {
         EnvFilter::try_new(if cfg!(debug_assertions) {
             "debug"
@@ -34,6 +152,7 @@ fn set_up_tracing() -> Result<()> {
     tracing_subscriber::registry()
         .with(filter_layer)
         .with(fmt_layer)
+        .with(json_layer)
         // .with(crate_filter)
         .init();
 
@@ -0,0 +1,265 @@
+use ahash::AHashMap;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use crate::{
+    event::{Event, EventId},
+    receipts::{hex, payload_sha256},
+    storage::{EventFilter, RetrieveError, Storage},
+};
+
+type Hash = [u8; 32];
+
+/// Root hash of a partition's tree, to publish so inclusion proofs can be checked against it.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct MerkleRoot {
+    /// Event type of the partition.
+    pub partition: String,
+
+    /// Number of events in the tree.
+    pub tree_size: u64,
+
+    pub root: String,
+}
+
+/// Proof that an event is in its partition's tree, as in RFC 9162.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct InclusionProof {
+    pub event_id: String,
+    pub partition: String,
+
+    /// Position of the event in the tree, in the order events were stored.
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    pub leaf_hash: String,
+
+    /// Hashes of the sibling subtrees on the way from the leaf to the root.
+    pub path: Vec<String>,
+    pub root: String,
+}
+
+/// Leaves of a partition's tree, and the ids of their events in insertion order.
+#[derive(Default)]
+struct Partition {
+    event_ids: Vec<EventId>,
+    leaves: Vec<Hash>,
+}
+
+#[derive(Default)]
+struct Trees {
+    partitions: AHashMap<String, Partition>,
+
+    /// Latest event appended to the trees.
+    last_event_id: Option<EventId>,
+}
+
+/// Append-only Merkle trees over the stored events, one per event type, so anyone holding a
+/// published root can check that an event was logged and nothing before it was changed.
+///
+/// The trees follow the storage by scanning the events stored since the last request, relying
+/// on ids increasing in insertion order. They are rebuilt from the storage on startup, so
+/// events deleted or changed while the tracker was down change the roots.
+pub struct MerkleLog {
+    trees: Mutex<Trees>,
+}
+
+impl MerkleLog {
+    pub fn new() -> Self {
+        Self {
+            trees: Mutex::new(Trees::default()),
+        }
+    }
+
+    /// Returns the root of each partition's tree, ordered by partition.
+    pub async fn roots(
+        &self,
+        store: &(dyn Storage + Send + Sync),
+    ) -> Result<Vec<MerkleRoot>, RetrieveError> {
+        let mut trees = self.trees.lock().await;
+        catch_up(&mut trees, store).await?;
+        let mut roots: Vec<_> = trees
+            .partitions
+            .iter()
+            .map(|(partition, tree)| MerkleRoot {
+                partition: partition.clone(),
+                tree_size: tree.leaves.len() as u64,
+                root: hex(&root(&tree.leaves)),
+            })
+            .collect();
+        roots.sort_by(|a, b| a.partition.cmp(&b.partition));
+        Ok(roots)
+    }
+
+    /// Proves that the event is in its partition's current tree. Returns `None` if the event
+    /// was never stored.
+    pub async fn prove(
+        &self,
+        store: &(dyn Storage + Send + Sync),
+        event_id: EventId,
+    ) -> Result<Option<InclusionProof>, RetrieveError> {
+        let mut trees = self.trees.lock().await;
+        catch_up(&mut trees, store).await?;
+        let found = trees.partitions.iter().find_map(|(partition, tree)| {
+            let index = tree.event_ids.binary_search(&event_id).ok()?;
+            Some((partition, tree, index))
+        });
+        Ok(found.map(|(partition, tree, index)| InclusionProof {
+            event_id: event_id.to_string(),
+            partition: partition.clone(),
+            leaf_index: index as u64,
+            tree_size: tree.leaves.len() as u64,
+            leaf_hash: hex(&tree.leaves[index]),
+            path: path(index, &tree.leaves)
+                .iter()
+                .map(|hash| hex(hash))
+                .collect(),
+            root: hex(&root(&tree.leaves)),
+        }))
+    }
+}
+
+/// Appends the events stored since the last call to the trees of their partitions.
+async fn catch_up(
+    trees: &mut Trees,
+    store: &(dyn Storage + Send + Sync),
+) -> Result<(), RetrieveError> {
+    let events = store
+        .scan(&EventFilter::default(), trees.last_event_id)
+        .await?;
+    for (event_id, event) in events {
+        let partition = trees
+            .partitions
+            .entry(event.event_type.clone())
+            .or_default();
+        partition.event_ids.push(event_id);
+        partition.leaves.push(leaf_hash(event_id, &event));
+        trees.last_event_id = Some(event_id);
+    }
+    Ok(())
+}
+
+/// Hashes what a leaf commits to: the id, type, timestamp and payload of the event. The count
+/// of coalesced events changes after storing, so it is left out.
+fn leaf_hash(event_id: EventId, event: &Event) -> Hash {
+    let data = format!(
+        "{event_id}\n{}\n{}\n{}",
+        event.event_type,
+        event.timestamp,
+        payload_sha256(event)
+    );
+    Sha256::new()
+        .chain_update([0])
+        .chain_update(data)
+        .finalize()
+        .into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new()
+        .chain_update([1])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// Largest power of two smaller than `n`, where the tree of `n` leaves splits.
+fn split(n: usize) -> usize {
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+fn root(leaves: &[Hash]) -> Hash {
+    match leaves {
+        [] => Sha256::digest(b"").into(),
+        [leaf] => *leaf,
+        _ => {
+            let (left, right) = leaves.split_at(split(leaves.len()));
+            node_hash(&root(left), &root(right))
+        }
+    }
+}
+
+/// Audit path of the leaf at `index`, from the leaf up.
+fn path(index: usize, leaves: &[Hash]) -> Vec<Hash> {
+    if leaves.len() <= 1 {
+        return vec![];
+    }
+    let (left, right) = leaves.split_at(split(leaves.len()));
+    let (mut path, sibling) = if index < left.len() {
+        (path(index, left), root(right))
+    } else {
+        (path(index - left.len(), right), root(left))
+    };
+    path.push(sibling);
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryStorage;
+
+    /// Verifies an audit path as described in RFC 9162, section 2.1.3.2.
+    fn verify(index: usize, tree_size: usize, leaf: Hash, path: &[Hash], root: Hash) -> bool {
+        let (mut index, mut last) = (index, tree_size - 1);
+        let mut hash = leaf;
+        for sibling in path {
+            if last == 0 {
+                return false;
+            }
+            if index & 1 == 1 || index == last {
+                hash = node_hash(sibling, &hash);
+                while index & 1 == 0 && index != 0 {
+                    index >>= 1;
+                    last >>= 1;
+                }
+            } else {
+                hash = node_hash(&hash, sibling);
+            }
+            index >>= 1;
+            last >>= 1;
+        }
+        last == 0 && hash == root
+    }
+
+    #[test]
+    fn test_inclusion_proofs() {
+        for size in 1..=9 {
+            let leaves: Vec<Hash> = (0..size).map(|leaf| [leaf as u8; 32]).collect();
+            let root = root(&leaves);
+            for index in 0..size {
+                let path = path(index, &leaves);
+                assert!(verify(index, size, leaves[index], &path, root));
+                assert!(!verify(index, size, [0xff; 32], &path, root));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merkle_log() {
+        let store = InMemoryStorage::new();
+        let log = MerkleLog::new();
+        let event = |event_type: &str| Event {
+            event_type: event_type.to_string(),
+            ..Default::default()
+        };
+        for event_type in ["click", "view", "click"] {
+            store.store(event(event_type)).await.unwrap();
+        }
+        let roots = log.roots(&store).await.unwrap();
+        assert_eq!(roots.len(), 2);
+        assert_eq!(
+            (roots[0].partition.as_str(), roots[0].tree_size),
+            ("click", 2)
+        );
+
+        // Appending an event changes the root, and proofs are against the new tree.
+        let third = store.store(event("click")).await.unwrap();
+        let proof = log.prove(&store, third).await.unwrap().unwrap();
+        assert_eq!((proof.leaf_index, proof.tree_size), (2, 3));
+        assert_ne!(proof.root, roots[0].root);
+        assert_eq!(proof.path.len(), 1);
+        assert_eq!(log.prove(&store, 99).await.unwrap(), None);
+    }
+}
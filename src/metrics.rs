@@ -0,0 +1,110 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{LazyLock, Mutex},
+};
+
+/// Process-wide metrics registry, rendered in the Prometheus text format.
+static REGISTRY: LazyLock<Mutex<BTreeMap<&'static str, Family>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// Samples of a metric by their rendered label set.
+struct Family {
+    kind: Kind,
+    samples: BTreeMap<String, f64>,
+}
+
+/// Adds a value to a counter.
+pub fn add(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    update(name, Kind::Counter, labels, |sample| *sample += value);
+}
+
+/// Increments a counter by one.
+pub fn increment(name: &'static str, labels: &[(&str, &str)]) {
+    add(name, labels, 1.0);
+}
+
+/// Sets the value of a gauge.
+pub fn set_gauge(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    update(name, Kind::Gauge, labels, |sample| *sample = value);
+}
+
+fn update(name: &'static str, kind: Kind, labels: &[(&str, &str)], f: impl FnOnce(&mut f64)) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let family = registry.entry(name).or_insert_with(|| Family {
+        kind,
+        samples: BTreeMap::new(),
+    });
+    debug_assert_eq!(
+        family.kind, kind,
+        "Metric '{name}' used with different kinds"
+    );
+    f(family.samples.entry(render_labels(labels)).or_default());
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                "{key}=\"{}\"",
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Renders all metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    render_registry(&REGISTRY.lock().unwrap())
+}
+
+/// Renders all metrics unless the registry is locked, e.g. by a panicking thread.
+pub fn try_render() -> Option<String> {
+    REGISTRY
+        .try_lock()
+        .ok()
+        .map(|registry| render_registry(&registry))
+}
+
+fn render_registry(registry: &BTreeMap<&'static str, Family>) -> String {
+    let mut output = String::new();
+    for (name, family) in registry.iter() {
+        let kind = match family.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        };
+        let _ = writeln!(output, "# TYPE {name} {kind}");
+        for (labels, value) in &family.samples {
+            let _ = writeln!(output, "{name}{labels} {value}");
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        increment("test_render_total", &[("outcome", "ok")]);
+        increment("test_render_total", &[("outcome", "ok")]);
+        set_gauge("test_render_gauge", &[], 1.5);
+
+        let output = render();
+        assert!(output.contains("# TYPE test_render_total counter\n"));
+        assert!(output.contains("test_render_total{outcome=\"ok\"} 2\n"));
+        assert!(output.contains("test_render_gauge 1.5\n"));
+    }
+}
@@ -0,0 +1,304 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as BASE64};
+use jsonwebtoken::{DecodingKey, Validation, jwk::JwkSet};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+
+use crate::receipts::{KeyRing, SigningKey, SigningKeys};
+
+/// How long a user has to complete a login at the identity provider.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Configures login to the admin APIs with an OpenID Connect identity provider.
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// Issuer URL of the provider, serving its metadata at `/.well-known/openid-configuration`.
+    pub issuer_url: String,
+
+    pub client_id: String,
+    pub client_secret: String,
+
+    /// Where the provider sends users back to, the `/auth/callback` URL of the tracker.
+    pub redirect_url: String,
+
+    /// ID token claim listing the groups of the user.
+    pub groups_claim: String,
+
+    /// How long a login lasts. Users removed at the provider lose access when it expires.
+    pub session_ttl: Duration,
+
+    /// Signs session cookies, so they stay valid across restarts and instances.
+    pub session_key: Option<SigningKeys>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OidcError {
+    #[error("Identity provider request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Invalid ID token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+
+    #[error("No signing key '{0}' in the identity provider's key set")]
+    UnknownKey(String),
+
+    #[error("Invalid authorization endpoint: {0}")]
+    InvalidEndpoint(String),
+
+    #[error("Unknown or expired login")]
+    UnknownLogin,
+
+    #[error("ID token doesn't belong to this login")]
+    NonceMismatch,
+}
+
+/// Endpoints of the provider, from its discovery document.
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// A logged in user, kept in a signed cookie.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Session {
+    /// Subject of the ID token, the user's id at the provider.
+    pub subject: String,
+
+    /// Email address or name of the user, for the logs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// Groups of the user at login.
+    pub groups: Vec<String>,
+
+    /// Unix timestamp in seconds after which the session is rejected.
+    pub expires_at: u64,
+}
+
+/// Logs users in with the authorization code flow, and issues and checks their sessions.
+pub struct Oidc {
+    config: OidcConfig,
+    client: reqwest::Client,
+    key: KeyRing,
+
+    /// Fetched at the first login.
+    provider: OnceCell<ProviderMetadata>,
+
+    /// Nonces of the logins in progress by their state parameter, and when they were started.
+    pending: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl Oidc {
+    /// Creates the login flow. Without a session key, a random one is generated, and users
+    /// have to log in again after a restart.
+    pub fn new(mut config: OidcConfig) -> Self {
+        let keys = config.session_key.take();
+        let key = KeyRing::new(keys.unwrap_or_else(|| SigningKey::random().into()));
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            key,
+            provider: OnceCell::new(),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Signs new sessions with the current key. Sessions of the previous key stay valid until
+    /// they expire.
+    pub fn rotate_session_key(&self, keys: SigningKeys) {
+        self.key.rotate(keys);
+    }
+
+    pub fn session_ttl(&self) -> Duration {
+        self.config.session_ttl
+    }
+
+    async fn provider(&self) -> Result<&ProviderMetadata, OidcError> {
+        self.provider
+            .get_or_try_init(|| async {
+                let issuer_url = self.config.issuer_url.trim_end_matches('/');
+                let url = format!("{issuer_url}/.well-known/openid-configuration");
+                let response = self.client.get(url).send().await?.error_for_status()?;
+                Ok(response.json().await?)
+            })
+            .await
+    }
+
+    /// Starts a login, returning the provider's URL to send the user to.
+    pub async fn start_login(&self) -> Result<String, OidcError> {
+        let provider = self.provider().await?;
+        let state = random_string();
+        let nonce = random_string();
+        let url = reqwest::Url::parse_with_params(
+            &provider.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", self.config.redirect_url.as_str()),
+                ("scope", "openid profile email"),
+                ("state", state.as_str()),
+                ("nonce", nonce.as_str()),
+            ],
+        )
+        .map_err(|error| OidcError::InvalidEndpoint(error.to_string()))?;
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, (_, started)| started.elapsed() < LOGIN_TIMEOUT);
+        pending.insert(state, (nonce, Instant::now()));
+        Ok(url.into())
+    }
+
+    /// Completes a login the provider sent the user back from, exchanging the code for an ID
+    /// token, and returns the user's session.
+    pub async fn complete_login(
+        &self,
+        code: &str,
+        state: &str,
+        now: u64,
+    ) -> Result<Session, OidcError> {
+        let nonce = match self.pending.lock().unwrap().remove(state) {
+            Some((nonce, started)) if started.elapsed() < LOGIN_TIMEOUT => nonce,
+            _ => return Err(OidcError::UnknownLogin),
+        };
+        let provider = self.provider().await?;
+        let response: TokenResponse = self
+            .client
+            .post(&provider.token_endpoint)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", self.config.redirect_url.as_str()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // Keys are fetched on each login, so rotated keys are picked up.
+        let keys: JwkSet = self
+            .client
+            .get(&provider.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let claims = self.verify_id_token(&response.id_token, &keys)?;
+        if claims.get("nonce").and_then(|nonce| nonce.as_str()) != Some(nonce.as_str()) {
+            return Err(OidcError::NonceMismatch);
+        }
+        Ok(self.session(&claims, now))
+    }
+
+    /// Checks the signature, issuer, audience and expiry of an ID token, returning its claims.
+    fn verify_id_token(
+        &self,
+        id_token: &str,
+        keys: &JwkSet,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, OidcError> {
+        let header = jsonwebtoken::decode_header(id_token)?;
+        let kid = header.kid.unwrap_or_default();
+        let key = match kid.as_str() {
+            "" => keys.keys.first(),
+            kid => keys.find(kid),
+        }
+        .ok_or(OidcError::UnknownKey(kid))?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_issuer(&[&self.config.issuer_url]);
+        let token = jsonwebtoken::decode(id_token, &DecodingKey::from_jwk(key)?, &validation)?;
+        Ok(token.claims)
+    }
+
+    fn session(&self, claims: &serde_json::Map<String, serde_json::Value>, now: u64) -> Session {
+        let string = |name: &str| claims.get(name)?.as_str().map(str::to_string);
+        let groups = match claims.get(&self.config.groups_claim) {
+            Some(serde_json::Value::Array(groups)) => groups
+                .iter()
+                .filter_map(|group| group.as_str().map(str::to_string))
+                .collect(),
+            Some(serde_json::Value::String(group)) => vec![group.clone()],
+            _ => Vec::new(),
+        };
+        Session {
+            subject: string("sub").unwrap_or_default(),
+            name: string("email").or_else(|| string("name")),
+            groups,
+            expires_at: now + self.config.session_ttl.as_secs(),
+        }
+    }
+
+    /// Returns the cookie value of a session: its claims in base64, followed by a dot and
+    /// their HMAC-SHA256.
+    pub fn issue_session(&self, session: &Session) -> String {
+        let claims = BASE64.encode(serde_json::to_vec(session).expect("Sessions always serialize"));
+        let signature = self.key.sign(claims.as_bytes());
+        format!("{claims}.{signature}")
+    }
+
+    /// Checks the signature and expiry of a session cookie, returning the session.
+    pub fn verify_session(&self, cookie: &str, now: u64) -> Option<Session> {
+        let (claims, signature) = cookie.split_once('.')?;
+        if !self.key.verify(claims.as_bytes(), signature) {
+            return None;
+        }
+        let session: Session = serde_json::from_slice(&BASE64.decode(claims).ok()?).ok()?;
+        (session.expires_at >= now).then_some(session)
+    }
+}
+
+fn random_string() -> String {
+    BASE64.encode(rand::random::<[u8; 16]>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oidc() -> Oidc {
+        Oidc::new(OidcConfig {
+            issuer_url: "https://sso.example.com/".to_string(),
+            client_id: "tracker".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_url: "https://tracker.example.com/auth/callback".to_string(),
+            groups_claim: "groups".to_string(),
+            session_ttl: Duration::from_secs(3600),
+            session_key: None,
+        })
+    }
+
+    #[test]
+    fn test_sessions() {
+        let oidc = oidc();
+        let claims = serde_json::json!({
+            "sub": "u123",
+            "email": "ops@example.com",
+            "groups": ["tracker-admins", "staff"],
+        });
+        let session = oidc.session(claims.as_object().unwrap(), 1000);
+        assert_eq!(session.groups, ["tracker-admins", "staff"]);
+        assert_eq!(session.name.as_deref(), Some("ops@example.com"));
+        assert_eq!(session.expires_at, 4600);
+
+        let cookie = oidc.issue_session(&session);
+        assert_eq!(oidc.verify_session(&cookie, 4600), Some(session));
+        assert_eq!(oidc.verify_session(&cookie, 4601), None);
+        assert_eq!(
+            Oidc::new(oidc.config.clone()).verify_session(&cookie, 0),
+            None
+        );
+        assert_eq!(oidc.verify_session("garbage", 0), None);
+    }
+}
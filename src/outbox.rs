@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{Mutex, MutexGuard},
+};
+use tracing::warn;
+
+use crate::event::Event;
+
+/// An ingest request accepted into the outbox.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OutboxEntry {
+    Event { event: Event },
+    Batch { events: Vec<Event>, atomic: bool },
+}
+
+/// Queues ingest requests while the server is in maintenance mode, to be applied to the store
+/// when maintenance ends.
+///
+/// Entries are appended to a JSON lines file if one is configured, so they survive a restart.
+pub struct Outbox {
+    state: Mutex<OutboxState>,
+}
+
+pub struct OutboxState {
+    file: Option<PathBuf>,
+    maintenance: bool,
+    entries: Vec<OutboxEntry>,
+}
+
+impl Outbox {
+    /// Opens the outbox, loading the entries left in its file.
+    pub fn open(file: Option<PathBuf>) -> Result<Self> {
+        let mut entries = vec![];
+        if let Some(path) = &file
+            && path.exists()
+        {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read outbox {}", path.display()))?;
+            for line in content.lines().filter(|line| !line.is_empty()) {
+                match serde_json::from_str(line) {
+                    Ok(entry) => entries.push(entry),
+                    // A torn write can only affect the last line.
+                    Err(error) => warn!("Ignoring invalid outbox entry: {error}"),
+                }
+            }
+        }
+        Ok(Self {
+            state: Mutex::new(OutboxState {
+                file,
+                maintenance: false,
+                entries,
+            }),
+        })
+    }
+
+    /// Locks the outbox. Ingest holds the lock while deciding whether to queue a request, so
+    /// requests can't overtake the queued ones while the outbox is applied.
+    pub async fn lock(&self) -> MutexGuard<'_, OutboxState> {
+        self.state.lock().await
+    }
+}
+
+impl OutboxState {
+    pub fn maintenance(&self) -> bool {
+        self.maintenance
+    }
+
+    pub fn set_maintenance(&mut self, maintenance: bool) {
+        self.maintenance = maintenance;
+    }
+
+    pub fn entries(&self) -> &[OutboxEntry] {
+        &self.entries
+    }
+
+    /// Queues an entry, and makes it durable before returning if there is an outbox file.
+    pub async fn push(&mut self, entry: OutboxEntry) -> std::io::Result<()> {
+        if let Some(path) = &self.file {
+            let mut line = serde_json::to_vec(&entry).expect("Events always serialize");
+            line.push(b'\n');
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(&line).await?;
+            file.sync_data().await?;
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Removes all entries, once they were applied.
+    pub async fn clear(&mut self) -> std::io::Result<()> {
+        if let Some(path) = &self.file {
+            tokio::fs::write(path, b"").await?;
+        }
+        self.entries.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_outbox_file() {
+        let path = std::env::temp_dir().join(format!("outbox-{}.jsonl", std::process::id()));
+        let entry = OutboxEntry::Event {
+            event: Event {
+                event_type: "test".to_string(),
+                ..Default::default()
+            },
+        };
+
+        let outbox = Outbox::open(Some(path.clone())).unwrap();
+        outbox.lock().await.push(entry.clone()).await.unwrap();
+
+        // Entries survive a restart until they are cleared.
+        let outbox = Outbox::open(Some(path.clone())).unwrap();
+        assert_eq!(outbox.lock().await.entries(), [entry]);
+        outbox.lock().await.clear().await.unwrap();
+        let outbox = Outbox::open(Some(path.clone())).unwrap();
+        assert!(outbox.lock().await.entries().is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
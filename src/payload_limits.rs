@@ -0,0 +1,110 @@
+use serde_json::Value;
+use std::io;
+
+/// Bounds on the payloads of ingested events, so a producer can't fill the storage with huge
+/// blobs or payloads too deeply nested to query.
+#[derive(Debug, Clone)]
+pub struct PayloadLimits {
+    /// Largest payload in bytes, as serialized JSON.
+    pub max_bytes: usize,
+
+    /// Deepest nesting of objects and arrays in a payload. A flat object is 1 deep.
+    pub max_depth: usize,
+}
+
+impl Default for PayloadLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1024 * 1024,
+            max_depth: 32,
+        }
+    }
+}
+
+/// Why a payload exceeds the limits.
+#[derive(Debug, PartialEq)]
+pub enum PayloadViolation {
+    TooLarge { limit: usize },
+    TooDeep { limit: usize },
+}
+
+impl PayloadLimits {
+    pub fn check(&self, payload: &Value) -> Result<(), PayloadViolation> {
+        if depth(payload) > self.max_depth {
+            return Err(PayloadViolation::TooDeep {
+                limit: self.max_depth,
+            });
+        }
+        let mut size = SizeLimit {
+            remaining: self.max_bytes,
+        };
+        // Stops serializing as soon as the limit is crossed.
+        if serde_json::to_writer(&mut size, payload).is_err() {
+            return Err(PayloadViolation::TooLarge {
+                limit: self.max_bytes,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Returns the deepest nesting of objects and arrays in a value.
+fn depth(value: &Value) -> usize {
+    let mut deepest = 0;
+    let mut stack = vec![(value, 0)];
+    while let Some((value, depth)) = stack.pop() {
+        let children: Box<dyn Iterator<Item = &Value>> = match value {
+            Value::Object(fields) => Box::new(fields.values()),
+            Value::Array(items) => Box::new(items.iter()),
+            _ => continue,
+        };
+        deepest = deepest.max(depth + 1);
+        stack.extend(children.map(|child| (child, depth + 1)));
+    }
+    deepest
+}
+
+/// Discards what is written to it, and fails once more than the limit was written.
+struct SizeLimit {
+    remaining: usize,
+}
+
+impl io::Write for SizeLimit {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.remaining = self
+            .remaining
+            .checked_sub(bytes.len())
+            .ok_or_else(|| io::Error::other("Payload too large"))?;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_payload_limits() {
+        let limits = PayloadLimits {
+            max_bytes: 20,
+            max_depth: 2,
+        };
+        assert_eq!(limits.check(&json!({"cart": {"items": 3}})), Ok(()));
+        assert_eq!(limits.check(&json!("scalar")), Ok(()));
+        assert_eq!(
+            limits.check(&json!({"cart": {"items": [3]}})),
+            Err(PayloadViolation::TooDeep { limit: 2 })
+        );
+        assert_eq!(
+            limits.check(&json!({"note": "a".repeat(20)})),
+            Err(PayloadViolation::TooLarge { limit: 20 })
+        );
+        // Exactly at the limit.
+        assert_eq!(limits.check(&json!({"note": "a".repeat(9)})), Ok(()));
+    }
+}
@@ -0,0 +1,106 @@
+use ahash::AHashMap;
+use rand::Rng;
+use serde::Serialize;
+use std::sync::Mutex;
+
+use crate::{access_policy::MaskedFields, event::Event};
+
+/// Payloads kept per event type.
+const RESERVOIR_SIZE: usize = 10;
+
+/// Representative payloads of an event type.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PayloadExamples {
+    /// Number of events of the type stored since startup.
+    seen: u64,
+
+    /// A uniform random sample of their payloads.
+    examples: Vec<serde_json::Value>,
+}
+
+impl PayloadExamples {
+    /// Redacts the masked fields of the example payloads.
+    pub fn redact(&mut self, masked: &MaskedFields) {
+        for payload in &mut self.examples {
+            masked.redact_payload(payload);
+        }
+    }
+}
+
+/// A payload chosen for the sample of its event type, added once the event is stored.
+pub struct SampledPayload {
+    event_type: String,
+    slot: usize,
+    payload: serde_json::Value,
+}
+
+/// Keeps a reservoir sample of the stored payloads of each event type, so consumers can see
+/// representative shapes without scrolling raw events.
+#[derive(Default)]
+pub struct PayloadSamples {
+    samples: Mutex<AHashMap<String, PayloadExamples>>,
+}
+
+impl PayloadSamples {
+    /// Counts an event about to be stored, and returns its payload if it was chosen for the
+    /// sample. Copies only the chosen payloads.
+    pub fn sample(&self, event: &Event) -> Option<SampledPayload> {
+        let mut samples = self.samples.lock().unwrap();
+        let examples = samples.entry(event.event_type.clone()).or_default();
+        examples.seen += 1;
+        let slot = match usize::try_from(examples.seen - 1) {
+            Ok(index) if index < RESERVOIR_SIZE => index,
+            // Keeps each of the seen payloads with equal probability.
+            _ => {
+                let index = rand::rng().random_range(0..examples.seen);
+                usize::try_from(index)
+                    .ok()
+                    .filter(|index| *index < RESERVOIR_SIZE)?
+            }
+        };
+        Some(SampledPayload {
+            event_type: event.event_type.clone(),
+            slot,
+            payload: event.payload.clone(),
+        })
+    }
+
+    /// Adds a sampled payload of a stored event.
+    pub fn add(&self, sampled: SampledPayload) {
+        let mut samples = self.samples.lock().unwrap();
+        let examples = samples.entry(sampled.event_type).or_default();
+        match examples.examples.get_mut(sampled.slot) {
+            Some(example) => *example = sampled.payload,
+            None => examples.examples.push(sampled.payload),
+        }
+    }
+
+    pub fn examples(&self, event_type: &str) -> Option<PayloadExamples> {
+        self.samples.lock().unwrap().get(event_type).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_samples() {
+        let samples = PayloadSamples::default();
+        for n in 0..100 {
+            let event = Event {
+                event_type: "click".to_string(),
+                payload: serde_json::json!({"n": n}),
+                ..Default::default()
+            };
+            if let Some(sampled) = samples.sample(&event) {
+                samples.add(sampled);
+            }
+        }
+
+        let examples = samples.examples("click").unwrap();
+        assert_eq!(examples.seen, 100);
+        assert_eq!(examples.examples.len(), RESERVOIR_SIZE);
+        assert!(samples.examples("signup").is_none());
+    }
+}
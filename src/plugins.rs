@@ -0,0 +1,275 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tracing::info;
+use wasmtime::{Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::event::Event;
+
+/// Instructions a plugin call may execute before it's aborted.
+const FUEL_PER_CALL: u64 = 10_000_000;
+
+/// Linear memory a plugin instance may grow to.
+const MAX_MEMORY_BYTES: usize = 16 << 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PluginError {
+    #[error("Event rejected by plugin '{0}'")]
+    Rejected(String),
+
+    #[error("Plugin '{plugin}' failed: {message}")]
+    Failed { plugin: String, message: String },
+}
+
+/// A WebAssembly module that filters or transforms events before they are stored.
+///
+/// The module exports its `memory` and an `alloc(len: i32) -> i32` function returning a buffer
+/// the host writes the event JSON into. Then it exports one or both of:
+///
+/// - `filter(ptr: i32, len: i32) -> i32`: returns 0 to reject the event.
+/// - `transform(ptr: i32, len: i32) -> i64`: returns the transformed event JSON as
+///   `ptr << 32 | len`.
+///
+/// Modules can't import anything, so they have no access to the host. Every call runs in a
+/// fresh instance with limited fuel and memory.
+pub struct WasmPlugin {
+    name: String,
+    instance: InstancePre<StoreLimits>,
+    filter: bool,
+    transform: bool,
+}
+
+/// Capabilities of a loaded plugin.
+#[derive(Debug, Serialize)]
+pub struct PluginInfo {
+    name: String,
+    filter: bool,
+    transform: bool,
+}
+
+impl WasmPlugin {
+    /// Compiles a module from WebAssembly binary or text.
+    pub fn new(engine: &Engine, name: &str, bytes: &[u8]) -> Result<Self> {
+        let module = Module::new(engine, bytes)?;
+        let instance = Linker::new(engine).instantiate_pre(&module)?;
+        Ok(Self {
+            name: name.to_string(),
+            filter: module.get_export("filter").is_some(),
+            transform: module.get_export("transform").is_some(),
+            instance,
+        })
+    }
+
+    /// Runs the plugin's filter and then its transform on the event.
+    pub fn apply(&self, mut event: Event) -> Result<Event, PluginError> {
+        let failed = |error: anyhow::Error| PluginError::Failed {
+            plugin: self.name.clone(),
+            message: format!("{error:#}"),
+        };
+        if self.filter && self.call_filter(&event).map_err(failed)? == 0 {
+            return Err(PluginError::Rejected(self.name.clone()));
+        }
+        if self.transform {
+            event = self.call_transform(&event).map_err(failed)?;
+        }
+        Ok(event)
+    }
+
+    fn call_filter(&self, event: &Event) -> Result<i32> {
+        let (mut store, instance, ptr, len) = self.instantiate(event)?;
+        let filter = instance.get_typed_func::<(u32, u32), i32>(&mut store, "filter")?;
+        filter.call(&mut store, (ptr, len))
+    }
+
+    fn call_transform(&self, event: &Event) -> Result<Event> {
+        let (mut store, instance, ptr, len) = self.instantiate(event)?;
+        let transform = instance.get_typed_func::<(u32, u32), u64>(&mut store, "transform")?;
+        let result = transform.call(&mut store, (ptr, len))?;
+        let (ptr, len) = ((result >> 32) as usize, result as u32 as usize);
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("Missing memory export")?;
+        let output = memory
+            .data(&store)
+            .get(ptr..ptr + len)
+            .context("Transform result out of bounds")?;
+        serde_json::from_slice(output).context("Transform returned an invalid event")
+    }
+
+    /// Creates a fresh instance and writes the event JSON into its memory.
+    fn instantiate(
+        &self,
+        event: &Event,
+    ) -> Result<(Store<StoreLimits>, wasmtime::Instance, u32, u32)> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MAX_MEMORY_BYTES)
+            .build();
+        let mut store = Store::new(self.instance.module().engine(), limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(FUEL_PER_CALL)?;
+        let instance = self.instance.instantiate(&mut store)?;
+
+        let input = serde_json::to_vec(event).expect("Events always serialize");
+        let len = u32::try_from(input.len()).context("Event too large")?;
+        let alloc = instance.get_typed_func::<u32, u32>(&mut store, "alloc")?;
+        let ptr = alloc.call(&mut store, len)?;
+        instance
+            .get_memory(&mut store, "memory")
+            .context("Missing memory export")?
+            .write(&mut store, ptr as usize, &input)?;
+        Ok((store, instance, ptr, len))
+    }
+
+    fn info(&self) -> PluginInfo {
+        PluginInfo {
+            name: self.name.clone(),
+            filter: self.filter,
+            transform: self.transform,
+        }
+    }
+}
+
+/// The plugins loaded from the plugin directory, applied to events in file name order.
+pub struct Plugins {
+    engine: Engine,
+    dir: Option<PathBuf>,
+    plugins: RwLock<Vec<Arc<WasmPlugin>>>,
+}
+
+impl Plugins {
+    /// Loads the `.wasm` modules of the directory, if there is one.
+    pub fn new(dir: Option<PathBuf>) -> Result<Self> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let plugins = Self {
+            engine: Engine::new(&config)?,
+            dir,
+            plugins: RwLock::new(vec![]),
+        };
+        plugins.reload()?;
+        Ok(plugins)
+    }
+
+    /// Loads the plugins again, so modules can be added, changed or removed without a restart.
+    /// The loaded plugins are kept if any module fails to load.
+    pub fn reload(&self) -> Result<Vec<PluginInfo>> {
+        let plugins = match &self.dir {
+            Some(dir) => load_dir(&self.engine, dir)?,
+            None => vec![],
+        };
+        let infos = plugins.iter().map(|plugin| plugin.info()).collect();
+        *self.plugins.write().unwrap() = plugins;
+        Ok(infos)
+    }
+
+    pub fn list(&self) -> Vec<PluginInfo> {
+        let plugins = self.plugins.read().unwrap();
+        plugins.iter().map(|plugin| plugin.info()).collect()
+    }
+
+    /// Runs the event through all plugins.
+    pub fn apply(&self, event: Event) -> Result<Event, PluginError> {
+        let plugins = self.plugins.read().unwrap().clone();
+        plugins
+            .iter()
+            .try_fold(event, |event, plugin| plugin.apply(event))
+    }
+}
+
+fn load_dir(engine: &Engine, dir: &Path) -> Result<Vec<Arc<WasmPlugin>>> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read plugin directory {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "wasm")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut plugins = vec![];
+    for path in paths {
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let bytes = std::fs::read(&path)?;
+        let plugin = WasmPlugin::new(engine, &name, &bytes)
+            .with_context(|| format!("Failed to load plugin {}", path.display()))?;
+        info!(
+            "Loaded plugin '{name}' (filter: {}, transform: {})",
+            plugin.filter, plugin.transform
+        );
+        plugins.push(Arc::new(plugin));
+    }
+    Ok(plugins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keeps events with short JSON, and passes them on unchanged.
+    const SHORT_EVENTS_ONLY: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (global $next (mut i32) (i32.const 1024))
+            (func (export "alloc") (param $len i32) (result i32)
+                (local $ptr i32)
+                (local.set $ptr (global.get $next))
+                (global.set $next (i32.add (global.get $next) (local.get $len)))
+                (local.get $ptr))
+            (func (export "filter") (param $ptr i32) (param $len i32) (result i32)
+                (i32.lt_u (local.get $len) (i32.const 100)))
+            (func (export "transform") (param $ptr i32) (param $len i32) (result i64)
+                (i64.or
+                    (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                    (i64.extend_i32_u (local.get $len)))))
+    "#;
+
+    /// Never returns.
+    const LOOP_FOREVER: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param $len i32) (result i32) (i32.const 0))
+            (func (export "filter") (param $ptr i32) (param $len i32) (result i32)
+                (loop $forever (br $forever))
+                (i32.const 1)))
+    "#;
+
+    #[test]
+    fn test_wasm_plugins() {
+        let plugins = Plugins::new(None).unwrap();
+        let plugin = WasmPlugin::new(&plugins.engine, "short", SHORT_EVENTS_ONLY.as_bytes());
+        let plugin = plugin.unwrap();
+        let event = Event {
+            event_type: "test".to_string(),
+            payload: serde_json::json!({"a": 1}),
+            ..Default::default()
+        };
+        assert_eq!(plugin.apply(event.clone()).unwrap(), event);
+
+        let long_event = Event {
+            payload: serde_json::json!({"text": "x".repeat(100)}),
+            ..event.clone()
+        };
+        assert!(matches!(
+            plugin.apply(long_event),
+            Err(PluginError::Rejected(_))
+        ));
+
+        // Runaway plugins run out of fuel.
+        let plugin = WasmPlugin::new(&plugins.engine, "loop", LOOP_FOREVER.as_bytes()).unwrap();
+        assert!(matches!(
+            plugin.apply(event),
+            Err(PluginError::Failed { .. })
+        ));
+    }
+}
@@ -0,0 +1,245 @@
+use ahash::AHashMap;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+use crate::metrics;
+
+/// Latest sequence gaps kept per producer.
+const MAX_GAPS: usize = 20;
+
+/// Configures the producer registry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProducerConfig {
+    /// Producers not seen for longer are reported as silent.
+    pub silence_timeout: Duration,
+}
+
+impl Default for ProducerConfig {
+    fn default() -> Self {
+        Self {
+            silence_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// What is known about a producer, as reported by `GET /admin/producers`.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ProducerStatus {
+    pub producer_id: String,
+
+    /// Version the producer last reported.
+    pub version: Option<String>,
+
+    /// Unix timestamps in seconds of the producer's first and latest request since startup.
+    pub first_seen: u64,
+    pub last_seen: u64,
+
+    /// Requests of the producer since startup, including heartbeats.
+    pub requests: u64,
+
+    /// Whether the producer hasn't been seen for longer than the silence timeout.
+    pub silent: bool,
+
+    /// Sequence number expected of the producer's next event, if it sends them.
+    pub next_sequence: Option<u64>,
+
+    /// Events skipped in the producer's sequence since startup, lost on the way.
+    pub missing_events: u64,
+
+    /// Times the producer's sequence went back since startup, eg. by a restart or a replay.
+    pub regressions: u64,
+
+    /// Latest gaps in the producer's sequence, oldest first.
+    pub gaps: Vec<SequenceGap>,
+}
+
+/// Sequence numbers of a producer's events that never arrived.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SequenceGap {
+    /// First and last missing sequence numbers.
+    pub from: u64,
+    pub to: u64,
+
+    /// Unix timestamp in seconds of when the gap was detected.
+    pub detected_at: u64,
+}
+
+#[derive(Default)]
+struct Producer {
+    version: Option<String>,
+    first_seen: u64,
+    last_seen: u64,
+    requests: u64,
+    next_sequence: Option<u64>,
+    missing_events: u64,
+    regressions: u64,
+    gaps: VecDeque<SequenceGap>,
+}
+
+/// Tracks the producers identifying themselves in requests, so dead shippers are noticed when
+/// they fall silent, not days later.
+pub struct ProducerRegistry {
+    config: ProducerConfig,
+    producers: Mutex<AHashMap<String, Producer>>,
+}
+
+impl ProducerRegistry {
+    pub fn new(config: ProducerConfig) -> Self {
+        Self {
+            config,
+            producers: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    /// Records a request of a producer, along with the version it reports, if any.
+    pub fn record(&self, producer_id: &str, version: Option<&str>) {
+        let now = now();
+        let mut producers = self.producers.lock().unwrap();
+        let producer = producers.entry(producer_id.to_string()).or_insert_with(|| {
+            info!("New producer '{producer_id}'");
+            Producer {
+                first_seen: now,
+                last_seen: now,
+                ..Default::default()
+            }
+        });
+        if let Some(version) = version
+            && producer.version.as_deref() != Some(version)
+        {
+            if let Some(previous) = &producer.version {
+                info!("Producer '{producer_id}' changed version from {previous} to {version}");
+            }
+            producer.version = Some(version.to_string());
+        }
+        producer.last_seen = now;
+        producer.requests += 1;
+        metrics::increment("producer_requests_total", &[("producer", producer_id)]);
+    }
+
+    /// Checks the sequence number of a producer's first event of `events` consecutive ones. A
+    /// number beyond the expected one is a gap of lost events, a number before it a regression,
+    /// after which the sequence is followed from the new number.
+    pub fn record_sequence(&self, producer_id: &str, sequence: u64, events: u64) {
+        let mut producers = self.producers.lock().unwrap();
+        let Some(producer) = producers.get_mut(producer_id) else {
+            return;
+        };
+        let labels = [("producer", producer_id)];
+        match producer.next_sequence {
+            Some(expected) if sequence > expected => {
+                warn!("Producer '{producer_id}' skipped sequence numbers {expected} to {sequence}");
+                producer.missing_events += sequence - expected;
+                metrics::add(
+                    "producer_missing_events_total",
+                    &labels,
+                    (sequence - expected) as f64,
+                );
+                if producer.gaps.len() == MAX_GAPS {
+                    producer.gaps.pop_front();
+                }
+                producer.gaps.push_back(SequenceGap {
+                    from: expected,
+                    to: sequence - 1,
+                    detected_at: now(),
+                });
+            }
+            Some(expected) if sequence < expected => {
+                warn!("Producer '{producer_id}' went back from sequence {expected} to {sequence}");
+                producer.regressions += 1;
+                metrics::increment("producer_sequence_regressions_total", &labels);
+            }
+            _ => {}
+        }
+        producer.next_sequence = Some(sequence.saturating_add(events));
+    }
+
+    /// Returns the known producers ordered by id, or only the silent ones if `silent_only`.
+    pub fn producers(&self, silent_only: bool) -> Vec<ProducerStatus> {
+        let now = now();
+        let producers = self.producers.lock().unwrap();
+        let mut statuses: Vec<_> = producers
+            .iter()
+            .map(|(producer_id, producer)| ProducerStatus {
+                producer_id: producer_id.clone(),
+                version: producer.version.clone(),
+                first_seen: producer.first_seen,
+                last_seen: producer.last_seen,
+                requests: producer.requests,
+                silent: Duration::from_secs(now.saturating_sub(producer.last_seen))
+                    > self.config.silence_timeout,
+                next_sequence: producer.next_sequence,
+                missing_events: producer.missing_events,
+                regressions: producer.regressions,
+                gaps: producer.gaps.iter().cloned().collect(),
+            })
+            .collect();
+        let silent = statuses.iter().filter(|status| status.silent).count();
+        metrics::set_gauge("producers", &[], statuses.len() as f64);
+        metrics::set_gauge("producers_silent", &[], silent as f64);
+        statuses.retain(|status| status.silent || !silent_only);
+        statuses.sort_by(|a, b| a.producer_id.cmp(&b.producer_id));
+        statuses
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_producer_registry() {
+        let registry = ProducerRegistry::new(ProducerConfig::default());
+        registry.record("shipper-b", Some("1.0"));
+        registry.record("shipper-b", None);
+        registry.record("shipper-a", Some("2.1"));
+        registry
+            .producers
+            .lock()
+            .unwrap()
+            .get_mut("shipper-a")
+            .unwrap()
+            .last_seen -= 600;
+
+        let producers = registry.producers(false);
+        assert_eq!(producers.len(), 2);
+        assert_eq!(producers[1].producer_id, "shipper-b");
+        assert_eq!(producers[1].version.as_deref(), Some("1.0"));
+        assert_eq!(producers[1].requests, 2);
+        assert!(!producers[1].silent);
+
+        let silent = registry.producers(true);
+        assert_eq!(silent.len(), 1);
+        assert_eq!(silent[0].producer_id, "shipper-a");
+    }
+
+    #[test]
+    fn test_sequence_gaps() {
+        let registry = ProducerRegistry::new(ProducerConfig::default());
+        registry.record("shipper", None);
+        registry.record_sequence("shipper", 0, 3);
+        registry.record_sequence("shipper", 3, 1);
+        registry.record_sequence("shipper", 10, 2);
+        // Restarted, counting from zero again.
+        registry.record_sequence("shipper", 0, 1);
+        registry.record_sequence("shipper", 1, 1);
+
+        let producer = &registry.producers(false)[0];
+        assert_eq!(producer.next_sequence, Some(2));
+        assert_eq!(producer.missing_events, 6);
+        assert_eq!(producer.regressions, 1);
+        assert_eq!(producer.gaps.len(), 1);
+        assert_eq!((producer.gaps[0].from, producer.gaps[0].to), (4, 9));
+    }
+}
@@ -0,0 +1,371 @@
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::PathBuf, sync::Arc};
+use tokio::sync::Mutex;
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    event::{Event, EventId, Timestamp},
+    snapshot_file::{self, SnapshotFormat},
+    storage::{EventFilter, RetrieveError, Storage},
+};
+
+/// Folds an event into the state of a projection.
+pub type FoldFn = Arc<dyn Fn(&mut serde_json::Value, &Event) + Send + Sync>;
+
+/// A read model computed by folding a filtered event stream.
+#[derive(Clone)]
+pub struct ProjectionDefinition {
+    pub name: String,
+    pub filter: EventFilter,
+    pub initial_state: serde_json::Value,
+    pub fold: FoldFn,
+}
+
+impl fmt::Debug for ProjectionDefinition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProjectionDefinition")
+            .field("name", &self.name)
+            .field("filter", &self.filter)
+            .field("initial_state", &self.initial_state)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Configures snapshots of projection states.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotConfig {
+    /// Directory to persist snapshots to. Snapshots are only kept in memory if not set.
+    pub dir: Option<PathBuf>,
+
+    /// Take a snapshot after this many events were folded. Zero disables periodic snapshots.
+    pub every_events: u64,
+
+    /// Compression and encryption of persisted snapshots.
+    pub format: SnapshotFormat,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("Failed to retrieve events: {0:?}")]
+    Retrieve(RetrieveError),
+
+    #[error("Failed to write snapshot: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl From<RetrieveError> for SnapshotError {
+    fn from(error: RetrieveError) -> Self {
+        SnapshotError::Retrieve(error)
+    }
+}
+
+/// State of a projection at a checkpoint. Rebuilds start from here instead of the first event.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ProjectionSnapshot {
+    checkpoint: Option<EventId>,
+    state: serde_json::Value,
+}
+
+/// Current state of a projection.
+#[derive(Serialize, Debug, Clone)]
+pub struct ProjectionView {
+    pub name: String,
+
+    /// The last event folded into the state.
+    pub checkpoint: Option<EventId>,
+
+    pub state: serde_json::Value,
+}
+
+struct Projection {
+    definition: ProjectionDefinition,
+    state: serde_json::Value,
+    checkpoint: Option<EventId>,
+    snapshot: Option<ProjectionSnapshot>,
+
+    /// Number of events folded since the last snapshot.
+    folded_since_snapshot: u64,
+}
+
+impl Projection {
+    /// Folds all matching events stored since the checkpoint.
+    async fn catch_up(&mut self, store: &(dyn Storage + Send + Sync)) -> Result<(), RetrieveError> {
+        let events = store.scan(&self.definition.filter, self.checkpoint).await?;
+        debug!(
+            "Folding {} events into projection '{}'",
+            events.len(),
+            self.definition.name
+        );
+        for (event_id, event) in events {
+            (self.definition.fold)(&mut self.state, &event);
+            self.checkpoint = Some(event_id);
+            self.folded_since_snapshot += 1;
+        }
+        Ok(())
+    }
+
+    /// Resets the state to the latest snapshot, or to the initial state if there is none.
+    fn restore(&mut self, use_snapshot: bool) {
+        match &self.snapshot {
+            Some(snapshot) if use_snapshot => {
+                self.state = snapshot.state.clone();
+                self.checkpoint = snapshot.checkpoint;
+            }
+            _ => {
+                self.state = self.definition.initial_state.clone();
+                self.checkpoint = None;
+            }
+        }
+        self.folded_since_snapshot = 0;
+    }
+
+    fn view(&self) -> ProjectionView {
+        ProjectionView {
+            name: self.definition.name.clone(),
+            checkpoint: self.checkpoint,
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// Registered projections.
+///
+/// Projections are updated lazily: reading a projection first folds the events stored since
+/// its checkpoint, so the read model is always up to date with the storage.
+pub struct Projections {
+    projections: Mutex<AHashMap<String, Projection>>,
+    snapshot_config: SnapshotConfig,
+}
+
+impl Projections {
+    /// Creates the projections, starting from their persisted snapshots if there are any.
+    pub fn new(definitions: Vec<ProjectionDefinition>, snapshot_config: SnapshotConfig) -> Self {
+        let projections = definitions
+            .into_iter()
+            .map(|definition| {
+                let mut projection = Projection {
+                    state: definition.initial_state.clone(),
+                    checkpoint: None,
+                    snapshot: load_snapshot(&snapshot_config, &definition.name),
+                    folded_since_snapshot: 0,
+                    definition,
+                };
+                projection.restore(true);
+                (projection.definition.name.clone(), projection)
+            })
+            .collect();
+        Self {
+            projections: Mutex::new(projections),
+            snapshot_config,
+        }
+    }
+
+    /// Returns the names of the projections the event would be folded into.
+    pub async fn matching(&self, event: &Event) -> Vec<String> {
+        let projections = self.projections.lock().await;
+        let mut names: Vec<_> = projections
+            .values()
+            .filter(|projection| projection.definition.filter.matches(event))
+            .map(|projection| projection.definition.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Returns the up-to-date state of a projection, or `None` if it doesn't exist.
+    #[instrument(skip(self, store))]
+    pub async fn get(
+        &self,
+        name: &str,
+        store: &(dyn Storage + Send + Sync),
+    ) -> Result<Option<ProjectionView>, RetrieveError> {
+        let mut projections = self.projections.lock().await;
+        let Some(projection) = projections.get_mut(name) else {
+            return Ok(None);
+        };
+        projection.catch_up(store).await?;
+
+        let every_events = self.snapshot_config.every_events;
+        if every_events > 0
+            && projection.folded_since_snapshot >= every_events
+            && let Err(error) = self.take_snapshot(projection).await
+        {
+            warn!("Failed to snapshot projection '{name}': {error}");
+        }
+        Ok(Some(projection.view()))
+    }
+
+    /// Computes the state of a projection from the events with timestamps up to `as_of`,
+    /// without touching its current state.
+    ///
+    /// This uses event time: events stored late but timestamped before `as_of` are included.
+    #[instrument(skip(self, store))]
+    pub async fn get_as_of(
+        &self,
+        name: &str,
+        store: &(dyn Storage + Send + Sync),
+        as_of: Timestamp,
+    ) -> Result<Option<ProjectionView>, RetrieveError> {
+        let definition = {
+            let projections = self.projections.lock().await;
+            let Some(projection) = projections.get(name) else {
+                return Ok(None);
+            };
+            projection.definition.clone()
+        };
+        let mut filter = definition.filter.clone();
+        filter.end = Some(filter.end.map_or(as_of, |end| end.min(as_of)));
+
+        let mut projection = Projection {
+            state: definition.initial_state.clone(),
+            checkpoint: None,
+            snapshot: None,
+            folded_since_snapshot: 0,
+            definition: ProjectionDefinition {
+                filter,
+                ..definition
+            },
+        };
+        projection.catch_up(store).await?;
+        Ok(Some(projection.view()))
+    }
+
+    /// Rebuilds a projection from its latest snapshot, or from the first event if there is no
+    /// snapshot or `from_scratch` is set.
+    #[instrument(skip(self, store))]
+    pub async fn rebuild(
+        &self,
+        name: &str,
+        store: &(dyn Storage + Send + Sync),
+        from_scratch: bool,
+    ) -> Result<Option<ProjectionView>, RetrieveError> {
+        let mut projections = self.projections.lock().await;
+        let Some(projection) = projections.get_mut(name) else {
+            return Ok(None);
+        };
+        projection.restore(!from_scratch);
+        projection.catch_up(store).await?;
+        Ok(Some(projection.view()))
+    }
+
+    /// Brings a projection up to date and takes a snapshot of it.
+    #[instrument(skip(self, store))]
+    pub async fn snapshot(
+        &self,
+        name: &str,
+        store: &(dyn Storage + Send + Sync),
+    ) -> Result<Option<ProjectionView>, SnapshotError> {
+        let mut projections = self.projections.lock().await;
+        let Some(projection) = projections.get_mut(name) else {
+            return Ok(None);
+        };
+        projection.catch_up(store).await?;
+        self.take_snapshot(projection).await?;
+        Ok(Some(projection.view()))
+    }
+
+    async fn take_snapshot(&self, projection: &mut Projection) -> Result<(), SnapshotError> {
+        let snapshot = ProjectionSnapshot {
+            checkpoint: projection.checkpoint,
+            state: projection.state.clone(),
+        };
+        if let Some(dir) = &self.snapshot_config.dir {
+            let path = dir.join(format!("{}.json", projection.definition.name));
+            let json = serde_json::to_vec(&snapshot).expect("JSON values always serialize");
+            snapshot_file::write(&path, &json, &self.snapshot_config.format).await?;
+        }
+        info!(
+            "Took snapshot of projection '{}' at checkpoint {:?}",
+            projection.definition.name, snapshot.checkpoint
+        );
+        projection.snapshot = Some(snapshot);
+        projection.folded_since_snapshot = 0;
+        Ok(())
+    }
+}
+
+/// Loads the persisted snapshot of a projection, if there is one.
+fn load_snapshot(config: &SnapshotConfig, name: &str) -> Option<ProjectionSnapshot> {
+    let path = config.dir.as_ref()?.join(format!("{name}.json"));
+    let json = snapshot_file::read(&path, &config.format)?;
+    match serde_json::from_slice(&json) {
+        Ok(snapshot) => Some(snapshot),
+        Err(error) => {
+            warn!("Ignoring invalid snapshot {}: {error}", path.display());
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryStorage;
+
+    #[tokio::test]
+    async fn test_projection() {
+        let revenue = ProjectionDefinition {
+            name: "revenue".to_string(),
+            filter: EventFilter {
+                event_type: Some("purchase".to_string()),
+                ..Default::default()
+            },
+            initial_state: serde_json::json!(0),
+            fold: Arc::new(|state: &mut serde_json::Value, event: &Event| {
+                let amount = event.payload["amount"].as_u64().unwrap_or(0);
+                *state = serde_json::json!(state.as_u64().unwrap_or(0) + amount);
+            }),
+        };
+        let projections = Projections::new(vec![revenue], SnapshotConfig::default());
+        let store = InMemoryStorage::new();
+        let purchase = |amount: u64| Event {
+            event_type: "purchase".to_string(),
+            timestamp: 1,
+            payload: serde_json::json!({ "amount": amount }),
+            ..Default::default()
+        };
+
+        store.store(purchase(10)).await.unwrap();
+        let view = projections.get("revenue", &store).await.unwrap().unwrap();
+        assert_eq!(view.state, serde_json::json!(10));
+
+        store.store(purchase(5)).await.unwrap();
+        store
+            .store(Event {
+                event_type: "refund".to_string(),
+                ..purchase(3)
+            })
+            .await
+            .unwrap();
+        let view = projections.get("revenue", &store).await.unwrap().unwrap();
+        assert_eq!(view.state, serde_json::json!(15));
+
+        let rebuilt = projections
+            .rebuild("revenue", &store, true)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rebuilt.state, view.state);
+        assert_eq!(rebuilt.checkpoint, view.checkpoint);
+
+        // Rebuilding from a snapshot only replays the tail.
+        projections.snapshot("revenue", &store).await.unwrap();
+        store.store(purchase(1)).await.unwrap();
+        let rebuilt = projections
+            .rebuild("revenue", &store, false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rebuilt.state, serde_json::json!(16));
+
+        let past = projections
+            .get_as_of("revenue", &store, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(past.state, serde_json::json!(0));
+
+        assert!(projections.get("missing", &store).await.unwrap().is_none());
+    }
+}
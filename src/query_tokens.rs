@@ -0,0 +1,143 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as BASE64};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    event::{Event, Timestamp},
+    receipts::{KeyRing, SigningKey, SigningKeys},
+};
+
+/// The events a query token grants access to: one event type, optionally in a time range.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TokenScope {
+    #[serde(rename = "type", alias = "event_type")]
+    pub event_type: String,
+
+    /// Inclusive timestamp range of the events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<Timestamp>,
+}
+
+impl TokenScope {
+    pub fn matches(&self, event: &Event) -> bool {
+        event.event_type == self.event_type
+            && self.start.is_none_or(|start| event.timestamp >= start)
+            && self.end.is_none_or(|end| event.timestamp <= end)
+    }
+}
+
+/// What a token says about itself, signed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TokenClaims {
+    #[serde(flatten)]
+    pub scope: TokenScope,
+
+    /// Unix timestamp in seconds after which the token is rejected.
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenError {
+    Invalid,
+    Expired,
+}
+
+/// Issues and checks short-lived tokens granting read access to one scope of events, so
+/// views can be shared without handing out API keys.
+///
+/// Tokens are their claims in base64, followed by a dot and the HMAC-SHA256 of the claims, so
+/// they can't be forged or widened without the key.
+pub struct QueryTokens {
+    key: KeyRing,
+}
+
+impl QueryTokens {
+    /// Creates the token issuer. Without a key, a random one is generated, and tokens are
+    /// invalidated by a restart.
+    pub fn new(keys: Option<SigningKeys>) -> Self {
+        Self {
+            key: KeyRing::new(keys.unwrap_or_else(|| SigningKey::random().into())),
+        }
+    }
+
+    /// Signs new tokens with the current key. Tokens of the previous key stay valid until they
+    /// expire.
+    pub fn rotate(&self, keys: SigningKeys) {
+        self.key.rotate(keys);
+    }
+
+    pub fn issue(&self, claims: &TokenClaims) -> String {
+        let claims = BASE64.encode(serde_json::to_vec(claims).expect("Claims always serialize"));
+        let signature = self.key.sign(claims.as_bytes());
+        format!("{claims}.{signature}")
+    }
+
+    /// Checks the signature and expiry of a token, returning its claims.
+    pub fn verify(&self, token: &str, now: u64) -> Result<TokenClaims, TokenError> {
+        let (claims, signature) = token.split_once('.').ok_or(TokenError::Invalid)?;
+        if !self.key.verify(claims.as_bytes(), signature) {
+            return Err(TokenError::Invalid);
+        }
+        let claims = BASE64.decode(claims).map_err(|_| TokenError::Invalid)?;
+        let claims: TokenClaims =
+            serde_json::from_slice(&claims).map_err(|_| TokenError::Invalid)?;
+        if claims.expires_at < now {
+            return Err(TokenError::Expired);
+        }
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_tokens() {
+        let tokens = QueryTokens::new(None);
+        let claims = TokenClaims {
+            scope: TokenScope {
+                event_type: "deploy".to_string(),
+                start: Some(100),
+                end: None,
+            },
+            expires_at: 1000,
+        };
+        let token = tokens.issue(&claims);
+        assert_eq!(tokens.verify(&token, 1000), Ok(claims.clone()));
+        assert_eq!(tokens.verify(&token, 1001), Err(TokenError::Expired));
+        assert_eq!(
+            QueryTokens::new(None).verify(&token, 0),
+            Err(TokenError::Invalid)
+        );
+
+        // Widening the scope breaks the signature.
+        let (_, signature) = token.split_once('.').unwrap();
+        let mut widened = claims;
+        widened.scope.start = None;
+        let widened = BASE64.encode(serde_json::to_vec(&widened).unwrap());
+        assert_eq!(
+            tokens.verify(&format!("{widened}.{signature}"), 0),
+            Err(TokenError::Invalid)
+        );
+        assert_eq!(tokens.verify("garbage", 0), Err(TokenError::Invalid));
+    }
+
+    #[test]
+    fn test_scope() {
+        let scope = TokenScope {
+            event_type: "deploy".to_string(),
+            start: Some(10),
+            end: Some(20),
+        };
+        let event = |event_type: &str, timestamp| Event {
+            event_type: event_type.to_string(),
+            timestamp,
+            ..Default::default()
+        };
+        assert!(scope.matches(&event("deploy", 10)));
+        assert!(!scope.matches(&event("deploy", 21)));
+        assert!(!scope.matches(&event("click", 15)));
+    }
+}
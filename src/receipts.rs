@@ -0,0 +1,289 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::event::{Event, EventId};
+
+const BLOCK_SIZE: usize = 64;
+
+/// Proof that the tracker accepted an event, returned to the producer on ingest.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Receipt {
+    pub event_id: String,
+
+    /// Unix timestamp in milliseconds of when the event was accepted.
+    pub received_at: u64,
+
+    /// SHA-256 of the event's payload as stored, in hex.
+    pub payload_sha256: String,
+
+    /// HMAC-SHA256 of the other fields in hex, `None` if no receipt key is configured.
+    pub signature: Option<String>,
+}
+
+/// 256-bit HMAC key signing receipts and query tokens, parsed from 64 hex digits.
+#[derive(Clone, PartialEq)]
+pub struct SigningKey([u8; 32]);
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SigningKey(..)")
+    }
+}
+
+impl FromStr for SigningKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || "Signing key must be 64 hex digits".to_string();
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let mut key = [0; 32];
+        for (index, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[index * 2..index * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(key))
+    }
+}
+
+impl SigningKey {
+    /// Generates a key, for signatures that needn't outlive the process.
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    /// Signs a message with HMAC-SHA256, returning the signature in hex.
+    pub fn sign(&self, message: &[u8]) -> String {
+        hex(&hmac_sha256(&self.0, message))
+    }
+}
+
+/// The key signing new receipts or tokens, and the retired keys whose signatures are still
+/// accepted. Parsed from comma separated keys, the signing key first, so a key can be replaced
+/// without invalidating what it signed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SigningKeys {
+    pub current: SigningKey,
+    pub retired: Vec<SigningKey>,
+}
+
+impl FromStr for SigningKeys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keys = s.split(',').map(|key| key.trim().parse());
+        let current = keys.next().expect("Splitting yields at least one key")?;
+        Ok(Self {
+            current,
+            retired: keys.collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl From<SigningKey> for SigningKeys {
+    fn from(current: SigningKey) -> Self {
+        Self {
+            current,
+            retired: Vec::new(),
+        }
+    }
+}
+
+/// Signing keys that can be rotated. Signatures of the key the current one replaced stay valid
+/// until the next rotation, so tokens and receipts issued just before a rotation aren't
+/// rejected. Signatures of the retired keys stay valid as long as they are configured.
+pub struct KeyRing {
+    /// The configured keys and the key the current one replaced.
+    keys: RwLock<(SigningKeys, Option<SigningKey>)>,
+}
+
+impl KeyRing {
+    pub fn new(keys: SigningKeys) -> Self {
+        Self {
+            keys: RwLock::new((keys, None)),
+        }
+    }
+
+    /// Signs with the current key.
+    pub fn sign(&self, message: &[u8]) -> String {
+        self.keys.read().unwrap().0.current.sign(message)
+    }
+
+    /// Whether the signature was made with the current, the previous or a retired key.
+    pub fn verify(&self, message: &[u8], signature: &str) -> bool {
+        let (keys, previous) = &*self.keys.read().unwrap();
+        std::iter::once(&keys.current)
+            .chain(previous)
+            .chain(&keys.retired)
+            .any(|key| signatures_match(&key.sign(message), signature))
+    }
+
+    /// Replaces the configured keys. The current key is kept as the previous one, unless it
+    /// stays the current one.
+    pub fn rotate(&self, keys: SigningKeys) {
+        let mut ring = self.keys.write().unwrap();
+        let (configured, previous) = &mut *ring;
+        if configured.current != keys.current {
+            *previous = Some(configured.current.clone());
+        }
+        *configured = keys;
+    }
+}
+
+/// Compares signatures in constant time, so they can't be guessed byte by byte.
+pub fn signatures_match(expected: &str, signature: &str) -> bool {
+    expected.len() == signature.len()
+        && expected
+            .bytes()
+            .zip(signature.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Issues receipts for accepted events, and verifies the ones producers present later.
+pub struct Receipts {
+    key: Option<KeyRing>,
+}
+
+impl Receipts {
+    pub fn new(keys: Option<SigningKeys>) -> Self {
+        Self {
+            key: keys.map(KeyRing::new),
+        }
+    }
+
+    /// Signs new receipts with the current key. Receipts of the previous key still verify.
+    pub fn rotate(&self, keys: SigningKeys) {
+        if let Some(ring) = &self.key {
+            ring.rotate(keys);
+        }
+    }
+
+    /// Whether receipts are signed, and so can be verified.
+    pub fn signed(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Issues a receipt for an accepted event, given the hash of its payload.
+    pub fn issue(&self, event_id: EventId, payload_sha256: String) -> Receipt {
+        let mut receipt = Receipt {
+            event_id: event_id.to_string(),
+            received_at: now_millis(),
+            payload_sha256,
+            signature: None,
+        };
+        receipt.signature = self.sign(&receipt);
+        receipt
+    }
+
+    /// Checks that the receipt was issued by a tracker with the same key, and not altered.
+    pub fn verify(&self, receipt: &Receipt) -> bool {
+        match (&self.key, &receipt.signature) {
+            (Some(keys), Some(signature)) => keys.verify(message(receipt).as_bytes(), signature),
+            _ => false,
+        }
+    }
+
+    fn sign(&self, receipt: &Receipt) -> Option<String> {
+        let keys = self.key.as_ref()?;
+        Some(keys.sign(message(receipt).as_bytes()))
+    }
+}
+
+/// The signed fields of a receipt.
+fn message(receipt: &Receipt) -> String {
+    format!(
+        "{}\n{}\n{}",
+        receipt.event_id, receipt.received_at, receipt.payload_sha256
+    )
+}
+
+/// Hashes the payload of an event as it is stored, for its receipt.
+pub fn payload_sha256(event: &Event) -> String {
+    let payload = serde_json::to_vec(&event.payload).expect("Payloads always serialize");
+    hex(&Sha256::digest(payload))
+}
+
+fn hmac_sha256(key: &[u8; 32], message: &[u8]) -> [u8; 32] {
+    let mut padded = [0; BLOCK_SIZE];
+    padded[..key.len()].copy_from_slice(key);
+    let inner_pad = padded.map(|byte| byte ^ 0x36);
+    let outer_pad = padded.map(|byte| byte ^ 0x5c);
+    let inner = Sha256::new()
+        .chain_update(inner_pad)
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(outer_pad)
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+/// Formats bytes as lowercase hex digits.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // The message of RFC 4231 test case 1 with a 32 byte key, as computed by Python's hmac.
+        let key = [0x0b; 32];
+        assert_eq!(
+            hex(&hmac_sha256(&key, b"Hi There")),
+            "198a607eb44bfbc69903a0f1cf2bbdc5ba0aa3f3d9ae3c1c7a3b1696a0b68cf7"
+        );
+    }
+
+    #[test]
+    fn test_receipts() {
+        let key = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        let receipts = Receipts::new(Some(key.parse().unwrap()));
+        let event = Event {
+            payload: serde_json::json!({"amount": 42}),
+            ..Default::default()
+        };
+        let receipt = receipts.issue(7, payload_sha256(&event));
+        assert_eq!(receipt.event_id, "7");
+        assert!(receipts.verify(&receipt));
+
+        let tampered = Receipt {
+            payload_sha256: payload_sha256(&Event::default()),
+            ..receipt.clone()
+        };
+        assert!(!receipts.verify(&tampered));
+        assert!(!Receipts::new(None).verify(&receipt));
+
+        // Receipts of the previous key verify after a rotation, but not after the next one.
+        receipts.rotate("11".repeat(32).parse().unwrap());
+        assert!(receipts.verify(&receipt));
+        let rotated = receipts.issue(8, payload_sha256(&event));
+        assert_ne!(rotated.signature, receipt.signature);
+        receipts.rotate("22".repeat(32).parse().unwrap());
+        assert!(!receipts.verify(&receipt));
+        assert!(receipts.verify(&rotated));
+
+        // Receipts of retired keys verify as long as they are configured.
+        let keys = format!("{},{key}", "33".repeat(32));
+        receipts.rotate(keys.parse().unwrap());
+        assert!(receipts.verify(&receipt));
+        assert!("00,zz".parse::<SigningKeys>().is_err());
+    }
+}
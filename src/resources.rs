@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::{
+    coalescing::CoalescingRule, event_types::EventTypeDoc, export_schedules::ExportSchedule,
+    schema_registry::PayloadSchema, scripts::ScriptRule, state_machine::StateMachineDefinition,
+};
+
+/// All non-event configuration that can be changed at runtime, as a single bundle.
+///
+/// Used to export and import the configuration, so environments can be kept in sync.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct ResourceBundle {
+    #[serde(default)]
+    pub state_machines: Vec<StateMachineDefinition>,
+
+    #[serde(default)]
+    pub coalescing: Vec<CoalescingRule>,
+
+    #[serde(default)]
+    pub scripts: Vec<ScriptRule>,
+
+    /// Documentation of event types.
+    #[serde(default)]
+    pub event_types: Vec<EventTypeDoc>,
+
+    #[serde(default)]
+    pub export_schedules: Vec<ExportSchedule>,
+
+    /// JSON Schemas the payloads of event types must match.
+    #[serde(default)]
+    pub schemas: Vec<PayloadSchema>,
+}
+
+impl ResourceBundle {
+    /// Loads a bundle from a YAML (`.yaml`, `.yml`) or TOML file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read resources file {}", path.display()))?;
+        let bundle = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => serde_yaml::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            _ => toml::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+        };
+        Ok(bundle)
+    }
+
+    /// Merges resources loaded from a file into the current ones.
+    ///
+    /// Resources defined in the file replace current ones with the same name, and resources
+    /// removed from the file since it was last loaded are deleted. Resources created at runtime
+    /// are kept.
+    pub fn reconcile(self, previous_file: &ResourceBundle, file: ResourceBundle) -> Self {
+        ResourceBundle {
+            state_machines: reconcile_by_name(
+                self.state_machines,
+                &previous_file.state_machines,
+                file.state_machines,
+                |machine| machine.name.as_str(),
+            ),
+            coalescing: reconcile_by_name(
+                self.coalescing,
+                &previous_file.coalescing,
+                file.coalescing,
+                |rule| rule.event_type.as_str(),
+            ),
+            scripts: reconcile_by_name(
+                self.scripts,
+                &previous_file.scripts,
+                file.scripts,
+                |rule| rule.name.as_str(),
+            ),
+            event_types: reconcile_by_name(
+                self.event_types,
+                &previous_file.event_types,
+                file.event_types,
+                |doc| doc.event_type.as_str(),
+            ),
+            export_schedules: reconcile_by_name(
+                self.export_schedules,
+                &previous_file.export_schedules,
+                file.export_schedules,
+                |schedule| schedule.name.as_str(),
+            ),
+            schemas: reconcile_by_name(
+                self.schemas,
+                &previous_file.schemas,
+                file.schemas,
+                |schema| schema.event_type.as_str(),
+            ),
+        }
+    }
+}
+
+fn reconcile_by_name<T>(
+    current: Vec<T>,
+    previous_file: &[T],
+    file: Vec<T>,
+    name: impl Fn(&T) -> &str,
+) -> Vec<T> {
+    let mut result: Vec<T> = current
+        .into_iter()
+        .filter(|item| {
+            let item_name = name(item);
+            !file.iter().any(|other| name(other) == item_name)
+                && !previous_file.iter().any(|other| name(other) == item_name)
+        })
+        .collect();
+    result.extend(file);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(name: &str, initial_state: &str) -> StateMachineDefinition {
+        StateMachineDefinition {
+            name: name.to_string(),
+            key_field: "id".to_string(),
+            initial_state: initial_state.to_string(),
+            transitions: vec![],
+        }
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let previous_file = ResourceBundle {
+            state_machines: vec![machine("order", "new"), machine("removed", "new")],
+            ..Default::default()
+        };
+        let current = ResourceBundle {
+            state_machines: vec![
+                machine("order", "new"),
+                machine("removed", "new"),
+                machine("runtime", "new"),
+            ],
+            ..Default::default()
+        };
+        let file: ResourceBundle = toml::from_str(
+            r#"
+            [[state_machines]]
+            name = "order"
+            key_field = "id"
+            initial_state = "created"
+            transitions = []
+            "#,
+        )
+        .unwrap();
+
+        let reconciled = current.reconcile(&previous_file, file);
+        assert_eq!(
+            reconciled.state_machines,
+            vec![machine("runtime", "new"), machine("order", "created")]
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+use tracing::{error, info};
+
+use crate::{
+    event::Timestamp,
+    event_policy::is_test_event_type,
+    health::Health,
+    legal_holds::{HoldFilter, LegalHolds},
+    metrics, sentry,
+    storage::{Storage, StoreError},
+};
+
+/// Configures the expiry of old events.
+#[derive(Debug, Clone)]
+pub struct RetentionConfig {
+    /// Events older than this many seconds are deleted, unless their type has its own TTL.
+    pub ttl: Option<Timestamp>,
+
+    /// TTLs in seconds overriding `ttl` for some event types.
+    pub ttl_by_type: BTreeMap<String, Timestamp>,
+
+    /// TTL in seconds of the event types in the test namespace without their own TTL.
+    pub test_ttl: Timestamp,
+
+    /// How often expired events are deleted.
+    pub interval: Duration,
+}
+
+impl RetentionConfig {
+    fn ttl(&self, event_type: &str) -> Option<Timestamp> {
+        match self.ttl_by_type.get(event_type) {
+            Some(ttl) => Some(*ttl),
+            None if is_test_event_type(event_type) => Some(self.test_ttl),
+            None => self.ttl,
+        }
+    }
+}
+
+/// Deletes the events older than the TTL of their type, except the ones under a legal hold.
+/// Returns the number of deleted events.
+pub async fn expire(
+    store: &(dyn Storage + Send + Sync),
+    config: &RetentionConfig,
+    held: &[HoldFilter],
+    now: Timestamp,
+) -> Result<u64, StoreError> {
+    let mut expired = 0;
+    for (event_type, _) in store.event_type_counts().await {
+        let Some(ttl) = config.ttl(&event_type) else {
+            continue;
+        };
+        let Some(last_expired) = now.saturating_sub(ttl).checked_sub(1) else {
+            continue;
+        };
+        let mut deleted = 0;
+        for (start, end) in unheld_ranges(&event_type, last_expired, held) {
+            deleted += store
+                .delete_events(Some(&event_type), Some(start), Some(end))
+                .await?;
+        }
+        if deleted > 0 {
+            metrics::add(
+                "retention_expired_events_total",
+                &[("event_type", event_type.as_str())],
+                deleted as f64,
+            );
+        }
+        expired += deleted;
+    }
+    Ok(expired)
+}
+
+/// Splits the timestamps up to `end` into the inclusive ranges not covered by the holds on
+/// the event type.
+fn unheld_ranges(
+    event_type: &str,
+    end: Timestamp,
+    held: &[HoldFilter],
+) -> Vec<(Timestamp, Timestamp)> {
+    let mut held_ranges: Vec<_> = held
+        .iter()
+        .filter(|hold| hold.event_type.as_deref().is_none_or(|t| t == event_type))
+        .map(|hold| (hold.start.unwrap_or(0), hold.end.unwrap_or(Timestamp::MAX)))
+        .filter(|(start, end)| start <= end)
+        .collect();
+    held_ranges.sort_unstable();
+
+    let mut ranges = vec![];
+    // The first timestamp not known to be held, `None` past the largest timestamp.
+    let mut next = Some(0);
+    for (held_start, held_end) in held_ranges {
+        let Some(start) = next.filter(|start| *start <= end) else {
+            break;
+        };
+        if held_start > start {
+            ranges.push((start, (held_start - 1).min(end)));
+        }
+        if held_end >= start {
+            next = held_end.checked_add(1);
+        }
+    }
+    if let Some(start) = next.filter(|start| *start <= end) {
+        ranges.push((start, end));
+    }
+    ranges
+}
+
+/// Deletes expired events periodically in the background. Skips runs while the storage
+/// backend is degraded.
+pub fn spawn(
+    store: Arc<dyn Storage + Send + Sync + 'static>,
+    health: Arc<Health>,
+    legal_holds: Arc<LegalHolds>,
+    config: RetentionConfig,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.wait_for(|shutting_down| *shutting_down) => break,
+            }
+            if health.is_degraded() {
+                continue;
+            }
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0);
+            let held: Vec<_> = legal_holds
+                .holds()
+                .await
+                .into_iter()
+                .map(|hold| hold.filter)
+                .collect();
+            match expire(store.as_ref(), &config, &held, now).await {
+                Ok(0) => {}
+                Ok(expired) => info!("Deleted {expired} expired events"),
+                Err(error) => {
+                    error!("Failed to delete expired events: {error:?}");
+                    sentry::capture_task_failure("retention", &format!("{error:?}"));
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{event::Event, storage::InMemoryStorage};
+
+    #[test]
+    fn test_unheld_ranges() {
+        let hold = |start, end| HoldFilter {
+            start,
+            end,
+            ..Default::default()
+        };
+        assert_eq!(unheld_ranges("click", 100, &[]), [(0, 100)]);
+        let held = [hold(Some(10), Some(20)), hold(Some(15), Some(30))];
+        assert_eq!(unheld_ranges("click", 100, &held), [(0, 9), (31, 100)]);
+        assert_eq!(unheld_ranges("click", 12, &held), [(0, 9)]);
+        assert!(unheld_ranges("click", 100, &[hold(None, None)]).is_empty());
+        assert_eq!(
+            unheld_ranges("click", 100, &[hold(Some(50), None)]),
+            [(0, 49)]
+        );
+
+        let views = HoldFilter {
+            event_type: Some("view".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(unheld_ranges("click", 100, &[views]), [(0, 100)]);
+    }
+
+    #[tokio::test]
+    async fn test_expire() {
+        let store = InMemoryStorage::new();
+        let events = [
+            ("click", 10),
+            ("click", 90),
+            ("audit", 10),
+            ("view", 50),
+            ("test.click", 75),
+            ("test.click", 85),
+        ];
+        for (event_type, timestamp) in events {
+            let event = Event {
+                event_type: event_type.to_string(),
+                timestamp,
+                ..Default::default()
+            };
+            store.store(event).await.unwrap();
+        }
+        let config = RetentionConfig {
+            ttl: Some(30),
+            ttl_by_type: BTreeMap::from([("audit".to_string(), 1000)]),
+            test_ttl: 20,
+            interval: Duration::from_secs(60),
+        };
+        let held = [HoldFilter {
+            event_type: Some("view".to_string()),
+            ..Default::default()
+        }];
+
+        assert_eq!(expire(&store, &config, &held, 100).await.unwrap(), 2);
+        let remaining = store.get_events(None, None, None).await.unwrap();
+        let remaining: Vec<_> = remaining
+            .iter()
+            .map(|event| (event.event_type.as_str(), event.timestamp))
+            .collect();
+        assert_eq!(
+            remaining,
+            [
+                ("audit", 10),
+                ("view", 50),
+                ("test.click", 85),
+                ("click", 90)
+            ]
+        );
+    }
+}
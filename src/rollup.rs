@@ -0,0 +1,238 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+use tracing::{error, info};
+
+use crate::{
+    event::{Event, EventId, Timestamp},
+    health::Health,
+    legal_holds::{HoldFilter, LegalHolds},
+    metrics, sentry,
+    storage::{EventFilter, RetrieveError, Storage, StoreError},
+};
+
+/// Payload field marking aggregate events, so they aren't rolled up again.
+const ROLLUP_FIELD: &str = "rollup";
+
+/// Configures downsampling of old raw events into aggregates.
+#[derive(Debug, Clone)]
+pub struct RollupConfig {
+    /// Events older than this many seconds are rolled up.
+    pub max_age: Timestamp,
+
+    /// Width of the aggregate buckets in seconds.
+    pub bucket: Timestamp,
+
+    /// Top-level numeric payload fields summed in the aggregates.
+    pub sum_fields: Vec<String>,
+
+    /// How often the rollup job runs.
+    pub interval: Duration,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RollupError {
+    #[error("Failed to retrieve events: {0:?}")]
+    Retrieve(RetrieveError),
+
+    #[error("Failed to store aggregates: {0:?}")]
+    Store(StoreError),
+}
+
+/// Aggregate of the raw events of a type in a bucket.
+#[derive(Default)]
+struct Aggregate {
+    event_ids: Vec<EventId>,
+    count: u64,
+    sums: BTreeMap<String, f64>,
+}
+
+/// Replaces raw events older than the configured age with one aggregate event per event type
+/// and bucket. Returns the number of raw events rolled up. Events under a legal hold are kept
+/// as they are.
+///
+/// Aggregates keep the event type, are timestamped at the start of their bucket and carry the
+/// number of raw events in `count`. Their payload looks like this:
+///
+/// ```json
+/// { "rollup": { "bucket": 3600, "sum": { "amount": 1250.0 } } }
+/// ```
+pub async fn roll_up(
+    store: &(dyn Storage + Send + Sync),
+    config: &RollupConfig,
+    held: &[HoldFilter],
+    now: Timestamp,
+) -> Result<usize, RollupError> {
+    // Only whole buckets are rolled up, so each bucket gets a single aggregate.
+    let bucket = config.bucket.max(1);
+    let cutoff = now.saturating_sub(config.max_age) / bucket * bucket;
+    if cutoff == 0 {
+        return Ok(0);
+    }
+    let filter = EventFilter {
+        end: Some(cutoff - 1),
+        ..Default::default()
+    };
+    let events = store
+        .scan(&filter, None)
+        .await
+        .map_err(RollupError::Retrieve)?;
+
+    let mut aggregates: BTreeMap<(String, Timestamp), Aggregate> = BTreeMap::new();
+    for (event_id, event) in events {
+        if event.payload.get(ROLLUP_FIELD).is_some() || held.iter().any(|hold| hold.matches(&event))
+        {
+            continue;
+        }
+        let bucket_start = event.timestamp / bucket * bucket;
+        let aggregate = aggregates
+            .entry((event.event_type, bucket_start))
+            .or_default();
+        let count = event.count.unwrap_or(1);
+        aggregate.event_ids.push(event_id);
+        aggregate.count += count;
+        for field in &config.sum_fields {
+            if let Some(value) = event.payload.get(field).and_then(|value| value.as_f64()) {
+                *aggregate.sums.entry(field.clone()).or_default() += value * count as f64;
+            }
+        }
+    }
+
+    let mut event_ids = vec![];
+    let mut replacements = vec![];
+    for ((event_type, timestamp), aggregate) in aggregates {
+        event_ids.extend(aggregate.event_ids);
+        replacements.push(Event {
+            event_type,
+            timestamp,
+            payload: serde_json::json!({
+                ROLLUP_FIELD: { "bucket": bucket, "sum": aggregate.sums },
+            }),
+            correlation_id: None,
+            count: Some(aggregate.count),
+            dedup_id: None,
+        });
+    }
+    if event_ids.is_empty() {
+        return Ok(0);
+    }
+    store
+        .replace_events(&event_ids, replacements)
+        .await
+        .map_err(RollupError::Store)?;
+    Ok(event_ids.len())
+}
+
+/// Runs the rollup job periodically in the background. Skips runs while the storage backend
+/// is degraded.
+pub fn spawn(
+    store: Arc<dyn Storage + Send + Sync + 'static>,
+    health: Arc<Health>,
+    legal_holds: Arc<LegalHolds>,
+    config: RollupConfig,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.wait_for(|shutting_down| *shutting_down) => break,
+            }
+            if health.is_degraded() {
+                continue;
+            }
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0);
+            let held: Vec<_> = legal_holds
+                .holds()
+                .await
+                .into_iter()
+                .map(|hold| hold.filter)
+                .collect();
+            match roll_up(store.as_ref(), &config, &held, now).await {
+                Ok(0) => {}
+                Ok(rolled_up) => {
+                    info!("Rolled up {rolled_up} events");
+                    metrics::add("rollup_events_total", &[], rolled_up as f64);
+                }
+                Err(error) => {
+                    error!("Rollup failed: {error}");
+                    sentry::capture_task_failure("rollup", &error);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryStorage;
+
+    #[tokio::test]
+    async fn test_roll_up() {
+        let store = InMemoryStorage::new();
+        for (timestamp, amount) in [(10, 1), (20, 2), (110, 4), (250, 8)] {
+            let event = Event {
+                event_type: "purchase".to_string(),
+                timestamp,
+                payload: serde_json::json!({ "amount": amount }),
+                ..Default::default()
+            };
+            store.store(event).await.unwrap();
+        }
+        let config = RollupConfig {
+            max_age: 100,
+            bucket: 100,
+            sum_fields: vec!["amount".to_string()],
+            interval: Duration::from_secs(60),
+        };
+
+        // The bucket starting at 200 isn't complete yet at the cutoff.
+        assert_eq!(roll_up(&store, &config, &[], 350).await.unwrap(), 3);
+        assert_eq!(roll_up(&store, &config, &[], 350).await.unwrap(), 0);
+
+        let events = store.scan(&EventFilter::default(), None).await.unwrap();
+        let events: Vec<_> = events.into_iter().map(|(_, event)| event).collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].timestamp, 250);
+        assert_eq!(events[1].timestamp, 0);
+        assert_eq!(events[1].count, Some(2));
+        assert_eq!(events[1].payload["rollup"]["sum"]["amount"], 3.0);
+        assert_eq!(events[2].timestamp, 100);
+        assert_eq!(events[2].count, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_roll_up_keeps_held_events() {
+        let store = InMemoryStorage::new();
+        for timestamp in [10, 20, 30] {
+            let event = Event {
+                event_type: "purchase".to_string(),
+                timestamp,
+                ..Default::default()
+            };
+            store.store(event).await.unwrap();
+        }
+        let config = RollupConfig {
+            max_age: 100,
+            bucket: 100,
+            sum_fields: vec![],
+            interval: Duration::from_secs(60),
+        };
+        let held = [HoldFilter {
+            start: Some(20),
+            end: Some(20),
+            ..Default::default()
+        }];
+        assert_eq!(roll_up(&store, &config, &held, 350).await.unwrap(), 2);
+        let events = store.get_events(None, Some(20), Some(20)).await.unwrap();
+        assert_eq!(events[0].count, None);
+    }
+}
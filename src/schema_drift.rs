@@ -0,0 +1,253 @@
+use ahash::AHashMap;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt,
+    sync::Mutex,
+};
+use tracing::warn;
+
+use crate::{
+    event::{Event, EventId, Timestamp},
+    metrics,
+};
+
+/// Drift entries kept per event type, older ones are dropped.
+const MAX_DRIFT_ENTRIES: usize = 100;
+
+/// JSON type of a payload field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Null,
+    Bool,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl FieldType {
+    fn of(value: &serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => FieldType::Null,
+            serde_json::Value::Bool(_) => FieldType::Bool,
+            serde_json::Value::Number(_) => FieldType::Number,
+            serde_json::Value::String(_) => FieldType::String,
+            serde_json::Value::Array(_) => FieldType::Array,
+            serde_json::Value::Object(_) => FieldType::Object,
+        }
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FieldType::Null => "null",
+            FieldType::Bool => "bool",
+            FieldType::Number => "number",
+            FieldType::String => "string",
+            FieldType::Array => "array",
+            FieldType::Object => "object",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A change in the payload shape of an event type.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Drift {
+    /// A field appeared that no earlier event had.
+    FieldAdded {
+        field: String,
+        field_type: FieldType,
+    },
+
+    /// A field has a type it didn't have in earlier events.
+    TypeChanged {
+        field: String,
+        from: BTreeSet<FieldType>,
+        to: FieldType,
+    },
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drift::FieldAdded { field, field_type } => {
+                write!(f, "New field '{field}' appeared with type {field_type}")
+            }
+            Drift::TypeChanged { field, from, to } => {
+                let from: Vec<_> = from.iter().map(FieldType::to_string).collect();
+                write!(
+                    f,
+                    "Type of field '{field}' changed from {} to {to}",
+                    from.join(" or ")
+                )
+            }
+        }
+    }
+}
+
+/// A drift and the event it was first seen in.
+#[derive(Debug, Clone, Serialize)]
+pub struct DriftEntry {
+    event_id: EventId,
+    timestamp: Timestamp,
+
+    #[serde(flatten)]
+    drift: Drift,
+
+    message: String,
+}
+
+/// Payload shape of an event type as observed since startup, and how it drifted.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DriftReport {
+    /// Types seen for each field. Nested fields are joined with dots, e.g. `customer.country`.
+    fields: BTreeMap<String, BTreeSet<FieldType>>,
+
+    /// Drifts in the order they were seen, the latest ones if there were many.
+    drift: VecDeque<DriftEntry>,
+}
+
+/// Payload fields of an event with their types.
+pub struct PayloadShape {
+    event_type: String,
+    timestamp: Timestamp,
+    fields: Vec<(String, FieldType)>,
+}
+
+impl PayloadShape {
+    pub fn of(event: &Event) -> Self {
+        let mut fields = vec![];
+        if let serde_json::Value::Object(object) = &event.payload {
+            collect_fields("", object, &mut fields);
+        }
+        Self {
+            event_type: event.event_type.clone(),
+            timestamp: event.timestamp,
+            fields,
+        }
+    }
+}
+
+/// Infers the payload shape of each event type from the stored events, and reports drifts.
+///
+/// The first event of a type sets the initial shape. Fields missing from later events aren't
+/// reported, as payloads often have optional fields.
+#[derive(Default)]
+pub struct SchemaTracker {
+    reports: Mutex<AHashMap<String, DriftReport>>,
+}
+
+impl SchemaTracker {
+    /// Records the payload shape of a stored event.
+    pub fn record(&self, event_id: EventId, shape: PayloadShape) {
+        let event_type = shape.event_type.as_str();
+        let mut reports = self.reports.lock().unwrap();
+        let first = !reports.contains_key(event_type);
+        let report = reports.entry(shape.event_type.clone()).or_default();
+        for (field, field_type) in shape.fields {
+            let types = report.fields.entry(field.clone()).or_default();
+            if types.contains(&field_type) {
+                continue;
+            }
+            let drift = if types.is_empty() {
+                Drift::FieldAdded { field, field_type }
+            } else {
+                Drift::TypeChanged {
+                    field,
+                    from: types.clone(),
+                    to: field_type,
+                }
+            };
+            types.insert(field_type);
+            if first {
+                continue;
+            }
+            let message = drift.to_string();
+            warn!("Schema drift in '{event_type}': {message}");
+            let kind = match drift {
+                Drift::FieldAdded { .. } => "field_added",
+                Drift::TypeChanged { .. } => "type_changed",
+            };
+            metrics::increment(
+                "schema_drift_total",
+                &[("event_type", event_type), ("kind", kind)],
+            );
+            if report.drift.len() >= MAX_DRIFT_ENTRIES {
+                report.drift.pop_front();
+            }
+            report.drift.push_back(DriftEntry {
+                event_id,
+                timestamp: shape.timestamp,
+                drift,
+                message,
+            });
+        }
+    }
+
+    /// Returns the drift report of an event type, if events of the type were stored.
+    pub fn report(&self, event_type: &str) -> Option<DriftReport> {
+        self.reports.lock().unwrap().get(event_type).cloned()
+    }
+}
+
+fn collect_fields(
+    prefix: &str,
+    object: &serde_json::Map<String, serde_json::Value>,
+    fields: &mut Vec<(String, FieldType)>,
+) {
+    for (key, value) in object {
+        let path = format!("{prefix}{key}");
+        if let serde_json::Value::Object(nested) = value {
+            collect_fields(&format!("{path}."), nested, fields);
+        }
+        fields.push((path, FieldType::of(value)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_drift() {
+        let tracker = SchemaTracker::default();
+        let record = |event_id, payload: serde_json::Value| {
+            let event = Event {
+                event_type: "order_paid".to_string(),
+                payload,
+                ..Default::default()
+            };
+            tracker.record(event_id, PayloadShape::of(&event));
+        };
+        record(1, serde_json::json!({"amount": 10, "customer": {"id": 1}}));
+        record(2, serde_json::json!({"amount": 10}));
+        record(
+            3,
+            serde_json::json!({"amount": "10", "customer": {"country": "HU"}}),
+        );
+
+        let report = tracker.report("order_paid").unwrap();
+        assert_eq!(
+            report.fields["amount"],
+            BTreeSet::from([FieldType::Number, FieldType::String])
+        );
+        let drift: Vec<_> = report
+            .drift
+            .iter()
+            .map(|entry| (entry.event_id, entry.message.as_str()))
+            .collect();
+        assert_eq!(
+            drift,
+            [
+                (3, "Type of field 'amount' changed from number to string"),
+                (3, "New field 'customer.country' appeared with type string"),
+            ]
+        );
+        assert!(tracker.report("signup").is_none());
+    }
+}
@@ -0,0 +1,207 @@
+use ahash::AHashMap;
+use jsonschema::Validator;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+
+use crate::event::Event;
+
+/// Field errors reported per payload, so a producer sending garbage doesn't get a huge
+/// response.
+const MAX_FIELD_ERRORS: usize = 20;
+
+/// A JSON Schema the payloads of an event type must match.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct PayloadSchema {
+    pub event_type: String,
+
+    /// The schema, of any draft from 4 to 2020-12, as declared by its `$schema` keyword.
+    /// Defaults to 2020-12.
+    pub schema: Value,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid schema for '{event_type}': {message}")]
+pub struct SchemaError {
+    event_type: String,
+    message: String,
+}
+
+/// A field of a payload that doesn't match the schema of its event type.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct FieldError {
+    /// JSON Pointer to the field in the payload, empty for the payload itself.
+    pub path: String,
+
+    pub message: String,
+}
+
+struct CompiledSchema {
+    definition: PayloadSchema,
+    validator: Validator,
+}
+
+/// Schemas compiled to replace the ones of the registry.
+pub struct CompiledSchemas(AHashMap<String, Arc<CompiledSchema>>);
+
+/// The schemas of the event types, validating the payloads of their events at ingest.
+#[derive(Default)]
+pub struct SchemaRegistry {
+    schemas: RwLock<AHashMap<String, Arc<CompiledSchema>>>,
+}
+
+impl SchemaRegistry {
+    pub fn new(schemas: Vec<PayloadSchema>) -> Result<Self, SchemaError> {
+        let registry = Self::default();
+        registry.set_schemas(Self::compile(schemas)?);
+        Ok(registry)
+    }
+
+    /// Compiles schemas, so they can replace the current ones once everything else changing
+    /// with them is valid too.
+    pub fn compile(schemas: Vec<PayloadSchema>) -> Result<CompiledSchemas, SchemaError> {
+        let compiled = schemas
+            .into_iter()
+            .map(|schema| {
+                let compiled = compile(schema)?;
+                Ok((compiled.definition.event_type.clone(), Arc::new(compiled)))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(CompiledSchemas(compiled))
+    }
+
+    /// Replaces all schemas.
+    pub fn set_schemas(&self, schemas: CompiledSchemas) {
+        *self.schemas.write().unwrap() = schemas.0;
+    }
+
+    /// Returns the schemas, sorted by event type.
+    pub fn schemas(&self) -> Vec<PayloadSchema> {
+        let schemas = self.schemas.read().unwrap();
+        let mut definitions: Vec<_> = schemas
+            .values()
+            .map(|compiled| compiled.definition.clone())
+            .collect();
+        definitions.sort_by(|a, b| a.event_type.cmp(&b.event_type));
+        definitions
+    }
+
+    pub fn get(&self, event_type: &str) -> Option<PayloadSchema> {
+        let schemas = self.schemas.read().unwrap();
+        schemas
+            .get(event_type)
+            .map(|compiled| compiled.definition.clone())
+    }
+
+    /// Sets the schema of an event type, replacing its previous one. Returns whether it had
+    /// one.
+    pub fn set(&self, schema: PayloadSchema) -> Result<bool, SchemaError> {
+        let compiled = compile(schema)?;
+        let mut schemas = self.schemas.write().unwrap();
+        let event_type = compiled.definition.event_type.clone();
+        Ok(schemas.insert(event_type, Arc::new(compiled)).is_some())
+    }
+
+    /// Removes the schema of an event type. Returns whether it had one.
+    pub fn remove(&self, event_type: &str) -> bool {
+        self.schemas.write().unwrap().remove(event_type).is_some()
+    }
+
+    /// Checks the payload of an event against the schema of its type. Events of types without
+    /// a schema are always valid.
+    pub fn validate(&self, event: &Event) -> Result<(), Vec<FieldError>> {
+        let Some(compiled) = self.schemas.read().unwrap().get(&event.event_type).cloned() else {
+            return Ok(());
+        };
+        let errors: Vec<_> = compiled
+            .validator
+            .iter_errors(&event.payload)
+            .take(MAX_FIELD_ERRORS)
+            .map(|error| FieldError {
+                path: error.instance_path.to_string(),
+                message: error.to_string(),
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn compile(schema: PayloadSchema) -> Result<CompiledSchema, SchemaError> {
+    let validator = jsonschema::validator_for(&schema.schema).map_err(|error| SchemaError {
+        event_type: schema.event_type.clone(),
+        message: error.to_string(),
+    })?;
+    Ok(CompiledSchema {
+        definition: schema,
+        validator,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn order(payload: Value) -> Event {
+        Event {
+            event_type: "order_paid".to_string(),
+            timestamp: 1,
+            payload,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_schema_registry() {
+        let registry = SchemaRegistry::default();
+        let schema = json!({
+            "type": "object",
+            "required": ["order_id", "amount"],
+            "properties": {
+                "order_id": {"type": "string"},
+                "amount": {"type": "number", "minimum": 0},
+            },
+        });
+        let replaced = registry.set(PayloadSchema {
+            event_type: "order_paid".to_string(),
+            schema,
+        });
+        assert!(!replaced.unwrap());
+
+        assert_eq!(
+            registry.validate(&order(json!({"order_id": "A1", "amount": 5}))),
+            Ok(())
+        );
+        let errors = registry
+            .validate(&order(json!({"order_id": 7, "amount": -1})))
+            .unwrap_err();
+        let mut paths: Vec<_> = errors.iter().map(|error| error.path.as_str()).collect();
+        paths.sort();
+        assert_eq!(paths, ["/amount", "/order_id"]);
+        let errors = registry.validate(&order(json!({}))).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].path, "");
+
+        // Other event types aren't validated.
+        let other = Event {
+            event_type: "page_view".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(registry.validate(&other), Ok(()));
+
+        let invalid = registry.set(PayloadSchema {
+            event_type: "page_view".to_string(),
+            schema: json!({"type": "no such type"}),
+        });
+        assert!(invalid.is_err());
+        assert!(registry.get("page_view").is_none());
+
+        assert!(registry.remove("order_paid"));
+        assert_eq!(registry.validate(&order(json!({}))), Ok(()));
+        assert!(registry.schemas().is_empty());
+    }
+}
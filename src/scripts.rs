@@ -0,0 +1,267 @@
+use anyhow::Result;
+use rhai::{AST, Array, Dynamic, Engine, Scope};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use crate::{event::Event, event_policy::glob_matches, metrics};
+
+/// Operations any script run may execute, regardless of its time limit.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+thread_local! {
+    /// When the script running on this thread has to stop. Scripts run synchronously, so a
+    /// thread runs at most one at a time.
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// A Rhai script run for each stored event of a type, to raise alerts or derive new events.
+///
+/// The script sees the stored event as the `event` constant, and can push to two arrays:
+///
+/// - `alerts`: messages logged as warnings and counted in the `script_alerts_total` metric.
+/// - `derived`: maps with an `event_type` and a `payload`, stored as new events with the
+///   timestamp and correlation id of the source event.
+///
+/// ```rhai
+/// if event.payload.amount > 10000 {
+///     alerts.push(`Large order ${event.payload.order_id}`);
+///     derived.push(#{ event_type: "large_order", payload: event.payload });
+/// }
+/// ```
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ScriptRule {
+    pub name: String,
+
+    /// Event type pattern the script runs for, exact or glob. Runs for all events if not set.
+    #[serde(default)]
+    pub event_type: Option<String>,
+
+    pub script: String,
+
+    /// Runs taking longer are aborted.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_timeout_ms() -> u64 {
+    10
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid script '{rule}': {message}")]
+pub struct ScriptError {
+    rule: String,
+    message: String,
+}
+
+/// An event pushed to `derived` by a script.
+#[derive(Deserialize)]
+struct DerivedEvent {
+    event_type: String,
+    #[serde(default)]
+    payload: serde_json::Value,
+}
+
+struct CompiledRule {
+    rule: ScriptRule,
+    ast: AST,
+}
+
+/// Compiles and runs the script rules.
+pub struct Scripts {
+    engine: Engine,
+    rules: RwLock<Vec<Arc<CompiledRule>>>,
+}
+
+impl Scripts {
+    pub fn new(rules: Vec<ScriptRule>) -> Result<Self, ScriptError> {
+        let scripts = Self {
+            engine: make_engine(),
+            rules: RwLock::new(vec![]),
+        };
+        scripts.set_rules(rules)?;
+        Ok(scripts)
+    }
+
+    pub fn rules(&self) -> Vec<ScriptRule> {
+        let rules = self.rules.read().unwrap();
+        rules.iter().map(|compiled| compiled.rule.clone()).collect()
+    }
+
+    /// Replaces the rules. Nothing changes if any script fails to compile.
+    pub fn set_rules(&self, rules: Vec<ScriptRule>) -> Result<(), ScriptError> {
+        let compiled = rules
+            .into_iter()
+            .map(|rule| {
+                let ast = self
+                    .engine
+                    .compile(&rule.script)
+                    .map_err(|error| ScriptError {
+                        rule: rule.name.clone(),
+                        message: error.to_string(),
+                    })?;
+                Ok(Arc::new(CompiledRule { rule, ast }))
+            })
+            .collect::<Result<_, _>>()?;
+        *self.rules.write().unwrap() = compiled;
+        Ok(())
+    }
+
+    /// Returns whether any script would run for the event.
+    pub fn matches(&self, event: &Event) -> bool {
+        let rules = self.rules.read().unwrap();
+        rules.iter().any(|compiled| compiled.matches(event))
+    }
+
+    /// Runs the matching scripts for a stored event and returns the events they derived.
+    ///
+    /// A failing script is logged and counted in the `script_runs_total` metric, and doesn't
+    /// affect the other scripts.
+    pub fn run(&self, event: &Event) -> Vec<Event> {
+        let rules = self.rules.read().unwrap().clone();
+        let mut derived = vec![];
+        for compiled in rules.iter().filter(|compiled| compiled.matches(event)) {
+            let name = compiled.rule.name.as_str();
+            match self.run_rule(compiled, event) {
+                Ok(events) => {
+                    metrics::increment("script_runs_total", &[("rule", name), ("outcome", "ok")]);
+                    derived.extend(events);
+                }
+                Err(error) => {
+                    warn!("Script '{name}' failed: {error:#}");
+                    metrics::increment(
+                        "script_runs_total",
+                        &[("rule", name), ("outcome", "failed")],
+                    );
+                }
+            }
+        }
+        derived
+    }
+
+    fn run_rule(&self, compiled: &CompiledRule, event: &Event) -> Result<Vec<Event>> {
+        let mut scope = Scope::new();
+        scope.push_constant("event", rhai::serde::to_dynamic(event)?);
+        scope.push("alerts", Array::new());
+        scope.push("derived", Array::new());
+
+        let timeout = Duration::from_millis(compiled.rule.timeout_ms);
+        DEADLINE.set(Some(Instant::now() + timeout));
+        let result = self.engine.run_ast_with_scope(&mut scope, &compiled.ast);
+        DEADLINE.set(None);
+        result?;
+
+        let name = compiled.rule.name.as_str();
+        for alert in scope.get_value::<Array>("alerts").unwrap_or_default() {
+            warn!("Alert from script '{name}': {alert}");
+            metrics::increment("script_alerts_total", &[("rule", name)]);
+        }
+        scope
+            .get_value::<Array>("derived")
+            .unwrap_or_default()
+            .iter()
+            .map(|value| {
+                let derived: DerivedEvent = rhai::serde::from_dynamic(value)?;
+                Ok(Event {
+                    event_type: derived.event_type,
+                    timestamp: event.timestamp,
+                    payload: derived.payload,
+                    correlation_id: event.correlation_id.clone(),
+                    count: None,
+                    dedup_id: None,
+                })
+            })
+            .collect()
+    }
+}
+
+impl CompiledRule {
+    fn matches(&self, event: &Event) -> bool {
+        self.rule
+            .event_type
+            .as_ref()
+            .is_none_or(|pattern| glob_matches(pattern, &event.event_type))
+    }
+}
+
+/// Creates a sandboxed engine: scripts can't access the file system or the network, and are
+/// limited in size, depth and running time.
+fn make_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(64 * 1024)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        .disable_symbol("eval")
+        .on_print(|message| info!("Script: {message}"))
+        .on_debug(|message, _, _| info!("Script: {message}"))
+        .on_progress(|operations| {
+            if operations % 256 != 0 {
+                return None;
+            }
+            let deadline = DEADLINE.get()?;
+            (Instant::now() > deadline).then(|| Dynamic::from("Time limit exceeded"))
+        });
+    engine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripts() {
+        let rule = |name: &str, script: &str| ScriptRule {
+            name: name.to_string(),
+            event_type: Some("order_*".to_string()),
+            script: script.to_string(),
+            timeout_ms: 10,
+        };
+        let scripts = Scripts::new(vec![
+            rule(
+                "large_orders",
+                r#"
+                if event.payload.amount > 100 {
+                    alerts.push("Large order");
+                    let payload = #{ amount: event.payload.amount };
+                    derived.push(#{ event_type: "large_order", payload: payload });
+                }
+                "#,
+            ),
+            rule("forever", "loop {}"),
+        ])
+        .unwrap();
+        let order = |amount: u64| Event {
+            event_type: "order_paid".to_string(),
+            timestamp: 42,
+            payload: serde_json::json!({"amount": amount}),
+            ..Default::default()
+        };
+
+        assert!(scripts.run(&order(5)).is_empty());
+        // The runaway script is stopped and doesn't affect the other one.
+        let derived = scripts.run(&order(500));
+        assert_eq!(
+            derived,
+            vec![Event {
+                event_type: "large_order".to_string(),
+                timestamp: 42,
+                payload: serde_json::json!({"amount": 500}),
+                ..Default::default()
+            }]
+        );
+        assert!(!scripts.matches(&Event::default()));
+
+        // Invalid scripts are rejected without replacing the current ones.
+        assert!(scripts.set_rules(vec![rule("broken", "if {")]).is_err());
+        assert_eq!(scripts.rules().len(), 2);
+    }
+}
@@ -0,0 +1,275 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::{
+    collections::BTreeMap,
+    env::VarError,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use crate::metrics;
+
+/// Environment variables that may reference a secret instead of holding its value.
+pub const SECRET_VARS: &[&str] = &[
+    "STORAGE",
+    "RECEIPT_KEY",
+    "QUERY_TOKEN_KEY",
+    "SNAPSHOT_ENCRYPTION_KEY",
+    "OIDC_CLIENT_SECRET",
+    "OIDC_SESSION_KEY",
+    "JWT_SECRET",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum SecretsError {
+    #[error("Secrets request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Failed to read secret file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Secret '{0}' not found")]
+    NotFound(String),
+
+    #[error("No secrets provider for '{0}'")]
+    UnknownProvider(String),
+}
+
+/// A store secrets are fetched from, like Vault. Providers are selected by the scheme of the
+/// reference, e.g. `vault:` in `vault:secret/tracker#receipt_key`.
+#[async_trait]
+pub trait SecretsProvider: Send + Sync {
+    /// Fetches the current value of a secret, given its reference without the scheme.
+    async fn fetch(&self, reference: &str) -> Result<String, SecretsError>;
+}
+
+/// Reads secrets from files, like the ones Vault Agent or a cloud KMS CSI driver mounts into
+/// a container. References are paths, and trailing whitespace is trimmed.
+pub struct FileSecrets;
+
+#[async_trait]
+impl SecretsProvider for FileSecrets {
+    async fn fetch(&self, reference: &str) -> Result<String, SecretsError> {
+        let value = tokio::fs::read_to_string(PathBuf::from(reference)).await?;
+        Ok(value.trim_end().to_string())
+    }
+}
+
+/// Reads secrets from the KV version 2 secrets engine of HashiCorp Vault. References are
+/// `<mount>/<path>#<field>`.
+pub struct VaultSecrets {
+    client: reqwest::Client,
+    address: String,
+    token: String,
+    namespace: Option<String>,
+}
+
+#[async_trait]
+impl SecretsProvider for VaultSecrets {
+    async fn fetch(&self, reference: &str) -> Result<String, SecretsError> {
+        let not_found = || SecretsError::NotFound(format!("vault:{reference}"));
+        let (path, field) = reference.split_once('#').ok_or_else(not_found)?;
+        let (mount, path) = path.split_once('/').ok_or_else(not_found)?;
+        let mut request = self
+            .client
+            .get(format!("{}/v1/{mount}/data/{path}", self.address))
+            .header("X-Vault-Token", &self.token);
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        let response: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+        response["data"]["data"][field]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(not_found)
+    }
+}
+
+/// Values of the environment variables referencing secrets, fetched from their providers and
+/// refreshed periodically, so rotated secrets are picked up.
+#[derive(Default)]
+pub struct Secrets {
+    providers: BTreeMap<String, Arc<dyn SecretsProvider>>,
+
+    /// Secret references of the environment variables, like `vault:secret/tracker#key`.
+    references: BTreeMap<String, String>,
+
+    values: Mutex<BTreeMap<String, String>>,
+
+    /// How often secrets are fetched again.
+    pub refresh_interval: Duration,
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secrets")
+            .field("references", &self.references)
+            .field("refresh_interval", &self.refresh_interval)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Secrets {
+    /// Sets up the providers from environment variables, and fetches the secrets referenced
+    /// by the secret variables. Vault is used if `VAULT_ADDR` is set.
+    pub async fn from_env() -> Result<Self> {
+        let mut providers: BTreeMap<String, Arc<dyn SecretsProvider>> = BTreeMap::new();
+        providers.insert("file".to_string(), Arc::new(FileSecrets));
+        if let Ok(address) = std::env::var("VAULT_ADDR") {
+            let token = match std::env::var("VAULT_TOKEN") {
+                Ok(token) => token,
+                Err(_) => {
+                    let path = std::env::var("VAULT_TOKEN_FILE")
+                        .context("VAULT_TOKEN or VAULT_TOKEN_FILE must be set for Vault")?;
+                    std::fs::read_to_string(&path)
+                        .with_context(|| format!("Failed to read {path}"))?
+                        .trim()
+                        .to_string()
+                }
+            };
+            let vault = VaultSecrets {
+                client: reqwest::Client::new(),
+                address: address.trim_end_matches('/').to_string(),
+                token,
+                namespace: std::env::var("VAULT_NAMESPACE").ok(),
+            };
+            providers.insert("vault".to_string(), Arc::new(vault));
+        }
+        let references = SECRET_VARS
+            .iter()
+            .filter_map(|name| Some((name.to_string(), std::env::var(name).ok()?)))
+            .filter(|(_, value)| {
+                value
+                    .split_once(':')
+                    .is_some_and(|(scheme, _)| providers.contains_key(scheme))
+            })
+            .collect();
+        let secrets = Self {
+            providers,
+            references,
+            values: Mutex::new(BTreeMap::new()),
+            refresh_interval: match std::env::var("SECRETS_REFRESH_INTERVAL") {
+                Ok(seconds) => Duration::from_secs(
+                    seconds
+                        .parse()
+                        .context("Invalid SECRETS_REFRESH_INTERVAL")?,
+                ),
+                Err(_) => Duration::from_secs(300),
+            },
+        };
+        secrets.refresh().await?;
+        Ok(secrets)
+    }
+
+    /// Whether any environment variable references a secret.
+    pub fn is_empty(&self) -> bool {
+        self.references.is_empty()
+    }
+
+    /// Returns the value of an environment variable, or of the secret it references.
+    pub fn var(&self, name: &str) -> Result<String, VarError> {
+        match self.values.lock().unwrap().get(name) {
+            Some(value) => Ok(value.clone()),
+            None => std::env::var(name),
+        }
+    }
+
+    /// Fetches the referenced secrets, returning the variables whose value changed.
+    pub async fn refresh(&self) -> Result<Vec<String>> {
+        let mut changed = Vec::new();
+        for (name, reference) in &self.references {
+            let (scheme, reference) = reference.split_once(':').expect("Checked when loading");
+            let provider = self
+                .providers
+                .get(scheme)
+                .ok_or_else(|| SecretsError::UnknownProvider(scheme.to_string()))?;
+            let value = provider
+                .fetch(reference)
+                .await
+                .with_context(|| format!("Failed to fetch the secret of {name}"))?;
+            let previous = self
+                .values
+                .lock()
+                .unwrap()
+                .insert(name.clone(), value.clone());
+            if previous.is_some_and(|previous| previous != value) {
+                changed.push(name.clone());
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Fetches the secrets periodically, calling `on_change` with the name and new value of
+    /// each variable whose secret was rotated.
+    pub fn spawn_refresh(self: Arc<Self>, on_change: impl Fn(&str, &str) + Send + Sync + 'static) {
+        if self.is_empty() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.refresh_interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match self.refresh().await {
+                    Ok(changed) => {
+                        for name in changed {
+                            info!("Secret of {name} rotated");
+                            metrics::increment(
+                                "secret_rotations_total",
+                                &[("name", name.as_str())],
+                            );
+                            if let Ok(value) = self.var(&name) {
+                                on_change(&name, &value);
+                            }
+                        }
+                    }
+                    Err(error) => {
+                        warn!("Failed to refresh secrets: {error:#}");
+                        metrics::increment("secret_refresh_failures_total", &[]);
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the value of the reference, changing on every fetch.
+    struct Rotating(std::sync::atomic::AtomicU64);
+
+    #[async_trait]
+    impl SecretsProvider for Rotating {
+        async fn fetch(&self, reference: &str) -> Result<String, SecretsError> {
+            let version = self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(format!("{reference}-{version}"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_secrets() {
+        let providers: BTreeMap<String, Arc<dyn SecretsProvider>> = BTreeMap::from([(
+            "test".to_string(),
+            Arc::new(Rotating(Default::default())) as Arc<dyn SecretsProvider>,
+        )]);
+        let references = BTreeMap::from([(
+            "SECRETS_TEST_KEY".to_string(),
+            "test:receipt_key".to_string(),
+        )]);
+        let secrets = Secrets {
+            providers,
+            references,
+            ..Default::default()
+        };
+        assert_eq!(secrets.refresh().await.unwrap(), Vec::<String>::new());
+        assert_eq!(secrets.var("SECRETS_TEST_KEY").unwrap(), "receipt_key-0");
+        assert_eq!(secrets.refresh().await.unwrap(), ["SECRETS_TEST_KEY"]);
+        assert_eq!(secrets.var("SECRETS_TEST_KEY").unwrap(), "receipt_key-1");
+        assert!(secrets.var("SECRETS_TEST_MISSING").is_err());
+    }
+}
@@ -0,0 +1,272 @@
+use anyhow::Context;
+use serde_json::{Value, json};
+use std::{
+    backtrace::Backtrace,
+    fmt::Display,
+    str::FromStr,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::metrics;
+
+/// Process-wide client, set up when Sentry is configured.
+static SENTRY: OnceLock<Sentry> = OnceLock::new();
+
+/// Sends errors, panics and background task failures to Sentry.
+#[derive(Debug, Clone)]
+pub struct SentryConfig {
+    pub dsn: SentryDsn,
+
+    /// Environment the events are tagged with, e.g. `production`.
+    pub environment: Option<String>,
+}
+
+/// Address and key of a Sentry project, parsed from a DSN like
+/// `https://<key>@o0.ingest.sentry.io/<project id>`.
+#[derive(Clone)]
+pub struct SentryDsn {
+    store_url: reqwest::Url,
+    key: String,
+}
+
+impl std::fmt::Debug for SentryDsn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SentryDsn").field(&self.store_url).finish()
+    }
+}
+
+impl FromStr for SentryDsn {
+    type Err = String;
+
+    fn from_str(dsn: &str) -> Result<Self, Self::Err> {
+        let mut url =
+            reqwest::Url::parse(dsn).map_err(|error| format!("Invalid Sentry DSN: {error}"))?;
+        let key = url.username().to_string();
+        let path = url.path().trim_matches('/').to_string();
+        let (prefix, project) = path.rsplit_once('/').unwrap_or(("", &path));
+        if key.is_empty() || project.is_empty() || url.host().is_none() {
+            return Err("Invalid Sentry DSN, expected https://<key>@<host>/<project id>".into());
+        }
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+        url.set_path(&format!("{prefix}/api/{project}/store/"));
+        Ok(Self {
+            store_url: url,
+            key,
+        })
+    }
+}
+
+struct Sentry {
+    config: SentryConfig,
+    client: reqwest::Client,
+
+    /// Events waiting to be sent by the background task.
+    queue: mpsc::UnboundedSender<Value>,
+}
+
+/// Sets up the process-wide client, and starts sending the captured events in the background.
+pub fn init(config: SentryConfig) {
+    let (queue, mut events) = mpsc::unbounded_channel();
+    let sentry = Sentry {
+        config,
+        client: reqwest::Client::new(),
+        queue,
+    };
+    if SENTRY.set(sentry).is_err() {
+        warn!("Sentry is already set up");
+        return;
+    }
+    info!("Sending errors to Sentry");
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if let Err(error) = send(event).await {
+                warn!("Failed to send an event to Sentry: {error:#}");
+            }
+        }
+    });
+}
+
+/// Whether Sentry is set up.
+pub fn is_enabled() -> bool {
+    SENTRY.get().is_some()
+}
+
+/// Queues an event to be sent in the background. Does nothing if Sentry isn't set up.
+pub fn capture(event: Value) {
+    if let Some(sentry) = SENTRY.get() {
+        let _ = sentry.queue.send(event);
+    }
+}
+
+/// Reports a failed run of a background task, like a backup or a rollup.
+pub fn capture_task_failure(task: &str, error: &dyn Display) {
+    if !is_enabled() {
+        return;
+    }
+    capture(json!({
+        "level": "error",
+        "logger": task,
+        "tags": {"task": task},
+        "exception": {
+            "values": [{
+                "type": "TaskFailed",
+                "value": error.to_string(),
+                "mechanism": {"type": "background_task", "handled": true},
+            }],
+        },
+    }));
+}
+
+/// Reports every panic, then runs the previous panic hook. Only used without crash reports,
+/// as those are sent with more context.
+pub fn capture_panics() {
+    let previous_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match info.payload().downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "Box<dyn Any>".to_string(),
+            },
+        };
+        capture(json!({
+            "level": "fatal",
+            "exception": {
+                "values": [{
+                    "type": "panic",
+                    "value": message,
+                    "mechanism": {"type": "panic", "handled": false},
+                }],
+            },
+            "extra": {
+                "location": info
+                    .location()
+                    .map(|location| format!("{}:{}", location.file(), location.line())),
+                "thread": std::thread::current().name(),
+                "backtrace": Backtrace::force_capture().to_string(),
+            },
+        }));
+        previous_hook(info);
+    }));
+}
+
+/// Sends an event right away, adding the fields every event has.
+pub async fn send(mut event: Value) -> anyhow::Result<()> {
+    let sentry = SENTRY.get().context("Sentry isn't set up")?;
+    complete_event(&mut event, &sentry.config);
+    let result = async {
+        sentry
+            .client
+            .post(sentry.config.dsn.store_url.clone())
+            .header(
+                "X-Sentry-Auth",
+                format!(
+                    "Sentry sentry_version=7, sentry_key={}, sentry_client=cside-event-tracker/{}",
+                    sentry.config.dsn.key,
+                    env!("CARGO_PKG_VERSION")
+                ),
+            )
+            .json(&event)
+            .send()
+            .await?
+            .error_for_status()?;
+        anyhow::Ok(())
+    }
+    .await;
+    let outcome = if result.is_ok() { "sent" } else { "failed" };
+    metrics::increment("sentry_events_total", &[("outcome", outcome)]);
+    result
+}
+
+/// Adds the id, time, release and environment to an event, unless it has them.
+fn complete_event(event: &mut Value, config: &SentryConfig) {
+    let Some(fields) = event.as_object_mut() else {
+        return;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or(0.0);
+    let defaults = [
+        (
+            "event_id",
+            json!(format!("{:032x}", rand::random::<u128>())),
+        ),
+        ("timestamp", json!(now)),
+        ("platform", json!("other")),
+        (
+            "release",
+            json!(format!("cside-event-tracker@{}", env!("CARGO_PKG_VERSION"))),
+        ),
+        ("environment", json!(config.environment)),
+    ];
+    for (name, value) in defaults {
+        if !value.is_null() {
+            fields.entry(name).or_insert(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sentry_dsn() {
+        let dsn: SentryDsn = "https://abc123@o1.ingest.sentry.io/42".parse().unwrap();
+        assert_eq!(dsn.key, "abc123");
+        assert_eq!(
+            dsn.store_url.as_str(),
+            "https://o1.ingest.sentry.io/api/42/store/"
+        );
+        let dsn: SentryDsn = "http://key@sentry.internal:9000/sentry/7".parse().unwrap();
+        assert_eq!(
+            dsn.store_url.as_str(),
+            "http://sentry.internal:9000/sentry/api/7/store/"
+        );
+        assert!(
+            "https://o1.ingest.sentry.io/42"
+                .parse::<SentryDsn>()
+                .is_err()
+        );
+        assert!(
+            "https://key@o1.ingest.sentry.io/"
+                .parse::<SentryDsn>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_complete_event() {
+        let config = SentryConfig {
+            dsn: "https://key@o1.ingest.sentry.io/42".parse().unwrap(),
+            environment: Some("staging".to_string()),
+        };
+        let mut event = json!({"level": "error", "timestamp": 1.5});
+        complete_event(&mut event, &config);
+        assert_eq!(event["level"], "error");
+        assert_eq!(event["timestamp"], 1.5);
+        assert_eq!(event["environment"], "staging");
+        assert_eq!(event["event_id"].as_str().unwrap().len(), 32);
+        assert!(
+            event["release"]
+                .as_str()
+                .unwrap()
+                .starts_with("cside-event-tracker@")
+        );
+
+        let mut event = json!({});
+        complete_event(
+            &mut event,
+            &SentryConfig {
+                environment: None,
+                ..config
+            },
+        );
+        assert!(event.get("environment").is_none());
+    }
+}
@@ -0,0 +1,319 @@
+use anyhow::{Result, bail};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use serde::Deserialize;
+use std::{num::NonZeroUsize, sync::Arc};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    client_fingerprints::ClientFingerprint,
+    event_snapshots::{ChainLink, EventSnapshots},
+    ingest_lag::{LagReport, Watermark},
+    merkle_log::{InclusionProof, MerkleRoot},
+    plugins::PluginInfo,
+    producers::ProducerStatus,
+    resources::ResourceBundle,
+    schema_registry::SchemaRegistry,
+    server::{AppState, app_error::AppError},
+    storage::{IntegrityReport, PoolStats},
+};
+
+#[derive(Deserialize, Debug)]
+pub struct ProducerParams {
+    /// Only list the producers not seen for longer than the silence timeout.
+    #[serde(default)]
+    silent: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IntegrityParams {
+    /// Rebuild the indexes if problems are found.
+    #[serde(default)]
+    repair: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EventSnapshotParams {
+    /// Take a full snapshot instead of an incremental one.
+    #[serde(default)]
+    full: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PoolLimits {
+    /// Most connections open at a time.
+    max_size: NonZeroUsize,
+}
+
+/// Exports all runtime configuration as a single bundle.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn export_resources(State(state): State<Arc<AppState>>) -> Json<ResourceBundle> {
+    let state_machines = state.state_machines.read().await;
+    Json(ResourceBundle {
+        state_machines: state_machines.definitions().to_vec(),
+        coalescing: state.coalescer.rules(),
+        scripts: state.scripts.rules(),
+        event_types: state.event_types.docs(),
+        export_schedules: state.export_schedules.schedules(),
+        schemas: state.schema_registry.schemas(),
+    })
+}
+
+/// Replaces all runtime configuration with the given bundle. Nothing changes if a script or a
+/// schema fails to compile.
+#[axum::debug_handler]
+#[instrument(skip(state, bundle))]
+pub async fn import_resources(
+    State(state): State<Arc<AppState>>,
+    Json(bundle): Json<ResourceBundle>,
+) -> Result<(), AppError> {
+    info!(
+        "Importing resources: {} state machines, {} coalescing rules, {} scripts, {} event types, \
+         {} export schedules, {} schemas",
+        bundle.state_machines.len(),
+        bundle.coalescing.len(),
+        bundle.scripts.len(),
+        bundle.event_types.len(),
+        bundle.export_schedules.len(),
+        bundle.schemas.len()
+    );
+    let schemas = SchemaRegistry::compile(bundle.schemas)?;
+    state.scripts.set_rules(bundle.scripts)?;
+    state.schema_registry.set_schemas(schemas);
+    state
+        .state_machines
+        .write()
+        .await
+        .set_definitions(bundle.state_machines);
+    state.coalescer.set_rules(bundle.coalescing);
+    state.event_types.set_docs(bundle.event_types);
+    state
+        .export_schedules
+        .set_schedules(bundle.export_schedules);
+    Ok(())
+}
+
+/// Reloads the resources file and reconciles it with the current configuration.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn reload_resources_file(State(state): State<Arc<AppState>>) -> Result<(), AppError> {
+    reload_resources(&state)
+        .await
+        .map_err(|error| AppError::ResourcesReloadFailed(format!("{error:#}")))
+}
+
+/// Reloads the resources file. Resources defined in the file override the ones created at
+/// runtime with the same name.
+pub async fn reload_resources(state: &AppState) -> Result<()> {
+    let Some(path) = &state.resources_file else {
+        bail!("No resources file configured");
+    };
+    let file = ResourceBundle::load(path)?;
+    info!("Reloading resources from {}", path.display());
+
+    let mut file_resources = state.file_resources.lock().await;
+    let mut state_machines = state.state_machines.write().await;
+    let current = ResourceBundle {
+        state_machines: state_machines.definitions().to_vec(),
+        coalescing: state.coalescer.rules(),
+        scripts: state.scripts.rules(),
+        event_types: state.event_types.docs(),
+        export_schedules: state.export_schedules.schedules(),
+        schemas: state.schema_registry.schemas(),
+    };
+    let reconciled = current.reconcile(&file_resources, file.clone());
+    let schemas = SchemaRegistry::compile(reconciled.schemas)?;
+    state.scripts.set_rules(reconciled.scripts)?;
+    state.schema_registry.set_schemas(schemas);
+    state_machines.set_definitions(reconciled.state_machines);
+    state.coalescer.set_rules(reconciled.coalescing);
+    state.event_types.set_docs(reconciled.event_types);
+    state
+        .export_schedules
+        .set_schedules(reconciled.export_schedules);
+    *file_resources = file;
+    Ok(())
+}
+
+/// Verifies the integrity of the storage, and optionally repairs it.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn check_integrity(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<IntegrityParams>,
+) -> Json<IntegrityReport> {
+    Json(verify_integrity(&state, params.repair).await)
+}
+
+/// Verifies the integrity of the storage and logs the problems found.
+pub async fn verify_integrity(state: &AppState, repair: bool) -> IntegrityReport {
+    let report = state.store.verify_integrity(repair).await;
+    for problem in &report.problems {
+        warn!("Storage integrity problem: {problem}");
+    }
+    info!(
+        "Checked integrity of {} events: {} problems{}",
+        report.checked_events,
+        report.problems.len(),
+        if report.repaired { ", repaired" } else { "" }
+    );
+    report
+}
+
+/// Returns the ingest lag percentiles of each event type.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_ingest_lag(State(state): State<Arc<AppState>>) -> Json<Vec<LagReport>> {
+    Json(state.ingest_lag.reports())
+}
+
+/// Returns the watermark of each event type: the timestamp up to which its events are
+/// complete, so consumers know when a time window can be processed.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_watermarks(State(state): State<Arc<AppState>>) -> Json<Vec<Watermark>> {
+    Json(state.ingest_lag.watermarks())
+}
+
+/// Returns the producers that identified themselves since startup, and whether they fell
+/// silent.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_producers(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ProducerParams>,
+) -> Json<Vec<ProducerStatus>> {
+    Json(state.producers.producers(params.silent))
+}
+
+/// Returns the learned ingest pattern of each client, and how often it was flagged as
+/// anomalous.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_client_fingerprints(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<ClientFingerprint>>, AppError> {
+    let fingerprints = state
+        .client_fingerprints
+        .as_ref()
+        .ok_or(AppError::AnomalyDetectionNotConfigured)?;
+    Ok(Json(fingerprints.fingerprints()))
+}
+
+/// Returns the root hash of each event type's Merkle tree over the stored events, to publish
+/// so inclusion proofs can be checked against it.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_merkle_roots(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<MerkleRoot>>, AppError> {
+    let roots = state
+        .merkle_log
+        .roots(state.store.as_ref())
+        .await
+        .map_err(AppError::from)?;
+    Ok(Json(roots))
+}
+
+/// Returns a proof that an event is in its event type's Merkle tree, against the current root.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_inclusion_proof(
+    State(state): State<Arc<AppState>>,
+    Path(event_id): Path<String>,
+) -> Result<Json<InclusionProof>, AppError> {
+    let not_found = || AppError::EventNotFound(event_id.clone());
+    let id = event_id.parse().map_err(|_| not_found())?;
+    let proof = state
+        .merkle_log
+        .prove(state.store.as_ref(), id)
+        .await
+        .map_err(AppError::from)?;
+    proof.map(Json).ok_or_else(not_found)
+}
+
+/// Returns the statistics of the storage's connection pool.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_storage_pool(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<PoolStats>, AppError> {
+    let stats = state.store.pool_stats();
+    stats
+        .map(Json)
+        .ok_or(AppError::NoConnectionPool(state.store.name()))
+}
+
+/// Changes the limits of the storage's connection pool, without a restart.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn set_storage_pool_limits(
+    State(state): State<Arc<AppState>>,
+    Json(limits): Json<PoolLimits>,
+) -> Result<Json<PoolStats>, AppError> {
+    let stats = state.store.resize_pool(limits.max_size.get());
+    let stats = stats.ok_or(AppError::NoConnectionPool(state.store.name()))?;
+    info!("Storage connection pool resized to {}", limits.max_size);
+    Ok(Json(stats))
+}
+
+/// Returns the event snapshot chain.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_event_snapshots(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<ChainLink>>, AppError> {
+    Ok(Json(event_snapshots(&state)?.chain().await))
+}
+
+/// Takes a snapshot of the event store.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn take_event_snapshot(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<EventSnapshotParams>,
+) -> Result<Json<ChainLink>, AppError> {
+    let link = event_snapshots(&state)?
+        .take(state.store.as_ref(), params.full)
+        .await?;
+    Ok(Json(link))
+}
+
+/// Collapses the event snapshot chain into a single full snapshot.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn collapse_event_snapshots(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Option<ChainLink>>, AppError> {
+    Ok(Json(event_snapshots(&state)?.collapse().await?))
+}
+
+fn event_snapshots(state: &AppState) -> Result<&EventSnapshots, AppError> {
+    state
+        .event_snapshots
+        .as_deref()
+        .ok_or(AppError::EventSnapshotsNotConfigured)
+}
+
+/// Lists the loaded WebAssembly plugins.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_plugins(State(state): State<Arc<AppState>>) -> Json<Vec<PluginInfo>> {
+    Json(state.plugins.list())
+}
+
+/// Loads the plugins from the plugin directory again.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn reload_plugins(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<PluginInfo>>, AppError> {
+    let plugins = state
+        .plugins
+        .reload()
+        .map_err(|error| AppError::PluginFailed(format!("{error:#}")))?;
+    Ok(Json(plugins))
+}
@@ -0,0 +1,222 @@
+use axum::{
+    Extension, Json,
+    extract::{Query, State},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::instrument;
+
+use crate::{
+    access_policy::MaskedFields,
+    analytics::{
+        AggregateGroup, AggregateQuery, Aggregator, Gap, GroupComparison, compare, find_gaps,
+        type_count_groups,
+    },
+    event::{Event, Timestamp},
+    event_policy::is_test_event_type,
+    server::{AppState, app_error::AppError, kill_switches::Feature},
+    storage::EventFilter,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct AggregateParams {
+    #[serde(rename = "type", alias = "event_type")]
+    event_type: Option<String>,
+    start: Option<Timestamp>,
+    end: Option<Timestamp>,
+
+    /// Field to group by, like `payload.country`.
+    group_by: Option<String>,
+
+    /// Comma separated aggregations.
+    #[serde(default = "default_aggregations")]
+    agg: String,
+
+    /// Numeric field to aggregate, like `payload.amount`.
+    field: Option<String>,
+
+    /// Length of the time buckets groups are split into, in seconds.
+    bucket: Option<Timestamp>,
+
+    /// Start of a previous range of the same length as `start` to `end`, to compare with.
+    compare_start: Option<Timestamp>,
+
+    /// Also aggregate the events in the test namespace. They are if `event_type` is in it.
+    #[serde(default)]
+    include_test: bool,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum AggregateResponse {
+    Groups(Vec<AggregateGroup>),
+    Comparison(Vec<GroupComparison>),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GapParams {
+    #[serde(rename = "type", alias = "event_type")]
+    event_type: String,
+    start: Option<Timestamp>,
+    end: Option<Timestamp>,
+
+    /// Only gaps longer than this many seconds are returned.
+    min_gap: Timestamp,
+}
+
+/// Events read from the storage at a time while aggregating.
+const AGGREGATE_PAGE_SIZE: usize = 1000;
+
+fn default_aggregations() -> String {
+    "count".to_string()
+}
+
+/// Groups the matching events by a field and aggregates each group.
+///
+/// Isn't subject to the result size limit, as events are read page by page and accumulated
+/// one by one. With `compare_start`, the groups are compared with the same buckets of a
+/// previous range. In privacy mode, small groups are left out and counts are noisy. Fields
+/// masked for the role of the API key can't be grouped by or aggregated. Test events are left
+/// out, unless asked for.
+///
+/// Counts grouped by `event_type` are counted by the storage on its indexes, without reading
+/// the events.
+#[axum::debug_handler]
+#[instrument(skip(state, masked))]
+pub async fn aggregate_events(
+    State(state): State<Arc<AppState>>,
+    masked: Option<Extension<Arc<MaskedFields>>>,
+    Query(params): Query<AggregateParams>,
+) -> Result<Json<AggregateResponse>, AppError> {
+    state.kill_switches.check(Feature::Analytics)?;
+    let query = AggregateQuery::parse(
+        params.group_by.as_deref(),
+        &params.agg,
+        params.field.as_deref(),
+        params.bucket,
+    )
+    .map_err(AppError::InvalidQuery)?;
+    if let Some(Extension(masked)) = masked {
+        let fields = [
+            (&query.group_by, &params.group_by),
+            (&query.field, &params.field),
+        ];
+        for (field, name) in fields {
+            if let (Some(field), Some(name)) = (field, name)
+                && masked.reveals(field)
+            {
+                return Err(AppError::MaskedField(name.clone()));
+            }
+        }
+    }
+    if let Some(privacy) = &state.privacy {
+        privacy.check(&query).map_err(AppError::InvalidQuery)?;
+    }
+    let privatize = |groups: Vec<AggregateGroup>| match &state.privacy {
+        Some(privacy) => privacy.apply(groups),
+        None => groups,
+    };
+    let include_test =
+        params.include_test || params.event_type.as_deref().is_some_and(is_test_event_type);
+    if query.counts_by_type() && params.compare_start.is_none() {
+        let counts = state.store.aggregate(params.start, params.end).await?;
+        let counts = counts.into_iter().filter(|(event_type, _)| {
+            params
+                .event_type
+                .as_ref()
+                .is_none_or(|only| only == event_type)
+                && (include_test || !is_test_event_type(event_type))
+        });
+        return Ok(Json(AggregateResponse::Groups(privatize(
+            type_count_groups(counts),
+        ))));
+    }
+    let mut filter = EventFilter {
+        event_type: params.event_type,
+        start: params.start,
+        end: params.end,
+        ..Default::default()
+    };
+    let groups = aggregate_pages(&state, &filter, &query, include_test, |event| event).await?;
+    let groups = privatize(groups);
+    let Some(compare_start) = params.compare_start else {
+        return Ok(Json(AggregateResponse::Groups(groups)));
+    };
+
+    let (Some(start), Some(end)) = (params.start, params.end) else {
+        let message = "Comparing requires a start and an end".to_string();
+        return Err(AppError::InvalidQuery(message));
+    };
+    let Some(compare_end) = compare_start.checked_add(end.saturating_sub(start)) else {
+        let message = "The compared range ends after the last timestamp".to_string();
+        return Err(AppError::InvalidQuery(message));
+    };
+    filter.start = Some(compare_start);
+    filter.end = Some(compare_end);
+    // Shifted into the current range, so the buckets of the two ranges align.
+    let shift = |mut event: Event| {
+        event.timestamp = event.timestamp - compare_start + start;
+        event
+    };
+    let previous = aggregate_pages(&state, &filter, &query, include_test, shift).await?;
+    let previous = privatize(previous);
+    Ok(Json(AggregateResponse::Comparison(compare(
+        groups, previous,
+    ))))
+}
+
+/// Aggregates the events matching the filter's type and time range, read page by page so only
+/// one page is in memory at a time. Events are mapped before they are added, and test events
+/// are left out unless included.
+async fn aggregate_pages(
+    state: &AppState,
+    filter: &EventFilter,
+    query: &AggregateQuery,
+    include_test: bool,
+    map: impl Fn(Event) -> Event,
+) -> Result<Vec<AggregateGroup>, AppError> {
+    let mut aggregator = Aggregator::new(query);
+    let mut cursor = None;
+    loop {
+        let page = state
+            .store
+            .get_events_page(
+                filter.event_type.as_deref(),
+                filter.start,
+                filter.end,
+                cursor,
+                AGGREGATE_PAGE_SIZE,
+            )
+            .await?;
+        for (_, event) in page.events {
+            if include_test || !is_test_event_type(&event.event_type) {
+                aggregator.add(&map(event));
+            }
+        }
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            return Ok(aggregator.finish());
+        }
+    }
+}
+
+/// Finds the time intervals in which an event type produced no events for longer than
+/// `min_gap` seconds, e.g. outages in heartbeat events.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn find_event_gaps(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<GapParams>,
+) -> Result<Json<Vec<Gap>>, AppError> {
+    state.kill_switches.check(Feature::Analytics)?;
+    let filter = EventFilter {
+        event_type: Some(params.event_type),
+        start: params.start,
+        end: params.end,
+        ..Default::default()
+    };
+    let events = state.store.scan(&filter, None).await?;
+    let timestamps = events.iter().map(|(_, event)| event.timestamp).collect();
+    let gaps = find_gaps(timestamps, params.start, params.end, params.min_gap);
+    Ok(Json(gaps))
+}
@@ -0,0 +1,99 @@
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use tracing::Span;
+
+use crate::{
+    access_policy::ApiKeyScope,
+    metrics,
+    server::{AppState, app_error::AppError, query_limits::API_KEY_HEADER},
+};
+
+/// Lets only requests with an API key of the access policy use the routes reading or writing
+/// events, if API keys are required. Queries need the `read` scope, deletions the `delete` scope
+/// and everything else the `ingest` scope.
+///
+/// The role of a known key is recorded as the `tenant` of the request's log span.
+pub async fn require_api_key(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let api_key = request
+        .headers()
+        .get(API_KEY_HEADER)
+        .map(|api_key| api_key.to_str().unwrap_or_default());
+    if let Some(api_key) = api_key.filter(|api_key| state.access_policy.is_known_key(api_key)) {
+        Span::current().record("tenant", state.access_policy.role(Some(api_key)));
+    }
+    if let Some(scope) = event_scope(request.method(), request.uri().path()) {
+        check_api_key(&state, api_key, scope)?;
+    }
+    Ok(next.run(request).await)
+}
+
+/// Returns the scope a request needs, `None` for the requests that don't read or write events:
+/// health and metadata routes, login, CORS preflight requests, and the admin APIs and catalog
+/// changes, which are guarded by their own role. GraphQL checks the scope of each field it
+/// resolves instead, and shared event views are authorized by the signed query token in their
+/// URL.
+pub fn event_scope(method: &Method, path: &str) -> Option<ApiKeyScope> {
+    let public = matches!(
+        path,
+        "/" | "/healthz" | "/readyz" | "/metrics" | "/version" | "/capabilities" | "/graphql"
+    ) || path.starts_with("/auth/")
+        || path.starts_with("/admin/")
+        || path.starts_with("/shared/")
+        || changes_catalog(method, path);
+    if public || method == Method::OPTIONS {
+        return None;
+    }
+    Some(match *method {
+        Method::GET | Method::HEAD => ApiKeyScope::Read,
+        Method::DELETE => ApiKeyScope::Delete,
+        _ if path == "/exports" => ApiKeyScope::Read,
+        _ => ApiKeyScope::Ingest,
+    })
+}
+
+/// Whether the request changes a payload schema or the documentation of an event type, which
+/// apply to every producer, so only admins may.
+fn changes_catalog(method: &Method, path: &str) -> bool {
+    let catalog = path.starts_with("/schemas/")
+        || (path.starts_with("/event-types/") && path.ends_with("/meta"));
+    catalog && !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Checks that the API key is known and its role has the scope, if API keys are required.
+/// Rejections are counted by reason.
+pub fn check_api_key(
+    state: &AppState,
+    api_key: Option<&str>,
+    scope: ApiKeyScope,
+) -> Result<(), AppError> {
+    if !state.require_api_key {
+        return Ok(());
+    }
+    let reject = |reason: &str, error: AppError| {
+        metrics::increment("api_key_rejections_total", &[("reason", reason)]);
+        Err(error)
+    };
+    let Some(api_key) = api_key else {
+        return reject("missing", AppError::MissingApiKey);
+    };
+    if !state.access_policy.is_known_key(api_key) {
+        return reject("unknown", AppError::InvalidApiKey);
+    }
+    let role = state.access_policy.role(Some(api_key));
+    if !state.access_policy.has_scope(role, scope) {
+        return reject(
+            "scope",
+            AppError::ApiKeyScopeRequired(scope.as_ref().to_string()),
+        );
+    }
+    Ok(())
+}
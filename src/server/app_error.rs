@@ -1,7 +1,21 @@
-use axum::{Json, http::StatusCode, response::IntoResponse};
+use axum::{
+    Json,
+    http::{HeaderValue, StatusCode, header},
+    response::IntoResponse,
+};
 use tracing::warn;
 
-use crate::storage::{RetrieveError, StoreError};
+use crate::{
+    event_snapshots::EventSnapshotError,
+    exports::ExportError,
+    payload_limits::PayloadViolation,
+    plugins::PluginError,
+    projections::SnapshotError,
+    schema_registry::{FieldError, SchemaError},
+    scripts::ScriptError,
+    state_machine::IllegalTransition,
+    storage::{RetrieveError, StoreError},
+};
 
 /// Error type for the REST API.
 ///
@@ -14,14 +28,324 @@ use crate::storage::{RetrieveError, StoreError};
 ///     "message": "Error message"
 /// }
 /// ```
+///
+/// Payloads not matching their schema also list the offending fields in `field_errors`.
 #[derive(Debug, thiserror::Error, strum::AsRefStr)]
 #[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
 pub enum AppError {
-    #[error("Invalid event type: '{0}'")]
-    InvalidEventType(String),
+    #[error("Event type not accepted by the event type policy: '{0}'")]
+    EventTypeForbidden(String),
 
     #[error("Result too large, limit is {0}")]
     ResultTooLarge(u64),
+
+    #[error("Illegal transition '{event_type}' from state '{state}' of '{key}' in '{machine}'")]
+    IllegalTransition {
+        machine: String,
+        key: String,
+        event_type: String,
+        state: String,
+    },
+
+    #[error("Entity not found: '{0}'")]
+    EntityNotFound(String),
+
+    #[error("Write condition failed: expected {expected} matching events, found {matched}")]
+    ConditionFailed { expected: u64, matched: u64 },
+
+    #[error("Version conflict: expected version {expected}, current version is {actual}")]
+    VersionConflict { expected: u64, actual: u64 },
+
+    #[error("Missing correlation id, required when an expected version is given")]
+    MissingCorrelationId,
+
+    #[error("Missing producer id, identify the producer with the X-Producer-Id header")]
+    MissingProducerId,
+
+    #[error("Invalid producer sequence number: {0}")]
+    InvalidProducerSequence(String),
+
+    #[error("Invalid Idempotency-Key header, expected visible ASCII characters")]
+    InvalidIdempotencyKey,
+
+    #[error("Invalid event: {0}")]
+    InvalidEvent(String),
+
+    #[error("Payload too large, limit is {0} bytes")]
+    PayloadTooLarge(usize),
+
+    #[error("Payload nested too deeply, limit is {0} levels")]
+    PayloadTooDeep(usize),
+
+    #[error("Failed to read the request body: {0}")]
+    RequestBodyFailed(String),
+
+    #[error("Projection not found: '{0}'")]
+    ProjectionNotFound(String),
+
+    #[error("Snapshot failed: {0}")]
+    SnapshotFailed(String),
+
+    #[error("Failed to reload resources: {0}")]
+    ResourcesReloadFailed(String),
+
+    #[error("Event not found: {0}")]
+    EventNotFound(String),
+
+    #[error("Event snapshots are not configured")]
+    EventSnapshotsNotConfigured,
+
+    #[error("The server is in read-only mode")]
+    ReadOnly,
+
+    #[error("The server is in maintenance mode, only unconditional writes are accepted")]
+    Maintenance,
+
+    #[error("Outbox failed: {0}")]
+    OutboxFailed(String),
+
+    #[error("Deadline of {0} ms exceeded")]
+    DeadlineExceeded(u64),
+
+    #[error("Event rejected by plugin '{0}'")]
+    EventRejected(String),
+
+    #[error("Plugin failed: {0}")]
+    PluginFailed(String),
+
+    #[error("{0}")]
+    InvalidScript(String),
+
+    #[error("Event type not documented: '{0}'")]
+    EventTypeNotDocumented(String),
+
+    #[error("No events of type '{0}' were stored since startup")]
+    EventTypeNotObserved(String),
+
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+
+    #[error("Exports are not configured")]
+    ExportsNotConfigured,
+
+    #[error("Job not found: {0}")]
+    JobNotFound(u64),
+
+    #[error("No export was written by job {0}")]
+    ExportNotFound(u64),
+
+    #[error("Export job {0} hasn't finished yet")]
+    ExportNotFinished(u64),
+
+    #[error("Requested range is outside the export of {0} bytes")]
+    RangeNotSatisfiable(u64),
+
+    #[error("Export failed: {0}")]
+    ExportFailed(String),
+
+    #[error("Storage failed: {0}")]
+    StorageFailed(String),
+
+    #[error("Too many concurrent queries, the limit is {0} per API key")]
+    TooManyQueries(usize),
+
+    #[error("The {0} storage has no connection pool")]
+    NoConnectionPool(&'static str),
+
+    #[error("Receipts aren't signed, set RECEIPT_KEY to sign them")]
+    ReceiptsNotSigned,
+
+    #[error("Missing reason, legal holds must tell why the events are held")]
+    MissingHoldReason,
+
+    #[error("Legal hold not found: {0}")]
+    LegalHoldNotFound(u64),
+
+    #[error("Events are under legal hold {0}")]
+    LegalHold(u64),
+
+    #[error("Failed to record the legal hold: {0}")]
+    LegalHoldFailed(String),
+
+    #[error("Field '{0}' is masked for the role of the API key")]
+    MaskedField(String),
+
+    #[error("Response can't be redacted for role '{0}', which has masked payload fields")]
+    NotRedactable(String),
+
+    #[error("Failed to redact the response: {0}")]
+    RedactionFailed(String),
+
+    #[error("Invalid query token")]
+    InvalidQueryToken,
+
+    #[error("Query token expired")]
+    QueryTokenExpired,
+
+    #[error("Login is not configured")]
+    LoginNotConfigured,
+
+    #[error("Login failed: {0}")]
+    LoginFailed(String),
+
+    #[error("Log in at /auth/login or use the API key of an admin role")]
+    LoginRequired,
+
+    #[error("User '{0}' has no admin role")]
+    AdminRoleRequired(String),
+
+    #[error("Role '{0}' of the API key isn't an admin role")]
+    AdminApiKeyRequired(String),
+
+    #[error("Client certificate '{0}' has no identity in the access policy")]
+    UnknownClientCertificate(String),
+
+    #[error("Missing API key, send it in the X-Api-Key header")]
+    MissingApiKey,
+
+    #[error("Unknown API key")]
+    InvalidApiKey,
+
+    #[error("API key lacks the '{0}' scope")]
+    ApiKeyScopeRequired(String),
+
+    #[error("Missing bearer token, send it in the Authorization header")]
+    BearerTokenRequired,
+
+    #[error("Invalid bearer token: {0}")]
+    InvalidBearerToken(String),
+
+    #[error("Subject '{subject}' lacks the '{role}' role")]
+    RoleRequired { subject: String, role: String },
+
+    #[error("Rate limit exceeded, retry in {0} seconds")]
+    RateLimited(u64),
+
+    #[error("Ingest rate limits are not configured")]
+    RateLimitNotConfigured,
+
+    #[error("Anomaly detection is not configured")]
+    AnomalyDetectionNotConfigured,
+
+    #[error("Feature '{feature}' is disabled: {reason}")]
+    FeatureDisabled { feature: String, reason: String },
+
+    #[error("Missing reason, switching a feature off must tell why")]
+    MissingDisableReason,
+
+    #[error("{0}")]
+    InvalidSchema(String),
+
+    #[error("No schema registered for event type '{0}'")]
+    SchemaNotFound(String),
+
+    #[error("Payload doesn't match the schema of '{event_type}': {}", describe(.errors))]
+    SchemaViolation {
+        event_type: String,
+        errors: Vec<FieldError>,
+    },
+}
+
+/// Lists the field errors in the message of a schema violation.
+fn describe(errors: &[FieldError]) -> String {
+    let errors: Vec<_> = errors
+        .iter()
+        .map(|error| match error.path.as_str() {
+            "" => error.message.clone(),
+            path => format!("{path}: {}", error.message),
+        })
+        .collect();
+    errors.join("; ")
+}
+
+impl AppError {
+    /// Fields of a payload not matching its schema.
+    pub fn field_errors(&self) -> &[FieldError] {
+        match self {
+            AppError::SchemaViolation { errors, .. } => errors,
+            _ => &[],
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::ResultTooLarge(_)
+            | AppError::SnapshotFailed(_)
+            | AppError::ResourcesReloadFailed(_)
+            | AppError::OutboxFailed(_)
+            | AppError::LegalHoldFailed(_)
+            | AppError::RedactionFailed(_)
+            | AppError::PluginFailed(_)
+            | AppError::ExportFailed(_)
+            | AppError::StorageFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::EventTypeForbidden(_)
+            | AppError::MaskedField(_)
+            | AppError::NotRedactable(_)
+            | AppError::AdminRoleRequired(_)
+            | AppError::AdminApiKeyRequired(_)
+            | AppError::UnknownClientCertificate(_)
+            | AppError::ApiKeyScopeRequired(_)
+            | AppError::RoleRequired { .. } => StatusCode::FORBIDDEN,
+            AppError::IllegalTransition { .. } => StatusCode::CONFLICT,
+            AppError::EntityNotFound(_)
+            | AppError::ProjectionNotFound(_)
+            | AppError::EventNotFound(_)
+            | AppError::EventSnapshotsNotConfigured
+            | AppError::EventTypeNotDocumented(_)
+            | AppError::EventTypeNotObserved(_)
+            | AppError::ExportsNotConfigured
+            | AppError::RateLimitNotConfigured
+            | AppError::AnomalyDetectionNotConfigured
+            | AppError::JobNotFound(_)
+            | AppError::ExportNotFound(_)
+            | AppError::NoConnectionPool(_)
+            | AppError::ReceiptsNotSigned
+            | AppError::LoginNotConfigured
+            | AppError::SchemaNotFound(_)
+            | AppError::LegalHoldNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::ConditionFailed { .. } => StatusCode::PRECONDITION_FAILED,
+            AppError::VersionConflict { .. }
+            | AppError::ExportNotFinished(_)
+            | AppError::LegalHold(_) => StatusCode::CONFLICT,
+            AppError::InvalidQueryToken
+            | AppError::QueryTokenExpired
+            | AppError::LoginFailed(_)
+            | AppError::LoginRequired
+            | AppError::MissingApiKey
+            | AppError::InvalidApiKey
+            | AppError::BearerTokenRequired
+            | AppError::InvalidBearerToken(_) => StatusCode::UNAUTHORIZED,
+            AppError::RangeNotSatisfiable(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::TooManyQueries(_) | AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::MissingCorrelationId
+            | AppError::MissingProducerId
+            | AppError::MissingHoldReason
+            | AppError::MissingDisableReason
+            | AppError::InvalidProducerSequence(_)
+            | AppError::InvalidIdempotencyKey
+            | AppError::InvalidEvent(_)
+            | AppError::PayloadTooDeep(_)
+            | AppError::RequestBodyFailed(_)
+            | AppError::InvalidScript(_)
+            | AppError::InvalidSchema(_)
+            | AppError::SchemaViolation { .. }
+            | AppError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+            AppError::ReadOnly | AppError::Maintenance | AppError::FeatureDisabled { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            AppError::DeadlineExceeded(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::EventRejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+/// Code and message of a server error, put into the extensions of its response for
+/// `report_server_errors`.
+#[derive(Debug, Clone)]
+pub struct ErrorDetails {
+    pub code: String,
+    pub message: String,
 }
 
 /// Converts errors into HTTP responses.
@@ -30,11 +354,26 @@ impl IntoResponse for AppError {
         // Error code is the enum variant name in SCREAMING_SNAKE_CASE.
         let error_code = self.as_ref();
         let message = self.to_string();
-        let status_code = StatusCode::INTERNAL_SERVER_ERROR;
-        let json = serde_json::json!({ "error": error_code, "message": message });
+        let status_code = self.status_code();
+        let mut json = serde_json::json!({ "error": error_code, "message": message });
+        if !self.field_errors().is_empty() {
+            json["field_errors"] = serde_json::json!(self.field_errors());
+        }
 
         warn!("Returning error {error_code}: {message}");
-        (status_code, Json(json)).into_response()
+        let mut response = (status_code, Json(json)).into_response();
+        if status_code.is_server_error() {
+            response.extensions_mut().insert(ErrorDetails {
+                code: error_code.to_string(),
+                message,
+            });
+        }
+        if let AppError::RateLimited(retry_after) = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
     }
 }
 
@@ -42,7 +381,11 @@ impl IntoResponse for AppError {
 impl From<StoreError> for AppError {
     fn from(error: StoreError) -> Self {
         match error {
-            StoreError::InvalidEventType(event_type) => AppError::InvalidEventType(event_type),
+            StoreError::ConditionFailed { expected, matched } => {
+                AppError::ConditionFailed { expected, matched }
+            }
+            StoreError::NotFound(event_id) => AppError::EventNotFound(event_id.to_string()),
+            StoreError::Backend(message) => AppError::StorageFailed(message),
         }
     }
 }
@@ -52,6 +395,80 @@ impl From<RetrieveError> for AppError {
     fn from(error: RetrieveError) -> Self {
         match error {
             RetrieveError::ResultTooLarge(n) => AppError::ResultTooLarge(n),
+            RetrieveError::Backend(message) => AppError::StorageFailed(message),
+        }
+    }
+}
+
+/// Converts state machine errors into application errors.
+impl From<IllegalTransition> for AppError {
+    fn from(error: IllegalTransition) -> Self {
+        AppError::IllegalTransition {
+            machine: error.machine,
+            key: error.key,
+            event_type: error.event_type,
+            state: error.state,
+        }
+    }
+}
+
+/// Converts event snapshot errors into application errors.
+impl From<EventSnapshotError> for AppError {
+    fn from(error: EventSnapshotError) -> Self {
+        AppError::SnapshotFailed(error.to_string())
+    }
+}
+
+/// Converts snapshot errors into application errors.
+impl From<SnapshotError> for AppError {
+    fn from(error: SnapshotError) -> Self {
+        match error {
+            SnapshotError::Retrieve(error) => AppError::from(error),
+            SnapshotError::Io(error) => AppError::SnapshotFailed(error.to_string()),
+        }
+    }
+}
+
+/// Converts plugin errors into application errors.
+impl From<PluginError> for AppError {
+    fn from(error: PluginError) -> Self {
+        match error {
+            PluginError::Rejected(plugin) => AppError::EventRejected(plugin),
+            error @ PluginError::Failed { .. } => AppError::PluginFailed(error.to_string()),
+        }
+    }
+}
+
+/// Converts script compilation errors into application errors.
+impl From<ScriptError> for AppError {
+    fn from(error: ScriptError) -> Self {
+        AppError::InvalidScript(error.to_string())
+    }
+}
+
+/// Converts schema compilation errors into application errors.
+impl From<SchemaError> for AppError {
+    fn from(error: SchemaError) -> Self {
+        AppError::InvalidSchema(error.to_string())
+    }
+}
+
+/// Converts export errors into application errors.
+impl From<ExportError> for AppError {
+    fn from(error: ExportError) -> Self {
+        match error {
+            ExportError::Retrieve(error) => AppError::from(error),
+            error => AppError::ExportFailed(error.to_string()),
+        }
+    }
+}
+
+/// Converts payload limit violations into application errors.
+impl From<PayloadViolation> for AppError {
+    fn from(violation: PayloadViolation) -> Self {
+        match violation {
+            PayloadViolation::TooLarge { limit } => AppError::PayloadTooLarge(limit),
+            PayloadViolation::TooDeep { limit } => AppError::PayloadTooDeep(limit),
         }
     }
 }
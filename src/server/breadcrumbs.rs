@@ -0,0 +1,24 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+
+use crate::server::AppState;
+
+/// Records every request for the crash reports, if they are configured: as in flight until it
+/// finishes, then as recently finished with its status.
+pub async fn record_breadcrumbs(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(crash_reporter) = &state.crash_reporter else {
+        return next.run(request).await;
+    };
+    let mut guard = crash_reporter.start_request(request.method().as_str(), request.uri().path());
+    let response = next.run(request).await;
+    guard.finish(response.status().as_u16());
+    response
+}
@@ -0,0 +1,118 @@
+use axum::{Json, extract::State};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::{config::ServerConfig, exports::ExportFormat, server::AppState};
+
+/// Optional subsystems of the server, so client SDKs can detect features instead of assuming
+/// them from the server version. Fixed at startup.
+#[derive(Serialize, Debug, Clone)]
+pub struct Capabilities {
+    streaming: Streaming,
+    analytics: Analytics,
+
+    /// Whether events are isolated per tenant. They aren't: all API keys share one store, their
+    /// roles only restrict scopes and mask payload fields (see `auth`).
+    multi_tenancy: bool,
+
+    formats: Formats,
+    auth: Auth,
+
+    /// `POST /graphql`.
+    graphql: bool,
+}
+
+/// Ways to receive events as they are stored.
+#[derive(Serialize, Debug, Clone)]
+struct Streaming {
+    /// `GET /events/stream`.
+    server_sent_events: bool,
+
+    /// `GET /ws`.
+    websocket: bool,
+
+    /// The `QueryEvents` call of the gRPC API.
+    grpc: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Analytics {
+    /// `GET /events/aggregate`.
+    aggregates: bool,
+
+    /// `GET /events/gaps`.
+    gaps: bool,
+
+    /// Whether small groups are suppressed and noise is added to aggregated counts.
+    privacy: bool,
+
+    /// Names of the configured projections, read at `GET /projections/{name}`.
+    projections: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Formats {
+    /// Bodies accepted by the ingest endpoints: `json` and `ndjson` (`POST /events/ndjson`).
+    ingest: Vec<&'static str>,
+
+    /// Formats of `POST /exports`, empty if exports aren't configured.
+    exports: Vec<ExportFormat>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Auth {
+    /// Whether `/events` requests need an API key.
+    api_keys: bool,
+
+    /// Whether API keys have roles with scopes and masked fields.
+    roles: bool,
+
+    /// Whether `/events` requests need a bearer token.
+    bearer_tokens: bool,
+
+    /// Whether users log in to the admin APIs with OpenID Connect.
+    oidc: bool,
+}
+
+impl Capabilities {
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            streaming: Streaming {
+                server_sent_events: true,
+                websocket: true,
+                grpc: config.grpc_port.is_some(),
+            },
+            analytics: Analytics {
+                aggregates: true,
+                gaps: true,
+                privacy: config.privacy.is_some(),
+                projections: config
+                    .projections
+                    .iter()
+                    .map(|projection| projection.name.clone())
+                    .collect(),
+            },
+            multi_tenancy: false,
+            formats: Formats {
+                ingest: vec!["json", "ndjson"],
+                exports: match config.exports {
+                    Some(_) => ExportFormat::ALL.to_vec(),
+                    None => vec![],
+                },
+            },
+            auth: Auth {
+                api_keys: config.require_api_key,
+                roles: config.access_policy_file.is_some(),
+                bearer_tokens: config.jwt.is_some(),
+                oidc: config.oidc.is_some(),
+            },
+            graphql: true,
+        }
+    }
+}
+
+/// Lists the optional subsystems enabled on the server.
+#[axum::debug_handler]
+pub async fn get_capabilities(State(state): State<Arc<AppState>>) -> Json<Capabilities> {
+    Json(state.capabilities.clone())
+}
@@ -0,0 +1,202 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderName, header},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use tracing::{Span, field};
+
+use crate::server::AppState;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// A network of IP addresses in CIDR notation like `10.0.0.0/8`. A plain address is a network
+/// of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address = address
+            .parse::<IpAddr>()
+            .map_err(|_| format!("Invalid IP address: '{address}'"))?
+            .to_canonical();
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("Invalid network prefix: '{prefix}'"))?,
+            None => max_prefix,
+        };
+        Ok(Self { address, prefix })
+    }
+}
+
+/// Address of the client that sent the request, as resolved by `resolve_client_ip`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Resolves the client's address and adds it to the request as `ClientIp`, and to the
+/// `client_ip` field of the request's log span.
+///
+/// Requests served without a connection address, like in tests, are passed on unchanged.
+pub async fn resolve_client_ip(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(ConnectInfo(peer)) = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .copied()
+    else {
+        return next.run(request).await;
+    };
+    let client_ip = resolve(peer.ip(), request.headers(), &state.trusted_proxies);
+    request.extensions_mut().insert(ClientIp(client_ip));
+    Span::current().record("client_ip", field::display(client_ip));
+    next.run(request).await
+}
+
+/// Returns the client's address.
+///
+/// The forwarded headers are only believed if the peer is a trusted proxy. Then the addresses
+/// they list are walked from the closest one, and the first address not belonging to a trusted
+/// proxy is the client.
+fn resolve(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNetwork]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|network| network.contains(ip));
+    let mut client = peer;
+    if !is_trusted(client) {
+        return client;
+    }
+    for hop in forwarded_for(headers).into_iter().rev() {
+        // Obfuscated or unknown addresses can't be followed further.
+        let Some(ip) = hop else { break };
+        client = ip;
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Returns the addresses listed in the `Forwarded` header, or in `X-Forwarded-For` if there is
+/// no `Forwarded` header, from the farthest to the closest one.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .into_iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+    };
+    if headers.contains_key(header::FORWARDED) {
+        values(header::FORWARDED)
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    name.trim()
+                        .eq_ignore_ascii_case("for")
+                        .then(|| parse_node(value))
+                })?
+            })
+            .collect()
+    } else {
+        values(X_FORWARDED_FOR).map(parse_node).collect()
+    }
+}
+
+/// Parses an address that may be quoted, bracketed or followed by a port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(bracketed) = node.strip_prefix('[') {
+        let (ip, _) = bracketed.split_once(']')?;
+        return ip.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|address| address.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let trusted: Vec<IpNetwork> = ["10.0.0.0/8", "2001:db8::1"]
+            .iter()
+            .map(|network| network.parse().unwrap())
+            .collect();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("198.51.100.7, 203.0.113.9, 10.1.1.1"),
+        );
+        // Headers from untrusted peers are ignored.
+        assert_eq!(
+            resolve(ip("192.0.2.1"), &headers, &trusted),
+            ip("192.0.2.1")
+        );
+        // The first untrusted hop is the client, the rest may be spoofed.
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted),
+            ip("203.0.113.9")
+        );
+
+        headers.insert(
+            header::FORWARDED,
+            HeaderValue::from_static(r#"for=192.0.2.60;proto=http, For="[2001:db8::1]:4711""#),
+        );
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted),
+            ip("192.0.2.60")
+        );
+
+        headers.insert(header::FORWARDED, HeaderValue::from_static("for=_hidden"));
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &trusted), ip("10.0.0.1"));
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!(
+            "::ffff:10.2.3.4"
+                .parse::<IpNetwork>()
+                .unwrap()
+                .contains(ip("10.2.3.4"))
+        );
+    }
+}
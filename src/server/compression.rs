@@ -0,0 +1,27 @@
+use tower_http::compression::{
+    CompressionLayer, DefaultPredicate, Predicate,
+    predicate::{And, SizeAbove},
+};
+
+/// Compresses responses with gzip or Brotli for clients that accept it.
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// Smallest response body in bytes that is compressed. Smaller ones aren't worth it.
+    pub min_size: u16,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self { min_size: 1024 }
+    }
+}
+
+/// Compresses the responses of at least the configured size, negotiated with the
+/// `Accept-Encoding` header. Server-sent events, gRPC and images are never compressed, so
+/// streams are flushed right away.
+pub fn compress_responses(
+    config: &CompressionConfig,
+) -> CompressionLayer<And<DefaultPredicate, SizeAbove>> {
+    CompressionLayer::new()
+        .compress_when(DefaultPredicate::new().and(SizeAbove::new(config.min_size)))
+}
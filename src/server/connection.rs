@@ -0,0 +1,225 @@
+use axum::{Router, extract::ConnectInfo, http::Request};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::watch,
+};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{debug, warn};
+
+use crate::{
+    metrics,
+    server::{
+        proxy_protocol,
+        tls::{ClientCertificate, TlsConfig},
+    },
+};
+
+/// How long a new connection may take to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tunes how client connections are served.
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    /// Address and port the server listens on.
+    pub address: SocketAddr,
+
+    /// Serves HTTP/2 besides HTTP/1. Clients can use it with prior knowledge, without TLS.
+    pub http2: bool,
+
+    /// Keeps HTTP/1 connections open between requests.
+    pub http1_keep_alive: bool,
+
+    /// How often idle HTTP/2 connections are pinged, or never if not set.
+    pub http2_keep_alive_interval: Option<Duration>,
+
+    /// HTTP/2 connections are closed if a ping isn't answered within this time.
+    pub http2_keep_alive_timeout: Duration,
+
+    /// Maximum number of concurrent requests on an HTTP/2 connection, hyper's default if not
+    /// set.
+    pub http2_max_concurrent_streams: Option<u32>,
+
+    /// Sends small responses right away instead of batching them with Nagle's algorithm.
+    pub tcp_nodelay: bool,
+
+    /// Expects a PROXY protocol v2 header on every connection, carrying the client's address.
+    /// Only enable it behind a load balancer that sends one.
+    pub proxy_protocol: bool,
+
+    /// Serves HTTPS instead of or besides plain HTTP, optionally requiring client certificates.
+    pub tls: Option<TlsConfig>,
+
+    /// Longest time requests in flight may take to finish when shutting down.
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            http2: true,
+            http1_keep_alive: true,
+            http2_keep_alive_interval: None,
+            http2_keep_alive_timeout: Duration::from_secs(20),
+            http2_max_concurrent_streams: None,
+            tcp_nodelay: true,
+            proxy_protocol: false,
+            tls: None,
+            shutdown_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Tells the connections to finish when the server shuts down, and waits until they did.
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            sender: watch::Sender::new(false),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Returns a receiver that is told when to shut down. Draining waits until every receiver
+    /// is dropped.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.sender.subscribe()
+    }
+
+    /// Tells the connections to finish their requests in flight and close, and waits until they
+    /// did.
+    pub async fn drain(self) {
+        self.sender.send_replace(true);
+        self.sender.closed().await;
+    }
+}
+
+/// Accepts connections and serves the app on them until the future is dropped. With a TLS
+/// acceptor, connections are served over TLS. Accepted connections are served until `shutdown`
+/// is told to drain them.
+///
+/// The peer address is passed to the app as `ConnectInfo<SocketAddr>`, and the client
+/// certificate, if any, as `ClientCertificate`.
+pub async fn serve_connections(
+    listener: TcpListener,
+    app: Router,
+    config: &ConnectionConfig,
+    tls: Option<TlsAcceptor>,
+    shutdown: watch::Receiver<bool>,
+) {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder.http1().keep_alive(config.http1_keep_alive);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval(config.http2_keep_alive_interval)
+        .keep_alive_timeout(config.http2_keep_alive_timeout)
+        .max_concurrent_streams(config.http2_max_concurrent_streams);
+    let builder = if config.http2 {
+        builder
+    } else {
+        builder.http1_only()
+    };
+
+    loop {
+        let (mut stream, remote) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                // Usually running out of file descriptors, give other connections time to close.
+                warn!("Failed to accept connection: {error}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        if let Err(error) = stream.set_nodelay(config.tcp_nodelay) {
+            warn!("Failed to set TCP_NODELAY: {error}");
+        }
+        let builder = builder.clone();
+        let app = app.clone();
+        let tls = tls.clone();
+        let shutdown = shutdown.clone();
+        let expect_proxy_header = config.proxy_protocol;
+        tokio::spawn(async move {
+            let mut peer = remote;
+            if expect_proxy_header {
+                let header = proxy_protocol::read_header(&mut stream);
+                match tokio::time::timeout(PROXY_HEADER_TIMEOUT, header).await {
+                    Ok(Ok(source)) => peer = source.unwrap_or(remote),
+                    Ok(Err(error)) => {
+                        warn!("Rejecting connection from {remote}: {error}");
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("Rejecting connection from {remote}: no PROXY protocol header");
+                        return;
+                    }
+                }
+            }
+            let Some(tls) = tls else {
+                serve_connection(&builder, stream, app, peer, None, shutdown).await;
+                return;
+            };
+            let stream = match tls.accept(stream).await {
+                Ok(stream) => stream,
+                Err(error) => {
+                    debug!("TLS handshake with {peer} failed: {error}");
+                    metrics::increment("tls_handshake_failures_total", &[]);
+                    return;
+                }
+            };
+            let certificate = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certificates| certificates.first())
+                .map(ClientCertificate::from_der);
+            serve_connection(&builder, stream, app, peer, certificate, shutdown).await;
+        });
+    }
+}
+
+/// Serves the requests of a connection until it closes. When shutting down, the requests in
+/// flight are finished, then the connection is closed.
+async fn serve_connection(
+    builder: &Builder<TokioExecutor>,
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    app: Router,
+    peer: SocketAddr,
+    certificate: Option<ClientCertificate>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let service = app.map_request(move |mut request: Request<Incoming>| {
+        request.extensions_mut().insert(ConnectInfo(peer));
+        if let Some(certificate) = &certificate {
+            request.extensions_mut().insert(certificate.clone());
+        }
+        request
+    });
+    let connection = builder
+        .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service));
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        // Mapped to `()` so the watch guard isn't held while the connection drains.
+        _ = async { let _ = shutdown.wait_for(|shutting_down| *shutting_down).await; } => {
+            connection.as_mut().graceful_shutdown();
+            connection.as_mut().await
+        }
+    };
+    if let Err(error) = result {
+        debug!("Connection from {peer} failed: {error}");
+    }
+}
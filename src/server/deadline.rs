@@ -0,0 +1,89 @@
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderName},
+    middleware::Next,
+    response::Response,
+};
+use std::time::Duration;
+
+use crate::{metrics, server::app_error::AppError};
+
+/// Header carrying the client's time budget in seconds, fractions allowed.
+const REQUEST_TIMEOUT_HEADER: HeaderName = HeaderName::from_static("request-timeout");
+
+/// Header carrying the client's time budget in the gRPC format, e.g. `250m` for 250 millis.
+const GRPC_TIMEOUT_HEADER: HeaderName = HeaderName::from_static("grpc-timeout");
+
+/// Cancels the request when the client's deadline passes, so work nobody waits for anymore
+/// doesn't keep running. Dropping the handler future cancels the storage call at its next await
+/// point.
+///
+/// A write may still complete if the deadline passes after it was stored, so clients should
+/// treat `504 Gateway Timeout` as an unknown outcome.
+pub async fn enforce_deadline(request: Request, next: Next) -> Result<Response, AppError> {
+    let Some(timeout) = parse_deadline(request.headers()) else {
+        return Ok(next.run(request).await);
+    };
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => Ok(response),
+        Err(_) => {
+            metrics::increment("deadline_exceeded_total", &[]);
+            Err(AppError::DeadlineExceeded(timeout.as_millis() as u64))
+        }
+    }
+}
+
+/// Returns the shortest timeout given in the request headers. Invalid values are ignored.
+fn parse_deadline(headers: &HeaderMap) -> Option<Duration> {
+    let request_timeout = headers
+        .get(REQUEST_TIMEOUT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| Duration::try_from_secs_f64(value.trim().parse().ok()?).ok());
+    let grpc_timeout = headers
+        .get(GRPC_TIMEOUT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_grpc_timeout);
+    request_timeout.into_iter().chain(grpc_timeout).min()
+}
+
+/// Parses a `grpc-timeout` value: at most 8 digits followed by a unit.
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let (amount, unit) = value.split_at_checked(value.len().checked_sub(1)?)?;
+    if amount.is_empty() || amount.len() > 8 {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 3600)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_parse_deadline() {
+        assert_eq!(parse_grpc_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout("123456789S"), None);
+        assert_eq!(parse_grpc_timeout("m"), None);
+        assert_eq!(parse_grpc_timeout("-1m"), None);
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_deadline(&headers), None);
+        headers.insert(REQUEST_TIMEOUT_HEADER, HeaderValue::from_static("1.5"));
+        assert_eq!(parse_deadline(&headers), Some(Duration::from_millis(1500)));
+        headers.insert(GRPC_TIMEOUT_HEADER, HeaderValue::from_static("100m"));
+        assert_eq!(parse_deadline(&headers), Some(Duration::from_millis(100)));
+        headers.insert(REQUEST_TIMEOUT_HEADER, HeaderValue::from_static("soon"));
+        assert_eq!(parse_deadline(&headers), Some(Duration::from_millis(100)));
+    }
+}
@@ -0,0 +1,36 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::instrument;
+
+use crate::server::{AppState, app_error::AppError};
+
+#[derive(Serialize, Debug)]
+pub struct EntityState {
+    key: String,
+    /// Current state by state machine name.
+    states: BTreeMap<String, String>,
+}
+
+/// Returns the current state of an entity in every state machine that tracks it.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_entity_state(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+) -> Result<Json<EntityState>, AppError> {
+    let state_machines = state.state_machines.read().await;
+    let states = state_machines
+        .entity_states(&key)
+        .map(|states| {
+            states
+                .iter()
+                .map(|(machine, current)| (machine.clone(), current.clone()))
+                .collect()
+        })
+        .ok_or_else(|| AppError::EntityNotFound(key.clone()))?;
+    Ok(Json(EntityState { key, states }))
+}
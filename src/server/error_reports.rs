@@ -0,0 +1,80 @@
+use axum::{extract::Request, http::Method, middleware::Next, response::Response};
+use serde_json::{Value, json};
+
+use crate::{sentry, server::app_error::ErrorDetails};
+
+/// Sends the server errors of the API to Sentry, if it's set up, with the request's method,
+/// path, request id and trace id.
+pub async fn report_server_errors(request: Request, next: Next) -> Response {
+    if !sentry::is_enabled() {
+        return next.run(request).await;
+    }
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let response = next.run(request).await;
+    if let Some(details) = response.extensions().get::<ErrorDetails>() {
+        sentry::capture(server_error_event(&method, &path, &response, details));
+    }
+    response
+}
+
+/// Describes a server error as a Sentry event.
+fn server_error_event(
+    method: &Method,
+    path: &str,
+    response: &Response,
+    details: &ErrorDetails,
+) -> Value {
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    json!({
+        "level": "error",
+        "logger": "http",
+        "transaction": format!("{method} {path}"),
+        "exception": {
+            "values": [{
+                "type": details.code,
+                "value": details.message,
+                "mechanism": {"type": "http", "handled": true},
+            }],
+        },
+        "request": {"method": method.as_str(), "url": path},
+        "tags": {
+            "error_code": details.code,
+            "status": response.status().as_u16(),
+            "request_id": header("x-request-id"),
+            "trace_id": header("x-trace-id"),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    use crate::server::app_error::AppError;
+
+    #[test]
+    fn test_server_error_event() {
+        let mut response = AppError::StorageFailed("disk full".to_string()).into_response();
+        response
+            .headers_mut()
+            .insert("x-request-id", "checkout-42".parse().unwrap());
+        let details = response.extensions().get::<ErrorDetails>().unwrap().clone();
+        let event = server_error_event(&Method::POST, "/events", &response, &details);
+        assert_eq!(event["exception"]["values"][0]["type"], "STORAGE_FAILED");
+        assert_eq!(event["transaction"], "POST /events");
+        assert_eq!(event["tags"]["status"], 500);
+        assert_eq!(event["tags"]["request_id"], "checkout-42");
+        assert!(event["tags"]["trace_id"].is_null());
+
+        // Client errors aren't reported.
+        let response = AppError::MissingApiKey.into_response();
+        assert!(response.extensions().get::<ErrorDetails>().is_none());
+    }
+}
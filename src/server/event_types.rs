@@ -0,0 +1,116 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::instrument;
+
+use crate::{
+    access_policy::MaskedFields,
+    event_types::EventTypeMeta,
+    payload_samples::PayloadExamples,
+    schema_drift::DriftReport,
+    server::{AppState, app_error::AppError},
+};
+
+/// An entry of the event catalog.
+#[derive(Serialize, Debug)]
+pub struct EventTypeSummary {
+    event_type: String,
+
+    /// Number of stored events of the type.
+    count: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<EventTypeMeta>,
+}
+
+/// Lists the event types that have stored events or documentation.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn list_event_types(State(state): State<Arc<AppState>>) -> Json<Vec<EventTypeSummary>> {
+    let mut summaries: Vec<_> = state
+        .store
+        .event_type_counts()
+        .await
+        .into_iter()
+        .map(|(event_type, count)| EventTypeSummary {
+            meta: state.event_types.get(&event_type),
+            event_type,
+            count,
+        })
+        .collect();
+    for doc in state.event_types.docs() {
+        if !summaries
+            .iter()
+            .any(|summary| summary.event_type == doc.event_type)
+        {
+            summaries.push(EventTypeSummary {
+                event_type: doc.event_type,
+                count: 0,
+                meta: Some(doc.meta),
+            });
+        }
+    }
+    summaries.sort_by(|a, b| a.event_type.cmp(&b.event_type));
+    Json(summaries)
+}
+
+/// Returns the documentation of an event type.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_event_type_meta(
+    State(state): State<Arc<AppState>>,
+    Path(event_type): Path<String>,
+) -> Result<Json<EventTypeMeta>, AppError> {
+    let meta = state
+        .event_types
+        .get(&event_type)
+        .ok_or(AppError::EventTypeNotDocumented(event_type))?;
+    Ok(Json(meta))
+}
+
+/// Documents an event type, replacing its previous documentation.
+#[axum::debug_handler]
+#[instrument(skip(state, meta))]
+pub async fn put_event_type_meta(
+    State(state): State<Arc<AppState>>,
+    Path(event_type): Path<String>,
+    Json(meta): Json<EventTypeMeta>,
+) {
+    state.event_types.set(&event_type, meta);
+}
+
+/// Reports the payload fields observed for an event type since startup, and how they drifted.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_event_type_drift(
+    State(state): State<Arc<AppState>>,
+    Path(event_type): Path<String>,
+) -> Result<Json<DriftReport>, AppError> {
+    let report = state
+        .schemas
+        .report(&event_type)
+        .ok_or(AppError::EventTypeNotObserved(event_type))?;
+    Ok(Json(report))
+}
+
+/// Returns a random sample of the payloads of an event type stored since startup, with the
+/// role's masked fields redacted.
+#[axum::debug_handler]
+#[instrument(skip(state, masked))]
+pub async fn get_event_type_examples(
+    State(state): State<Arc<AppState>>,
+    masked: Option<Extension<Arc<MaskedFields>>>,
+    Path(event_type): Path<String>,
+) -> Result<Json<PayloadExamples>, AppError> {
+    let mut examples = state
+        .samples
+        .examples(&event_type)
+        .ok_or(AppError::EventTypeNotObserved(event_type))?;
+    if let Some(Extension(masked)) = masked {
+        examples.redact(&masked);
+    }
+    Ok(Json(examples))
+}
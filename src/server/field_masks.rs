@@ -0,0 +1,61 @@
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Request, State},
+    http::{Method, header},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+
+use crate::{
+    metrics,
+    server::{AppState, app_error::AppError, query_limits::API_KEY_HEADER},
+};
+
+/// Redacts the payload fields masked for the role of the request's API key in `GET` responses.
+///
+/// Only JSON responses can be redacted, so other successful ones, like event streams and
+/// export downloads, are refused for roles with masked fields. The masked fields are put into
+/// the request extensions, so handlers can refuse to reveal them in other ways, like grouping
+/// by them.
+pub async fn mask_payload_fields(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if request.method() != Method::GET {
+        return Ok(next.run(request).await);
+    }
+    let api_key = request
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|api_key| api_key.to_str().ok());
+    let role = state.access_policy.role(api_key).to_string();
+    let Some(masked) = state.access_policy.masked_fields(&role) else {
+        return Ok(next.run(request).await);
+    };
+    request.extensions_mut().insert(masked.clone());
+    let response = next.run(request).await;
+
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        if response.status().is_success() {
+            return Err(AppError::NotRedactable(role));
+        }
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|error| AppError::RedactionFailed(error.to_string()))?;
+    let mut value: serde_json::Value = serde_json::from_slice(&body)
+        .map_err(|error| AppError::RedactionFailed(error.to_string()))?;
+    masked.redact(&mut value);
+    metrics::increment("redacted_responses_total", &[("role", role.as_str())]);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let body = serde_json::to_vec(&value).expect("JSON values always serialize");
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
@@ -0,0 +1,239 @@
+use async_graphql::{
+    Context, EmptySubscription, ErrorExtensions, InputObject, Json, Object, Schema, SimpleObject,
+    http::GraphiQLSource,
+};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::{
+    extract::State,
+    http::{HeaderMap, header},
+    response::Html,
+};
+use std::sync::{Arc, atomic::Ordering};
+use tracing::instrument;
+
+use crate::{
+    access_policy::{ApiKeyScope, MaskedFields},
+    event::{Event, EventId, Timestamp},
+    outbox::OutboxEntry,
+    receipts::Receipt,
+    server::{
+        AppState,
+        api_keys::check_api_key,
+        app_error::AppError,
+        ingest::{fingerprint_client, ingest_or_queue},
+        jwt::check_bearer_token,
+        query_limits::{API_KEY_HEADER, Client},
+    },
+};
+
+pub type EventSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+/// Builds the schema. Resolvers get the state and the masked fields with each request.
+pub fn make_schema() -> EventSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription).finish()
+}
+
+/// A stored event.
+#[derive(SimpleObject, Debug)]
+#[graphql(name = "Event")]
+pub struct EventObject {
+    id: String,
+    event_type: String,
+    timestamp: Timestamp,
+    payload: Json<serde_json::Value>,
+    correlation_id: Option<String>,
+    count: Option<u64>,
+}
+
+/// An event to store.
+#[derive(InputObject, Debug)]
+#[graphql(name = "EventInput")]
+pub struct EventInput {
+    event_type: String,
+    timestamp: Timestamp,
+    payload: Option<Json<serde_json::Value>>,
+    correlation_id: Option<String>,
+    count: Option<u64>,
+    dedup_id: Option<String>,
+}
+
+/// Proof that an event was accepted, like the one returned by `POST /events`.
+#[derive(SimpleObject, Debug)]
+#[graphql(name = "Receipt")]
+pub struct ReceiptObject {
+    event_id: String,
+    received_at: u64,
+    payload_sha256: String,
+    signature: Option<String>,
+}
+
+/// The API key and bearer token of a request, checked for the scope of each field.
+struct Credentials {
+    api_key: Option<String>,
+    authorization: Option<String>,
+}
+
+impl Credentials {
+    /// Checks the credentials like the REST API does for requests needing the scope.
+    fn check(context: &Context<'_>, scope: ApiKeyScope) -> async_graphql::Result<()> {
+        let state = context.data::<Arc<AppState>>()?;
+        let credentials = context.data_opt::<Credentials>();
+        let api_key = credentials.and_then(|credentials| credentials.api_key.as_deref());
+        let authorization =
+            credentials.and_then(|credentials| credentials.authorization.as_deref());
+        check_api_key(state, api_key, scope).map_err(|error| error.extend())?;
+        check_bearer_token(state, authorization, scope).map_err(|error| error.extend())?;
+        Ok(())
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Events filtered by type and inclusive timestamp range, ordered by timestamp. Returns at
+    /// most `limit` events, capped by the result size limit.
+    async fn events(
+        &self,
+        context: &Context<'_>,
+        #[graphql(name = "type")] event_type: Option<String>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<Vec<EventObject>> {
+        let state = context.data::<Arc<AppState>>()?;
+        Credentials::check(context, ApiKeyScope::Read)?;
+        let page = state
+            .store
+            .get_events_page(
+                event_type.as_deref(),
+                start,
+                end,
+                None,
+                limit.unwrap_or(usize::MAX),
+            )
+            .await
+            .map_err(|error| AppError::from(error).extend())?;
+        let masked = context.data_opt::<Arc<MaskedFields>>();
+        Ok(page
+            .events
+            .into_iter()
+            .map(|(event_id, event)| EventObject::new(event_id, event, masked.map(Arc::as_ref)))
+            .collect())
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Stores an event like `POST /events`. Returns its receipt, or `null` if it was queued in
+    /// maintenance mode.
+    async fn insert_event(
+        &self,
+        context: &Context<'_>,
+        event: EventInput,
+    ) -> async_graphql::Result<Option<ReceiptObject>> {
+        let state = context.data::<Arc<AppState>>()?;
+        Credentials::check(context, ApiKeyScope::Ingest)?;
+        if state.read_only.load(Ordering::Relaxed) {
+            return Err(AppError::ReadOnly.extend());
+        }
+        let event: Event = event.into();
+        if let Some(client) = context.data_opt::<Client>() {
+            fingerprint_client(state, client, [&event]).await;
+        }
+        let entry = OutboxEntry::Event { event };
+        let (_, mut report) = ingest_or_queue(state, entry)
+            .await
+            .map_err(|error| error.extend())?;
+        Ok(report.take_receipt().map(Into::into))
+    }
+}
+
+impl EventObject {
+    fn new(event_id: EventId, event: Event, masked: Option<&MaskedFields>) -> Self {
+        let mut payload = event.payload;
+        if let Some(masked) = masked {
+            masked.redact_payload(&mut payload);
+        }
+        Self {
+            id: event_id.to_string(),
+            event_type: event.event_type,
+            timestamp: event.timestamp,
+            payload: Json(payload),
+            correlation_id: event.correlation_id,
+            count: event.count,
+        }
+    }
+}
+
+impl From<EventInput> for Event {
+    fn from(event: EventInput) -> Self {
+        Event {
+            event_type: event.event_type,
+            timestamp: event.timestamp,
+            payload: event
+                .payload
+                .map_or(serde_json::Value::Null, |Json(payload)| payload),
+            correlation_id: event.correlation_id,
+            count: event.count,
+            dedup_id: event.dedup_id,
+        }
+    }
+}
+
+impl From<Receipt> for ReceiptObject {
+    fn from(receipt: Receipt) -> Self {
+        ReceiptObject {
+            event_id: receipt.event_id,
+            received_at: receipt.received_at,
+            payload_sha256: receipt.payload_sha256,
+            signature: receipt.signature,
+        }
+    }
+}
+
+/// Puts the REST API's error code into the `code` extension of GraphQL errors.
+impl ErrorExtensions for AppError {
+    fn extend(&self) -> async_graphql::Error {
+        let code = self.as_ref().to_string();
+        async_graphql::Error::new(self.to_string())
+            .extend_with(|_, extensions| extensions.set("code", code))
+    }
+}
+
+/// Executes a GraphQL request. Payload fields masked for the role of the request's API key are
+/// redacted. If API keys or bearer tokens are required, queries need the `read` scope and
+/// mutations the `ingest` scope.
+#[axum::debug_handler]
+#[instrument(skip_all)]
+pub async fn graphql(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    client: Client,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    let api_key = headers
+        .get(API_KEY_HEADER)
+        .and_then(|api_key| api_key.to_str().ok());
+    let masked = state
+        .access_policy
+        .masked_fields(state.access_policy.role(api_key));
+    let mut request = request.into_inner().data(state.clone()).data(client);
+    if let Some(masked) = masked {
+        request = request.data(masked);
+    }
+    request = request.data(Credentials {
+        api_key: api_key.map(str::to_string),
+        authorization: headers
+            .get(header::AUTHORIZATION)
+            .map(|authorization| authorization.to_str().unwrap_or_default().to_string()),
+    });
+    state.graphql_schema.execute(request).await.into()
+}
+
+/// Serves the GraphiQL IDE for exploring the schema.
+pub async fn graphiql() -> Html<String> {
+    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+}
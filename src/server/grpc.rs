@@ -0,0 +1,432 @@
+use anyhow::{Context, Result};
+use axum::http::StatusCode;
+use futures_util::{Stream, StreamExt, stream};
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, atomic::Ordering},
+};
+use tokio::sync::watch;
+use tonic::{
+    Code, Request, Response, Status,
+    transport::{Certificate, Identity, Server, ServerTlsConfig},
+};
+use tracing::{info, instrument};
+
+use crate::{
+    access_policy::ApiKeyScope,
+    event::Event,
+    outbox::OutboxEntry,
+    receipts::Receipt,
+    server::{
+        AppState,
+        api_keys::check_api_key,
+        app_error::AppError,
+        ingest::{BatchReport, ingest_or_queue},
+        jwt::check_bearer_token,
+        query_limits::API_KEY_HEADER,
+        tls::{ClientCertificate, TlsConfig, client_identity},
+    },
+    storage::PageCursor,
+};
+
+pub mod proto {
+    tonic::include_proto!("events");
+}
+
+use proto::event_tracker_server::{EventTracker, EventTrackerServer};
+
+/// Events read from the storage at a time while streaming query results.
+const QUERY_PAGE_SIZE: usize = 1000;
+
+/// Serves the gRPC API on its own port, sharing the state of the REST API. With TLS, the same
+/// certificates are used as for HTTPS. When told to shut down, the calls in flight are finished
+/// before returning, and the shutdown is held off until then.
+pub async fn serve(
+    state: Arc<AppState>,
+    address: SocketAddr,
+    tls: Option<TlsConfig>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut server = Server::builder();
+    if let Some(tls) = &tls {
+        server = server
+            .tls_config(make_tls_config(tls)?)
+            .context("Failed to set up gRPC TLS")?;
+    }
+    info!("Listening for gRPC on {address}");
+    let mut signal = shutdown.clone();
+    server
+        .add_service(EventTrackerServer::new(GrpcService { state }))
+        .serve_with_shutdown(address, async move {
+            let _ = signal.wait_for(|shutting_down| *shutting_down).await;
+        })
+        .await
+        .with_context(|| format!("Failed to serve gRPC on {address}"))?;
+    drop(shutdown);
+    Ok(())
+}
+
+/// Loads the certificates and key of the HTTPS listener. Like there, calls without a valid
+/// client certificate are refused if client CAs are set.
+fn make_tls_config(config: &TlsConfig) -> Result<ServerTlsConfig> {
+    let read = |path: &std::path::Path| {
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))
+    };
+    let identity = Identity::from_pem(read(&config.cert_file)?, read(&config.key_file)?);
+    let mut tls_config = ServerTlsConfig::new().identity(identity);
+    if let Some(path) = &config.client_ca_file {
+        tls_config = tls_config.client_ca_root(Certificate::from_pem(read(path)?));
+    }
+    Ok(tls_config)
+}
+
+struct GrpcService {
+    state: Arc<AppState>,
+}
+
+#[tonic::async_trait]
+impl EventTracker for GrpcService {
+    type QueryEventsStream = Pin<Box<dyn Stream<Item = Result<proto::StoredEvent, Status>> + Send>>;
+
+    #[instrument(skip_all)]
+    async fn store_event(
+        &self,
+        request: Request<proto::StoreEventRequest>,
+    ) -> Result<Response<proto::StoreEventResponse>, Status> {
+        self.authorize(&request, ApiKeyScope::Ingest)?;
+        let event = request
+            .into_inner()
+            .event
+            .ok_or_else(|| Status::invalid_argument("Missing event"))?;
+        let entry = OutboxEntry::Event {
+            event: event.try_into()?,
+        };
+        let mut report = self.ingest(entry).await?;
+        Ok(Response::new(proto::StoreEventResponse {
+            receipt: report.take_receipt().map(Into::into),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn store_event_batch(
+        &self,
+        request: Request<proto::StoreEventBatchRequest>,
+    ) -> Result<Response<proto::BatchReport>, Status> {
+        self.authorize(&request, ApiKeyScope::Ingest)?;
+        let request = request.into_inner();
+        let events = request
+            .events
+            .into_iter()
+            .map(Event::try_from)
+            .collect::<Result<_, _>>()?;
+        let entry = OutboxEntry::Batch {
+            events,
+            atomic: request.atomic,
+        };
+        let report = self.ingest(entry).await?;
+        Ok(Response::new(report.into()))
+    }
+
+    #[instrument(skip_all)]
+    async fn query_events(
+        &self,
+        request: Request<proto::QueryEventsRequest>,
+    ) -> Result<Response<Self::QueryEventsStream>, Status> {
+        self.authorize(&request, ApiKeyScope::Read)?;
+        // Payloads are redacted for the role of the API key like REST responses are.
+        let api_key = request
+            .metadata()
+            .get(API_KEY_HEADER.as_str())
+            .and_then(|api_key| api_key.to_str().ok());
+        let access_policy = &self.state.access_policy;
+        let masked = access_policy.masked_fields(access_policy.role(api_key));
+        let request = request.into_inner();
+        let state = self.state.clone();
+        // Pages are read as the client consumes the stream, `None` after the last one.
+        let pages = stream::unfold(Some(None), move |cursor: Option<Option<PageCursor>>| {
+            let (state, request, masked) = (state.clone(), request.clone(), masked.clone());
+            async move {
+                let page = state
+                    .store
+                    .get_events_page(
+                        request.event_type.as_deref(),
+                        request.start,
+                        request.end,
+                        cursor?,
+                        QUERY_PAGE_SIZE,
+                    )
+                    .await;
+                match page {
+                    Ok(page) => {
+                        let events: Vec<_> = page
+                            .events
+                            .into_iter()
+                            .map(|(event_id, mut event)| {
+                                if let Some(masked) = &masked {
+                                    masked.redact_payload(&mut event.payload);
+                                }
+                                proto::StoredEvent {
+                                    event_id: event_id.to_string(),
+                                    event: Some(event.into()),
+                                }
+                            })
+                            .map(Ok)
+                            .collect();
+                        Some((events, page.next_cursor.map(Some)))
+                    }
+                    Err(error) => Some((vec![Err(AppError::from(error).into())], None)),
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(pages.flat_map(stream::iter))))
+    }
+}
+
+impl GrpcService {
+    /// Checks the API key in the call's `x-api-key` metadata and the bearer token in its
+    /// `authorization` metadata like the REST API does. Clients with a certificate use the API
+    /// key the access policy maps the certificate to instead.
+    fn authorize<T>(&self, request: &Request<T>, scope: ApiKeyScope) -> Result<(), AppError> {
+        let metadata = |key: &str| {
+            request
+                .metadata()
+                .get(key)
+                .map(|value| value.to_str().unwrap_or_default())
+        };
+        let certificate = request
+            .peer_certs()
+            .and_then(|certificates| certificates.first().map(ClientCertificate::from_der));
+        let api_key = match &certificate {
+            Some(certificate) => client_identity(&self.state, certificate)?
+                .api_key
+                .as_deref(),
+            None => metadata(API_KEY_HEADER.as_str()),
+        };
+        check_api_key(&self.state, api_key, scope)?;
+        check_bearer_token(&self.state, metadata("authorization"), scope)?;
+        Ok(())
+    }
+
+    /// Ingests like the REST API: rejected in read-only mode, queued in maintenance mode.
+    async fn ingest(&self, entry: OutboxEntry) -> Result<BatchReport, Status> {
+        if self.state.read_only.load(Ordering::Relaxed) {
+            return Err(AppError::ReadOnly.into());
+        }
+        let (_, report) = ingest_or_queue(&self.state, entry).await?;
+        Ok(report)
+    }
+}
+
+impl TryFrom<proto::Event> for Event {
+    type Error = Status;
+
+    fn try_from(event: proto::Event) -> Result<Self, Status> {
+        let payload = match event.payload_json.as_str() {
+            "" => serde_json::Value::Null,
+            json => serde_json::from_str(json)
+                .map_err(|error| Status::invalid_argument(format!("Invalid payload: {error}")))?,
+        };
+        Ok(Event {
+            event_type: event.event_type,
+            timestamp: event.timestamp,
+            payload,
+            correlation_id: event.correlation_id,
+            count: event.count,
+            dedup_id: event.dedup_id,
+        })
+    }
+}
+
+impl From<Event> for proto::Event {
+    fn from(event: Event) -> Self {
+        proto::Event {
+            event_type: event.event_type,
+            timestamp: event.timestamp,
+            payload_json: event.payload.to_string(),
+            correlation_id: event.correlation_id,
+            count: event.count,
+            dedup_id: event.dedup_id,
+        }
+    }
+}
+
+impl From<Receipt> for proto::Receipt {
+    fn from(receipt: Receipt) -> Self {
+        proto::Receipt {
+            event_id: receipt.event_id,
+            received_at: receipt.received_at,
+            payload_sha256: receipt.payload_sha256,
+            signature: receipt.signature,
+        }
+    }
+}
+
+impl From<BatchReport> for proto::BatchReport {
+    fn from(report: BatchReport) -> Self {
+        proto::BatchReport {
+            accepted: report.accepted as u64,
+            rejected: report.rejected as u64,
+            queued: report.queued as u64,
+            errors: report
+                .errors
+                .into_iter()
+                .map(|error| proto::BatchError {
+                    index: error.index as u64,
+                    error: error.error,
+                    message: error.message,
+                })
+                .collect(),
+            receipts: report.receipts.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// Converts application errors into gRPC statuses with the closest code, keeping the REST
+/// error code in the message.
+impl From<AppError> for Status {
+    fn from(error: AppError) -> Self {
+        let code = match error.status_code() {
+            StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => Code::InvalidArgument,
+            StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+            StatusCode::FORBIDDEN => Code::PermissionDenied,
+            StatusCode::NOT_FOUND => Code::NotFound,
+            StatusCode::CONFLICT => Code::Aborted,
+            StatusCode::PRECONDITION_FAILED => Code::FailedPrecondition,
+            StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+            StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+            StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+            _ => Code::Internal,
+        };
+        Status::new(code, format!("{}: {error}", error.as_ref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ServerConfig, server::make_app_state};
+
+    #[tokio::test]
+    async fn test_grpc_service() {
+        let service = GrpcService {
+            state: make_app_state(ServerConfig::default()).unwrap(),
+        };
+        let event = |timestamp, payload_json: &str| proto::Event {
+            event_type: "click".to_string(),
+            timestamp,
+            payload_json: payload_json.to_string(),
+            ..Default::default()
+        };
+        let request = proto::StoreEventRequest {
+            event: Some(event(2, r#"{"page": "home"}"#)),
+        };
+        let response = service.store_event(Request::new(request)).await.unwrap();
+        assert!(response.into_inner().receipt.is_some());
+        let request = proto::StoreEventBatchRequest {
+            events: vec![event(1, ""), event(3, "not json")],
+            atomic: false,
+        };
+        let status = service.store_event_batch(Request::new(request)).await;
+        assert_eq!(status.unwrap_err().code(), Code::InvalidArgument);
+        let request = proto::StoreEventBatchRequest {
+            events: vec![event(1, ""), event(3, "[]")],
+            atomic: false,
+        };
+        let report = service.store_event_batch(Request::new(request)).await;
+        assert_eq!(report.unwrap().into_inner().accepted, 2);
+
+        let request = proto::QueryEventsRequest {
+            event_type: Some("click".to_string()),
+            ..Default::default()
+        };
+        let events = service.query_events(Request::new(request)).await.unwrap();
+        let events: Vec<_> = events.into_inner().collect().await;
+        let timestamps: Vec<_> = events
+            .into_iter()
+            .map(|event| event.unwrap().event.unwrap().timestamp)
+            .collect();
+        assert_eq!(timestamps, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_api_keys() {
+        let path = std::env::temp_dir().join(format!("grpc-keys-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[api_keys]\nreader = \"reader\"\n\n[roles.reader]\nscopes = [\"read\"]\n",
+        )
+        .unwrap();
+        let config = ServerConfig {
+            access_policy_file: Some(path.clone()),
+            require_api_key: true,
+            ..Default::default()
+        };
+        let service = GrpcService {
+            state: make_app_state(config).unwrap(),
+        };
+        std::fs::remove_file(path).unwrap();
+        let request = |api_key: Option<&str>| {
+            let mut request = Request::new(proto::QueryEventsRequest::default());
+            if let Some(api_key) = api_key {
+                request
+                    .metadata_mut()
+                    .insert("x-api-key", api_key.parse().unwrap());
+            }
+            request
+        };
+
+        let status = service.query_events(request(None)).await.err().unwrap();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        assert!(service.query_events(request(Some("reader"))).await.is_ok());
+        let mut store = Request::new(proto::StoreEventRequest::default());
+        store
+            .metadata_mut()
+            .insert("x-api-key", "reader".parse().unwrap());
+        let status = service.store_event(store).await.unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_field_masks() {
+        let path = std::env::temp_dir().join(format!("grpc-masks-{}.toml", std::process::id()));
+        let policy = "[api_keys]\nroot = \"admin\"\n\n\
+            [roles.anonymous]\nmasked_fields = [\"payload.email\"]\n";
+        std::fs::write(&path, policy).unwrap();
+        let config = ServerConfig {
+            access_policy_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let service = &GrpcService {
+            state: make_app_state(config).unwrap(),
+        };
+        std::fs::remove_file(path).unwrap();
+        let event = proto::Event {
+            event_type: "signup".to_string(),
+            payload_json: r#"{"email": "a@example.com", "plan": "free"}"#.to_string(),
+            ..Default::default()
+        };
+        let request = proto::StoreEventRequest { event: Some(event) };
+        service.store_event(Request::new(request)).await.unwrap();
+        let query = |api_key: Option<&str>| {
+            let mut request = Request::new(proto::QueryEventsRequest::default());
+            if let Some(api_key) = api_key {
+                request
+                    .metadata_mut()
+                    .insert("x-api-key", api_key.parse().unwrap());
+            }
+            async move {
+                let events = service.query_events(request).await.unwrap();
+                let mut events = events.into_inner();
+                let event = events.next().await.unwrap().unwrap().event.unwrap();
+                serde_json::from_str::<serde_json::Value>(&event.payload_json).unwrap()
+            }
+        };
+
+        assert_eq!(
+            query(None).await,
+            serde_json::json!({"email": "[redacted]", "plan": "free"})
+        );
+        assert_eq!(query(Some("root")).await["email"], "a@example.com");
+    }
+}
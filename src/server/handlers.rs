@@ -1,47 +1,478 @@
 use axum::{
     Json,
-    extract::{Query, State},
+    body::Body,
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
 };
-use serde::Deserialize;
-use std::sync::Arc;
-use tracing::instrument;
+use serde::{Deserialize, Serialize};
+use std::{num::NonZeroUsize, str::FromStr, sync::Arc};
+use tracing::{info, instrument};
 
 use crate::{
-    event::Event,
-    server::{AppState, app_error::AppError},
+    event::{Event, EventId},
+    legal_holds::HoldFilter,
+    outbox::OutboxEntry,
+    receipts::Receipt,
+    server::{
+        AppState,
+        app_error::AppError,
+        ingest::{
+            BatchReport, DryRunReport, dry_run, fingerprint_client, ingest_conditional_event,
+            ingest_ndjson, ingest_or_queue, reject_in_maintenance,
+        },
+        jwt::Identity,
+        kill_switches::Feature,
+        producers::ProducerSequence,
+        query_limits::Client,
+    },
+    storage::{EventFilter, PageCursor, QueryPlan, WriteCondition},
 };
 
+/// Methods supported by the `/events` route.
+const EVENTS_ALLOWED_METHODS: &str = "GET, HEAD, POST, DELETE, OPTIONS";
+
+/// Header carrying the number of matching events in `HEAD /events` responses.
+const TOTAL_COUNT_HEADER: HeaderName = HeaderName::from_static("x-total-count");
+
+/// Header identifying a request for its producer, so retries of it are stored once.
+const IDEMPOTENCY_KEY_HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+
+#[derive(Deserialize, Debug)]
+pub struct ConditionalEvent {
+    event: Event,
+    condition: WriteCondition,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AppendParams {
+    /// Number of events expected in the event's correlation id stream before this one.
+    expected_version: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BatchParams {
+    /// Store all events or none of them.
+    #[serde(default)]
+    atomic: bool,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct QueryParams {
     event_type: Option<String>,
     start: Option<u64>,
     end: Option<u64>,
+
+    /// Most events in a page. Giving it or `cursor` returns a page instead of a list.
+    limit: Option<NonZeroUsize>,
+
+    /// Where the page starts, the `next_cursor` of the previous page.
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteParams {
+    event_type: Option<String>,
+    start: Option<u64>,
+    end: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeletedEvents {
+    deleted: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReceiptVerification {
+    valid: bool,
+}
+
+/// An event along with the id it was stored with.
+#[derive(Serialize, Debug)]
+pub struct StoredEvent {
+    /// In decimal, as ids can be wider than JSON numbers.
+    event_id: String,
+
+    #[serde(flatten)]
+    event: Event,
 }
 
-/// Returns a list of events.
+impl StoredEvent {
+    pub fn new(event_id: EventId, event: Event) -> Self {
+        Self {
+            event_id: event_id.to_string(),
+            event,
+        }
+    }
+}
+
+/// A page of events, and where the next one starts.
+#[derive(Serialize, Debug)]
+pub struct EventsPage {
+    events: Vec<StoredEvent>,
+
+    /// Cursor of the next page, `None` on the last page.
+    next_cursor: Option<String>,
+}
+
+/// Returns a list of events with their ids.
 ///
-/// The list is filtered by event type and timestamp range, if specified.
+/// The list is filtered by event type and timestamp range, if specified. With `limit` or
+/// `cursor`, returns a page of the list instead, so lists over the result size limit can be
+/// walked through.
 #[axum::debug_handler]
 #[instrument(skip(state))]
 pub async fn get_events(
     State(state): State<Arc<AppState>>,
     Query(params): Query<QueryParams>,
-) -> Result<Json<Vec<Event>>, AppError> {
-    let result = state
+) -> Result<Response, AppError> {
+    if params.limit.is_none() && params.cursor.is_none() {
+        // The largest page, which holds the whole list unless it's over the result size limit.
+        let page = state
+            .store
+            .get_events_page(
+                params.event_type.as_deref(),
+                params.start,
+                params.end,
+                None,
+                usize::MAX,
+            )
+            .await
+            .map_err(AppError::from)?;
+        if page.next_cursor.is_some() {
+            return Err(AppError::ResultTooLarge(page.events.len() as u64));
+        }
+        let events: Vec<_> = page
+            .events
+            .into_iter()
+            .map(|(event_id, event)| StoredEvent::new(event_id, event))
+            .collect();
+        return Ok(Json(events).into_response());
+    }
+
+    let cursor = params
+        .cursor
+        .as_deref()
+        .map(PageCursor::from_str)
+        .transpose()
+        .map_err(AppError::InvalidQuery)?;
+    let limit = params.limit.map_or(usize::MAX, NonZeroUsize::get);
+    let page = state
         .store
-        .get_events(params.event_type.as_deref(), params.start, params.end)
+        .get_events_page(
+            params.event_type.as_deref(),
+            params.start,
+            params.end,
+            cursor,
+            limit,
+        )
         .await
         .map_err(AppError::from)?;
-    Ok(Json(result))
+    let page = EventsPage {
+        events: page
+            .events
+            .into_iter()
+            .map(|(event_id, event)| StoredEvent::new(event_id, event))
+            .collect(),
+        next_cursor: page.next_cursor.map(|cursor| cursor.to_string()),
+    };
+    Ok(Json(page).into_response())
 }
 
-/// Inserts a new event into the event storage.
+/// Returns an event by the id it was stored with, as given in its receipt.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_event(
+    State(state): State<Arc<AppState>>,
+    Path(event_id): Path<String>,
+) -> Result<Json<StoredEvent>, AppError> {
+    let not_found = || AppError::EventNotFound(event_id.clone());
+    let id = event_id.parse().map_err(|_| not_found())?;
+    let event = state
+        .store
+        .get_by_id(id)
+        .await
+        .map_err(AppError::from)?
+        .ok_or_else(not_found)?;
+    Ok(Json(StoredEvent::new(id, event)))
+}
+
+/// Deletes the events matching the filters, eg. to purge old or bad data, and returns how many
+/// were deleted.
+///
+/// At least one filter is required, so a bare `DELETE /events` can't wipe the storage. Fails
+/// without deleting anything if a legal hold could cover any of the events.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn delete_events(
+    State(state): State<Arc<AppState>>,
+    identity: Option<Identity>,
+    Query(params): Query<DeleteParams>,
+) -> Result<Json<DeletedEvents>, AppError> {
+    state.kill_switches.check(Feature::BulkDelete)?;
+    if params.event_type.is_none() && params.start.is_none() && params.end.is_none() {
+        return Err(AppError::InvalidQuery(
+            "Deleting events requires event_type, start or end".to_string(),
+        ));
+    }
+    reject_in_maintenance(&state).await?;
+    let filter = HoldFilter {
+        event_type: params.event_type.clone(),
+        start: params.start,
+        end: params.end,
+    };
+    if let Some(hold) = state.legal_holds.overlapping(&filter).await {
+        return Err(AppError::LegalHold(hold.id));
+    }
+    let deleted = state
+        .store
+        .delete_events(params.event_type.as_deref(), params.start, params.end)
+        .await
+        .map_err(AppError::from)?;
+    match identity {
+        Some(identity) => info!(
+            "{} deleted {deleted} events matching {params:?}",
+            identity.subject
+        ),
+        None => info!("Deleted {deleted} events matching {params:?}"),
+    }
+    Ok(Json(DeletedEvents { deleted }))
+}
+
+/// Returns the number of matching events in a header, without a body.
+///
+/// Accepts the same filters as `get_events`, but isn't subject to the result size limit.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn head_events(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<QueryParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let count = state
+        .store
+        .count_events(params.event_type.as_deref(), params.start, params.end)
+        .await
+        .map_err(AppError::from)?;
+    Ok([(TOTAL_COUNT_HEADER, count.to_string())])
+}
+
+/// Describes how the storage would execute a `get_events` query, without running it.
+///
+/// Accepts the same filters as `get_events`.
 #[axum::debug_handler]
 #[instrument(skip(state))]
+pub async fn explain_events(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<QueryParams>,
+) -> Json<QueryPlan> {
+    let plan = state
+        .store
+        .explain(params.event_type.as_deref(), params.start, params.end)
+        .await;
+    Json(plan)
+}
+
+/// Advertises the methods supported by `/events`.
+///
+/// Also answers CORS preflight requests by allowing the requesting origin and headers.
+#[axum::debug_handler]
+#[instrument]
+pub async fn options_events(headers: HeaderMap) -> impl IntoResponse {
+    let allowed_methods = HeaderValue::from_static(EVENTS_ALLOWED_METHODS);
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::ALLOW, allowed_methods.clone());
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        response_headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        response_headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, allowed_methods);
+        if let Some(request_headers) = headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+            response_headers.insert(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                request_headers.clone(),
+            );
+        }
+        response_headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+    }
+    (StatusCode::NO_CONTENT, response_headers)
+}
+
+/// Inserts a new event into the event storage.
+///
+/// If `expected_version` is given, the event is only stored if its correlation id stream has
+/// exactly that many events. This allows optimistic concurrency for entity streams.
+///
+/// Returns a receipt of the stored event. In maintenance mode, the event is queued and
+/// `202 Accepted` is returned without a receipt, unless an `expected_version` is given.
+///
+/// Events numbered by their producer with `X-Producer-Sequence` are checked for gaps once
+/// accepted.
+///
+/// An `Idempotency-Key` header is the dedup id of an event without one: retries of the event
+/// within the dedup window return the receipt of the stored one instead of storing it again.
+#[axum::debug_handler]
+#[instrument(skip(state), fields(event_type = %event.event_type))]
 pub async fn post_event(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AppendParams>,
+    headers: HeaderMap,
+    client: Client,
+    Json(mut event): Json<Event>,
+) -> Result<Response, AppError> {
+    let sequence = ProducerSequence::from_headers(&headers)?;
+    if event.dedup_id.is_none() {
+        event.dedup_id = idempotency_key(&headers)?;
+    }
+    fingerprint_client(&state, &client, [&event]).await;
+    let Some(expected_version) = params.expected_version else {
+        let (status, mut report) = ingest_or_queue(&state, OutboxEntry::Event { event }).await?;
+        if let Some(sequence) = sequence {
+            sequence.record(&state, 1);
+        }
+        return Ok(match report.take_receipt() {
+            Some(receipt) => (status, Json(receipt)).into_response(),
+            None => status.into_response(),
+        });
+    };
+    reject_in_maintenance(&state).await?;
+    let Some(correlation_id) = event.correlation_id.clone() else {
+        return Err(AppError::MissingCorrelationId);
+    };
+    let condition = WriteCondition {
+        filter: EventFilter {
+            correlation_id: Some(correlation_id),
+            ..Default::default()
+        },
+        matches: expected_version,
+    };
+    let receipt = ingest_conditional_event(&state, event, &condition)
+        .await
+        .map_err(|error| match error {
+            AppError::ConditionFailed { expected, matched } => AppError::VersionConflict {
+                expected,
+                actual: matched,
+            },
+            error => error,
+        })?;
+    if let Some(sequence) = sequence {
+        sequence.record(&state, 1);
+    }
+    Ok(Json(receipt).into_response())
+}
+
+/// Inserts a new event only if the number of existing events matching the condition's filter
+/// equals the expected count, and returns a receipt of it.
+#[axum::debug_handler]
+#[instrument(skip(state), fields(event_type = %request.event.event_type))]
+pub async fn post_conditional_event(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    client: Client,
+    Json(mut request): Json<ConditionalEvent>,
+) -> Result<Json<Receipt>, AppError> {
+    let sequence = ProducerSequence::from_headers(&headers)?;
+    if request.event.dedup_id.is_none() {
+        request.event.dedup_id = idempotency_key(&headers)?;
+    }
+    fingerprint_client(&state, &client, [&request.event]).await;
+    reject_in_maintenance(&state).await?;
+    let receipt = ingest_conditional_event(&state, request.event, &request.condition).await?;
+    if let Some(sequence) = sequence {
+        sequence.record(&state, 1);
+    }
+    Ok(Json(receipt))
+}
+
+/// Checks that a receipt was issued by this tracker and wasn't altered, so a producer can prove
+/// that an event was accepted.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn verify_receipt(
+    State(state): State<Arc<AppState>>,
+    Json(receipt): Json<Receipt>,
+) -> Result<Json<ReceiptVerification>, AppError> {
+    if !state.receipts.signed() {
+        return Err(AppError::ReceiptsNotSigned);
+    }
+    Ok(Json(ReceiptVerification {
+        valid: state.receipts.verify(&receipt),
+    }))
+}
+
+/// Inserts a list of events, and returns the number of accepted and rejected events.
+///
+/// With `atomic=true`, the events are stored in a single transaction. Otherwise each event is
+/// stored on its own, and the rejected ones are reported with their errors.
+///
+/// In maintenance mode, the batch is queued and `202 Accepted` is returned.
+///
+/// A batch numbered by its producer with `X-Producer-Sequence` is checked for gaps once
+/// processed, even if some of its events were rejected, as they weren't lost.
+///
+/// With an `Idempotency-Key` header, events without a dedup id get the key and their index in
+/// the batch as one, so a retried batch stores only the events missing from the first attempt.
+#[axum::debug_handler]
+#[instrument(skip(state, events))]
+pub async fn post_event_batch(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<BatchParams>,
+    headers: HeaderMap,
+    client: Client,
+    Json(mut events): Json<Vec<Event>>,
+) -> Result<(StatusCode, Json<BatchReport>), AppError> {
+    let sequence = ProducerSequence::from_headers(&headers)?;
+    if let Some(key) = idempotency_key(&headers)? {
+        for (index, event) in events.iter_mut().enumerate() {
+            event
+                .dedup_id
+                .get_or_insert_with(|| format!("{key}:{index}"));
+        }
+    }
+    fingerprint_client(&state, &client, &events).await;
+    let count = events.len();
+    let entry = OutboxEntry::Batch {
+        events,
+        atomic: params.atomic,
+    };
+    let (status, report) = ingest_or_queue(&state, entry).await?;
+    if let Some(sequence) = sequence {
+        sequence.record(&state, count);
+    }
+    Ok((status, Json(report)))
+}
+
+/// Returns the `Idempotency-Key` header of a request, if it has one.
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, AppError> {
+    let Some(key) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+    let key = key.to_str().map_err(|_| AppError::InvalidIdempotencyKey)?;
+    Ok(Some(key.to_string()))
+}
+
+/// Inserts newline-delimited JSON events, one per line, reading them as the body streams in.
+/// Meant for bulk backfills too large to hold in memory. Returns the number of accepted and
+/// rejected events, with the rejected ones reported by their line index.
+///
+/// The events are stored like a batch without `atomic`. Streams can't be queued, so it fails in
+/// maintenance mode.
+#[axum::debug_handler]
+#[instrument(skip(state, body))]
+pub async fn post_ndjson_events(
+    State(state): State<Arc<AppState>>,
+    client: Client,
+    body: Body,
+) -> Result<Json<BatchReport>, AppError> {
+    reject_in_maintenance(&state).await?;
+    let report = ingest_ndjson(&state, &client, body.into_data_stream()).await?;
+    Ok(Json(report))
+}
+
+/// Runs an event through the ingest pipeline and returns what would have happened, without
+/// storing it.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn validate_event(
     State(state): State<Arc<AppState>>,
     Json(event): Json<Event>,
-) -> Result<(), AppError> {
-    state.store.store(event).await.map_err(AppError::from)?;
-    Ok(())
+) -> Json<DryRunReport> {
+    Json(dry_run(&state, event).await)
 }
@@ -0,0 +1,765 @@
+use axum::{body::Bytes, http::StatusCode};
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use std::{
+    fmt::Display,
+    pin::Pin,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+use crate::{
+    event::{Event, EventId},
+    metrics,
+    outbox::{OutboxEntry, OutboxState},
+    receipts::{Receipt, payload_sha256},
+    schema_drift::PayloadShape,
+    schema_registry::FieldError,
+    server::{AppState, app_error::AppError, query_limits::Client},
+    state_machine::PlannedTransition,
+    storage::{StoreError, WriteCondition},
+};
+
+/// Lines of newline-delimited JSON ingested together.
+const NDJSON_CHUNK_LINES: usize = 1000;
+
+/// Room on a line of newline-delimited JSON for the fields of the event around its payload.
+const NDJSON_ENVELOPE_BYTES: usize = 64 * 1024;
+
+/// Outcome of running an event through the ingest pipeline without storing it.
+#[derive(Serialize, Debug)]
+pub struct DryRunReport {
+    /// The event as it would be stored.
+    event: Event,
+
+    /// The storage backend the event would be stored in.
+    backend: &'static str,
+
+    /// Why the event would be rejected, if it would be.
+    error: Option<DryRunError>,
+
+    /// State machine transitions the event would trigger.
+    transitions: Vec<PlannedTransition>,
+
+    /// Projections the event would be folded into.
+    projections: Vec<String>,
+}
+
+/// Outcome of a batch of events stored one by one.
+#[derive(Serialize, Debug, Default)]
+pub struct BatchReport {
+    /// Events stored, or counted into a coalesced event.
+    pub accepted: usize,
+
+    /// Events not stored, see `errors`.
+    pub rejected: usize,
+
+    /// Events queued in maintenance mode, stored when it ends.
+    pub queued: usize,
+
+    /// Why each rejected event wasn't stored.
+    pub errors: Vec<BatchError>,
+
+    /// Receipts of the accepted events, in order.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub receipts: Vec<Receipt>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BatchError {
+    /// Position of the event in the batch.
+    pub index: usize,
+
+    pub error: String,
+    pub message: String,
+
+    /// Fields of the payload not matching its schema.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub field_errors: Vec<FieldError>,
+}
+
+impl BatchReport {
+    fn accept(&mut self, receipt: Receipt) {
+        self.accepted += 1;
+        self.receipts.push(receipt);
+        metrics::increment("batch_events_total", &[("outcome", "accepted")]);
+    }
+
+    /// Takes the receipt of a single accepted event.
+    pub fn take_receipt(&mut self) -> Option<Receipt> {
+        self.receipts.pop()
+    }
+
+    fn reject(&mut self, index: usize, error: AppError) {
+        self.rejected += 1;
+        metrics::increment("batch_events_total", &[("outcome", "rejected")]);
+        self.errors.push(BatchError {
+            index,
+            error: error.as_ref().to_string(),
+            message: error.to_string(),
+            field_errors: error.field_errors().to_vec(),
+        });
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct DryRunError {
+    error: String,
+    message: String,
+}
+
+impl From<AppError> for DryRunError {
+    fn from(error: AppError) -> Self {
+        DryRunError {
+            error: error.as_ref().to_string(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Runs an event through the ingest pipeline and stores it.
+///
+/// The event is filtered and transformed by the plugins first. If the event type has a
+/// coalescing rule and an identical event was stored within its window, the stored event's
+/// count is incremented instead, and the receipt carries the stored event's id.
+///
+/// Events repeating the dedup id of an event stored within the dedup window aren't stored
+/// again, their receipt carries the stored event's id.
+pub async fn ingest_event(state: &AppState, event: Event) -> Result<Receipt, AppError> {
+    let event = state.plugins.apply(event)?;
+    ingest_once(state, event, None).await
+}
+
+/// Stores an event already transformed by the plugins, unless its dedup id was seen within the
+/// dedup window. Events without a write condition are coalesced if the event type has a
+/// coalescing rule.
+async fn ingest_once(
+    state: &AppState,
+    mut event: Event,
+    condition: Option<&WriteCondition>,
+) -> Result<Receipt, AppError> {
+    let payload_sha256 = payload_sha256(&event);
+    let Some(dedup_id) = event.dedup_id.take() else {
+        let event_id = ingest_coalesced(state, event, condition).await?;
+        return Ok(state.receipts.issue(event_id, payload_sha256));
+    };
+    let mut ids = state.dedup.lock().await;
+    if let Some(event_id) = ids.find(&dedup_id, Instant::now()) {
+        metrics::increment("duplicate_events_total", &[]);
+        return Ok(state.receipts.issue(event_id, payload_sha256));
+    }
+    let event_id = ingest_coalesced(state, event, condition).await?;
+    ids.insert(dedup_id, event_id, Instant::now());
+    Ok(state.receipts.issue(event_id, payload_sha256))
+}
+
+/// Stores an event, coalescing it if it has no write condition and the event type has a
+/// coalescing rule. Returns the id of the stored event, or of the one it was counted into.
+async fn ingest_coalesced(
+    state: &AppState,
+    event: Event,
+    condition: Option<&WriteCondition>,
+) -> Result<EventId, AppError> {
+    let window = match condition {
+        Some(_) => None,
+        None => state.coalescer.window(&event.event_type),
+    };
+    let Some(window) = window else {
+        return ingest(state, event, condition).await;
+    };
+    let mut bursts = state.coalescer.lock().await;
+    if let Some(event_id) = bursts.find(&event, window) {
+        match state.store.increment_count(event_id, 1).await {
+            // The first event of the burst was rolled up since, start a new burst.
+            Err(StoreError::NotFound(_)) => {}
+            result => {
+                result?;
+                return Ok(event_id);
+            }
+        }
+    }
+    let stored = event.clone();
+    let event_id = ingest(state, event, None).await?;
+    bursts.start(&stored, event_id, window);
+    Ok(event_id)
+}
+
+/// Runs an event through the ingest pipeline and stores it if the write condition holds.
+pub async fn ingest_conditional_event(
+    state: &AppState,
+    event: Event,
+    condition: &WriteCondition,
+) -> Result<Receipt, AppError> {
+    let event = state.plugins.apply(event)?;
+    ingest_once(state, event, Some(condition)).await
+}
+
+/// Runs events through the ingest pipeline and stores them atomically: either all of them are
+/// stored or none.
+///
+/// Events repeating the dedup id of an event stored within the dedup window are left out of
+/// the transaction, their receipts carry the stored events' ids.
+pub async fn ingest_transaction(
+    state: &AppState,
+    events: Vec<Event>,
+) -> Result<Vec<Receipt>, AppError> {
+    let mut events = events
+        .into_iter()
+        .map(|event| state.plugins.apply(event))
+        .collect::<Result<Vec<_>, _>>()?;
+    let payload_hashes: Vec<_> = events.iter().map(payload_sha256).collect();
+    let dedup_ids: Vec<_> = events
+        .iter_mut()
+        .map(|event| event.dedup_id.take())
+        .collect();
+    // Hold the dedup lock while storing so that concurrent retries are stored once.
+    let mut ids = if dedup_ids.iter().any(Option::is_some) {
+        Some(state.dedup.lock().await)
+    } else {
+        None
+    };
+    let now = Instant::now();
+    let duplicates: Vec<_> = dedup_ids
+        .iter()
+        .map(|dedup_id| {
+            let ids = ids.as_ref()?;
+            ids.find(dedup_id.as_deref()?, now)
+        })
+        .collect();
+    let new_events: Vec<_> = events
+        .into_iter()
+        .zip(&duplicates)
+        .filter(|(_, duplicate)| duplicate.is_none())
+        .map(|(event, _)| event)
+        .collect();
+    let mut stored_ids = if new_events.is_empty() {
+        vec![]
+    } else {
+        commit_transaction(state, new_events, true).await?
+    }
+    .into_iter();
+    let mut event_ids = Vec::with_capacity(duplicates.len());
+    for (duplicate, dedup_id) in duplicates.into_iter().zip(dedup_ids) {
+        if let Some(event_id) = duplicate {
+            metrics::increment("duplicate_events_total", &[]);
+            event_ids.push(event_id);
+            continue;
+        }
+        let Some(event_id) = stored_ids.next() else {
+            break;
+        };
+        if let (Some(ids), Some(dedup_id)) = (ids.as_mut(), dedup_id) {
+            ids.insert(dedup_id, event_id, now);
+        }
+        event_ids.push(event_id);
+    }
+    drop(ids);
+    let receipts = event_ids
+        .into_iter()
+        .zip(payload_hashes)
+        .map(|(event_id, payload_sha256)| state.receipts.issue(event_id, payload_sha256))
+        .collect();
+    Ok(receipts)
+}
+
+/// Runs events through the ingest pipeline and stores each on its own, reporting the rejected
+/// ones instead of failing.
+///
+/// Runs of consecutive events without a dedup id that no coalescing rule or state machine cares
+/// about are stored with a single write. The others are ingested one by one, in order.
+pub async fn ingest_batch(state: &AppState, events: Vec<Event>) -> BatchReport {
+    let mut report = BatchReport::default();
+    ingest_indexed(state, events.into_iter().enumerate().collect(), &mut report).await;
+    report
+}
+
+/// Ingests events read from newline-delimited JSON as the chunks of it arrive, so requests of
+/// any size are ingested in constant memory. Every `NDJSON_CHUNK_LINES` lines are ingested like
+/// a batch, and rejected events are reported by the index of their line. Blank lines are
+/// skipped. A line longer than the payload size limit allows fails the request, so a body
+/// without newlines can't be buffered whole.
+pub async fn ingest_ndjson<S, E>(
+    state: &AppState,
+    client: &Client,
+    mut chunks: S,
+) -> Result<BatchReport, AppError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Display,
+{
+    let max_line = state.payload_limits.max_bytes + NDJSON_ENVELOPE_BYTES;
+    let mut report = BatchReport::default();
+    let mut partial_line = vec![];
+    let mut events = vec![];
+    let mut line_index = 0;
+    let mut parse = |line: &[u8], events: &mut Vec<_>, report: &mut BatchReport| {
+        if !line.trim_ascii().is_empty() {
+            match serde_json::from_slice::<Event>(line) {
+                Ok(event) => events.push((line_index, event)),
+                Err(error) => report.reject(line_index, AppError::InvalidEvent(error.to_string())),
+            }
+        }
+        line_index += 1;
+    };
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|error| AppError::RequestBodyFailed(error.to_string()))?;
+        let mut lines = chunk.split(|byte| *byte == b'\n');
+        // The last piece is the start of a line continued in the next chunk.
+        let last = lines.next_back().unwrap_or_default();
+        for line in lines {
+            if partial_line.is_empty() {
+                parse(line, &mut events, &mut report);
+            } else {
+                partial_line.extend_from_slice(line);
+                parse(&partial_line, &mut events, &mut report);
+                partial_line.clear();
+            }
+        }
+        partial_line.extend_from_slice(last);
+        if partial_line.len() > max_line {
+            return Err(AppError::InvalidEvent(format!(
+                "Lines can't be longer than {max_line} bytes"
+            )));
+        }
+        if events.len() >= NDJSON_CHUNK_LINES {
+            fingerprint_client(state, client, events.iter().map(|(_, event)| event)).await;
+            ingest_indexed(state, std::mem::take(&mut events), &mut report).await;
+            // Receipts of millions of events would take as much memory as the events.
+            report.receipts.clear();
+        }
+    }
+    parse(&partial_line, &mut events, &mut report);
+    fingerprint_client(state, client, events.iter().map(|(_, event)| event)).await;
+    ingest_indexed(state, events, &mut report).await;
+    report.receipts.clear();
+    Ok(report)
+}
+
+/// Ingests the events of a batch, reporting them by their given index.
+async fn ingest_indexed(state: &AppState, events: Vec<(usize, Event)>, report: &mut BatchReport) {
+    let mut run = vec![];
+    for (index, event) in events {
+        let event = match state.plugins.apply(event) {
+            Ok(event) => event,
+            Err(error) => {
+                report.reject(index, error.into());
+                continue;
+            }
+        };
+        if let Err(error) = check_policy(state, &event) {
+            report.reject(index, error);
+            continue;
+        }
+        let tracked = event.dedup_id.is_some()
+            || state.coalescer.window(&event.event_type).is_some()
+            || state.state_machines.read().await.tracks(&event.event_type);
+        if !tracked {
+            run.push((index, event));
+            continue;
+        }
+        store_run(state, std::mem::take(&mut run), report).await;
+        match ingest_once(state, event, None).await {
+            Ok(receipt) => report.accept(receipt),
+            Err(error) => report.reject(index, error),
+        }
+    }
+    store_run(state, run, report).await;
+}
+
+/// Stores a run of events of a batch with a single write, then runs the hooks and scripts for
+/// the stored ones.
+async fn store_run(state: &AppState, run: Vec<(usize, Event)>, report: &mut BatchReport) {
+    if run.is_empty() {
+        return;
+    }
+    let (indexes, events): (Vec<_>, Vec<_>) = run.into_iter().unzip();
+    let stored: Vec<_> = events
+        .iter()
+        .map(|event| {
+            (state.hooks.matches(event) || state.scripts.matches(event)).then(|| event.clone())
+        })
+        .collect();
+    let payload_hashes: Vec<_> = events.iter().map(payload_sha256).collect();
+    let results = store_batch(state, events).await;
+    let outcomes = results.into_iter().zip(payload_hashes).zip(stored);
+    for (index, ((result, payload_sha256), event)) in indexes.into_iter().zip(outcomes) {
+        match result {
+            Ok(event_id) => {
+                report.accept(state.receipts.issue(event_id, payload_sha256));
+                if let Some(event) = event {
+                    after_store(state, event_id, event, true).await;
+                }
+            }
+            Err(error) => report.reject(index, error.into()),
+        }
+    }
+}
+
+/// Stores events atomically, then runs the hooks for them, and the scripts if `run_scripts`.
+/// Returns the ids of the stored events.
+async fn commit_transaction(
+    state: &AppState,
+    events: Vec<Event>,
+    run_scripts: bool,
+) -> Result<Vec<EventId>, AppError> {
+    for event in &events {
+        check_policy(state, event)?;
+    }
+    let stored: Vec<_> = events
+        .iter()
+        .map(|event| {
+            let scripted = run_scripts && state.scripts.matches(event);
+            (scripted || state.hooks.matches(event)).then(|| event.clone())
+        })
+        .collect();
+    let mut state_machines = state.state_machines.write().await;
+    let transitions = state_machines.plan_batch(&events)?;
+    let event_ids = if transitions.is_empty() {
+        drop(state_machines);
+        store_transaction(state, events).await?
+    } else {
+        let event_ids = store_transaction(state, events).await?;
+        state_machines.apply(transitions);
+        drop(state_machines);
+        event_ids
+    };
+    for (event_id, event) in event_ids.iter().zip(stored) {
+        if let Some(event) = event {
+            after_store(state, *event_id, event, run_scripts).await;
+        }
+    }
+    Ok(event_ids)
+}
+
+/// Records the events sent by a client in its fingerprint, if anomaly detection is configured.
+///
+/// Anomalous clients are logged, counted in the `client_anomalies_total` metric and stored as
+/// `client_anomaly` events, so scripts can raise alerts for them.
+pub async fn fingerprint_client<'a>(
+    state: &AppState,
+    client: &Client,
+    events: impl IntoIterator<Item = &'a Event>,
+) {
+    let Some(fingerprints) = &state.client_fingerprints else {
+        return;
+    };
+    let Some(anomaly) = fingerprints.observe(&client.0, events, Instant::now()) else {
+        return;
+    };
+    let reasons: Vec<&str> = anomaly.reasons.iter().map(AsRef::as_ref).collect();
+    warn!(
+        "Anomalous traffic from client {}: {}",
+        anomaly.client,
+        reasons.join(", ")
+    );
+    for reason in reasons {
+        metrics::increment("client_anomalies_total", &[("reason", reason)]);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    if let Err(error) = commit_transaction(state, vec![anomaly.to_event(now)], true).await {
+        warn!("Failed to store client anomaly: {error}");
+    }
+}
+
+/// Queues the request into the outbox in maintenance mode, or applies it right away otherwise.
+///
+/// Returns `202 Accepted` for queued requests and `200 OK` for applied ones, along with the
+/// number of queued, accepted and rejected events.
+pub async fn ingest_or_queue(
+    state: &AppState,
+    entry: OutboxEntry,
+) -> Result<(StatusCode, BatchReport), AppError> {
+    let mut outbox = state.outbox.lock().await;
+    if outbox.maintenance() {
+        let events = match &entry {
+            OutboxEntry::Event { event } => std::slice::from_ref(event),
+            OutboxEntry::Batch { events, .. } => events.as_slice(),
+        };
+        // Oversized payloads aren't written to the outbox, they would be rejected anyway.
+        for event in events {
+            state.payload_limits.check(&event.payload)?;
+        }
+        let queued = events.len();
+        outbox
+            .push(entry)
+            .await
+            .map_err(|error| AppError::OutboxFailed(error.to_string()))?;
+        let report = BatchReport {
+            queued,
+            ..Default::default()
+        };
+        return Ok((StatusCode::ACCEPTED, report));
+    }
+    drop(outbox);
+    let report = apply_outbox_entry(state, entry).await?;
+    Ok((StatusCode::OK, report))
+}
+
+/// Fails in maintenance mode, for requests that can't be queued because their outcome depends
+/// on the current state of the store.
+pub async fn reject_in_maintenance(state: &AppState) -> Result<(), AppError> {
+    if state.outbox.lock().await.maintenance() {
+        return Err(AppError::Maintenance);
+    }
+    Ok(())
+}
+
+/// Applies the queued requests in order and empties the outbox. Returns the number of queued
+/// requests.
+///
+/// Requests that fail are logged and dropped, as their producers were already told they were
+/// accepted.
+pub async fn apply_outbox(state: &AppState, outbox: &mut OutboxState) -> std::io::Result<usize> {
+    let entries = outbox.entries().to_vec();
+    for entry in &entries {
+        match apply_outbox_entry(state, entry.clone()).await {
+            Ok(report) => {
+                if report.rejected > 0 {
+                    warn!(
+                        "Dropping {} rejected events of an outbox batch",
+                        report.rejected
+                    );
+                }
+                metrics::increment("outbox_entries_total", &[("outcome", "applied")]);
+            }
+            Err(error) => {
+                warn!("Dropping outbox entry that failed to apply: {error}");
+                metrics::increment("outbox_entries_total", &[("outcome", "failed")]);
+            }
+        }
+    }
+    outbox.clear().await?;
+    Ok(entries.len())
+}
+
+async fn apply_outbox_entry(state: &AppState, entry: OutboxEntry) -> Result<BatchReport, AppError> {
+    let receipts = match entry {
+        OutboxEntry::Event { event } => vec![ingest_event(state, event).await?],
+        OutboxEntry::Batch {
+            events,
+            atomic: true,
+        } => ingest_transaction(state, events).await?,
+        OutboxEntry::Batch {
+            events,
+            atomic: false,
+        } => return Ok(ingest_batch(state, events).await),
+    };
+    Ok(BatchReport {
+        accepted: receipts.len(),
+        receipts,
+        ..Default::default()
+    })
+}
+
+/// Runs an event through the ingest pipeline and reports what would happen, without storing it.
+pub async fn dry_run(state: &AppState, event: Event) -> DryRunReport {
+    let (event, plugin_error) = match state.plugins.apply(event.clone()) {
+        Ok(transformed) => (transformed, None),
+        Err(error) => (event, Some(AppError::from(error))),
+    };
+    let mut error = plugin_error
+        .or_else(|| check_policy(state, &event).err())
+        .or_else(|| state.store.validate(&event).err().map(AppError::from));
+    let transitions = match state.state_machines.read().await.plan(&event) {
+        Ok(transitions) => transitions,
+        Err(illegal) => {
+            error = error.or(Some(AppError::from(illegal)));
+            vec![]
+        }
+    };
+    DryRunReport {
+        backend: state.store.name(),
+        error: error.map(DryRunError::from),
+        transitions,
+        projections: state.projections.matching(&event).await,
+        event,
+    }
+}
+
+/// Rejects events whose type isn't accepted by the event type policy, or whose payload
+/// exceeds the limits or doesn't match the schema of its type.
+fn check_policy(state: &AppState, event: &Event) -> Result<(), AppError> {
+    if !state.event_policy.accepts(&event.event_type) {
+        return Err(AppError::EventTypeForbidden(event.event_type.clone()));
+    }
+    state.payload_limits.check(&event.payload)?;
+    state
+        .schema_registry
+        .validate(event)
+        .map_err(|errors| AppError::SchemaViolation {
+            event_type: event.event_type.clone(),
+            errors,
+        })?;
+    Ok(())
+}
+
+async fn ingest(
+    state: &AppState,
+    event: Event,
+    condition: Option<&WriteCondition>,
+) -> Result<EventId, AppError> {
+    check_policy(state, &event)?;
+    let stored =
+        (state.hooks.matches(&event) || state.scripts.matches(&event)).then(|| event.clone());
+    // Hold the state machine lock while storing so that transitions of the same entity
+    // are validated and applied in order.
+    let mut state_machines = state.state_machines.write().await;
+    let transitions = state_machines.plan(&event)?;
+    let event_id = if transitions.is_empty() {
+        // Don't block other events while storing one no state machine cares about.
+        drop(state_machines);
+        store_event(state, event, condition).await?
+    } else {
+        let event_id = store_event(state, event, condition).await?;
+        state_machines.apply(transitions);
+        drop(state_machines);
+        event_id
+    };
+    // Hooks and scripts run without locks, so a slow one doesn't hold up other events.
+    if let Some(event) = stored {
+        after_store(state, event_id, event, true).await;
+    }
+    Ok(event_id)
+}
+
+/// Runs the hooks for a stored event, and the scripts if `run_scripts`. Events derived by the
+/// scripts are stored afterwards.
+async fn after_store(state: &AppState, event_id: EventId, event: Event, run_scripts: bool) {
+    let derived = if run_scripts {
+        state.scripts.run(&event)
+    } else {
+        vec![]
+    };
+    state.hooks.run(event_id, event).await;
+    if !derived.is_empty() {
+        store_derived(state, derived).await;
+    }
+}
+
+/// Stores the events derived from one event by scripts, atomically. Scripts don't run for
+/// derived events, so they can't trigger each other endlessly.
+///
+/// Storing is boxed as it recurses into the ingest pipeline.
+fn store_derived(
+    state: &AppState,
+    events: Vec<Event>,
+) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+    Box::pin(async move {
+        if let Err(error) = commit_transaction(state, events, false).await {
+            warn!("Failed to store events derived by scripts: {error}");
+        }
+    })
+}
+
+async fn store_event(
+    state: &AppState,
+    event: Event,
+    condition: Option<&WriteCondition>,
+) -> Result<EventId, StoreError> {
+    state.ingest_lag.record(&event);
+    let shadow_events = sample_shadow(state, std::slice::from_ref(&event));
+    let shape = PayloadShape::of(&event);
+    let sampled = state.samples.sample(&event);
+    let live = state.live_events.has_subscribers().then(|| event.clone());
+    let started = Instant::now();
+    let result = match condition {
+        Some(condition) => state.store.store_if(event, condition).await,
+        None => state.store.store(event).await,
+    };
+    run_shadow(state, shadow_events, result.is_ok(), started);
+    if let Ok(event_id) = &result {
+        state.schemas.record(*event_id, shape);
+        if let Some(sampled) = sampled {
+            state.samples.add(sampled);
+        }
+        if let Some(event) = live {
+            state.live_events.publish(*event_id, event);
+        }
+    }
+    result
+}
+
+async fn store_transaction(
+    state: &AppState,
+    events: Vec<Event>,
+) -> Result<Vec<EventId>, StoreError> {
+    for event in &events {
+        state.ingest_lag.record(event);
+    }
+    let shadow_events = sample_shadow(state, &events);
+    let shapes: Vec<_> = events.iter().map(PayloadShape::of).collect();
+    let sampled: Vec<_> = events
+        .iter()
+        .filter_map(|event| state.samples.sample(event))
+        .collect();
+    let live = state.live_events.has_subscribers().then(|| events.clone());
+    let started = Instant::now();
+    let result = state.store.store_transaction(events).await;
+    run_shadow(state, shadow_events, result.is_ok(), started);
+    if let Ok(event_ids) = &result {
+        for (event_id, shape) in event_ids.iter().zip(shapes) {
+            state.schemas.record(*event_id, shape);
+        }
+        for sampled in sampled {
+            state.samples.add(sampled);
+        }
+        for (event_id, event) in event_ids.iter().zip(live.into_iter().flatten()) {
+            state.live_events.publish(*event_id, event);
+        }
+    }
+    result
+}
+
+/// Stores each event of a batch on its own, see `Storage::store_batch`.
+async fn store_batch(state: &AppState, events: Vec<Event>) -> Vec<Result<EventId, StoreError>> {
+    for event in &events {
+        state.ingest_lag.record(event);
+    }
+    let shadow_events = sample_shadow(state, &events);
+    let shapes: Vec<_> = events.iter().map(PayloadShape::of).collect();
+    let sampled: Vec<_> = events
+        .iter()
+        .map(|event| state.samples.sample(event))
+        .collect();
+    let mut live = state
+        .live_events
+        .has_subscribers()
+        .then(|| events.clone())
+        .map(Vec::into_iter);
+    let started = Instant::now();
+    let results = state.store.store_batch(events).await;
+    let all_ok = results.iter().all(Result::is_ok);
+    run_shadow(state, shadow_events, all_ok, started);
+    for ((result, shape), sampled) in results.iter().zip(shapes).zip(sampled) {
+        let live = live.as_mut().and_then(Iterator::next);
+        if let Ok(event_id) = result {
+            state.schemas.record(*event_id, shape);
+            if let Some(sampled) = sampled {
+                state.samples.add(sampled);
+            }
+            if let Some(event) = live {
+                state.live_events.publish(*event_id, event);
+            }
+        }
+    }
+    results
+}
+
+/// Returns a copy of the events if they should be mirrored into the shadow backend.
+fn sample_shadow(state: &AppState, events: &[Event]) -> Option<Vec<Event>> {
+    let shadow = state.shadow.as_ref()?;
+    shadow.sample().then(|| events.to_vec())
+}
+
+/// Mirrors the events into the shadow backend in the background.
+fn run_shadow(state: &AppState, events: Option<Vec<Event>>, primary_ok: bool, started: Instant) {
+    let (Some(shadow), Some(events)) = (&state.shadow, events) else {
+        return;
+    };
+    let primary_latency = started.elapsed();
+    let shadow = shadow.clone();
+    tokio::spawn(async move { shadow.run(events, primary_ok, primary_latency).await });
+}
@@ -0,0 +1,141 @@
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{AppendHeaders, IntoResponse, Response},
+};
+use std::{ops::Range, sync::Arc};
+use tracing::instrument;
+
+use crate::{
+    exports::{ExportRequest, ExportResult, export, read_export},
+    jobs::{Job, JobId, JobState},
+    server::{AppState, app_error::AppError, kill_switches::Feature},
+};
+
+/// Starts exporting the matching events to the configured object store. Completion is
+/// reported through the returned job.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn start_export(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ExportRequest>,
+) -> Result<(StatusCode, Json<Job>), AppError> {
+    state.kill_switches.check(Feature::Exports)?;
+    let config = state
+        .exports
+        .clone()
+        .ok_or(AppError::ExportsNotConfigured)?;
+    let store = state.store.clone();
+    let job = state.jobs.start("export", async move {
+        let result = export(store.as_ref(), &config, &request).await?;
+        Ok::<_, anyhow::Error>(serde_json::to_value(result)?)
+    });
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Downloads the object written by an export job. A single `Range` is honored, so an
+/// interrupted download can resume where it stopped. The ETag is the SHA-256 hash of the
+/// export, so `If-Range` makes sure the parts belong to the same export.
+#[axum::debug_handler]
+#[instrument(skip(state, headers))]
+pub async fn download_export(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<JobId>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    state.kill_switches.check(Feature::Exports)?;
+    let config = state
+        .exports
+        .clone()
+        .ok_or(AppError::ExportsNotConfigured)?;
+    let job = state.jobs.get(id).filter(|job| job.kind == "export");
+    let job = job.ok_or(AppError::ExportNotFound(id))?;
+    if job.state == JobState::Running {
+        return Err(AppError::ExportNotFinished(id));
+    }
+    let export: ExportResult = job
+        .result
+        .and_then(|result| serde_json::from_value(result).ok())
+        .ok_or(AppError::ExportNotFound(id))?;
+
+    let size = export.bytes as usize;
+    let etag = format!("\"{}\"", export.sha256);
+    let if_range = headers.get(header::IF_RANGE);
+    let range = match headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+    {
+        Some(range) if if_range.is_none_or(|if_range| if_range == etag.as_str()) => {
+            parse_range(range, size)?
+        }
+        _ => None,
+    };
+    let object = read_export(&config, &export.object, range.clone()).await?;
+
+    let mut response_headers = vec![
+        (
+            header::CONTENT_TYPE,
+            export.format.content_type().to_string(),
+        ),
+        (header::ETAG, etag),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+    ];
+    let status = match &range {
+        Some(range) => {
+            let content_range = format!("bytes {}-{}/{size}", range.start, range.end - 1);
+            response_headers.push((header::CONTENT_RANGE, content_range));
+            response_headers.push((header::CONTENT_LENGTH, range.len().to_string()));
+            StatusCode::PARTIAL_CONTENT
+        }
+        None => {
+            response_headers.push((header::CONTENT_LENGTH, size.to_string()));
+            StatusCode::OK
+        }
+    };
+    let body = Body::from_stream(object.into_stream());
+    Ok((status, AppendHeaders(response_headers), body).into_response())
+}
+
+/// Parses a `Range` header of a single byte range. Returns `None` for headers that aren't
+/// supported, like multiple ranges, so the whole export is sent.
+fn parse_range(header: &str, size: usize) -> Result<Option<Range<usize>>, AppError> {
+    let Some((start, end)) = header
+        .strip_prefix("bytes=")
+        .filter(|ranges| !ranges.contains(','))
+        .and_then(|range| range.trim().split_once('-'))
+    else {
+        return Ok(None);
+    };
+    let range = match (start.parse::<usize>(), end.parse::<usize>()) {
+        (Ok(start), Ok(end)) if start <= end => start..size.min(end + 1),
+        (Ok(start), Err(_)) if end.is_empty() => start..size,
+        (Err(_), Ok(suffix)) if start.is_empty() => size.saturating_sub(suffix)..size,
+        _ => return Ok(None),
+    };
+    if range.start >= size {
+        return Err(AppError::RangeNotSatisfiable(size as u64));
+    }
+    Ok(Some(range))
+}
+
+/// Lists the background jobs, newest first.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn list_jobs(State(state): State<Arc<AppState>>) -> Json<Vec<Job>> {
+    Json(state.jobs.list())
+}
+
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<JobId>,
+) -> Result<Json<Job>, AppError> {
+    state
+        .jobs
+        .get(id)
+        .map(Json)
+        .ok_or(AppError::JobNotFound(id))
+}
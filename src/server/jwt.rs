@@ -0,0 +1,225 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::{FromRequestParts, OptionalFromRequestParts, Request, State},
+    http::{header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use std::{convert::Infallible, path::PathBuf, sync::Arc};
+
+use crate::{
+    access_policy::ApiKeyScope,
+    metrics,
+    server::{AppState, api_keys::event_scope, app_error::AppError},
+};
+
+/// Role allowed to store events.
+const INGEST_ROLE: &str = "ingest";
+
+/// Role allowed to query events.
+const READER_ROLE: &str = "reader";
+
+/// Role allowed to delete events, and everything the other roles may do.
+const ADMIN_ROLE: &str = "admin";
+
+/// Requires a JWT bearer token for the routes reading or writing events.
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    pub key: JwtKey,
+
+    /// Required `iss` claim, if set.
+    pub issuer: Option<String>,
+
+    /// Required `aud` claim, if set.
+    pub audience: Option<String>,
+
+    /// Claim listing the roles of the token's subject, as an array or a space separated string.
+    pub roles_claim: String,
+}
+
+/// Key verifying the signatures of tokens.
+#[derive(Clone)]
+pub enum JwtKey {
+    /// Shared secret of HS256 tokens.
+    Hs256(String),
+
+    /// PEM file of the RSA public key of RS256 tokens.
+    Rs256(PathBuf),
+}
+
+impl std::fmt::Debug for JwtKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JwtKey::Hs256(_) => f.write_str("Hs256(..)"),
+            JwtKey::Rs256(path) => f.debug_tuple("Rs256").field(path).finish(),
+        }
+    }
+}
+
+/// Checks bearer tokens, returning the identity they were issued to.
+pub struct JwtVerifier {
+    key: DecodingKey,
+    validation: Validation,
+    roles_claim: String,
+}
+
+/// The subject of a request's bearer token and its roles, put into the request's extensions.
+/// Handlers take it as an extractor, or as an `Option` if tokens aren't required.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub subject: String,
+    pub roles: Vec<String>,
+}
+
+impl JwtVerifier {
+    pub fn new(config: JwtConfig) -> Result<Self> {
+        let (key, algorithm) = match &config.key {
+            JwtKey::Hs256(secret) => (
+                DecodingKey::from_secret(secret.as_bytes()),
+                Algorithm::HS256,
+            ),
+            JwtKey::Rs256(path) => {
+                let pem = std::fs::read(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                let key = DecodingKey::from_rsa_pem(&pem)
+                    .with_context(|| format!("Invalid RSA public key in {}", path.display()))?;
+                (key, Algorithm::RS256)
+            }
+        };
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Ok(Self {
+            key,
+            validation,
+            roles_claim: config.roles_claim,
+        })
+    }
+
+    /// Checks the signature, expiry, issuer and audience of a token.
+    pub fn verify(&self, token: &str) -> Result<Identity, jsonwebtoken::errors::Error> {
+        let token = jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(
+            token,
+            &self.key,
+            &self.validation,
+        )?;
+        let claims = token.claims;
+        let roles = match claims.get(&self.roles_claim) {
+            Some(serde_json::Value::Array(roles)) => roles
+                .iter()
+                .filter_map(|role| role.as_str().map(str::to_string))
+                .collect(),
+            Some(serde_json::Value::String(roles)) => {
+                roles.split_whitespace().map(str::to_string).collect()
+            }
+            _ => Vec::new(),
+        };
+        Ok(Identity {
+            subject: claims
+                .get("sub")
+                .and_then(|subject| subject.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            roles,
+        })
+    }
+}
+
+impl Identity {
+    /// Whether the identity has the role, or the admin role.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles
+            .iter()
+            .any(|granted| granted == role || granted == ADMIN_ROLE)
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Identity {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Identity>()
+            .cloned()
+            .ok_or(AppError::BearerTokenRequired)
+    }
+}
+
+impl<S: Send + Sync> OptionalFromRequestParts<S> for Identity {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Ok(parts.extensions.get::<Identity>().cloned())
+    }
+}
+
+/// Lets only requests with a valid bearer token use the routes reading or writing events, if
+/// tokens are configured. They are the routes needing an API key scope, and each scope needs a
+/// role: `read` the `reader` role, `delete` the `admin` role, and `ingest` the `ingest` role.
+pub async fn require_bearer_token(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(scope) = event_scope(request.method(), request.uri().path()) else {
+        return Ok(next.run(request).await);
+    };
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .map(|authorization| authorization.to_str().unwrap_or_default());
+    if let Some(identity) = check_bearer_token(&state, authorization, scope)? {
+        request.extensions_mut().insert(identity);
+    }
+    Ok(next.run(request).await)
+}
+
+/// Checks that the `Authorization` header carries a valid bearer token with the role of the
+/// scope, if tokens are configured. Returns the token's identity, `None` if tokens aren't
+/// configured. Rejections are counted by reason.
+pub fn check_bearer_token(
+    state: &AppState,
+    authorization: Option<&str>,
+    scope: ApiKeyScope,
+) -> Result<Option<Identity>, AppError> {
+    let Some(verifier) = &state.jwt else {
+        return Ok(None);
+    };
+    let reject = |reason: &str, error: AppError| {
+        metrics::increment("bearer_token_rejections_total", &[("reason", reason)]);
+        Err(error)
+    };
+    let Some(token) = authorization.and_then(|authorization| authorization.strip_prefix("Bearer "))
+    else {
+        return reject("missing", AppError::BearerTokenRequired);
+    };
+    let identity = match verifier.verify(token.trim()) {
+        Ok(identity) => identity,
+        Err(error) => return reject("invalid", AppError::InvalidBearerToken(error.to_string())),
+    };
+    let role = match scope {
+        ApiKeyScope::Read => READER_ROLE,
+        ApiKeyScope::Delete => ADMIN_ROLE,
+        ApiKeyScope::Ingest => INGEST_ROLE,
+    };
+    if !identity.has_role(role) {
+        return reject(
+            "role",
+            AppError::RoleRequired {
+                subject: identity.subject,
+                role: role.to_string(),
+            },
+        );
+    }
+    Ok(Some(identity))
+}
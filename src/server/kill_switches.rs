@@ -0,0 +1,132 @@
+use ahash::AHashMap;
+use axum::{
+    Json,
+    extract::{Path, State},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use tracing::{info, instrument};
+
+use crate::{
+    metrics,
+    server::{AppState, app_error::AppError},
+};
+
+/// Expensive features operators can switch off at runtime, e.g. to shed load during an
+/// incident.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, strum::AsRefStr)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Feature {
+    /// `POST /exports` and `GET /exports/{id}/download`.
+    Exports,
+
+    /// `GET /events/aggregate` and `GET /events/gaps`.
+    Analytics,
+
+    /// `DELETE /events`.
+    BulkDelete,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 3] = [Feature::Exports, Feature::Analytics, Feature::BulkDelete];
+}
+
+/// Features switched off, with the reason given by the operator.
+#[derive(Default)]
+pub struct KillSwitches {
+    disabled: RwLock<AHashMap<Feature, String>>,
+}
+
+impl KillSwitches {
+    /// Fails with the reason if the feature is switched off.
+    pub fn check(&self, feature: Feature) -> Result<(), AppError> {
+        match self.disabled.read().unwrap().get(&feature) {
+            Some(reason) => Err(AppError::FeatureDisabled {
+                feature: feature.as_ref().to_string(),
+                reason: reason.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn state(&self, feature: Feature) -> FeatureState {
+        let reason = self.disabled.read().unwrap().get(&feature).cloned();
+        FeatureState {
+            feature,
+            enabled: reason.is_none(),
+            reason,
+        }
+    }
+
+    fn set(&self, feature: Feature, disabled_reason: Option<String>) {
+        let mut disabled = self.disabled.write().unwrap();
+        match disabled_reason {
+            Some(reason) => {
+                info!("Feature {} disabled: {reason}", feature.as_ref());
+                disabled.insert(feature, reason);
+            }
+            None => {
+                if disabled.remove(&feature).is_some() {
+                    info!("Feature {} enabled", feature.as_ref());
+                }
+            }
+        }
+        let value = if disabled.contains_key(&feature) {
+            1.0
+        } else {
+            0.0
+        };
+        metrics::set_gauge("feature_disabled", &[("feature", feature.as_ref())], value);
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct FeatureState {
+    feature: Feature,
+    enabled: bool,
+
+    /// Why the feature is switched off.
+    reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetFeature {
+    enabled: bool,
+
+    /// Why the feature is switched off, returned to the rejected clients. Required when
+    /// switching it off.
+    reason: Option<String>,
+}
+
+/// Lists the features that can be switched off, and whether they are.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn list_features(State(state): State<Arc<AppState>>) -> Json<Vec<FeatureState>> {
+    let states = Feature::ALL
+        .iter()
+        .map(|feature| state.kill_switches.state(*feature))
+        .collect();
+    Json(states)
+}
+
+/// Switches a feature on or off without a restart. While it is off, its endpoints are rejected
+/// with `503 Service Unavailable` and the reason.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn set_feature(
+    State(state): State<Arc<AppState>>,
+    Path(feature): Path<Feature>,
+    Json(request): Json<SetFeature>,
+) -> Result<Json<FeatureState>, AppError> {
+    let disabled_reason = if request.enabled {
+        None
+    } else {
+        match request.reason {
+            Some(reason) if !reason.trim().is_empty() => Some(reason),
+            _ => return Err(AppError::MissingDisableReason),
+        }
+    };
+    state.kill_switches.set(feature, disabled_reason);
+    Ok(Json(state.kill_switches.state(feature)))
+}
@@ -0,0 +1,70 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::instrument;
+
+use crate::{
+    legal_holds::{HoldAuditEntry, HoldFilter, LegalHold},
+    server::{AppState, app_error::AppError},
+};
+
+#[derive(Deserialize, Debug)]
+pub struct PlaceHold {
+    #[serde(flatten)]
+    filter: HoldFilter,
+
+    /// Why the events are held, eg. a case number.
+    reason: String,
+}
+
+/// Lists the legal holds in force.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn list_legal_holds(State(state): State<Arc<AppState>>) -> Json<Vec<LegalHold>> {
+    Json(state.legal_holds.holds().await)
+}
+
+/// Places a legal hold on the events matching a filter, exempting them from deletion,
+/// retention and rollup until it is released.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn place_legal_hold(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PlaceHold>,
+) -> Result<(StatusCode, Json<LegalHold>), AppError> {
+    if request.reason.trim().is_empty() {
+        return Err(AppError::MissingHoldReason);
+    }
+    let hold = state
+        .legal_holds
+        .place(request.filter, request.reason)
+        .await
+        .map_err(|error| AppError::LegalHoldFailed(error.to_string()))?;
+    Ok((StatusCode::CREATED, Json(hold)))
+}
+
+/// Releases a legal hold, and returns it.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn release_legal_hold(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<LegalHold>, AppError> {
+    let hold = state
+        .legal_holds
+        .release(id)
+        .await
+        .map_err(|error| AppError::LegalHoldFailed(error.to_string()))?;
+    hold.map(Json).ok_or(AppError::LegalHoldNotFound(id))
+}
+
+/// Returns every placement and release of legal holds, oldest first.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_legal_hold_audit(State(state): State<Arc<AppState>>) -> Json<Vec<HoldAuditEntry>> {
+    Json(state.legal_holds.audit_trail().await)
+}
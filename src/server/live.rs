@@ -0,0 +1,165 @@
+use axum::{
+    extract::{Query, State},
+    response::sse::{Event as SseEvent, KeepAlive, Sse},
+};
+use futures_util::{Stream, StreamExt, stream};
+use serde::Deserialize;
+use std::{convert::Infallible, future::Future, sync::Arc};
+use tokio::sync::broadcast::{self, Receiver, error::RecvError};
+use tracing::instrument;
+
+use crate::{
+    event::{Event, EventId},
+    server::{AppState, handlers::StoredEvent},
+};
+
+/// Stored events buffered for each subscriber. Subscribers falling further behind miss events.
+const LIVE_BUFFER_SIZE: usize = 1024;
+
+type LiveEvent = Arc<(EventId, Event)>;
+
+/// Publishes the stored events to the subscribers of the live stream.
+pub struct LiveEvents {
+    sender: broadcast::Sender<LiveEvent>,
+}
+
+impl LiveEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(LIVE_BUFFER_SIZE);
+        Self { sender }
+    }
+
+    /// Whether anyone is subscribed, so events are only copied for publishing if needed.
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn publish(&self, event_id: EventId, event: Event) {
+        // Fails only if everyone unsubscribed since checking.
+        let _ = self.sender.send(Arc::new((event_id, event)));
+    }
+
+    pub fn subscribe(&self) -> Receiver<LiveEvent> {
+        self.sender.subscribe()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StreamParams {
+    #[serde(rename = "type", alias = "event_type")]
+    event_type: Option<String>,
+}
+
+/// What a subscriber is sent next.
+#[derive(Debug, PartialEq)]
+pub enum LiveMessage {
+    Event(LiveEvent),
+
+    /// The subscriber fell behind and missed this many events.
+    Lagged(u64),
+}
+
+/// Waits for the next matching event. Returns `None` once the server shuts down.
+pub async fn next_message(
+    receiver: &mut Receiver<LiveEvent>,
+    matches: impl Fn(&Event) -> bool,
+) -> Option<LiveMessage> {
+    loop {
+        match receiver.recv().await {
+            Ok(live) => {
+                if matches(&live.1) {
+                    return Some(LiveMessage::Event(live));
+                }
+            }
+            Err(RecvError::Lagged(missed)) => return Some(LiveMessage::Lagged(missed)),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Streams the events stored from now on as Server-Sent Events, with the event ids as SSE ids.
+///
+/// A subscriber falling too far behind is sent a `lagged` event with the number of events it
+/// missed, which it can fetch from `GET /events`.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn stream_events(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<StreamParams>,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let event_type = params.event_type;
+    let matches = move |event: &Event| event_type.as_ref().is_none_or(|t| *t == event.event_type);
+    event_stream(&state, matches, std::future::pending())
+}
+
+/// Streams the matching events stored from now on as Server-Sent Events, until `until`
+/// completes.
+pub fn event_stream<F, U>(
+    state: &AppState,
+    matches: F,
+    until: U,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>> + use<F, U>>
+where
+    F: Fn(&Event) -> bool + Send + Sync + 'static,
+    U: Future<Output = ()> + Send + 'static,
+{
+    let receiver = state.live_events.subscribe();
+    let messages = stream::unfold((receiver, matches), |(mut receiver, matches)| async move {
+        let message = match next_message(&mut receiver, &matches).await? {
+            LiveMessage::Event(live) => {
+                let (event_id, event) = live.as_ref();
+                SseEvent::default()
+                    .id(event_id.to_string())
+                    .json_data(StoredEvent::new(*event_id, event.clone()))
+                    .expect("Events always serialize")
+            }
+            LiveMessage::Lagged(missed) => {
+                SseEvent::default().event("lagged").data(missed.to_string())
+            }
+        };
+        Some((Ok(message), (receiver, matches)))
+    });
+    Sse::new(messages.take_until(until)).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_next_message() {
+        let live = LiveEvents::new();
+        assert!(!live.has_subscribers());
+        let mut receiver = live.subscribe();
+        assert!(live.has_subscribers());
+        for (event_id, event_type) in [(1, "click"), (2, "view"), (3, "click")] {
+            let event = Event {
+                event_type: event_type.to_string(),
+                ..Default::default()
+            };
+            live.publish(event_id, event);
+        }
+
+        let next_id = |message: Option<LiveMessage>| match message {
+            Some(LiveMessage::Event(event)) => event.0,
+            message => panic!("Unexpected message: {message:?}"),
+        };
+        let views = |event: &Event| event.event_type == "view";
+        assert_eq!(next_id(next_message(&mut receiver, views).await), 2);
+        assert_eq!(next_id(next_message(&mut receiver, |_| true).await), 3);
+
+        for event_id in 0..LIVE_BUFFER_SIZE as u128 + 10 {
+            live.publish(event_id, Event::default());
+        }
+        assert_eq!(
+            next_message(&mut receiver, |_| true).await,
+            Some(LiveMessage::Lagged(10))
+        );
+        drop(live);
+        // Buffered events are still delivered after the sender is gone.
+        for _ in 0..LIVE_BUFFER_SIZE {
+            assert!(next_message(&mut receiver, |_| true).await.is_some());
+        }
+        assert_eq!(next_message(&mut receiver, |_| true).await, None);
+    }
+}
@@ -0,0 +1,161 @@
+use axum::{
+    Json,
+    extract::{Query, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use serde::Deserialize;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, instrument};
+
+use crate::{
+    metrics,
+    oidc::{Oidc, Session},
+    server::{AppState, app_error::AppError, query_limits::API_KEY_HEADER},
+};
+
+/// Cookie holding the signed session of a logged in user.
+const SESSION_COOKIE: &str = "tracker_session";
+
+#[derive(Deserialize, Debug)]
+pub struct CallbackParams {
+    code: String,
+    state: String,
+}
+
+/// Sends the user to the identity provider to log in.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn login(State(state): State<Arc<AppState>>) -> Result<Redirect, AppError> {
+    let oidc = oidc(&state)?;
+    let url = oidc
+        .start_login()
+        .await
+        .map_err(|error| AppError::LoginFailed(error.to_string()))?;
+    Ok(Redirect::to(&url))
+}
+
+/// Completes a login the identity provider sent the user back from. Only users with a group
+/// mapped to an admin role get a session.
+#[axum::debug_handler]
+#[instrument(skip(state, params))]
+pub async fn login_callback(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CallbackParams>,
+) -> Result<Response, AppError> {
+    let oidc = oidc(&state)?;
+    let session = oidc
+        .complete_login(&params.code, &params.state, now())
+        .await
+        .map_err(|error| {
+            metrics::increment("logins_total", &[("result", "failed")]);
+            AppError::LoginFailed(error.to_string())
+        })?;
+    let user = session.name.as_deref().unwrap_or(&session.subject);
+    if !is_admin(&state, &session) {
+        metrics::increment("logins_total", &[("result", "denied")]);
+        return Err(AppError::AdminRoleRequired(user.to_string()));
+    }
+    info!("User {user} logged in with groups {:?}", session.groups);
+    metrics::increment("logins_total", &[("result", "succeeded")]);
+    let cookie = format!(
+        "{SESSION_COOKIE}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        oidc.issue_session(&session),
+        oidc.session_ttl().as_secs()
+    );
+    Ok((
+        [(header::SET_COOKIE, cookie)],
+        Redirect::to("/auth/session"),
+    )
+        .into_response())
+}
+
+/// Returns the session of the logged in user.
+#[axum::debug_handler]
+#[instrument(skip_all)]
+pub async fn get_session(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Session>, AppError> {
+    let oidc = oidc(&state)?;
+    let session = session(oidc, &headers).ok_or(AppError::LoginRequired)?;
+    Ok(Json(session))
+}
+
+/// Logs the user out by removing the session cookie.
+#[axum::debug_handler]
+pub async fn logout() -> impl IntoResponse {
+    let cookie = format!("{SESSION_COOKIE}=; Path=/; Max-Age=0; HttpOnly; Secure; SameSite=Lax");
+    (StatusCode::NO_CONTENT, [(header::SET_COOKIE, cookie)])
+}
+
+/// Lets only admins use the admin APIs when login is enabled: logged in users with a group
+/// mapped to an admin role, and requests with the API key of an admin role. Without login, only
+/// the API keys of admin roles may use them if API keys are required.
+pub async fn require_admin(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let api_key = request
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|api_key| api_key.to_str().ok());
+    let role = state.access_policy.role(api_key);
+    if state.access_policy.is_admin(role) {
+        return Ok(next.run(request).await);
+    }
+    let Some(oidc) = &state.oidc else {
+        if !state.require_api_key {
+            return Ok(next.run(request).await);
+        }
+        return Err(match api_key {
+            None => AppError::MissingApiKey,
+            Some(api_key) if !state.access_policy.is_known_key(api_key) => AppError::InvalidApiKey,
+            Some(_) => AppError::AdminApiKeyRequired(role.to_string()),
+        });
+    };
+    match session(oidc, request.headers()) {
+        Some(session) if is_admin(&state, &session) => Ok(next.run(request).await),
+        Some(session) => Err(AppError::AdminRoleRequired(
+            session.name.unwrap_or(session.subject),
+        )),
+        None => Err(AppError::LoginRequired),
+    }
+}
+
+fn oidc(state: &AppState) -> Result<&Oidc, AppError> {
+    state.oidc.as_ref().ok_or(AppError::LoginNotConfigured)
+}
+
+/// Whether any group of the user maps to an admin role. Roles are resolved on each request, so
+/// changes of the access policy apply to existing sessions.
+fn is_admin(state: &AppState, session: &Session) -> bool {
+    state
+        .access_policy
+        .group_roles(&session.groups)
+        .any(|role| state.access_policy.is_admin(role))
+}
+
+/// Returns the valid session in the request's cookies, if any.
+fn session(oidc: &Oidc, headers: &HeaderMap) -> Option<Session> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|cookies| cookies.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .filter(|(name, _)| *name == SESSION_COOKIE)
+        .find_map(|(_, value)| oidc.verify_session(value, now()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
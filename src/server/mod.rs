@@ -1,22 +1,331 @@
+mod admin;
+mod analytics;
+mod api_keys;
 mod app_error;
+mod breadcrumbs;
+mod capabilities;
+mod client_ip;
+mod compression;
+mod connection;
+mod deadline;
+mod entities;
+mod error_reports;
+mod event_types;
+mod field_masks;
+mod graphql;
+mod grpc;
 mod handlers;
+mod ingest;
+mod jobs;
+mod jwt;
+mod kill_switches;
+mod legal_holds;
+mod live;
+mod login;
+mod modes;
+mod producers;
+mod projections;
+mod proxy_protocol;
+mod query_limits;
+mod query_tokens;
+mod rate_limits;
+mod schemas;
+mod subscriptions;
+mod tls;
+mod trace_context;
+mod version;
 
-use anyhow::{Context, Result};
-use axum::{Router, response::IntoResponse, routing::get};
-use std::sync::Arc;
-use tracing::info;
+use anyhow::{Context, Result, bail};
+use axum::{
+    Json, Router,
+    extract::State,
+    http::StatusCode,
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get, post, put},
+};
+use serde::Serialize;
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, atomic::AtomicBool},
+    time::Instant,
+};
+use tokio::sync::Mutex;
+use tower_http::decompression::RequestDecompressionLayer;
+use tracing::{error, info, warn};
 
 use crate::{
-    server::handlers::{get_events, post_event},
-    storage::{InMemoryStorage, Storage},
+    access_policy::AccessPolicy,
+    analytics::PrivacyConfig,
+    backups,
+    client_fingerprints::ClientFingerprints,
+    coalescing::Coalescer,
+    config::ServerConfig,
+    crash_reports::CrashReporter,
+    dedup::DedupIndex,
+    discovery,
+    event_policy::EventTypePolicy,
+    event_snapshots::EventSnapshots,
+    event_types::EventTypeCatalog,
+    export_schedules::ExportScheduler,
+    exports::ExportConfig,
+    health::{Health, HealthStatus},
+    hooks::IngestHooks,
+    id_generator::{IdGenerator, make_id_generator},
+    ingest_lag::IngestLag,
+    jobs::Jobs,
+    legal_holds::LegalHolds,
+    merkle_log::MerkleLog,
+    metrics,
+    oidc::Oidc,
+    outbox::Outbox,
+    payload_limits::PayloadLimits,
+    payload_samples::PayloadSamples,
+    plugins::Plugins,
+    producers::ProducerRegistry,
+    projections::Projections,
+    query_tokens::QueryTokens,
+    receipts::{Receipts, SigningKeys},
+    resources::ResourceBundle,
+    retention, rollup,
+    schema_drift::SchemaTracker,
+    schema_registry::SchemaRegistry,
+    scripts::Scripts,
+    secrets::Secrets,
+    sentry,
+    server::{
+        admin::{
+            check_integrity, collapse_event_snapshots, export_resources, get_client_fingerprints,
+            get_event_snapshots, get_inclusion_proof, get_ingest_lag, get_merkle_roots,
+            get_plugins, get_producers, get_storage_pool, get_watermarks, import_resources,
+            reload_plugins, reload_resources, reload_resources_file, set_storage_pool_limits,
+            take_event_snapshot, verify_integrity,
+        },
+        analytics::{aggregate_events, find_event_gaps},
+        api_keys::require_api_key,
+        breadcrumbs::record_breadcrumbs,
+        capabilities::{Capabilities, get_capabilities},
+        client_ip::resolve_client_ip,
+        compression::compress_responses,
+        connection::{Shutdown, serve_connections},
+        deadline::enforce_deadline,
+        entities::get_entity_state,
+        error_reports::report_server_errors,
+        event_types::{
+            get_event_type_drift, get_event_type_examples, get_event_type_meta, list_event_types,
+            put_event_type_meta,
+        },
+        field_masks::mask_payload_fields,
+        graphql::{EventSchema, graphiql, graphql, make_schema},
+        handlers::{
+            delete_events, explain_events, get_event, get_events, head_events, options_events,
+            post_conditional_event, post_event, post_event_batch, post_ndjson_events,
+            validate_event, verify_receipt,
+        },
+        ingest::apply_outbox,
+        jobs::{download_export, get_job, list_jobs, start_export},
+        jwt::{JwtVerifier, require_bearer_token},
+        kill_switches::{KillSwitches, list_features, set_feature},
+        legal_holds::{
+            get_legal_hold_audit, list_legal_holds, place_legal_hold, release_legal_hold,
+        },
+        live::{LiveEvents, stream_events},
+        login::{get_session, login, login_callback, logout, require_admin},
+        modes::{
+            get_maintenance, get_read_only, reject_writes_when_read_only, set_maintenance,
+            set_read_only,
+        },
+        producers::{post_heartbeat, track_producers},
+        projections::{get_projection, rebuild_projection, snapshot_projection},
+        query_limits::{QueryLimiter, limit_queries},
+        query_tokens::{create_query_token, get_shared_events, stream_shared_events},
+        rate_limits::{RateLimiter, get_usage, limit_ingest_rate},
+        schemas::{delete_schema, get_schema, list_schemas, put_schema},
+        subscriptions::{Subscriptions, list_subscriptions, subscribe_events},
+        tls::{apply_client_identity, make_acceptor},
+        trace_context::propagate_trace_context,
+        version::get_version,
+    },
+    shadow::Shadow,
+    state_machine::StateMachines,
+    storage::{
+        InMemoryStorage, PostgresStorage, ReplicatedStorage, SqliteStorage, Storage, StorageBackend,
+    },
 };
 
-/// Default port for the server
-const PORT: u16 = 3000;
+pub use client_ip::IpNetwork;
+pub use compression::CompressionConfig;
+pub use connection::ConnectionConfig;
+pub use jwt::{JwtConfig, JwtKey};
+pub use query_limits::QueryConcurrencyConfig;
+pub use rate_limits::{OverflowPolicy, RateLimitConfig, SmoothingConfig};
+pub use tls::TlsConfig;
 
 /// Shared application state.
 struct AppState {
     store: Arc<dyn Storage + Send + Sync + 'static>,
+    state_machines: StateMachines,
+    projections: Projections,
+    resources_file: Option<PathBuf>,
+
+    /// Resources as last loaded from the resources file.
+    file_resources: Mutex<ResourceBundle>,
+
+    shadow: Option<Arc<Shadow>>,
+    coalescer: Coalescer,
+    health: Arc<Health>,
+    event_snapshots: Option<Arc<EventSnapshots>>,
+
+    /// Rejects requests that would store or change events.
+    read_only: AtomicBool,
+
+    /// Features switched off at runtime.
+    kill_switches: KillSwitches,
+
+    /// Compresses responses, if configured.
+    compression: Option<CompressionConfig>,
+
+    /// Queues ingest requests during maintenance mode.
+    outbox: Outbox,
+
+    /// Exempts events from deletion, retention and rollup.
+    legal_holds: Arc<LegalHolds>,
+
+    /// Roles of the API keys, and the payload fields redacted for them.
+    access_policy: AccessPolicy,
+
+    /// Rejects requests reading or writing events without a known API key with the request's
+    /// scope.
+    require_api_key: bool,
+
+    /// Checks the bearer tokens required for requests reading or writing events, if configured.
+    jwt: Option<JwtVerifier>,
+
+    /// Proxies whose forwarded headers are believed when resolving client addresses.
+    trusted_proxies: Vec<IpNetwork>,
+
+    /// Limits concurrent queries per API key, if configured.
+    query_limiter: Option<QueryLimiter>,
+
+    /// Limits the rate of ingest requests per API key, if configured.
+    rate_limiter: Option<RateLimiter>,
+
+    event_policy: EventTypePolicy,
+
+    /// Bounds on the size and nesting of ingested payloads.
+    payload_limits: PayloadLimits,
+
+    hooks: IngestHooks,
+    plugins: Plugins,
+    scripts: Scripts,
+    event_types: EventTypeCatalog,
+
+    /// Payload shapes of the event types, to report schema drift.
+    schemas: SchemaTracker,
+
+    /// JSON Schemas validating the payloads of event types at ingest.
+    schema_registry: SchemaRegistry,
+
+    /// Example payloads of the event types.
+    samples: PayloadSamples,
+
+    /// How far behind the producers of each event type are.
+    ingest_lag: IngestLag,
+
+    /// Producers identifying themselves in requests, and when they were last seen.
+    producers: ProducerRegistry,
+
+    /// Ingest patterns of the clients, to flag anomalous ones, if configured.
+    client_fingerprints: Option<ClientFingerprints>,
+
+    /// Stored events by dedup id, so retried events are stored once.
+    dedup: DedupIndex,
+
+    receipts: Receipts,
+
+    /// Issues and checks the tokens of shared event views.
+    query_tokens: QueryTokens,
+
+    /// Logs users in to the admin APIs, if configured.
+    oidc: Option<Oidc>,
+
+    /// Merkle trees over the stored events, for inclusion proofs.
+    merkle_log: MerkleLog,
+
+    /// Stored events, published to the subscribers of the live stream.
+    live_events: LiveEvents,
+
+    /// Subscriptions of the WebSocket clients.
+    subscriptions: Subscriptions,
+
+    /// Schema of the GraphQL API.
+    graphql_schema: EventSchema,
+
+    /// Applied to every aggregate if configured.
+    privacy: Option<PrivacyConfig>,
+
+    exports: Option<ExportConfig>,
+    export_schedules: Arc<ExportScheduler>,
+    jobs: Arc<Jobs>,
+
+    /// Writes crash reports with the recent requests when the server panics, if configured.
+    crash_reporter: Option<Arc<CrashReporter>>,
+
+    /// When the server started, for its uptime.
+    started: Instant,
+
+    /// Optional subsystems enabled by the configuration.
+    capabilities: Capabilities,
+}
+
+/// Returns all metrics in the Prometheus text format.
+async fn get_metrics() -> String {
+    metrics::render()
+}
+
+/// Readiness of the instance to serve requests.
+#[derive(Serialize, Debug)]
+struct Readiness {
+    ready: bool,
+
+    /// Why the storage backend's health check failed, if it did.
+    storage_error: Option<String>,
+
+    /// Outcome of the background probes.
+    #[serde(flatten)]
+    probes: HealthStatus,
+}
+
+/// Reports whether the process is alive, without checking its dependencies, so orchestrators
+/// only restart it if it stops responding.
+async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Reports whether the storage backend is reachable and passes its self-checks, so
+/// orchestrators stop routing traffic to the instance while it isn't.
+async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Readiness>) {
+    let storage_error = match state.store.health_check().await {
+        Ok(()) => None,
+        Err(error) => {
+            warn!("Storage health check failed: {error:?}");
+            Some(format!("{error:?}"))
+        }
+    };
+    let probes = state.health.status();
+    let ready = storage_error.is_none() && !probes.degraded;
+    let status_code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let readiness = Readiness {
+        ready,
+        storage_error,
+        probes,
+    };
+    (status_code, Json(readiness))
 }
 
 /// Dummy handler to show the server is running.
@@ -24,36 +333,589 @@ async fn welcome() -> impl IntoResponse {
     "I'm completely operational, and all my circuits are functioning perfectly."
 }
 
-/// Creates a new server with the default storage. Used for testing, too.
+/// Creates a new server with the default storage, for tests.
+#[cfg(test)]
 pub fn make_server() -> Router {
-    let store = Arc::new(InMemoryStorage::new());
-    let shared_state = Arc::new(AppState { store });
-    Router::new()
-        .route("/events", get(get_events).post(post_event))
+    make_server_with_config(ServerConfig::default()).expect("Default configuration is valid")
+}
+
+/// Creates a new server with the default storage and the given configuration, for tests.
+#[cfg(test)]
+pub fn make_server_with_config(config: ServerConfig) -> Result<Router> {
+    Ok(make_router(make_app_state(config)?))
+}
+
+/// Opens a database storage backend.
+fn open_database(
+    backend: &StorageBackend,
+    id_generator: Box<dyn IdGenerator + Send + Sync>,
+    config: &ServerConfig,
+) -> Result<Arc<dyn Storage + Send + Sync>> {
+    Ok(match backend {
+        StorageBackend::Memory => bail!("The in-memory storage isn't a database"),
+        StorageBackend::Sqlite(path) => Arc::new(
+            SqliteStorage::open(path, id_generator, config.write_batch)
+                .context("Failed to open SQLite database")?,
+        ),
+        StorageBackend::Postgres(url) => Arc::new(
+            PostgresStorage::new(
+                url,
+                id_generator,
+                config.postgres_pool,
+                config.postgres_ca_file.as_deref(),
+            )
+            .context("Failed to set up PostgreSQL storage")?,
+        ),
+    })
+}
+
+fn make_app_state(config: ServerConfig) -> Result<Arc<AppState>> {
+    let id_generator = make_id_generator(config.id_strategy);
+    let store: Arc<dyn Storage + Send + Sync> = match &config.storage {
+        StorageBackend::Memory => match &config.wal_file {
+            Some(path) => Arc::new(
+                InMemoryStorage::with_wal(path, id_generator)
+                    .with_context(|| format!("Failed to recover events from {}", path.display()))?,
+            ),
+            None => Arc::new(InMemoryStorage::with_id_generator(id_generator)),
+        },
+        database => open_database(database, id_generator, &config)?,
+    };
+    let store: Arc<dyn Storage + Send + Sync> = match &config.replica {
+        Some(replica) => {
+            let id_generator = make_id_generator(config.id_strategy);
+            let replica_store = open_database(&replica.storage, id_generator, &config)
+                .context("Failed to set up the storage replica")?;
+            Arc::new(ReplicatedStorage::new(
+                store,
+                replica_store,
+                replica.hedge_after,
+            ))
+        }
+        None => store,
+    };
+
+    let capabilities = Capabilities::new(&config);
+    let file_resources = match &config.resources_file {
+        Some(path) => ResourceBundle::load(path)?,
+        None => ResourceBundle::default(),
+    };
+    let resources = ResourceBundle {
+        state_machines: config.state_machines,
+        coalescing: config.coalescing,
+        scripts: config.scripts,
+        ..Default::default()
+    }
+    .reconcile(&ResourceBundle::default(), file_resources.clone());
+
+    let health = Arc::new(Health::new(store.clone(), config.health));
+    let crash_reporter = config
+        .crash_reports
+        .map(|config| Arc::new(CrashReporter::new(config, store.clone(), health.clone())));
+    Ok(Arc::new(AppState {
+        health,
+        store,
+        state_machines: StateMachines::new(resources.state_machines),
+        projections: Projections::new(config.projections, config.projection_snapshots),
+        resources_file: config.resources_file,
+        file_resources: Mutex::new(file_resources),
+        shadow: config.shadow.map(|shadow| {
+            // Only the in-memory storage exists, so that's the shadow backend, too.
+            let shadow_store =
+                InMemoryStorage::with_id_generator(make_id_generator(config.id_strategy));
+            Arc::new(Shadow::new(Arc::new(shadow_store), shadow))
+        }),
+        coalescer: Coalescer::new(resources.coalescing),
+        event_snapshots: config
+            .event_snapshots
+            .map(|config| Arc::new(EventSnapshots::new(config))),
+        read_only: AtomicBool::new(false),
+        kill_switches: KillSwitches::default(),
+        compression: config.compression,
+        outbox: Outbox::open(config.outbox_file)?,
+        legal_holds: Arc::new(LegalHolds::open(config.legal_holds_file)?),
+        access_policy: match &config.access_policy_file {
+            Some(path) => AccessPolicy::load(path)?,
+            None => AccessPolicy::default(),
+        },
+        require_api_key: config.require_api_key,
+        jwt: config.jwt.map(JwtVerifier::new).transpose()?,
+        trusted_proxies: config.trusted_proxies,
+        query_limiter: config.query_concurrency.map(QueryLimiter::new),
+        rate_limiter: config.ingest_rate_limit.map(RateLimiter::new),
+        event_policy: config.event_policy,
+        payload_limits: config.payload_limits,
+        hooks: config.hooks,
+        plugins: Plugins::new(config.plugin_dir)?,
+        scripts: Scripts::new(resources.scripts)?,
+        event_types: EventTypeCatalog::new(resources.event_types),
+        schemas: SchemaTracker::default(),
+        schema_registry: SchemaRegistry::new(resources.schemas)?,
+        samples: PayloadSamples::default(),
+        ingest_lag: IngestLag::default(),
+        producers: ProducerRegistry::new(config.producers),
+        client_fingerprints: config.client_fingerprints.map(ClientFingerprints::new),
+        dedup: DedupIndex::new(config.dedup),
+        receipts: Receipts::new(config.receipt_key),
+        query_tokens: QueryTokens::new(config.query_token_key),
+        oidc: config.oidc.map(Oidc::new),
+        merkle_log: MerkleLog::new(),
+        live_events: LiveEvents::new(),
+        subscriptions: Subscriptions::default(),
+        graphql_schema: make_schema(),
+        privacy: config.privacy,
+        exports: config.exports,
+        export_schedules: Arc::new(ExportScheduler::new(resources.export_schedules)),
+        jobs: Arc::new(Jobs::default()),
+        crash_reporter,
+        started: Instant::now(),
+        capabilities,
+    }))
+}
+
+fn make_router(shared_state: Arc<AppState>) -> Router {
+    // Routes that store or change events, rejected in read-only mode. Their `POST` requests are
+    // rate limited per API key, and their bodies may be gzip compressed.
+    let write_routes = Router::new()
+        .route(
+            "/events",
+            get(get_events)
+                .head(head_events)
+                .post(post_event)
+                .delete(delete_events)
+                .options(options_events),
+        )
+        .route("/events/conditional", post(post_conditional_event))
+        .route("/events/batch", post(post_event_batch))
+        .route("/events/ndjson", post(post_ndjson_events))
+        .route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            reject_writes_when_read_only,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            limit_ingest_rate,
+        ))
+        .route_layer(RequestDecompressionLayer::new());
+
+    // Routes that query events, limited per API key. Only `GET` and `HEAD` requests count, so
+    // the writes to `/events` pass.
+    let query_routes = Router::new()
+        .merge(write_routes)
+        .route("/events/explain", get(explain_events))
+        .route("/events/aggregate", get(aggregate_events))
+        .route("/events/gaps", get(find_event_gaps))
+        .route("/events/{id}", get(get_event))
+        .route("/exports/{id}/download", get(download_export))
+        .route("/entities/{key}/state", get(get_entity_state))
+        .route("/projections/{name}", get(get_projection))
+        .route("/shared/{token}/events", get(get_shared_events))
+        .route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            limit_queries,
+        ));
+
+    // Routes returning events or payloads, redacted for roles with masked fields.
+    let masked_routes = Router::new()
+        .merge(query_routes)
+        .route("/events/stream", get(stream_events))
+        .route("/ws", get(subscribe_events))
+        .route("/shared/{token}/stream", get(stream_shared_events))
+        .route(
+            "/event-types/{event_type}/examples",
+            get(get_event_type_examples),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            mask_payload_fields,
+        ));
+
+    // Admin APIs, only for admins if login is configured.
+    let admin_routes = Router::new()
+        .route(
+            "/admin/resources",
+            get(export_resources).put(import_resources),
+        )
+        .route("/admin/resources/reload", post(reload_resources_file))
+        .route("/admin/integrity", post(check_integrity))
+        .route("/admin/lag", get(get_ingest_lag))
+        .route("/admin/producers", get(get_producers))
+        .route("/admin/clients", get(get_client_fingerprints))
+        .route("/admin/usage", get(get_usage))
+        .route("/admin/subscriptions", get(list_subscriptions))
+        .route("/admin/query-tokens", post(create_query_token))
+        .route("/admin/merkle-roots", get(get_merkle_roots))
+        .route(
+            "/admin/legal-holds",
+            get(list_legal_holds).post(place_legal_hold),
+        )
+        .route("/admin/legal-holds/audit", get(get_legal_hold_audit))
+        .route("/admin/legal-holds/{id}", delete(release_legal_hold))
+        .route("/admin/proof/{id}", get(get_inclusion_proof))
+        .route(
+            "/admin/storage/pool",
+            get(get_storage_pool).post(set_storage_pool_limits),
+        )
+        .route(
+            "/admin/snapshots",
+            get(get_event_snapshots).post(take_event_snapshot),
+        )
+        .route("/admin/snapshots/collapse", post(collapse_event_snapshots))
+        .route("/admin/plugins", get(get_plugins))
+        .route("/admin/plugins/reload", post(reload_plugins))
+        .route("/admin/read-only", get(get_read_only).post(set_read_only))
+        .route("/admin/features", get(list_features))
+        .route("/admin/features/{feature}", post(set_feature))
+        .route(
+            "/admin/maintenance",
+            get(get_maintenance).post(set_maintenance),
+        )
+        .route(
+            "/admin/projections/{name}/rebuild",
+            post(rebuild_projection),
+        )
+        .route(
+            "/admin/projections/{name}/snapshot",
+            post(snapshot_projection),
+        )
+        // Schemas and event type documentation apply to every producer.
+        .route(
+            "/schemas/{event_type}",
+            put(put_schema).delete(delete_schema),
+        )
+        .route("/event-types/{event_type}/meta", put(put_event_type_meta))
+        .route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            require_admin,
+        ));
+
+    let router = Router::new()
+        .merge(masked_routes)
+        .route("/events/validate", post(validate_event))
+        .route("/graphql", get(graphiql).post(graphql))
+        .route("/event-types", get(list_event_types))
+        .route("/watermarks", get(get_watermarks))
+        .route("/producers/heartbeat", post(post_heartbeat))
+        .route("/receipts/verify", post(verify_receipt))
+        .route("/event-types/{event_type}/meta", get(get_event_type_meta))
+        .route("/event-types/{event_type}/drift", get(get_event_type_drift))
+        .route("/schemas", get(list_schemas))
+        .route("/schemas/{event_type}", get(get_schema))
+        .route("/exports", post(start_export))
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/{id}", get(get_job))
+        .merge(admin_routes)
+        .route("/auth/login", get(login))
+        .route("/auth/callback", get(login_callback))
+        .route("/auth/session", get(get_session))
+        .route("/auth/logout", post(logout))
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(healthz))
+        .route("/version", get(get_version))
+        .route("/capabilities", get(get_capabilities))
+        .route("/readyz", get(readyz))
         .route("/", get(welcome))
-        .with_state(shared_state)
+        .layer(middleware::from_fn(enforce_deadline))
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            track_producers,
+        ))
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            require_api_key,
+        ))
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            require_bearer_token,
+        ))
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            apply_client_identity,
+        ))
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            resolve_client_ip,
+        ))
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            record_breadcrumbs,
+        ))
+        .layer(middleware::from_fn(propagate_trace_context))
+        .layer(middleware::from_fn(report_server_errors));
+    let router = match &shared_state.compression {
+        Some(config) => router.layer(compress_responses(config)),
+        None => router,
+    };
+    router.with_state(shared_state)
+}
+
+/// Reloads the resources file whenever the process receives SIGHUP.
+#[cfg(unix)]
+fn reload_resources_on_sighup(state: Arc<AppState>) -> Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    if state.resources_file.is_none() {
+        return Ok(());
+    }
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            if let Err(err) = reload_resources(&state).await {
+                error!("Failed to reload resources: {err:#}");
+            }
+        }
+    });
+    Ok(())
 }
 
 /// Starts the server on the default port.
-#[tracing::instrument]
-pub async fn serve() -> Result<()> {
-    let app = make_server();
+#[tracing::instrument(skip_all)]
+pub async fn serve(config: ServerConfig) -> Result<()> {
+    // Dependencies enable more than one crypto provider of rustls, so TLS configs can't pick
+    // one on their own.
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let rollup_config = config.rollup.clone();
+    let retention_config = config.retention.clone();
+    let backup_config = config.backups.clone();
+    let connection_config = config.connection.clone();
+    let discovery_config = config.discovery.clone();
+    let grpc_port = config.grpc_port;
+    let secrets = config.secrets.clone();
+    let sentry_config = config.sentry.clone();
+    let state = make_app_state(config)?;
+    if let Some(sentry_config) = sentry_config {
+        sentry::init(sentry_config);
+        // Crash reports are sent to Sentry with more context.
+        if state.crash_reporter.is_none() {
+            sentry::capture_panics();
+        }
+    }
+    if let Some(crash_reporter) = &state.crash_reporter {
+        crash_reporter.clone().install();
+    }
+    // Background tasks and connections hold a receiver of it, so the storage is closed only
+    // once they all stopped writing.
+    let shutdown = Shutdown::default();
+    if let Some(event_snapshots) = &state.event_snapshots {
+        event_snapshots
+            .restore(state.store.as_ref())
+            .await
+            .context("Failed to restore event snapshots")?;
+        event_snapshots
+            .clone()
+            .spawn(state.store.clone(), shutdown.subscribe());
+    }
+    verify_integrity(&state, true).await;
+    // Requests queued before a restart are applied now, so they are delivered at least once.
+    let applied = apply_outbox(&state, &mut *state.outbox.lock().await)
+        .await
+        .context("Failed to apply the outbox")?;
+    if applied > 0 {
+        info!("Applied {applied} requests left in the outbox");
+    }
+    #[cfg(unix)]
+    reload_resources_on_sighup(state.clone())?;
+    rotate_signing_keys(state.clone(), secrets);
+    state.health.clone().spawn(shutdown.subscribe());
+    if let Some(rollup_config) = rollup_config {
+        rollup::spawn(
+            state.store.clone(),
+            state.health.clone(),
+            state.legal_holds.clone(),
+            rollup_config,
+            shutdown.subscribe(),
+        );
+    }
+    if let Some(retention_config) = retention_config {
+        retention::spawn(
+            state.store.clone(),
+            state.health.clone(),
+            state.legal_holds.clone(),
+            retention_config,
+            shutdown.subscribe(),
+        );
+    }
+    if let Some(backup_config) = backup_config {
+        backups::spawn(state.store.clone(), backup_config, shutdown.subscribe());
+    }
+    if let Some(export_config) = state.exports.clone() {
+        let jobs = state.jobs.clone();
+        let store = state.store.clone();
+        state
+            .export_schedules
+            .clone()
+            .spawn(store, export_config, jobs, shutdown.subscribe());
+    }
+    let mut grpc = grpc_port.map(|port| {
+        let address = SocketAddr::new(connection_config.address.ip(), port);
+        let tls = connection_config.tls.clone();
+        tokio::spawn(grpc::serve(
+            state.clone(),
+            address,
+            tls,
+            shutdown.subscribe(),
+        ))
+    });
+    let store = state.store.clone();
+    let app = make_router(state);
+    let tls = match &connection_config.tls {
+        Some(tls_config) => Some(
+            make_acceptor(tls_config, connection_config.http2).context("Failed to set up TLS")?,
+        ),
+        None => None,
+    };
 
-    info!("Listening on http://localhost:{}", PORT);
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", PORT))
+    let address = connection_config.address;
+    // With a separate HTTPS port, plain HTTP keeps being served on the main one.
+    let (tls, https) = match connection_config.tls.as_ref().and_then(|tls| tls.port) {
+        Some(port) => (
+            None,
+            tls.map(|tls| (SocketAddr::new(address.ip(), port), tls)),
+        ),
+        None => (tls, None),
+    };
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("Listening on {scheme}://{address}");
+    let listener = tokio::net::TcpListener::bind(address)
         .await
-        .with_context(|| format!("Failed to bind to port {PORT}"))?;
+        .with_context(|| format!("Failed to bind to {address}"))?;
+    let https = match https {
+        Some((https_address, tls)) => {
+            info!("Listening on https://{https_address}");
+            let listener = tokio::net::TcpListener::bind(https_address)
+                .await
+                .with_context(|| format!("Failed to bind to {https_address}"))?;
+            Some(serve_connections(
+                listener,
+                app.clone(),
+                &connection_config,
+                Some(tls),
+                shutdown.subscribe(),
+            ))
+        }
+        None => None,
+    };
 
-    axum::serve(listener, app)
+    let registration = match &discovery_config {
+        Some(discovery_config) => Some(
+            discovery::register(discovery_config, address.port())
+                .await
+                .context("Failed to register with service discovery")?,
+        ),
+        None => None,
+    };
+
+    tokio::select! {
+        _ = serve_connections(listener, app, &connection_config, tls, shutdown.subscribe()) => {}
+        Some(()) = async {
+            https?.await;
+            Some(())
+        } => {}
+        Some(result) = async { Some(grpc.as_mut()?.await) } => {
+            grpc = None;
+            result.context("gRPC server panicked")??;
+        }
+        result = shutdown_signal() => {
+            result?;
+            info!("Shutting down");
+        }
+    }
+    if let Some(registration) = registration
+        && let Err(error) = registration.deregister().await
+    {
+        warn!("Failed to deregister from service discovery: {error}");
+    }
+
+    // New connections aren't accepted anymore, the open ones finish their requests.
+    let timeout = connection_config.shutdown_timeout;
+    if tokio::time::timeout(timeout, shutdown.drain())
         .await
-        .context("Failed to start server")
+        .is_err()
+    {
+        warn!("Requests still in flight after {timeout:?}, shutting down anyway");
+    }
+    if let Some(grpc) = grpc
+        && let Ok(Err(error)) = grpc.await
+    {
+        warn!("gRPC server failed while shutting down: {error:#}");
+    }
+    if let Err(error) = store.close().await {
+        error!("Failed to close the storage: {error:?}");
+    }
+    info!("Shut down");
+    Ok(())
+}
+
+/// Rotates the signing keys when their secrets change. Other secrets are only read at startup.
+fn rotate_signing_keys(state: Arc<AppState>, secrets: Arc<Secrets>) {
+    secrets.spawn_refresh(move |name, value| {
+        if !matches!(name, "RECEIPT_KEY" | "QUERY_TOKEN_KEY" | "OIDC_SESSION_KEY") {
+            warn!("Secret of {name} changed, restart to apply it");
+            return;
+        }
+        let keys = match value.parse::<SigningKeys>() {
+            Ok(keys) => keys,
+            Err(error) => {
+                error!("Rotated secret of {name} is invalid: {error}");
+                return;
+            }
+        };
+        match name {
+            "RECEIPT_KEY" => state.receipts.rotate(keys),
+            "QUERY_TOKEN_KEY" => state.query_tokens.rotate(keys),
+            _ => {
+                if let Some(oidc) = &state.oidc {
+                    oidc.rotate_session_key(keys);
+                }
+            }
+        }
+    });
+}
+
+/// Waits for Ctrl+C, or SIGTERM on Unix.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate =
+            signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.context("Failed to listen for Ctrl+C")?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .context("Failed to listen for Ctrl+C")?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use axum::http::{HeaderName, HeaderValue, Method, header};
     use axum_test::TestServer;
 
-    use crate::{event::Event, server::make_server};
+    use std::time::Duration;
+
+    use crate::{
+        analytics::PrivacyConfig,
+        client_fingerprints::FingerprintConfig,
+        coalescing::CoalescingRule,
+        config::ServerConfig,
+        event::Event,
+        event_policy::EventTypePolicy,
+        exports::ExportConfig,
+        oidc::{OidcConfig, Session},
+        payload_limits::PayloadLimits,
+        scripts::ScriptRule,
+        server::{
+            CompressionConfig, JwtConfig, JwtKey, OverflowPolicy, RateLimitConfig, SmoothingConfig,
+            make_app_state, make_router, make_server, make_server_with_config,
+        },
+        state_machine::{StateMachineDefinition, Transition},
+    };
 
     fn make_test_server() -> TestServer {
         let app = make_server();
@@ -67,6 +929,7 @@ mod tests {
             event_type: "test".to_string(),
             timestamp: 42,
             payload: serde_json::json!({"test": "data"}),
+            ..Default::default()
         };
         let response = server.post("/events").json(&event).await;
         assert_eq!(response.status_code(), 200);
@@ -76,4 +939,2082 @@ mod tests {
         let events = events.json::<Vec<Event>>();
         assert_eq!(events, vec![event]);
     }
+
+    #[tokio::test]
+    async fn test_ingest_lag() {
+        let server = make_test_server();
+        let event = Event {
+            event_type: "test".to_string(),
+            timestamp: 42,
+            ..Default::default()
+        };
+        server.post("/events").json(&event).await;
+
+        let lag = server.get("/admin/lag").await.json::<serde_json::Value>();
+        assert_eq!(lag[0]["event_type"], "test");
+        assert_eq!(lag[0]["events"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_watermarks() {
+        let server = make_test_server();
+        let event = Event {
+            event_type: "test".to_string(),
+            timestamp: 42,
+            ..Default::default()
+        };
+        server.post("/events").json(&event).await;
+
+        let watermarks = server.get("/watermarks").await.json::<serde_json::Value>();
+        assert_eq!(watermarks[0]["event_type"], "test");
+        assert_eq!(watermarks[0]["late_events"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_receipts() {
+        let config = ServerConfig {
+            receipt_key: Some("ab".repeat(32).parse().unwrap()),
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        let event = Event {
+            event_type: "payment".to_string(),
+            timestamp: 1,
+            payload: serde_json::json!({"amount": 42}),
+            ..Default::default()
+        };
+        let response = server.post("/events").json(&event).await;
+        assert_eq!(response.status_code(), 200);
+        let mut receipt = response.json::<serde_json::Value>();
+        assert!(receipt["signature"].is_string());
+
+        let response = server.post("/receipts/verify").json(&receipt).await;
+        assert_eq!(response.json::<serde_json::Value>()["valid"], true);
+        receipt["received_at"] = serde_json::json!(0);
+        let response = server.post("/receipts/verify").json(&receipt).await;
+        assert_eq!(response.json::<serde_json::Value>()["valid"], false);
+
+        // Without a key, receipts are unsigned and can't be verified.
+        let server = make_test_server();
+        let receipt = server.post("/events").json(&event).await;
+        let receipt = receipt.json::<serde_json::Value>();
+        assert_eq!(receipt["signature"], serde_json::Value::Null);
+        let response = server.post("/receipts/verify").json(&receipt).await;
+        assert_eq!(response.status_code(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_head_and_options() {
+        let server = make_test_server();
+        for timestamp in [1, 2, 3] {
+            let event = Event {
+                event_type: "test".to_string(),
+                timestamp,
+                payload: serde_json::json!({}),
+                ..Default::default()
+            };
+            server.post("/events").json(&event).await;
+        }
+
+        let response = server
+            .method(Method::HEAD, "/events")
+            .add_query_param("start", 2)
+            .await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header("x-total-count"), "2");
+        assert!(response.as_bytes().is_empty());
+        let response = server
+            .method(Method::HEAD, "/events")
+            .add_query_param("start", 5)
+            .add_query_param("end", 3)
+            .await;
+        assert_eq!(response.header("x-total-count"), "0");
+
+        let response = server
+            .get("/events/explain")
+            .add_query_param("event_type", "test")
+            .await;
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!({
+                "index": "events_by_type_by_timestamp",
+                "estimated_rows": 3,
+                "predicates": [{"field": "event_type", "index_backed": true}],
+                "limit": 4,
+                "exceeds_limit": false,
+            })
+        );
+        let response = server
+            .get("/events/explain")
+            .add_query_param("start", 5)
+            .add_query_param("end", 3)
+            .await;
+        assert_eq!(response.json::<serde_json::Value>()["estimated_rows"], 0);
+
+        let response = server
+            .method(Method::OPTIONS, "/events")
+            .add_header(
+                header::ORIGIN,
+                HeaderValue::from_static("https://example.com"),
+            )
+            .await;
+        assert_eq!(response.status_code(), 204);
+        assert_eq!(response.header("allow"), "GET, HEAD, POST, DELETE, OPTIONS");
+        assert_eq!(
+            response.header("access-control-allow-origin"),
+            "https://example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_paginated_events() {
+        let server = make_test_server();
+        for timestamp in 1..=6 {
+            let event = Event {
+                event_type: "test".to_string(),
+                timestamp,
+                payload: serde_json::json!({}),
+                ..Default::default()
+            };
+            server.post("/events").json(&event).await;
+        }
+        let response = server.get("/events").await;
+        assert_eq!(response.status_code(), 500);
+
+        let page = server
+            .get("/events")
+            .add_query_param("limit", 4)
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(page["events"].as_array().unwrap().len(), 4);
+        let cursor = page["next_cursor"].as_str().unwrap();
+
+        let page = server
+            .get("/events")
+            .add_query_param("cursor", cursor)
+            .await
+            .json::<serde_json::Value>();
+        let timestamps: Vec<_> = page["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event["timestamp"].as_u64().unwrap())
+            .collect();
+        assert_eq!(timestamps, vec![5, 6]);
+        assert_eq!(page["next_cursor"], serde_json::Value::Null);
+
+        let response = server
+            .get("/events")
+            .add_query_param("cursor", "nope")
+            .await;
+        assert_eq!(response.status_code(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_delete_events() {
+        let server = make_test_server();
+        for timestamp in 1..=4 {
+            let event = Event {
+                event_type: "test".to_string(),
+                timestamp,
+                payload: serde_json::json!({}),
+                ..Default::default()
+            };
+            server.post("/events").json(&event).await;
+        }
+        let response = server.delete("/events").await;
+        assert_eq!(response.status_code(), 400);
+
+        let response = server
+            .delete("/events")
+            .add_query_param("event_type", "test")
+            .add_query_param("end", 2)
+            .await;
+        assert_eq!(response.json::<serde_json::Value>()["deleted"], 2);
+        let events = server.get("/events").await.json::<Vec<Event>>();
+        let timestamps: Vec<_> = events.iter().map(|event| event.timestamp).collect();
+        assert_eq!(timestamps, vec![3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_legal_holds() {
+        let server = make_test_server();
+        let event = Event {
+            event_type: "payment".to_string(),
+            timestamp: 15,
+            payload: serde_json::json!({}),
+            ..Default::default()
+        };
+        server.post("/events").json(&event).await;
+        let mut hold = serde_json::json!({"event_type": "payment", "start": 10, "end": 20});
+        hold["reason"] = serde_json::json!("");
+        let response = server.post("/admin/legal-holds").json(&hold).await;
+        assert_eq!(response.status_code(), 400);
+        hold["reason"] = serde_json::json!("case 7");
+        let response = server.post("/admin/legal-holds").json(&hold).await;
+        assert_eq!(response.status_code(), 201);
+        let hold_id = response.json::<serde_json::Value>()["id"].as_u64().unwrap();
+
+        let response = server.delete("/events").add_query_param("end", 100).await;
+        assert_eq!(response.status_code(), 409);
+        let response = server.delete("/events").add_query_param("start", 21).await;
+        assert_eq!(response.status_code(), 200);
+
+        let response = server
+            .delete(&format!("/admin/legal-holds/{hold_id}"))
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let response = server
+            .delete(&format!("/admin/legal-holds/{hold_id}"))
+            .await;
+        assert_eq!(response.status_code(), 404);
+        let holds = server
+            .get("/admin/legal-holds")
+            .await
+            .json::<Vec<serde_json::Value>>();
+        assert!(holds.is_empty());
+        let audit = server
+            .get("/admin/legal-holds/audit")
+            .await
+            .json::<Vec<serde_json::Value>>();
+        let actions: Vec<_> = audit.iter().map(|entry| entry["action"].clone()).collect();
+        assert_eq!(actions, ["placed", "released"]);
+
+        let response = server.delete("/events").add_query_param("end", 100).await;
+        assert_eq!(response.json::<serde_json::Value>()["deleted"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_merkle_proofs() {
+        let server = make_test_server();
+        let mut event_ids = vec![];
+        for timestamp in 1..=3 {
+            let event = Event {
+                event_type: "test".to_string(),
+                timestamp,
+                payload: serde_json::json!({}),
+                ..Default::default()
+            };
+            let receipt = server.post("/events").json(&event).await;
+            event_ids.push(receipt.json::<serde_json::Value>()["event_id"].clone());
+        }
+        let roots = server
+            .get("/admin/merkle-roots")
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(roots[0]["partition"], "test");
+        assert_eq!(roots[0]["tree_size"], 3);
+
+        let event_id = event_ids[1].as_str().unwrap();
+        let proof = server
+            .get(&format!("/admin/proof/{event_id}"))
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(proof["leaf_index"], 1);
+        assert_eq!(proof["root"], roots[0]["root"]);
+        assert_eq!(proof["path"].as_array().unwrap().len(), 2);
+        let response = server.get("/admin/proof/12345").await;
+        assert_eq!(response.status_code(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_get_event_by_id() {
+        let server = make_test_server();
+        let event = Event {
+            event_type: "test".to_string(),
+            timestamp: 1,
+            payload: serde_json::json!({"a": 1}),
+            ..Default::default()
+        };
+        let receipt = server.post("/events").json(&event).await;
+        let event_id = receipt.json::<serde_json::Value>()["event_id"].clone();
+        let event_id = event_id.as_str().unwrap();
+
+        let response = server.get(&format!("/events/{event_id}")).await;
+        assert_eq!(response.status_code(), 200);
+        let stored = response.json::<serde_json::Value>();
+        assert_eq!(stored["event_id"], event_id);
+        assert_eq!(stored["payload"], serde_json::json!({"a": 1}));
+        assert_eq!(response.json::<Event>(), event);
+        let events = server.get("/events").await.json::<serde_json::Value>();
+        assert_eq!(events[0]["event_id"], event_id);
+
+        assert_eq!(server.get("/events/12345").await.status_code(), 404);
+        assert_eq!(server.get("/events/nope").await.status_code(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_producers() {
+        let server = make_test_server();
+        let event = Event {
+            event_type: "test".to_string(),
+            timestamp: 1,
+            payload: serde_json::json!({}),
+            ..Default::default()
+        };
+        server
+            .post("/events")
+            .add_header(
+                HeaderName::from_static("x-producer-id"),
+                HeaderValue::from_static("shipper-1"),
+            )
+            .add_header(
+                HeaderName::from_static("x-producer-version"),
+                HeaderValue::from_static("1.2.0"),
+            )
+            .add_header(
+                HeaderName::from_static("x-producer-sequence"),
+                HeaderValue::from_static("0"),
+            )
+            .json(&event)
+            .await;
+        // The events numbered 1 to 4 are lost on the way.
+        server
+            .post("/events/batch")
+            .add_header(
+                HeaderName::from_static("x-producer-id"),
+                HeaderValue::from_static("shipper-1"),
+            )
+            .add_header(
+                HeaderName::from_static("x-producer-sequence"),
+                HeaderValue::from_static("5"),
+            )
+            .json(&[event.clone(), event.clone()])
+            .await;
+        let response = server
+            .post("/producers/heartbeat")
+            .add_header(
+                HeaderName::from_static("x-producer-id"),
+                HeaderValue::from_static("shipper-1"),
+            )
+            .await;
+        assert_eq!(response.status_code(), 204);
+        let response = server.post("/producers/heartbeat").await;
+        assert_eq!(response.status_code(), 400);
+
+        let producers = server
+            .get("/admin/producers")
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(producers[0]["producer_id"], "shipper-1");
+        assert_eq!(producers[0]["version"], "1.2.0");
+        assert_eq!(producers[0]["requests"], 3);
+        assert_eq!(producers[0]["silent"], false);
+        assert_eq!(producers[0]["next_sequence"], 7);
+        assert_eq!(producers[0]["missing_events"], 4);
+        assert_eq!(producers[0]["gaps"][0]["from"], 1);
+        assert_eq!(producers[0]["gaps"][0]["to"], 4);
+        let silent = server
+            .get("/admin/producers")
+            .add_query_param("silent", true)
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(silent, serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let server = make_test_server();
+        let version = server.get("/version").await.json::<serde_json::Value>();
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(version["storage_backend"], "memory");
+        assert!(
+            version["git_sha"]
+                .as_str()
+                .is_some_and(|sha| !sha.is_empty())
+        );
+        assert!(
+            version["build_timestamp"]
+                .as_u64()
+                .is_some_and(|time| time > 0)
+        );
+        assert!(version["features"].is_array());
+        assert!(version["started_at"].as_u64().is_some_and(|time| time > 0));
+    }
+
+    #[tokio::test]
+    async fn test_capabilities() {
+        let server = make_test_server();
+        let capabilities = server
+            .get("/capabilities")
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(capabilities["streaming"]["websocket"], true);
+        assert_eq!(capabilities["streaming"]["grpc"], false);
+        assert_eq!(capabilities["multi_tenancy"], false);
+        assert_eq!(
+            capabilities["formats"]["ingest"],
+            serde_json::json!(["json", "ndjson"])
+        );
+        assert_eq!(capabilities["formats"]["exports"], serde_json::json!([]));
+        assert_eq!(capabilities["auth"]["api_keys"], false);
+
+        let config = ServerConfig {
+            grpc_port: Some(50051),
+            exports: Some(ExportConfig {
+                destination: "file:///tmp/exports".to_string(),
+            }),
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        let capabilities = server
+            .get("/capabilities")
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(capabilities["streaming"]["grpc"], true);
+        assert_eq!(
+            capabilities["formats"]["exports"],
+            serde_json::json!(["ndjson", "parquet"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_health_checks() {
+        let server = make_test_server();
+        let response = server.get("/healthz").await;
+        assert_eq!(response.status_code(), 200);
+
+        let response = server.get("/readyz").await;
+        assert_eq!(response.status_code(), 200);
+        let readiness = response.json::<serde_json::Value>();
+        assert_eq!(readiness["ready"], true);
+        assert_eq!(readiness["degraded"], false);
+        assert!(readiness["storage_error"].is_null());
+
+        let response = server.post("/admin/integrity").await;
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!({"checked_events": 0, "problems": [], "repaired": false})
+        );
+
+        // The in-memory storage has no connection pool.
+        let response = server.get("/admin/storage/pool").await;
+        assert_eq!(response.status_code(), 404);
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "NO_CONNECTION_POOL"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trace_headers() {
+        let server = make_test_server();
+        let response = server.get("/").await;
+        let trace_id = response.header("x-trace-id").to_str().unwrap().to_string();
+        assert_eq!(trace_id.len(), 32);
+        let traceparent = response.header("traceparent");
+        assert!(
+            traceparent
+                .to_str()
+                .unwrap()
+                .starts_with(&format!("00-{trace_id}-"))
+        );
+
+        // An incoming trace is continued.
+        let response = server
+            .get("/")
+            .add_header(
+                HeaderName::from_static("traceparent"),
+                HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            )
+            .await;
+        assert_eq!(
+            response.header("x-trace-id"),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(response.header("x-request-id").len(), 16);
+
+        // A client's request id is kept, unless it isn't safe to log.
+        let request_id = HeaderName::from_static("x-request-id");
+        let response = server
+            .get("/")
+            .add_header(request_id.clone(), HeaderValue::from_static("checkout-42"))
+            .await;
+        assert_eq!(response.header("x-request-id"), "checkout-42");
+        let response = server
+            .get("/")
+            .add_header(request_id, HeaderValue::from_static("a b"))
+            .await;
+        assert_ne!(response.header("x-request-id"), "a b");
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let server = make_test_server();
+        let event = Event {
+            event_type: "test".to_string(),
+            ..Default::default()
+        };
+
+        let response = server
+            .post("/admin/read-only")
+            .json(&serde_json::json!({"enabled": true}))
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let response = server.post("/events").json(&event).await;
+        assert_eq!(response.status_code(), 503);
+        let response = server.get("/events").await;
+        assert_eq!(response.status_code(), 200);
+
+        server
+            .post("/admin/read-only")
+            .json(&serde_json::json!({"enabled": false}))
+            .await;
+        let response = server.post("/events").json(&event).await;
+        assert_eq!(response.status_code(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+        use std::io::{Read, Write};
+
+        let config = ServerConfig {
+            compression: Some(CompressionConfig::default()),
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        let payload = serde_json::json!({"message": "a".repeat(500)});
+        let events: Vec<_> = (0..50)
+            .map(|i| serde_json::json!({"event_type": "test", "timestamp": i, "payload": payload}))
+            .collect();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&serde_json::to_vec(&events).unwrap())
+            .unwrap();
+        let response = server
+            .post("/events/batch")
+            .add_header(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"))
+            .add_header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )
+            .bytes(encoder.finish().unwrap().into())
+            .await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.json::<serde_json::Value>()["accepted"], 50);
+
+        let response = server
+            .get("/events")
+            .add_query_param("limit", 4)
+            .add_header(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"))
+            .await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let mut body = String::new();
+        GzDecoder::new(response.as_bytes().as_ref())
+            .read_to_string(&mut body)
+            .unwrap();
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(page["events"][0]["payload"], payload);
+
+        // Small responses aren't worth compressing.
+        let response = server
+            .get("/capabilities")
+            .add_header(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"))
+            .await;
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_kill_switches() {
+        let server = make_test_server();
+        let response = server
+            .post("/admin/features/analytics")
+            .json(&serde_json::json!({"enabled": false}))
+            .await;
+        assert_eq!(response.status_code(), 400);
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "MISSING_DISABLE_REASON"
+        );
+
+        let response = server
+            .post("/admin/features/analytics")
+            .json(&serde_json::json!({"enabled": false, "reason": "Incident 42"}))
+            .await;
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!({"feature": "analytics", "enabled": false, "reason": "Incident 42"})
+        );
+        let response = server.get("/events/aggregate").await;
+        assert_eq!(response.status_code(), 503);
+        let error = response.json::<serde_json::Value>();
+        assert_eq!(error["error"], "FEATURE_DISABLED");
+        assert_eq!(
+            error["message"],
+            "Feature 'analytics' is disabled: Incident 42"
+        );
+        // Other features are still on.
+        let response = server
+            .delete("/events")
+            .add_query_param("event_type", "test")
+            .await;
+        assert_eq!(response.status_code(), 200);
+
+        let features = server
+            .get("/admin/features")
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(features.as_array().unwrap().len(), 3);
+        assert_eq!(features[1]["feature"], "analytics");
+        assert_eq!(features[1]["enabled"], false);
+
+        server
+            .post("/admin/features/analytics")
+            .json(&serde_json::json!({"enabled": true}))
+            .await;
+        let response = server.get("/events/aggregate").await;
+        assert_eq!(response.status_code(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        let server = make_test_server();
+        let event = Event {
+            event_type: "test".to_string(),
+            ..Default::default()
+        };
+
+        server
+            .post("/admin/maintenance")
+            .json(&serde_json::json!({"enabled": true}))
+            .await;
+        let response = server.post("/events").json(&event).await;
+        assert_eq!(response.status_code(), 202);
+        let response = server
+            .post("/events")
+            .add_query_param("expected_version", 0)
+            .json(&event)
+            .await;
+        assert_eq!(response.status_code(), 503);
+        let response = server.method(Method::HEAD, "/events").await;
+        assert_eq!(response.header("x-total-count"), "0");
+
+        // Queued events are stored when maintenance ends.
+        server
+            .post("/admin/maintenance")
+            .json(&serde_json::json!({"enabled": false}))
+            .await;
+        let events = server.get("/events").await.json::<Vec<Event>>();
+        assert_eq!(events, vec![event]);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_event() {
+        let server = make_test_server();
+        let request = serde_json::json!({
+            "event": {"event_type": "signup", "timestamp": 1, "payload": {"user_id": 7}},
+            "condition": {"filter": {"event_type": "signup", "payload": {"user_id": 7}}},
+        });
+
+        let response = server.post("/events/conditional").json(&request).await;
+        assert_eq!(response.status_code(), 200);
+
+        // The second signup of the same user is rejected.
+        let response = server.post("/events/conditional").json(&request).await;
+        assert_eq!(response.status_code(), 412);
+
+        // No event matches an inverted time range.
+        let request = serde_json::json!({
+            "event": {"event_type": "signup", "timestamp": 2, "payload": {"user_id": 8}},
+            "condition": {"filter": {"event_type": "signup", "start": 5, "end": 3}},
+        });
+        let response = server.post("/events/conditional").json(&request).await;
+        assert_eq!(response.status_code(), 200);
+
+        let response = server.method(Method::HEAD, "/events").await;
+        assert_eq!(response.header("x-total-count"), "2");
+    }
+
+    #[tokio::test]
+    async fn test_expected_version() {
+        let server = make_test_server();
+        let event = Event {
+            event_type: "order_created".to_string(),
+            timestamp: 1,
+            correlation_id: Some("order-1".to_string()),
+            ..Default::default()
+        };
+
+        let response = server
+            .post("/events")
+            .add_query_param("expected_version", 0)
+            .json(&event)
+            .await;
+        assert_eq!(response.status_code(), 200);
+
+        // Another writer appended to the stream in the meantime.
+        let response = server
+            .post("/events")
+            .add_query_param("expected_version", 0)
+            .json(&event)
+            .await;
+        assert_eq!(response.status_code(), 409);
+
+        let response = server
+            .post("/events")
+            .add_query_param("expected_version", 1)
+            .json(&event)
+            .await;
+        assert_eq!(response.status_code(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_atomic_batch() {
+        let config = ServerConfig {
+            event_policy: EventTypePolicy {
+                deny: vec!["debug_*".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        let events = serde_json::json!([
+            {"event_type": "test", "timestamp": 1, "payload": {}},
+            {"event_type": "debug_ping", "timestamp": 2, "payload": {}},
+        ]);
+
+        // The denied second event prevents the first one from being stored.
+        let response = server
+            .post("/events/batch")
+            .add_query_param("atomic", true)
+            .json(&events)
+            .await;
+        assert_eq!(response.status_code(), 403);
+        let response = server.method(Method::HEAD, "/events").await;
+        assert_eq!(response.header("x-total-count"), "0");
+
+        // Without `atomic`, only the denied event is rejected.
+        let response = server.post("/events/batch").json(&events).await;
+        assert_eq!(response.status_code(), 200);
+        let report = response.json::<serde_json::Value>();
+        assert_eq!(report["accepted"], 1);
+        assert_eq!(report["rejected"], 1);
+        assert_eq!(report["errors"][0]["index"], 1);
+        assert_eq!(report["errors"][0]["error"], "EVENT_TYPE_FORBIDDEN");
+        let response = server.method(Method::HEAD, "/events").await;
+        assert_eq!(response.header("x-total-count"), "1");
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_events() {
+        let server = make_test_server();
+        let body = concat!(
+            "{\"event_type\": \"test\", \"timestamp\": 1, \"payload\": {}}\n",
+            "\n",
+            "not json\n",
+            "{\"event_type\": \"test\", \"timestamp\": 2, \"payload\": {}}",
+        );
+        let response = server.post("/events/ndjson").text(body).await;
+        assert_eq!(response.status_code(), 200);
+        let report = response.json::<serde_json::Value>();
+        assert_eq!(report["accepted"], 2);
+        assert_eq!(report["rejected"], 1);
+        assert_eq!(report["errors"][0]["index"], 2);
+        assert_eq!(report["errors"][0]["error"], "INVALID_EVENT");
+        let response = server.method(Method::HEAD, "/events").await;
+        assert_eq!(response.header("x-total-count"), "2");
+    }
+
+    #[tokio::test]
+    async fn test_coalescing() {
+        let config = ServerConfig {
+            coalescing: vec![CoalescingRule {
+                event_type: "heartbeat".to_string(),
+                window: 10,
+            }],
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        for timestamp in [1, 2, 3, 20] {
+            let event = Event {
+                event_type: "heartbeat".to_string(),
+                timestamp,
+                payload: serde_json::json!({"host": "a"}),
+                ..Default::default()
+            };
+            let response = server.post("/events").json(&event).await;
+            assert_eq!(response.status_code(), 200);
+        }
+
+        let events = server.get("/events").await.json::<Vec<Event>>();
+        let counts: Vec<_> = events.iter().map(|event| event.count).collect();
+        assert_eq!(counts, vec![Some(3), None]);
+
+        // Counted by type as the events they stand for.
+        let response = server
+            .get("/events/aggregate")
+            .add_query_param("group_by", "event_type")
+            .await;
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!([{"key": "heartbeat", "count": 4}])
+        );
+        let response = server
+            .get("/events/aggregate")
+            .add_query_param("group_by", "event_type")
+            .add_query_param("start", 10)
+            .await;
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!([{"key": "heartbeat", "count": 1}])
+        );
+        let response = server
+            .get("/events/aggregate")
+            .add_query_param("group_by", "event_type")
+            .add_query_param("start", 5)
+            .add_query_param("end", 3)
+            .await;
+        assert_eq!(response.json::<serde_json::Value>(), serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_resources_export_import() {
+        let server = make_test_server();
+        let bundle = serde_json::json!({
+            "state_machines": [{
+                "name": "order",
+                "key_field": "order_id",
+                "initial_state": "new",
+                "transitions": [{"event_type": "order_paid", "from": ["new"], "to": "paid"}],
+            }],
+            "coalescing": [{"event_type": "heartbeat", "window": 60}],
+            "scripts": [{
+                "name": "alert",
+                "event_type": "order_*",
+                "script": "alerts.push(event.event_type);",
+                "timeout_ms": 10,
+            }],
+            "event_types": [{
+                "event_type": "order_paid",
+                "description": "An order was paid",
+                "owners": ["checkout"],
+                "examples": [{"order_id": 1}],
+            }],
+            "export_schedules": [{
+                "name": "daily",
+                "every": 86400,
+                "event_types": ["order_paid"],
+                "format": "parquet",
+                "notify_url": "http://localhost:8080/exports",
+            }],
+            "schemas": [{
+                "event_type": "order_paid",
+                "schema": {"type": "object", "required": ["order_id"]},
+            }],
+        });
+
+        let response = server.put("/admin/resources").json(&bundle).await;
+        assert_eq!(response.status_code(), 200);
+
+        let response = server.get("/admin/resources").await;
+        assert_eq!(response.json::<serde_json::Value>(), bundle);
+    }
+
+    #[tokio::test]
+    async fn test_event_catalog() {
+        let server = make_test_server();
+        let event = Event {
+            event_type: "signup".to_string(),
+            ..Default::default()
+        };
+        server.post("/events").json(&event).await;
+        let response = server.get("/event-types/order_paid/meta").await;
+        assert_eq!(response.status_code(), 404);
+
+        let meta = serde_json::json!({
+            "description": "An order was paid",
+            "owners": ["checkout"],
+            "examples": [{"order_id": 1, "amount": 10}],
+        });
+        let response = server.put("/event-types/order_paid/meta").json(&meta).await;
+        assert_eq!(response.status_code(), 200);
+        let response = server.get("/event-types/order_paid/meta").await;
+        assert_eq!(response.json::<serde_json::Value>(), meta);
+
+        let response = server.get("/event-types").await;
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!([
+                {"event_type": "order_paid", "count": 0, "meta": meta},
+                {"event_type": "signup", "count": 1},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_schema_drift() {
+        let server = make_test_server();
+        for amount in [serde_json::json!(10), serde_json::json!("10")] {
+            let event = Event {
+                event_type: "order_paid".to_string(),
+                payload: serde_json::json!({"amount": amount}),
+                ..Default::default()
+            };
+            server.post("/events").json(&event).await;
+        }
+
+        let response = server.get("/event-types/order_paid/drift").await;
+        let report = response.json::<serde_json::Value>();
+        assert_eq!(
+            report["fields"]["amount"],
+            serde_json::json!(["number", "string"])
+        );
+        assert_eq!(report["drift"][0]["kind"], "type_changed");
+        let response = server.get("/event-types/signup/drift").await;
+        assert_eq!(response.status_code(), 404);
+
+        let response = server.get("/event-types/order_paid/examples").await;
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!({"seen": 2, "examples": [{"amount": 10}, {"amount": "10"}]})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_aggregate() {
+        let server = make_test_server();
+        for (country, amount) in [("HU", 10), ("HU", 30), ("DE", 5)] {
+            let event = Event {
+                event_type: "purchase".to_string(),
+                payload: serde_json::json!({"country": country, "amount": amount}),
+                ..Default::default()
+            };
+            server.post("/events").json(&event).await;
+        }
+
+        let response = server
+            .get("/events/aggregate")
+            .add_query_param("type", "purchase")
+            .add_query_param("group_by", "payload.country")
+            .add_query_param("agg", "count,avg")
+            .add_query_param("field", "payload.amount")
+            .await;
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!([
+                {"key": "DE", "count": 1, "avg": 5.0},
+                {"key": "HU", "count": 2, "avg": 20.0},
+            ])
+        );
+        let response = server
+            .get("/events/aggregate")
+            .add_query_param("agg", "sum")
+            .await;
+        assert_eq!(response.status_code(), 400);
+
+        let response = server
+            .get("/events/aggregate")
+            .add_query_param("type", "purchase")
+            .add_query_param("start", 0)
+            .add_query_param("end", 10)
+            .add_query_param("compare_start", 100)
+            .await;
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!([{
+                "key": null,
+                "current": {"count": 3},
+                "previous": {},
+                "change": {"count": null},
+            }])
+        );
+
+        let response = server
+            .get("/events/aggregate")
+            .add_query_param("start", 0)
+            .add_query_param("end", 10)
+            .add_query_param("compare_start", u64::MAX)
+            .await;
+        assert_eq!(response.status_code(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_query_tokens() {
+        let server = make_test_server();
+        for (event_type, timestamp) in [("deploy", 10), ("deploy", 50), ("click", 20)] {
+            let event = Event {
+                event_type: event_type.to_string(),
+                timestamp,
+                ..Default::default()
+            };
+            server.post("/events").json(&event).await;
+        }
+
+        let request = serde_json::json!({"type": "deploy", "start": 20, "expires_in": 60});
+        let response = server.post("/admin/query-tokens").json(&request).await;
+        assert_eq!(response.status_code(), 201);
+        let issued = response.json::<serde_json::Value>();
+        let events_url = issued["events_url"].as_str().unwrap();
+        let events = server.get(events_url).await.json::<Vec<Event>>();
+        let timestamps: Vec<_> = events.iter().map(|event| event.timestamp).collect();
+        assert_eq!(timestamps, [50]);
+
+        let tampered = events_url.replace("/events", "0/events");
+        assert_eq!(server.get(&tampered).await.status_code(), 401);
+        let request = serde_json::json!({"type": "deploy", "expires_in": 0});
+        let response = server.post("/admin/query-tokens").json(&request).await;
+        assert_eq!(response.status_code(), 400);
+        let request = serde_json::json!({"type": "deploy", "start": 50, "end": 10});
+        let response = server.post("/admin/query-tokens").json(&request).await;
+        assert_eq!(response.status_code(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_graphql() {
+        let server = make_test_server();
+        let mutation = r#"mutation {
+            insertEvent(event: {eventType: "click", timestamp: 5, payload: {page: "home"}}) {
+                eventId
+            }
+        }"#;
+        let response = server
+            .post("/graphql")
+            .json(&serde_json::json!({"query": mutation}))
+            .await
+            .json::<serde_json::Value>();
+        let event_id = response["data"]["insertEvent"]["eventId"].clone();
+
+        let query = r#"{ events(type: "click", start: 1) { id payload } }"#;
+        let response = server
+            .post("/graphql")
+            .json(&serde_json::json!({"query": query}))
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(
+            response["data"]["events"],
+            serde_json::json!([{"id": event_id, "payload": {"page": "home"}}])
+        );
+
+        server
+            .post("/admin/read-only")
+            .json(&serde_json::json!({"enabled": true}))
+            .await;
+        let response = server
+            .post("/graphql")
+            .json(&serde_json::json!({"query": mutation}))
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(response["errors"][0]["extensions"]["code"], "READ_ONLY");
+    }
+
+    #[tokio::test]
+    async fn test_websocket_subscription() {
+        let server = TestServer::builder()
+            .http_transport()
+            .build(make_server())
+            .unwrap();
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        websocket
+            .send_json(&serde_json::json!({"types": ["click"], "payload": {"page": "home"}}))
+            .await;
+        let subscribed = websocket.receive_json::<serde_json::Value>().await;
+        let id = subscribed["subscribed"].clone();
+
+        let subscriptions = server.get("/admin/subscriptions").await;
+        let subscriptions = subscriptions.json::<serde_json::Value>();
+        assert_eq!(subscriptions[0]["id"], id);
+        for (event_type, page) in [("view", "home"), ("click", "about"), ("click", "home")] {
+            let event = Event {
+                event_type: event_type.to_string(),
+                payload: serde_json::json!({"page": page}),
+                ..Default::default()
+            };
+            server.post("/events").json(&event).await;
+        }
+        let pushed = websocket.receive_json::<serde_json::Value>().await;
+        assert_eq!(pushed["event_type"], "click");
+        assert_eq!(pushed["payload"]["page"], "home");
+
+        websocket.send_text("not a subscription").await;
+        let error = websocket.receive_json::<serde_json::Value>().await;
+        assert!(
+            error["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Invalid subscription")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_field_masks() {
+        let path = std::env::temp_dir().join(format!("access-{}.toml", std::process::id()));
+        let policy = "[api_keys]\nroot = \"admin\"\n\n\
+            [roles.anonymous]\nmasked_fields = [\"payload.email\"]\n";
+        std::fs::write(&path, policy).unwrap();
+        let config = ServerConfig {
+            access_policy_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let event = Event {
+            event_type: "signup".to_string(),
+            payload: serde_json::json!({"email": "a@example.com", "plan": "free"}),
+            ..Default::default()
+        };
+        let receipt = server
+            .post("/events")
+            .json(&event)
+            .await
+            .json::<serde_json::Value>();
+
+        let events = server.get("/events").await.json::<serde_json::Value>();
+        assert_eq!(
+            events[0]["payload"],
+            serde_json::json!({"email": "[redacted]", "plan": "free"})
+        );
+        let event_id = receipt["event_id"].as_str().unwrap();
+        let response = server.get(&format!("/events/{event_id}")).await;
+        assert_eq!(
+            response.json::<serde_json::Value>()["payload"]["email"],
+            "[redacted]"
+        );
+        let events = server
+            .get("/events")
+            .add_header(
+                HeaderName::from_static("x-api-key"),
+                HeaderValue::from_static("root"),
+            )
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(events[0]["payload"], event.payload);
+
+        let response = server
+            .get("/events/aggregate")
+            .add_query_param("group_by", "payload.email")
+            .await;
+        assert_eq!(response.status_code(), 403);
+        let response = server.get("/events/stream").await;
+        assert_eq!(response.status_code(), 403);
+        let response = server.get("/event-types/signup/examples").await;
+        assert_eq!(
+            response.json::<serde_json::Value>()["examples"],
+            serde_json::json!([{"email": "[redacted]", "plan": "free"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_live_events() {
+        let state = make_app_state(ServerConfig::default()).unwrap();
+        let mut receiver = state.live_events.subscribe();
+        let server = TestServer::new(make_router(state)).unwrap();
+        let event = Event {
+            event_type: "click".to_string(),
+            ..Default::default()
+        };
+        server.post("/events").json(&event).await;
+        server.post("/events/batch").json(&[&event, &event]).await;
+
+        let mut published = vec![];
+        while let Ok(live) = receiver.try_recv() {
+            assert_eq!(live.1, event);
+            published.push(live.0);
+        }
+        assert_eq!(published.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_admin_login() {
+        let path = std::env::temp_dir().join(format!("admins-{}.toml", std::process::id()));
+        let policy = "[api_keys]\nroot = \"admin\"\n\n[groups]\nops = \"admin\"\n\n\
+            [roles.admin]\nadmin = true\n";
+        std::fs::write(&path, policy).unwrap();
+        let config = ServerConfig {
+            access_policy_file: Some(path.clone()),
+            oidc: Some(OidcConfig {
+                issuer_url: "https://sso.example.com".to_string(),
+                client_id: "tracker".to_string(),
+                client_secret: "secret".to_string(),
+                redirect_url: "https://tracker.example.com/auth/callback".to_string(),
+                groups_claim: "groups".to_string(),
+                session_ttl: Duration::from_secs(3600),
+                session_key: None,
+            }),
+            ..Default::default()
+        };
+        let state = make_app_state(config).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let oidc = state.oidc.as_ref().unwrap();
+        let cookie = |groups: &[&str]| {
+            let session = Session {
+                subject: "u1".to_string(),
+                name: None,
+                groups: groups.iter().map(|group| group.to_string()).collect(),
+                expires_at: u64::MAX,
+            };
+            let cookie = format!("tracker_session={}", oidc.issue_session(&session));
+            HeaderValue::from_str(&cookie).unwrap()
+        };
+        let (admin, staff) = (cookie(&["ops"]), cookie(&["staff"]));
+        let server = TestServer::new(make_router(state)).unwrap();
+
+        assert_eq!(server.get("/admin/lag").await.status_code(), 401);
+        let response = server
+            .get("/admin/lag")
+            .add_header(header::COOKIE, staff)
+            .await;
+        assert_eq!(response.status_code(), 403);
+        let response = server
+            .get("/admin/lag")
+            .add_header(header::COOKIE, admin.clone())
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let response = server
+            .get("/admin/lag")
+            .add_header(
+                HeaderName::from_static("x-api-key"),
+                HeaderValue::from_static("root"),
+            )
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let response = server
+            .get("/auth/session")
+            .add_header(header::COOKIE, admin)
+            .await;
+        assert_eq!(response.json::<serde_json::Value>()["groups"][0], "ops");
+
+        // Other routes are open.
+        assert_eq!(server.get("/events").await.status_code(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_api_keys() {
+        let path = std::env::temp_dir().join(format!("api-keys-{}.toml", std::process::id()));
+        let policy = "[api_keys]\nproducer = \"ingest\"\nanalyst = \"analyst\"\nroot = \"ops\"\n\n\
+            [roles.ingest]\nscopes = [\"ingest\"]\n\n[roles.ops]\nadmin = true\n";
+        std::fs::write(&path, policy).unwrap();
+        let config = ServerConfig {
+            access_policy_file: Some(path.clone()),
+            require_api_key: true,
+            ..Default::default()
+        };
+        let state = make_app_state(config).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let server = TestServer::new(make_router(state)).unwrap();
+        let api_key = HeaderName::from_static("x-api-key");
+        let event = Event {
+            event_type: "test".to_string(),
+            timestamp: 42,
+            payload: serde_json::json!({"test": "data"}),
+            ..Default::default()
+        };
+
+        let response = server.get("/events").await;
+        assert_eq!(response.status_code(), 401);
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "MISSING_API_KEY"
+        );
+        let response = server
+            .get("/events")
+            .add_header(api_key.clone(), HeaderValue::from_static("guess"))
+            .await;
+        assert_eq!(response.status_code(), 401);
+        let response = server
+            .post("/events")
+            .add_header(api_key.clone(), HeaderValue::from_static("producer"))
+            .json(&event)
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let response = server
+            .get("/events")
+            .add_header(api_key.clone(), HeaderValue::from_static("producer"))
+            .await;
+        assert_eq!(response.status_code(), 403);
+        let response = server
+            .get("/events")
+            .add_header(api_key.clone(), HeaderValue::from_static("analyst"))
+            .await;
+        assert_eq!(response.json::<Vec<Event>>().len(), 1);
+        let response = server
+            .delete("/events")
+            .add_header(api_key.clone(), HeaderValue::from_static("producer"))
+            .await;
+        assert_eq!(response.status_code(), 403);
+
+        // Every route reading events needs a key, GraphQL mutations the ingest scope.
+        assert_eq!(server.get("/event-types").await.status_code(), 401);
+        let query = serde_json::json!({"query": "{ events { id } }"});
+        let response = server.post("/graphql").json(&query).await;
+        let response = response.json::<serde_json::Value>();
+        assert_eq!(
+            response["errors"][0]["extensions"]["code"],
+            "MISSING_API_KEY"
+        );
+        let mutation = serde_json::json!({
+            "query": "mutation { insertEvent(event: {eventType: \"t\", timestamp: 1}) { eventId } }"
+        });
+        let response = server
+            .post("/graphql")
+            .add_header(api_key.clone(), HeaderValue::from_static("producer"))
+            .json(&mutation)
+            .await;
+        let response = response.json::<serde_json::Value>();
+        assert!(response["data"]["insertEvent"]["eventId"].is_string());
+        assert_eq!(server.get("/healthz").await.status_code(), 200);
+
+        // Without login, the admin APIs need the key of an admin role.
+        let request = serde_json::json!({"type": "test", "expires_in": 60});
+        let response = server.post("/admin/query-tokens").json(&request).await;
+        assert_eq!(response.status_code(), 401);
+        let response = server
+            .post("/admin/query-tokens")
+            .add_header(api_key.clone(), HeaderValue::from_static("producer"))
+            .json(&request)
+            .await;
+        assert_eq!(response.status_code(), 403);
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "ADMIN_API_KEY_REQUIRED"
+        );
+        let schema = serde_json::json!({"type": "object"});
+        let response = server
+            .put("/schemas/test")
+            .add_header(api_key.clone(), HeaderValue::from_static("producer"))
+            .json(&schema)
+            .await;
+        assert_eq!(response.status_code(), 403);
+        let response = server
+            .put("/schemas/test")
+            .add_header(api_key.clone(), HeaderValue::from_static("root"))
+            .json(&schema)
+            .await;
+        assert_eq!(response.status_code(), 201);
+        let response = server
+            .get("/schemas/test")
+            .add_header(api_key.clone(), HeaderValue::from_static("analyst"))
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let response = server
+            .post("/admin/query-tokens")
+            .add_header(api_key, HeaderValue::from_static("root"))
+            .json(&request)
+            .await;
+        let issued = response.json::<serde_json::Value>();
+
+        // Shared event views are authorized by their query token instead.
+        let response = server.get(issued["events_url"].as_str().unwrap()).await;
+        assert_eq!(response.json::<Vec<Event>>().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_bearer_tokens() {
+        let config = ServerConfig {
+            jwt: Some(JwtConfig {
+                key: JwtKey::Hs256("secret".to_string()),
+                issuer: Some("https://sso.example.com".to_string()),
+                audience: None,
+                roles_claim: "roles".to_string(),
+            }),
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        let bearer = |roles: &[&str], secret: &str| {
+            let claims = serde_json::json!({
+                "sub": "checkout",
+                "iss": "https://sso.example.com",
+                "exp": u32::MAX,
+                "roles": roles,
+            });
+            let key = jsonwebtoken::EncodingKey::from_secret(secret.as_bytes());
+            let token = jsonwebtoken::encode(&Default::default(), &claims, &key).unwrap();
+            HeaderValue::from_str(&format!("Bearer {token}")).unwrap()
+        };
+        let event = Event {
+            event_type: "test".to_string(),
+            timestamp: 42,
+            payload: serde_json::json!({"test": "data"}),
+            ..Default::default()
+        };
+
+        let response = server.get("/events").await;
+        assert_eq!(response.status_code(), 401);
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "BEARER_TOKEN_REQUIRED"
+        );
+        let response = server
+            .get("/events")
+            .add_header(header::AUTHORIZATION, bearer(&["reader"], "guess"))
+            .await;
+        assert_eq!(response.status_code(), 401);
+        let response = server
+            .post("/events")
+            .add_header(header::AUTHORIZATION, bearer(&["ingest"], "secret"))
+            .json(&event)
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let response = server
+            .get("/events")
+            .add_header(header::AUTHORIZATION, bearer(&["ingest"], "secret"))
+            .await;
+        assert_eq!(response.status_code(), 403);
+        let response = server
+            .delete("/events")
+            .add_query_param("event_type", "test")
+            .add_header(header::AUTHORIZATION, bearer(&["reader"], "secret"))
+            .await;
+        assert_eq!(response.status_code(), 403);
+        let response = server
+            .delete("/events")
+            .add_query_param("event_type", "test")
+            .add_header(header::AUTHORIZATION, bearer(&["admin"], "secret"))
+            .await;
+        assert_eq!(response.json::<serde_json::Value>()["deleted"], 1);
+
+        // Every route reading events needs a token, GraphQL queries the reader role.
+        assert_eq!(server.get("/event-types").await.status_code(), 401);
+        let query = serde_json::json!({"query": "{ events { id } }"});
+        let response = server
+            .post("/graphql")
+            .add_header(header::AUTHORIZATION, bearer(&["ingest"], "secret"))
+            .json(&query)
+            .await;
+        let response = response.json::<serde_json::Value>();
+        assert_eq!(response["errors"][0]["extensions"]["code"], "ROLE_REQUIRED");
+        let response = server
+            .post("/graphql")
+            .add_header(header::AUTHORIZATION, bearer(&["reader"], "secret"))
+            .json(&query)
+            .await;
+        assert!(response.json::<serde_json::Value>()["data"]["events"].is_array());
+        assert_eq!(server.get("/healthz").await.status_code(), 200);
+
+        // Shared event views are authorized by their query token instead.
+        server
+            .post("/events")
+            .add_header(header::AUTHORIZATION, bearer(&["ingest"], "secret"))
+            .json(&event)
+            .await;
+        let request = serde_json::json!({"type": "test", "expires_in": 60});
+        let response = server.post("/admin/query-tokens").json(&request).await;
+        let issued = response.json::<serde_json::Value>();
+        let response = server.get(issued["events_url"].as_str().unwrap()).await;
+        assert_eq!(response.json::<Vec<Event>>().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_ingest_rate_limit() {
+        let config = ServerConfig {
+            ingest_rate_limit: Some(RateLimitConfig {
+                rate: 0.1,
+                burst: 1.0,
+                smoothing: None,
+            }),
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        let event = Event {
+            event_type: "test".to_string(),
+            timestamp: 42,
+            payload: serde_json::json!({"test": "data"}),
+            ..Default::default()
+        };
+        let api_key = HeaderName::from_static("x-api-key");
+
+        let response = server.post("/events").json(&event).await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header("x-ratelimit-limit"), "1");
+        assert_eq!(response.header("x-ratelimit-remaining"), "0");
+        assert_eq!(response.header("x-ratelimit-reset"), "10");
+        let response = server.post("/events").json(&event).await;
+        assert_eq!(response.status_code(), 429);
+        assert_eq!(response.header(header::RETRY_AFTER), "10");
+        assert_eq!(response.header("x-ratelimit-remaining"), "0");
+        assert_eq!(server.get("/events").await.status_code(), 200);
+        let response = server
+            .post("/events")
+            .add_header(api_key, HeaderValue::from_static("other"))
+            .json(&event)
+            .await;
+        assert_eq!(response.status_code(), 200);
+
+        let usage = server.get("/admin/usage").await.json::<serde_json::Value>();
+        assert_eq!(usage.as_array().unwrap().len(), 2);
+        let anonymous = usage
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|client| client["client"] == "anonymous")
+            .unwrap();
+        assert_eq!(anonymous["requests"], 1);
+        assert_eq!(anonymous["rejected"], 1);
+        assert!(!usage.to_string().contains("other"));
+
+        let server = make_test_server();
+        let response = server.get("/admin/usage").await;
+        assert_eq!(response.status_code(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_ingest_smoothing() {
+        let config = ServerConfig {
+            ingest_rate_limit: Some(RateLimitConfig {
+                rate: 10.0,
+                burst: 1.0,
+                smoothing: Some(SmoothingConfig {
+                    max_queued: 2,
+                    max_wait: Duration::from_millis(250),
+                    overflow: OverflowPolicy::Reject,
+                }),
+            }),
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        let event = Event {
+            event_type: "test".to_string(),
+            ..Default::default()
+        };
+
+        // The burst is spread out instead of rejected, as long as it fits in the queue.
+        let responses = futures_util::future::join_all(
+            (0..4).map(|_| server.post("/events").json(&event).into_future()),
+        )
+        .await;
+        let statuses: Vec<_> = responses
+            .iter()
+            .map(|response| response.status_code().as_u16())
+            .collect();
+        assert_eq!(statuses.iter().filter(|status| **status == 200).count(), 3);
+        assert_eq!(statuses.iter().filter(|status| **status == 429).count(), 1);
+
+        let usage = server.get("/admin/usage").await.json::<serde_json::Value>();
+        assert_eq!(usage[0]["requests"], 3);
+        assert_eq!(usage[0]["rejected"], 1);
+        assert_eq!(usage[0]["queued"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_payload_limits() {
+        let config = ServerConfig {
+            payload_limits: PayloadLimits {
+                max_bytes: 64,
+                max_depth: 2,
+            },
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        let event =
+            |payload| serde_json::json!({"event_type": "test", "timestamp": 1, "payload": payload});
+
+        let response = server
+            .post("/events")
+            .json(&event(serde_json::json!({"note": "a".repeat(100)})))
+            .await;
+        assert_eq!(response.status_code(), 413);
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "PAYLOAD_TOO_LARGE"
+        );
+        let response = server
+            .post("/events")
+            .json(&event(serde_json::json!({"a": {"b": {"c": 1}}})))
+            .await;
+        assert_eq!(response.status_code(), 400);
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "PAYLOAD_TOO_DEEP"
+        );
+        let response = server.method(Method::HEAD, "/events").await;
+        assert_eq!(response.header("x-total-count"), "0");
+
+        // In a batch, only the offending event is rejected.
+        let response = server
+            .post("/events/batch")
+            .json(&[
+                event(serde_json::json!({"a": {"b": 1}})),
+                event(serde_json::json!([[[1]]])),
+            ])
+            .await;
+        let report = response.json::<serde_json::Value>();
+        assert_eq!(report["accepted"], 1);
+        assert_eq!(report["errors"][0]["error"], "PAYLOAD_TOO_DEEP");
+
+        // NDJSON lines are only buffered up to the payload limit and room for the other fields.
+        let line = event(serde_json::json!({"note": "a".repeat(100_000)})).to_string();
+        let response = server.post("/events/ndjson").text(line).await;
+        assert_eq!(response.status_code(), 400);
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "INVALID_EVENT"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_anomalies() {
+        let config = ServerConfig {
+            // Every request closes the window of the previous one.
+            client_fingerprints: Some(FingerprintConfig {
+                window: Duration::ZERO,
+                warm_up: 2,
+                rate_factor: 3.0,
+                min_events: 5,
+                ..Default::default()
+            }),
+            scripts: vec![ScriptRule {
+                name: "stolen_keys".to_string(),
+                event_type: Some("client_anomaly".to_string()),
+                script:
+                    r#"derived.push(#{ event_type: "security_alert", payload: event.payload });"#
+                        .to_string(),
+                timeout_ms: 100,
+            }],
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        let event = Event {
+            event_type: "test".to_string(),
+            timestamp: 1,
+            payload: serde_json::json!({"page": "/"}),
+            ..Default::default()
+        };
+        let api_key = HeaderName::from_static("x-api-key");
+        for _ in 0..3 {
+            server
+                .post("/events")
+                .add_header(api_key.clone(), HeaderValue::from_static("shop"))
+                .json(&event)
+                .await;
+        }
+        // A burst from the same key, reported once its window is over.
+        server
+            .post("/events/batch")
+            .add_header(api_key.clone(), HeaderValue::from_static("shop"))
+            .json(&vec![event.clone(); 10])
+            .await;
+        server
+            .post("/events")
+            .add_header(api_key, HeaderValue::from_static("shop"))
+            .json(&event)
+            .await;
+
+        let alerts = server
+            .get("/events")
+            .add_query_param("event_type", "security_alert")
+            .await
+            .json::<Vec<Event>>();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].payload["reasons"], serde_json::json!(["rate"]));
+        assert_eq!(alerts[0].payload["events"], 10);
+        // The API key isn't leaked.
+        let client = alerts[0].payload["client"].as_str().unwrap();
+        assert!(client.starts_with("key:") && !client.contains("shop"));
+
+        let clients = server
+            .get("/admin/clients")
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(clients[0]["client"], client);
+        assert_eq!(clients[0]["anomalies"], 1);
+        assert_eq!(clients[0]["event_types"], serde_json::json!(["test"]));
+    }
+
+    #[tokio::test]
+    async fn test_schema_registry() {
+        let server = make_test_server();
+        let schema = serde_json::json!({
+            "type": "object",
+            "required": ["order_id"],
+            "properties": {"amount": {"type": "number", "minimum": 0}},
+        });
+        let response = server.put("/schemas/order_paid").json(&schema).await;
+        assert_eq!(response.status_code(), 201);
+        let response = server.put("/schemas/order_paid").json(&schema).await;
+        assert_eq!(response.status_code(), 200);
+        let response = server
+            .put("/schemas/page_view")
+            .json(&serde_json::json!({"type": 7}))
+            .await;
+        assert_eq!(response.status_code(), 400);
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "INVALID_SCHEMA"
+        );
+
+        let order = |payload| Event {
+            event_type: "order_paid".to_string(),
+            timestamp: 1,
+            payload,
+            ..Default::default()
+        };
+        let response = server
+            .post("/events")
+            .json(&order(serde_json::json!({"order_id": "A1", "amount": 5})))
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let response = server
+            .post("/events")
+            .json(&order(serde_json::json!({"order_id": "A2", "amount": -5})))
+            .await;
+        assert_eq!(response.status_code(), 400);
+        let error = response.json::<serde_json::Value>();
+        assert_eq!(error["error"], "SCHEMA_VIOLATION");
+        assert_eq!(error["field_errors"][0]["path"], "/amount");
+
+        // In a batch, only the invalid event is rejected, with its field errors.
+        let response = server
+            .post("/events/batch")
+            .json(&[
+                order(serde_json::json!({"order_id": "A3"})),
+                order(serde_json::json!({"amount": 1})),
+            ])
+            .await;
+        let report = response.json::<serde_json::Value>();
+        assert_eq!(report["accepted"], 1);
+        assert_eq!(report["errors"][0]["index"], 1);
+        assert_eq!(report["errors"][0]["field_errors"][0]["path"], "");
+
+        let resources = server
+            .get("/admin/resources")
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(resources["schemas"][0]["event_type"], "order_paid");
+        let response = server.get("/schemas/order_paid").await;
+        assert_eq!(response.json::<serde_json::Value>(), schema);
+
+        let response = server.delete("/schemas/order_paid").await;
+        assert_eq!(response.status_code(), 204);
+        let response = server.get("/schemas/order_paid").await;
+        assert_eq!(response.status_code(), 404);
+        let response = server
+            .post("/events")
+            .json(&order(serde_json::json!({})))
+            .await;
+        assert_eq!(response.status_code(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_test_events() {
+        let config = ServerConfig {
+            event_policy: EventTypePolicy {
+                allow: vec!["purchase".to_string()],
+                deny: vec![],
+            },
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        for event_type in ["purchase", "test.purchase", "test.purchase"] {
+            let event = Event {
+                event_type: event_type.to_string(),
+                payload: serde_json::json!({"amount": 10}),
+                ..Default::default()
+            };
+            let response = server.post("/events").json(&event).await;
+            assert_eq!(response.status_code(), 200);
+        }
+
+        // Queryable like any other event.
+        let events = server
+            .get("/events")
+            .add_query_param("event_type", "test.purchase")
+            .await
+            .json::<Vec<Event>>();
+        assert_eq!(events.len(), 2);
+
+        // Left out of analytics, unless asked for.
+        let count = |response: axum_test::TestResponse| {
+            response.json::<serde_json::Value>()[0]["count"].clone()
+        };
+        let response = server.get("/events/aggregate").await;
+        assert_eq!(count(response), 1);
+        let response = server
+            .get("/events/aggregate")
+            .add_query_param("include_test", true)
+            .await;
+        assert_eq!(count(response), 3);
+        let response = server
+            .get("/events/aggregate")
+            .add_query_param("type", "test.purchase")
+            .await;
+        assert_eq!(count(response), 2);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency() {
+        let server = make_test_server();
+        let event = Event {
+            event_type: "order_paid".to_string(),
+            payload: serde_json::json!({"order_id": "A1"}),
+            ..Default::default()
+        };
+        let post = || {
+            server
+                .post("/events")
+                .add_header(
+                    HeaderName::from_static("idempotency-key"),
+                    HeaderValue::from_static("pay-A1"),
+                )
+                .json(&event)
+        };
+        let first = post().await.json::<serde_json::Value>();
+        let retried = post().await.json::<serde_json::Value>();
+        assert_eq!(first["event_id"], retried["event_id"]);
+
+        // A dedup id in the event works like the header.
+        let with_dedup_id = Event {
+            dedup_id: Some("pay-A1".to_string()),
+            ..event.clone()
+        };
+        let response = server.post("/events").json(&with_dedup_id).await;
+        assert_eq!(
+            response.json::<serde_json::Value>()["event_id"],
+            first["event_id"]
+        );
+
+        // A retried batch stores only the events missing from the first attempt.
+        let batch = |events: &[Event]| {
+            server
+                .post("/events/batch")
+                .add_header(
+                    HeaderName::from_static("idempotency-key"),
+                    HeaderValue::from_static("batch-1"),
+                )
+                .json(&events)
+        };
+        batch(std::slice::from_ref(&event)).await;
+        let response = batch(&[event.clone(), event.clone()]).await;
+        assert_eq!(response.json::<serde_json::Value>()["accepted"], 2);
+        let atomic = batch(&[event.clone(), event.clone(), event.clone()])
+            .add_query_param("atomic", true)
+            .await;
+        assert_eq!(atomic.json::<serde_json::Value>()["accepted"], 3);
+
+        let events = server.get("/events").await.json::<Vec<Event>>();
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|event| event.dedup_id.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_private_aggregate() {
+        let config = ServerConfig {
+            privacy: Some(PrivacyConfig {
+                min_group_size: 2,
+                epsilon: None,
+            }),
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        for country in ["HU", "HU", "DE"] {
+            let event = Event {
+                event_type: "purchase".to_string(),
+                payload: serde_json::json!({"country": country}),
+                ..Default::default()
+            };
+            server.post("/events").json(&event).await;
+        }
+
+        let response = server
+            .get("/events/aggregate")
+            .add_query_param("group_by", "payload.country")
+            .await;
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!([{"key": "HU", "count": 2}])
+        );
+        let response = server
+            .get("/events/aggregate")
+            .add_query_param("agg", "first")
+            .await;
+        assert_eq!(response.status_code(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_event_gaps() {
+        let server = make_test_server();
+        for timestamp in [100, 110, 200] {
+            let event = Event {
+                event_type: "heartbeat".to_string(),
+                timestamp,
+                ..Default::default()
+            };
+            server.post("/events").json(&event).await;
+        }
+
+        let response = server
+            .get("/events/gaps")
+            .add_query_param("type", "heartbeat")
+            .add_query_param("min_gap", 30)
+            .add_query_param("end", 240)
+            .await;
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!([
+                {"start": 110, "end": 200, "duration": 90},
+                {"start": 200, "end": 240, "duration": 40},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_job() {
+        let request = serde_json::json!({"filter": {"event_type": "test"}, "name": "test.ndjson"});
+        let response = make_test_server().post("/exports").json(&request).await;
+        assert_eq!(response.status_code(), 404);
+
+        let dir = std::env::temp_dir().join(format!("export-job-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = ServerConfig {
+            exports: Some(ExportConfig {
+                destination: format!("file://{}", dir.display()),
+            }),
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        let event = Event {
+            event_type: "test".to_string(),
+            ..Default::default()
+        };
+        server.post("/events").json(&event).await;
+        let response = server.post("/exports").json(&request).await;
+        assert_eq!(response.status_code(), 202);
+        let id = response.json::<serde_json::Value>()["id"].clone();
+
+        let job = loop {
+            let job = server.get(&format!("/jobs/{id}")).await;
+            let job = job.json::<serde_json::Value>();
+            if job["state"] != "running" {
+                break job;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        assert_eq!(job["state"], "succeeded");
+        assert_eq!(job["result"]["events"], 1);
+        let content = std::fs::read_to_string(dir.join("test.ndjson")).unwrap();
+
+        let url = format!("/exports/{id}/download");
+        let response = server.get(&url).await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.text(), content);
+        let etag = response.header(header::ETAG);
+        assert_eq!(
+            etag,
+            format!("\"{}\"", job["result"]["sha256"].as_str().unwrap())
+        );
+
+        let response = server
+            .get(&url)
+            .add_header(header::RANGE, HeaderValue::from_static("bytes=10-"))
+            .add_header(header::IF_RANGE, etag.clone())
+            .await;
+        assert_eq!(response.status_code(), 206);
+        assert_eq!(response.text(), content[10..]);
+        let content_range = format!("bytes 10-{}/{}", content.len() - 1, content.len());
+        assert_eq!(response.header(header::CONTENT_RANGE), content_range);
+
+        // A changed export is sent whole.
+        let response = server
+            .get(&url)
+            .add_header(header::RANGE, HeaderValue::from_static("bytes=10-"))
+            .add_header(header::IF_RANGE, HeaderValue::from_static("\"outdated\""))
+            .await;
+        assert_eq!(response.status_code(), 200);
+
+        let response = server
+            .get(&url)
+            .add_header(header::RANGE, HeaderValue::from_static("bytes=100000-"))
+            .await;
+        assert_eq!(response.status_code(), 416);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scripts() {
+        let server = make_test_server();
+        let script = r#"
+            if event.payload.amount > 100 {
+                derived.push(#{ event_type: "large_order", payload: event.payload });
+            }
+        "#;
+        let bundle = serde_json::json!({
+            "scripts": [{"name": "large_orders", "event_type": "order_*", "script": script}],
+        });
+        let response = server.put("/admin/resources").json(&bundle).await;
+        assert_eq!(response.status_code(), 200);
+
+        for amount in [50, 500] {
+            let event = Event {
+                event_type: "order_paid".to_string(),
+                timestamp: 7,
+                payload: serde_json::json!({"amount": amount}),
+                ..Default::default()
+            };
+            let response = server.post("/events").json(&event).await;
+            assert_eq!(response.status_code(), 200);
+        }
+        let events = server.get("/events").await.json::<Vec<Event>>();
+        let event_types: Vec<_> = events
+            .iter()
+            .map(|event| event.event_type.as_str())
+            .collect();
+        assert_eq!(event_types, ["order_paid", "order_paid", "large_order"]);
+        assert_eq!(events[2].timestamp, 7);
+
+        // Scripts that don't compile are rejected.
+        let bundle = serde_json::json!({"scripts": [{"name": "broken", "script": "if {"}]});
+        let response = server.put("/admin/resources").json(&bundle).await;
+        assert_eq!(response.status_code(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_entity_state_machine() {
+        let config = ServerConfig {
+            state_machines: vec![StateMachineDefinition {
+                name: "order".to_string(),
+                key_field: "order_id".to_string(),
+                initial_state: "new".to_string(),
+                transitions: vec![
+                    Transition {
+                        event_type: "order_paid".to_string(),
+                        from: vec!["new".to_string()],
+                        to: "paid".to_string(),
+                    },
+                    Transition {
+                        event_type: "order_shipped".to_string(),
+                        from: vec!["paid".to_string()],
+                        to: "shipped".to_string(),
+                    },
+                ],
+            }],
+            ..Default::default()
+        };
+        let server = TestServer::new(make_server_with_config(config).unwrap()).unwrap();
+        let order_event = |event_type: &str| Event {
+            event_type: event_type.to_string(),
+            timestamp: 42,
+            payload: serde_json::json!({"order_id": "abc"}),
+            ..Default::default()
+        };
+
+        let response = server.get("/entities/abc/state").await;
+        assert_eq!(response.status_code(), 404);
+
+        let response = server
+            .post("/events")
+            .json(&order_event("order_shipped"))
+            .await;
+        assert_eq!(response.status_code(), 409);
+
+        let response = server
+            .post("/events")
+            .json(&order_event("order_paid"))
+            .await;
+        assert_eq!(response.status_code(), 200);
+
+        let response = server
+            .post("/events/validate")
+            .json(&order_event("order_shipped"))
+            .await;
+        assert_eq!(
+            response.json::<serde_json::Value>()["transitions"][0]["to"],
+            "shipped"
+        );
+
+        let response = server.get("/entities/abc/state").await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!({"key": "abc", "states": {"order": "paid"}})
+        );
+    }
 }
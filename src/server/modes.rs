@@ -0,0 +1,94 @@
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, atomic::Ordering};
+use tracing::{info, instrument};
+
+use crate::server::{AppState, app_error::AppError, ingest::apply_outbox};
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ReadOnlyMode {
+    enabled: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct MaintenanceMode {
+    enabled: bool,
+}
+
+/// Returns whether the server is in read-only mode.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_read_only(State(state): State<Arc<AppState>>) -> Json<ReadOnlyMode> {
+    Json(ReadOnlyMode {
+        enabled: state.read_only.load(Ordering::Relaxed),
+    })
+}
+
+/// Switches read-only mode on or off. While it is on, requests that would store or change
+/// events are rejected, but queries are served as usual.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn set_read_only(
+    State(state): State<Arc<AppState>>,
+    Json(mode): Json<ReadOnlyMode>,
+) -> Json<ReadOnlyMode> {
+    info!(
+        "Read-only mode {}",
+        if mode.enabled { "enabled" } else { "disabled" }
+    );
+    state.read_only.store(mode.enabled, Ordering::Relaxed);
+    Json(mode)
+}
+
+/// Returns whether the server is in maintenance mode.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_maintenance(State(state): State<Arc<AppState>>) -> Json<MaintenanceMode> {
+    Json(MaintenanceMode {
+        enabled: state.outbox.lock().await.maintenance(),
+    })
+}
+
+/// Switches maintenance mode on or off. While it is on, ingested events are queued into the
+/// outbox instead of being stored. Switching it off applies the queued requests before new
+/// ones are accepted.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn set_maintenance(
+    State(state): State<Arc<AppState>>,
+    Json(mode): Json<MaintenanceMode>,
+) -> Result<Json<MaintenanceMode>, AppError> {
+    let mut outbox = state.outbox.lock().await;
+    if mode.enabled {
+        info!("Maintenance mode enabled");
+    } else if outbox.maintenance() {
+        let applied = apply_outbox(&state, &mut outbox)
+            .await
+            .map_err(|error| AppError::OutboxFailed(error.to_string()))?;
+        info!("Maintenance mode disabled, applied {applied} queued requests");
+    }
+    outbox.set_maintenance(mode.enabled);
+    Ok(Json(mode))
+}
+
+/// Rejects requests with mutating methods in read-only mode.
+pub async fn reject_writes_when_read_only(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let safe = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if !safe && state.read_only.load(Ordering::Relaxed) {
+        return Err(AppError::ReadOnly);
+    }
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,87 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use tracing::instrument;
+
+use crate::server::{AppState, app_error::AppError};
+
+/// Header identifying the producer sending the request.
+pub const X_PRODUCER_ID: HeaderName = HeaderName::from_static("x-producer-id");
+
+/// Header carrying the version of the producer, optional.
+const X_PRODUCER_VERSION: HeaderName = HeaderName::from_static("x-producer-version");
+
+/// Header carrying the producer's sequence number of the request's first event, optional.
+/// The events of a batch are numbered consecutively.
+const X_PRODUCER_SEQUENCE: HeaderName = HeaderName::from_static("x-producer-sequence");
+
+/// Sequence number of an ingest request's first event, see `X_PRODUCER_SEQUENCE`.
+#[derive(Debug)]
+pub struct ProducerSequence {
+    producer_id: String,
+    sequence: u64,
+}
+
+impl ProducerSequence {
+    /// Reads the sequence number of the request, if it has one. The producer must identify
+    /// itself to number its events.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, AppError> {
+        let Some(sequence) = headers.get(X_PRODUCER_SEQUENCE) else {
+            return Ok(None);
+        };
+        let sequence = sequence
+            .to_str()
+            .ok()
+            .and_then(|sequence| sequence.parse().ok())
+            .ok_or_else(|| AppError::InvalidProducerSequence(format!("{sequence:?}")))?;
+        let producer_id = producer_id(headers).ok_or(AppError::MissingProducerId)?;
+        Ok(Some(Self {
+            producer_id: producer_id.to_string(),
+            sequence,
+        }))
+    }
+
+    /// Checks the sequence for gaps once the request's events were accepted. Rejected requests
+    /// aren't checked, so their retries don't count as regressions.
+    pub fn record(&self, state: &AppState, events: usize) {
+        state
+            .producers
+            .record_sequence(&self.producer_id, self.sequence, events as u64);
+    }
+}
+
+/// Records the producer of every request identified by the `X-Producer-Id` header.
+pub async fn track_producers(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(producer_id) = producer_id(request.headers()) {
+        let version = request
+            .headers()
+            .get(X_PRODUCER_VERSION)
+            .and_then(|version| version.to_str().ok());
+        state.producers.record(producer_id, version);
+    }
+    next.run(request).await
+}
+
+/// Lets a producer with no events to send tell that it is alive. The producer itself is
+/// recorded by `track_producers`, so this only checks that it identified itself.
+#[axum::debug_handler]
+#[instrument]
+pub async fn post_heartbeat(headers: HeaderMap) -> Result<StatusCode, AppError> {
+    producer_id(&headers).ok_or(AppError::MissingProducerId)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn producer_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(X_PRODUCER_ID)
+        .and_then(|producer_id| producer_id.to_str().ok())
+        .filter(|producer_id| !producer_id.is_empty())
+}
@@ -0,0 +1,75 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::instrument;
+
+use crate::{
+    event::Timestamp,
+    projections::ProjectionView,
+    server::{AppState, app_error::AppError},
+};
+
+#[derive(Deserialize, Debug)]
+pub struct RebuildParams {
+    /// Ignore the latest snapshot and replay all events.
+    #[serde(default)]
+    from_scratch: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ProjectionParams {
+    /// Compute the state as it stood at this timestamp.
+    as_of: Option<Timestamp>,
+}
+
+/// Returns the current state of a projection, or its state at a past moment.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_projection(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<ProjectionParams>,
+) -> Result<Json<ProjectionView>, AppError> {
+    let view = match params.as_of {
+        Some(as_of) => {
+            state
+                .projections
+                .get_as_of(&name, &*state.store, as_of)
+                .await?
+        }
+        None => state.projections.get(&name, &*state.store).await?,
+    };
+    let view = view.ok_or(AppError::ProjectionNotFound(name))?;
+    Ok(Json(view))
+}
+
+/// Rebuilds a projection from its latest snapshot and the events stored since.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn rebuild_projection(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<RebuildParams>,
+) -> Result<Json<ProjectionView>, AppError> {
+    let view = state
+        .projections
+        .rebuild(&name, &*state.store, params.from_scratch)
+        .await?;
+    let view = view.ok_or(AppError::ProjectionNotFound(name))?;
+    Ok(Json(view))
+}
+
+/// Brings a projection up to date and takes a snapshot of it.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn snapshot_projection(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<ProjectionView>, AppError> {
+    let view = state.projections.snapshot(&name, &*state.store).await?;
+    let view = view.ok_or(AppError::ProjectionNotFound(name))?;
+    Ok(Json(view))
+}
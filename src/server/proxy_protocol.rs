@@ -0,0 +1,96 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature starting every PROXY protocol v2 header.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Version 2 with the LOCAL command, used by the proxy for its own health checks.
+const LOCAL: u8 = 0x20;
+
+/// Version 2 with the PROXY command, used for relayed client connections.
+const PROXY: u8 = 0x21;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyHeaderError {
+    #[error("Missing PROXY protocol v2 signature")]
+    MissingSignature,
+
+    #[error("Unsupported PROXY protocol version and command: {0:#04x}")]
+    Unsupported(u8),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Reads the PROXY protocol v2 header the load balancer sends at the start of a connection.
+///
+/// Returns the address of the original client, or `None` if the connection was opened by the
+/// proxy itself or the address family isn't TCP over IPv4 or IPv6.
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let mut header = [0; 16];
+    stream.read_exact(&mut header).await?;
+    if header[..12] != SIGNATURE {
+        return Err(ProxyHeaderError::MissingSignature);
+    }
+    let family = header[13];
+    let mut addresses = vec![0; u16::from_be_bytes([header[14], header[15]]) as usize];
+    stream.read_exact(&mut addresses).await?;
+    match header[12] {
+        LOCAL => Ok(None),
+        PROXY => Ok(parse_source(family, &addresses)),
+        other => Err(ProxyHeaderError::Unsupported(other)),
+    }
+}
+
+/// Parses the source address from the address block. Anything after the addresses, like TLVs,
+/// is ignored.
+fn parse_source(family: u8, addresses: &[u8]) -> Option<SocketAddr> {
+    match family {
+        // TCP over IPv4: source and destination addresses, then source and destination ports.
+        0x11 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).ok()?);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        // TCP over IPv6, laid out the same way.
+        0x21 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).ok()?);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(IpAddr::V6(ip), port))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let mut data = SIGNATURE.to_vec();
+        data.extend([PROXY, 0x11, 0, 12]);
+        data.extend([192, 0, 2, 1, 10, 0, 0, 1]);
+        data.extend(4711u16.to_be_bytes());
+        data.extend(3000u16.to_be_bytes());
+        data.extend(b"GET / HTTP/1.1\r\n");
+
+        let mut stream = data.as_slice();
+        let source = read_header(&mut stream).await.unwrap();
+        assert_eq!(source, Some("192.0.2.1:4711".parse().unwrap()));
+        // The rest of the stream is left for HTTP.
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut data = SIGNATURE.to_vec();
+        data.extend([LOCAL, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut data.as_slice()).await.unwrap(), None);
+
+        let mut stream = &b"GET / HTTP/1.1\r\nHost: example.com\r\n"[..];
+        assert!(matches!(
+            read_header(&mut stream).await,
+            Err(ProxyHeaderError::MissingSignature)
+        ));
+    }
+}
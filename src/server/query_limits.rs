@@ -0,0 +1,156 @@
+use ahash::AHashMap;
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{Extensions, HeaderMap, HeaderName, Method, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    metrics,
+    receipts::hex,
+    server::{AppState, app_error::AppError, client_ip::ClientIp},
+};
+
+/// Header identifying the team or service sending a query.
+pub const API_KEY_HEADER: HeaderName = HeaderName::from_static("x-api-key");
+
+/// Keys tracked before the idle ones are forgotten.
+const MAX_IDLE_KEYS: usize = 1000;
+
+/// Limits the queries running at the same time per API key.
+#[derive(Debug, Clone)]
+pub struct QueryConcurrencyConfig {
+    /// Maximum number of concurrent queries of an API key.
+    pub max_per_key: usize,
+
+    /// How long a query over the limit waits for another to finish before it's rejected.
+    pub queue_timeout: Duration,
+}
+
+/// Tracks the in-flight queries of each API key, so one client's parallel queries can't
+/// monopolize the read path.
+pub struct QueryLimiter {
+    config: QueryConcurrencyConfig,
+    slots: Mutex<AHashMap<String, Arc<Semaphore>>>,
+}
+
+impl QueryLimiter {
+    pub fn new(config: QueryConcurrencyConfig) -> Self {
+        Self {
+            config,
+            slots: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    /// Takes a query slot of the key, waiting for one up to the queue timeout. The slot is
+    /// freed when the permit is dropped.
+    async fn acquire(&self, key: String) -> Result<OwnedSemaphorePermit, AppError> {
+        let semaphore = {
+            let mut slots = self.slots.lock().unwrap();
+            if slots.len() > MAX_IDLE_KEYS {
+                // Permits hold a reference, so only keys without queries are removed.
+                slots.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+            }
+            let max_per_key = self.config.max_per_key;
+            slots
+                .entry(key)
+                .or_insert_with(|| Arc::new(Semaphore::new(max_per_key)))
+                .clone()
+        };
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        metrics::increment("queries_queued_total", &[]);
+        match tokio::time::timeout(self.config.queue_timeout, semaphore.acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => {
+                metrics::increment("queries_rejected_total", &[]);
+                Err(AppError::TooManyQueries(self.config.max_per_key))
+            }
+        }
+    }
+}
+
+/// Limits the concurrent `GET` and `HEAD` requests per API key, given in the `X-Api-Key`
+/// header. Requests without a key are limited per client address.
+pub async fn limit_queries(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(limiter) = &state.query_limiter else {
+        return Ok(next.run(request).await);
+    };
+    if !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return Ok(next.run(request).await);
+    }
+    let _permit = limiter.acquire(client_key(&request)).await?;
+    Ok(next.run(request).await)
+}
+
+/// Identifies the client of a request for limits: by its API key, or without one, by its
+/// address.
+pub fn client_key(request: &Request) -> String {
+    key_of(request.headers(), request.extensions())
+}
+
+fn key_of(headers: &HeaderMap, extensions: &Extensions) -> String {
+    match headers.get(API_KEY_HEADER) {
+        Some(api_key) => format!("key:{}", String::from_utf8_lossy(api_key.as_bytes())),
+        None => match extensions.get::<ClientIp>() {
+            Some(ClientIp(client_ip)) => format!("ip:{client_ip}"),
+            None => "anonymous".to_string(),
+        },
+    }
+}
+
+/// Hides the API key of a client key, so clients can be shown without leaking keys.
+pub fn display_key(key: &str) -> String {
+    match key.strip_prefix("key:") {
+        Some(api_key) => format!("key:{}", &hex(&Sha256::digest(api_key))[..12]),
+        None => key.to_string(),
+    }
+}
+
+/// The client of a request, identified like for limits, with its API key hidden.
+#[derive(Debug, Clone)]
+pub struct Client(pub String);
+
+impl<S: Send + Sync> FromRequestParts<S> for Client {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Client(display_key(&key_of(
+            &parts.headers,
+            &parts.extensions,
+        ))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_query_limiter() {
+        let limiter = QueryLimiter::new(QueryConcurrencyConfig {
+            max_per_key: 1,
+            queue_timeout: Duration::from_millis(10),
+        });
+        let permit = limiter.acquire("key:backfill".to_string()).await.unwrap();
+        let queued = limiter.acquire("key:backfill".to_string()).await;
+        assert!(matches!(queued, Err(AppError::TooManyQueries(1))));
+        assert!(limiter.acquire("key:dashboard".to_string()).await.is_ok());
+
+        drop(permit);
+        assert!(limiter.acquire("key:backfill".to_string()).await.is_ok());
+    }
+}
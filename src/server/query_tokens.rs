@@ -0,0 +1,133 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::sse::{Event as SseEvent, Sse},
+};
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, instrument};
+
+use crate::{
+    event::Event,
+    query_tokens::{TokenClaims, TokenError, TokenScope},
+    server::{AppState, app_error::AppError, live::event_stream},
+};
+
+/// Longest lifetime of a query token in seconds, as tokens can't be revoked.
+const MAX_TOKEN_LIFETIME: u64 = 7 * 24 * 3600;
+
+#[derive(Deserialize, Debug)]
+pub struct CreateQueryToken {
+    #[serde(flatten)]
+    scope: TokenScope,
+
+    /// Seconds the token is valid for.
+    #[serde(default = "default_expires_in")]
+    expires_in: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct IssuedQueryToken {
+    token: String,
+    expires_at: u64,
+
+    /// Paths of the events in the token's scope, and of their live stream.
+    events_url: String,
+    stream_url: String,
+}
+
+fn default_expires_in() -> u64 {
+    3600
+}
+
+/// Issues a token granting read access to the events of one type and time range, until it
+/// expires.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn create_query_token(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<CreateQueryToken>,
+) -> Result<(StatusCode, Json<IssuedQueryToken>), AppError> {
+    if request.expires_in == 0 || request.expires_in > MAX_TOKEN_LIFETIME {
+        return Err(AppError::InvalidQuery(format!(
+            "Tokens must expire in 1 to {MAX_TOKEN_LIFETIME} seconds"
+        )));
+    }
+    if let (Some(start), Some(end)) = (request.scope.start, request.scope.end)
+        && start > end
+    {
+        return Err(AppError::InvalidQuery(format!(
+            "The range starts at {start}, after its end at {end}"
+        )));
+    }
+    let claims = TokenClaims {
+        scope: request.scope,
+        expires_at: now() + request.expires_in,
+    };
+    let token = state.query_tokens.issue(&claims);
+    info!("Issued query token for {:?}", claims.scope);
+    Ok((
+        StatusCode::CREATED,
+        Json(IssuedQueryToken {
+            events_url: format!("/shared/{token}/events"),
+            stream_url: format!("/shared/{token}/stream"),
+            token,
+            expires_at: claims.expires_at,
+        }),
+    ))
+}
+
+/// Returns the events in the scope of a query token.
+#[axum::debug_handler]
+#[instrument(skip_all)]
+pub async fn get_shared_events(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<String>,
+) -> Result<Json<Vec<Event>>, AppError> {
+    let scope = verify(&state, &token)?.scope;
+    let events = state
+        .store
+        .get_events(Some(&scope.event_type), scope.start, scope.end)
+        .await
+        .map_err(AppError::from)?;
+    Ok(Json(events))
+}
+
+/// Streams the events in the scope of a query token as Server-Sent Events as they are stored.
+/// The stream ends when the token expires.
+#[axum::debug_handler]
+#[instrument(skip_all)]
+pub async fn stream_shared_events(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<SseEvent, Infallible>>>, AppError> {
+    let claims = verify(&state, &token)?;
+    let expiry = tokio::time::sleep(Duration::from_secs(claims.expires_at.saturating_sub(now())));
+    let scope = claims.scope;
+    Ok(event_stream(
+        &state,
+        move |event| scope.matches(event),
+        expiry,
+    ))
+}
+
+fn verify(state: &AppState, token: &str) -> Result<TokenClaims, AppError> {
+    match state.query_tokens.verify(token, now()) {
+        Ok(claims) => Ok(claims),
+        Err(TokenError::Invalid) => Err(AppError::InvalidQueryToken),
+        Err(TokenError::Expired) => Err(AppError::QueryTokenExpired),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
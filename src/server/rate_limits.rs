@@ -0,0 +1,481 @@
+use ahash::AHashMap;
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+use tracing::{instrument, warn};
+
+use crate::{
+    metrics,
+    server::{
+        AppState,
+        app_error::AppError,
+        query_limits::{client_key, display_key},
+    },
+};
+
+const RATE_LIMIT_LIMIT_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const RATE_LIMIT_REMAINING_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+const RATE_LIMIT_RESET_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+
+/// Clients tracked before the ones with a full bucket are forgotten.
+const MAX_IDLE_CLIENTS: usize = 1000;
+
+/// Limits the ingest requests of each client with a token bucket.
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Requests per second a client may send in the long run.
+    pub rate: f64,
+
+    /// Requests a client may send at once after being idle.
+    pub burst: f64,
+
+    /// Queues the requests over the limit instead of rejecting them right away, if set.
+    pub smoothing: Option<SmoothingConfig>,
+}
+
+/// Queues requests over the rate limit until the client's bucket has a token for them, so
+/// the short bursts of spiky producers, e.g. cron jobs, are spread out instead of rejected.
+#[derive(Debug, Clone)]
+pub struct SmoothingConfig {
+    /// Requests of a client that may wait at a time.
+    pub max_queued: usize,
+
+    /// Longest time a request may wait. Requests that would wait longer are rejected.
+    pub max_wait: Duration,
+
+    /// What happens to a request arriving while the client's queue is full.
+    pub overflow: OverflowPolicy,
+}
+
+/// How a full queue of waiting requests makes room.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverflowPolicy {
+    /// The new request is rejected.
+    #[default]
+    Reject,
+
+    /// The request waiting the longest is rejected, and the new one is queued.
+    DropOldest,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    /// Parses `reject` or `drop_oldest`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(OverflowPolicy::Reject),
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            _ => Err(format!("Unknown overflow policy: '{s}'")),
+        }
+    }
+}
+
+/// Share of the burst left below which a client is warned that it approaches its limit.
+const WARN_BELOW: f64 = 0.2;
+
+/// Remaining requests of a client, and when they were last refilled.
+struct Bucket {
+    /// Negative while queued requests wait for the tokens reserved for them.
+    tokens: f64,
+    refilled: Instant,
+
+    /// Requests allowed and rejected since the bucket was created.
+    requests: u64,
+    rejected: u64,
+
+    /// Whether the client was warned since it last had enough requests left.
+    warned: bool,
+
+    /// Requests waiting for their token, the oldest first, with the channel rejecting them.
+    queue: VecDeque<(u64, oneshot::Sender<u64>)>,
+}
+
+/// A request waiting for the token reserved for it.
+struct QueuedRequest {
+    id: u64,
+
+    /// Time until the reserved token is available.
+    wait: Duration,
+
+    /// Receives the seconds to retry after if the request is dropped from the queue.
+    dropped: oneshot::Receiver<u64>,
+}
+
+/// Removes a request from its client's queue when it stops waiting, also if its client
+/// disconnects.
+struct QueueSlot<'a> {
+    limiter: &'a RateLimiter,
+    key: &'a str,
+    id: u64,
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        let mut buckets = self.limiter.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get_mut(self.key) {
+            bucket.queue.retain(|(id, _)| *id != self.id);
+        }
+    }
+}
+
+/// State of a client's bucket after a request, sent back in the `X-RateLimit-*` headers.
+#[derive(Debug, PartialEq)]
+struct Quota {
+    /// Requests the client may still send right away.
+    remaining: u64,
+
+    /// Seconds until the bucket is full again.
+    reset: u64,
+
+    /// Seconds to wait for the next request, if this one was rejected.
+    retry_after: Option<u64>,
+
+    /// Whether the client just dropped below the warning threshold.
+    approaching: bool,
+}
+
+/// Consumption of a client's rate limit.
+#[derive(Serialize, Debug)]
+pub struct ClientUsage {
+    /// `key:` and the start of the API key's SHA-256 hash, or `ip:` and the client's address.
+    client: String,
+
+    requests: u64,
+    rejected: u64,
+    remaining: u64,
+
+    /// Requests waiting for a token.
+    queued: usize,
+
+    /// Seconds until the client may send a full burst again.
+    reset: u64,
+}
+
+/// Tracks the token buckets of the clients sending ingest requests, so one producer can't
+/// flood the server.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<AHashMap<String, Bucket>>,
+
+    /// Id of the next queued request.
+    next_queued_id: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(AHashMap::new()),
+            next_queued_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes a token of the client's bucket if it has one, and returns what is left. Without
+    /// one, the request is queued for a token if smoothing allows it.
+    fn acquire(&self, key: String, now: Instant) -> (Quota, Option<QueuedRequest>) {
+        let RateLimitConfig { rate, burst, .. } = self.config;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_IDLE_CLIENTS {
+            // Forgetting a bucket that would be full by now changes nothing.
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            refilled: now,
+            requests: 0,
+            rejected: 0,
+            warned: false,
+            queue: VecDeque::new(),
+        });
+        self.refill(bucket, now);
+        let mut queued = None;
+        let retry_after = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.requests += 1;
+            None
+        } else {
+            queued = self.enqueue(bucket);
+            if queued.is_none() {
+                bucket.rejected += 1;
+                Some(((1.0 - bucket.tokens) / rate).ceil() as u64)
+            } else {
+                None
+            }
+        };
+        let low = bucket.tokens < burst * WARN_BELOW;
+        let approaching = low && !bucket.warned;
+        bucket.warned = low;
+        let quota = Quota {
+            remaining: bucket.tokens as u64,
+            reset: self.reset(bucket),
+            retry_after,
+            approaching,
+        };
+        (quota, queued)
+    }
+
+    /// Reserves the next token of the bucket for a request, if it doesn't have to wait too
+    /// long and the queue has room for it.
+    fn enqueue(&self, bucket: &mut Bucket) -> Option<QueuedRequest> {
+        let smoothing = self.config.smoothing.as_ref()?;
+        let full = bucket.queue.len() >= smoothing.max_queued;
+        if full && (smoothing.overflow == OverflowPolicy::Reject || bucket.queue.is_empty()) {
+            return None;
+        }
+        // The token of a dropped request goes to the new one.
+        let tokens = if full {
+            bucket.tokens + 1.0
+        } else {
+            bucket.tokens
+        };
+        // Zero if the oldest request's wait just ended, but it hasn't left the queue yet.
+        let wait = Duration::try_from_secs_f64((1.0 - tokens) / self.config.rate)
+            .unwrap_or(Duration::ZERO);
+        if wait > smoothing.max_wait {
+            return None;
+        }
+        if full {
+            let (_, dropped) = bucket.queue.pop_front()?;
+            let _ = dropped.send(wait.as_secs_f64().ceil() as u64);
+        }
+        bucket.tokens = tokens - 1.0;
+        let id = self.next_queued_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, dropped) = oneshot::channel();
+        bucket.queue.push_back((id, sender));
+        Some(QueuedRequest { id, wait, dropped })
+    }
+
+    /// Counts a queued request once it is let through or dropped.
+    fn record_queued(&self, key: &str, passed: bool) {
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get_mut(key) {
+            if passed {
+                bucket.requests += 1;
+            } else {
+                bucket.rejected += 1;
+            }
+        }
+    }
+
+    /// Returns the consumption of the tracked clients, the busiest first.
+    pub fn usage(&self, now: Instant) -> Vec<ClientUsage> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut usage: Vec<_> = buckets
+            .iter_mut()
+            .map(|(key, bucket)| {
+                self.refill(bucket, now);
+                ClientUsage {
+                    client: display_key(key),
+                    requests: bucket.requests,
+                    rejected: bucket.rejected,
+                    remaining: bucket.tokens as u64,
+                    queued: bucket.queue.len(),
+                    reset: self.reset(bucket),
+                }
+            })
+            .collect();
+        usage.sort_by(|a, b| (b.requests, &a.client).cmp(&(a.requests, &b.client)));
+        usage
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.config.rate).min(self.config.burst);
+        bucket.refilled = now;
+    }
+
+    fn reset(&self, bucket: &Bucket) -> u64 {
+        ((self.config.burst - bucket.tokens) / self.config.rate).ceil() as u64
+    }
+}
+
+/// Limits the rate of `POST` requests per API key, given in the `X-Api-Key` header. Requests
+/// without a key are limited per client address.
+///
+/// Responses carry the `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`
+/// headers, so producers can slow down before they are rejected.
+pub async fn limit_ingest_rate(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = &state.rate_limiter else {
+        return next.run(request).await;
+    };
+    if *request.method() != Method::POST {
+        return next.run(request).await;
+    }
+    let key = client_key(&request);
+    let (quota, queued) = limiter.acquire(key.clone(), Instant::now());
+    if quota.approaching {
+        warn!(
+            "Client {} is approaching its rate limit, {} requests left",
+            display_key(&key),
+            quota.remaining
+        );
+    }
+    let retry_after = match queued {
+        Some(queued) => {
+            metrics::increment("ingest_rate_queued_total", &[]);
+            let _slot = QueueSlot {
+                limiter,
+                key: &key,
+                id: queued.id,
+            };
+            let retry_after = tokio::select! {
+                () = tokio::time::sleep(queued.wait) => None,
+                retry_after = queued.dropped => Some(retry_after.unwrap_or(1)),
+            };
+            limiter.record_queued(&key, retry_after.is_none());
+            retry_after
+        }
+        None => quota.retry_after,
+    };
+    let mut response = match retry_after {
+        Some(retry_after) => {
+            metrics::increment("ingest_rate_limited_total", &[]);
+            AppError::RateLimited(retry_after).into_response()
+        }
+        None => next.run(request).await,
+    };
+    let headers = response.headers_mut();
+    headers.insert(
+        RATE_LIMIT_LIMIT_HEADER,
+        HeaderValue::from(limiter.config.burst as u64),
+    );
+    headers.insert(
+        RATE_LIMIT_REMAINING_HEADER,
+        HeaderValue::from(quota.remaining),
+    );
+    headers.insert(RATE_LIMIT_RESET_HEADER, HeaderValue::from(quota.reset));
+    response
+}
+
+/// Lists the rate limit consumption of the clients sending ingest requests.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_usage(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<ClientUsage>>, AppError> {
+    let limiter = state
+        .rate_limiter
+        .as_ref()
+        .ok_or(AppError::RateLimitNotConfigured)?;
+    Ok(Json(limiter.usage(Instant::now())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            rate: 0.5,
+            burst: 2.0,
+            smoothing: None,
+        });
+        let start = Instant::now();
+        let at = |seconds: f64| start + Duration::from_secs_f64(seconds);
+        let acquire = |key: &str, seconds: f64| limiter.acquire(key.to_string(), at(seconds)).0;
+        assert_eq!(
+            acquire("key:backfill", 0.0),
+            Quota {
+                remaining: 1,
+                reset: 2,
+                retry_after: None,
+                approaching: false,
+            }
+        );
+        assert_eq!(
+            acquire("key:backfill", 0.0),
+            Quota {
+                remaining: 0,
+                reset: 4,
+                retry_after: None,
+                approaching: true,
+            }
+        );
+        let rejected = acquire("key:backfill", 0.0);
+        assert_eq!(rejected.retry_after, Some(2));
+        // Only warned once while the client stays close to the limit.
+        assert!(!rejected.approaching);
+        assert_eq!(acquire("key:dashboard", 0.0).retry_after, None);
+        assert_eq!(acquire("key:backfill", 1.0).retry_after, Some(1));
+        assert_eq!(acquire("key:backfill", 2.0).retry_after, None);
+
+        let usage = limiter.usage(at(2.0));
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage[0].client, display_key("key:backfill"));
+        assert!(!usage[0].client.contains("backfill"));
+        assert_eq!((usage[0].requests, usage[0].rejected), (3, 2));
+        assert_eq!((usage[1].requests, usage[1].rejected), (1, 0));
+        assert_eq!(usage[1].remaining, 2);
+    }
+
+    #[test]
+    fn test_smoothing() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            rate: 1.0,
+            burst: 1.0,
+            smoothing: Some(SmoothingConfig {
+                max_queued: 2,
+                max_wait: Duration::from_secs(2),
+                overflow: OverflowPolicy::Reject,
+            }),
+        });
+        let now = Instant::now();
+        let acquire = || limiter.acquire("key:cron".to_string(), now);
+        let (quota, queued) = acquire();
+        assert!(quota.retry_after.is_none() && queued.is_none());
+        // The burst over the limit waits for the next tokens, one second apart.
+        let (_, first) = acquire();
+        assert_eq!(first.unwrap().wait, Duration::from_secs(1));
+        let (_, second) = acquire();
+        assert_eq!(second.unwrap().wait, Duration::from_secs(2));
+        // Both the queue and the longest wait are full.
+        let (quota, queued) = acquire();
+        assert!(queued.is_none());
+        assert_eq!(quota.retry_after, Some(3));
+    }
+
+    #[test]
+    fn test_smoothing_drops_oldest() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            rate: 1.0,
+            burst: 1.0,
+            smoothing: Some(SmoothingConfig {
+                max_queued: 1,
+                max_wait: Duration::from_secs(5),
+                overflow: OverflowPolicy::DropOldest,
+            }),
+        });
+        let now = Instant::now();
+        let acquire = || limiter.acquire("key:cron".to_string(), now);
+        acquire();
+        let (_, oldest) = acquire();
+        let mut oldest = oldest.unwrap();
+        let (_, newest) = acquire();
+        // The newest request takes the token of the dropped one.
+        assert_eq!(newest.unwrap().wait, Duration::from_secs(1));
+        assert_eq!(oldest.dropped.try_recv(), Ok(1));
+    }
+}
@@ -0,0 +1,71 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde_json::Value;
+use std::sync::Arc;
+use tracing::{info, instrument};
+
+use crate::{
+    schema_registry::PayloadSchema,
+    server::{AppState, app_error::AppError},
+};
+
+/// Lists the registered schemas, sorted by event type.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn list_schemas(State(state): State<Arc<AppState>>) -> Json<Vec<PayloadSchema>> {
+    Json(state.schema_registry.schemas())
+}
+
+/// Returns the JSON Schema of an event type.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn get_schema(
+    State(state): State<Arc<AppState>>,
+    Path(event_type): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    let schema = state
+        .schema_registry
+        .get(&event_type)
+        .ok_or(AppError::SchemaNotFound(event_type))?;
+    Ok(Json(schema.schema))
+}
+
+/// Registers the JSON Schema of an event type, replacing its previous one. Events of the type
+/// ingested afterwards are rejected unless their payload matches it.
+///
+/// Returns `201 Created` for a new schema, and `200 OK` for a replaced one.
+#[axum::debug_handler]
+#[instrument(skip(state, schema))]
+pub async fn put_schema(
+    State(state): State<Arc<AppState>>,
+    Path(event_type): Path<String>,
+    Json(schema): Json<Value>,
+) -> Result<StatusCode, AppError> {
+    let replaced = state.schema_registry.set(PayloadSchema {
+        event_type: event_type.clone(),
+        schema,
+    })?;
+    info!("Schema of '{event_type}' registered");
+    Ok(if replaced {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    })
+}
+
+/// Removes the schema of an event type, so its events are no longer validated.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn delete_schema(
+    State(state): State<Arc<AppState>>,
+    Path(event_type): Path<String>,
+) -> Result<StatusCode, AppError> {
+    if !state.schema_registry.remove(&event_type) {
+        return Err(AppError::SchemaNotFound(event_type));
+    }
+    info!("Schema of '{event_type}' removed");
+    Ok(StatusCode::NO_CONTENT)
+}
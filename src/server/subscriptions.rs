@@ -0,0 +1,271 @@
+use axum::{
+    Extension, Json,
+    extract::{
+        State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tracing::{info, instrument};
+
+use crate::{
+    access_policy::MaskedFields,
+    analytics::FieldPath,
+    event::Event,
+    metrics,
+    server::{
+        AppState,
+        handlers::StoredEvent,
+        live::{LiveMessage, next_message},
+    },
+    storage::EventFilter,
+};
+
+/// Events a WebSocket client subscribes to, sent as its first message. Sending another one
+/// replaces the subscription.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct SubscriptionFilter {
+    /// Event types to receive, all of them if empty.
+    #[serde(default)]
+    pub types: Vec<String>,
+
+    /// Time range and payload fields that must be equal to the given values.
+    #[serde(flatten)]
+    pub filter: EventFilter,
+}
+
+impl SubscriptionFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        (self.types.is_empty() || self.types.contains(&event.event_type))
+            && self.filter.matches(event)
+    }
+}
+
+/// A subscription of a connected client.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Subscription {
+    pub id: u64,
+
+    #[serde(flatten)]
+    pub filter: SubscriptionFilter,
+}
+
+/// Tracks the subscriptions of the connected WebSocket clients. Events are fanned out to the
+/// clients by the live event channel, and each client filters them by its subscription.
+#[derive(Default)]
+pub struct Subscriptions {
+    next_id: AtomicU64,
+    active: Mutex<BTreeMap<u64, SubscriptionFilter>>,
+}
+
+impl Subscriptions {
+    /// Registers a subscription, returning its id.
+    fn register(&self, filter: SubscriptionFilter) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut active = self.active.lock().unwrap();
+        active.insert(id, filter);
+        metrics::set_gauge("websocket_subscriptions", &[], active.len() as f64);
+        id
+    }
+
+    fn unregister(&self, id: u64) {
+        let mut active = self.active.lock().unwrap();
+        active.remove(&id);
+        metrics::set_gauge("websocket_subscriptions", &[], active.len() as f64);
+    }
+
+    /// Returns the active subscriptions, ordered by id.
+    pub fn list(&self) -> Vec<Subscription> {
+        let active = self.active.lock().unwrap();
+        active
+            .iter()
+            .map(|(id, filter)| Subscription {
+                id: *id,
+                filter: filter.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Messages sent to WebSocket clients.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ServerMessage {
+    /// The subscription was accepted, and matching events follow.
+    Subscribed(u64),
+
+    /// The client fell behind and missed this many events.
+    Lagged(u64),
+
+    /// The client's message isn't a valid subscription.
+    Error(String),
+}
+
+/// Upgrades to a WebSocket pushing the events matching the client's subscription as they are
+/// stored.
+#[axum::debug_handler]
+#[instrument(skip(state, masked, upgrade))]
+pub async fn subscribe_events(
+    State(state): State<Arc<AppState>>,
+    masked: Option<Extension<Arc<MaskedFields>>>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let masked = masked.map(|Extension(masked)| masked);
+    upgrade.on_upgrade(move |socket| serve_subscription(state, masked, socket))
+}
+
+/// Runs a WebSocket connection until the client disconnects or the server shuts down.
+async fn serve_subscription(
+    state: Arc<AppState>,
+    masked: Option<Arc<MaskedFields>>,
+    mut socket: WebSocket,
+) {
+    let mut receiver = state.live_events.subscribe();
+    let mut subscription: Option<(u64, SubscriptionFilter)> = None;
+    loop {
+        let message = tokio::select! {
+            message = socket.recv() => {
+                let Some(Ok(message)) = message else {
+                    break;
+                };
+                let Message::Text(text) = message else {
+                    continue;
+                };
+                let filter = match parse_subscription(&text, masked.as_deref()) {
+                    Ok(filter) => filter,
+                    Err(error) => {
+                        if send(&mut socket, &ServerMessage::Error(error)).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                if let Some((id, _)) = subscription.take() {
+                    state.subscriptions.unregister(id);
+                }
+                let id = state.subscriptions.register(filter.clone());
+                info!("WebSocket subscription {id}: {filter:?}");
+                subscription = Some((id, filter));
+                // Events stored before subscribing aren't sent.
+                receiver = receiver.resubscribe();
+                ServerMessage::Subscribed(id).into()
+            }
+            message = async {
+                let (_, filter) = subscription.as_ref()?;
+                next_message(&mut receiver, |event| filter.matches(event)).await
+            }, if subscription.is_some() => {
+                match message {
+                    Some(LiveMessage::Event(live)) => {
+                        let (event_id, event) = live.as_ref();
+                        let stored = StoredEvent::new(*event_id, event.clone());
+                        let mut value =
+                            serde_json::to_value(stored).expect("Events always serialize");
+                        if let Some(masked) = &masked {
+                            masked.redact(&mut value);
+                        }
+                        value
+                    }
+                    Some(LiveMessage::Lagged(missed)) => ServerMessage::Lagged(missed).into(),
+                    None => break,
+                }
+            }
+        };
+        if send(&mut socket, &message).await.is_err() {
+            break;
+        }
+    }
+    if let Some((id, _)) = subscription {
+        state.subscriptions.unregister(id);
+    }
+}
+
+/// Parses a subscription message. Filtering by masked fields would reveal them, so it's refused.
+fn parse_subscription(
+    text: &str,
+    masked: Option<&MaskedFields>,
+) -> Result<SubscriptionFilter, String> {
+    let filter: SubscriptionFilter =
+        serde_json::from_str(text).map_err(|error| format!("Invalid subscription: {error}"))?;
+    if let Some(masked) = masked
+        && let Some(field) = filter
+            .filter
+            .payload
+            .keys()
+            .find(|field| masked.reveals(&FieldPath::Payload(vec![field.to_string()])))
+    {
+        return Err(format!(
+            "Invalid subscription: field 'payload.{field}' is masked"
+        ));
+    }
+    Ok(filter)
+}
+
+impl From<ServerMessage> for serde_json::Value {
+    fn from(message: ServerMessage) -> Self {
+        serde_json::to_value(message).expect("Server messages always serialize")
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &impl Serialize) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).expect("Messages always serialize");
+    socket.send(Message::Text(text.into())).await
+}
+
+/// Returns the subscriptions of the connected WebSocket clients.
+#[axum::debug_handler]
+#[instrument(skip(state))]
+pub async fn list_subscriptions(State(state): State<Arc<AppState>>) -> Json<Vec<Subscription>> {
+    Json(state.subscriptions.list())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscription_filter() {
+        let filter: SubscriptionFilter = serde_json::from_value(serde_json::json!({
+            "types": ["click", "view"],
+            "start": 10,
+            "payload": {"country": "HU"},
+        }))
+        .unwrap();
+        let event = |event_type: &str, timestamp, country: &str| Event {
+            event_type: event_type.to_string(),
+            timestamp,
+            payload: serde_json::json!({"country": country}),
+            ..Default::default()
+        };
+        assert!(filter.matches(&event("view", 10, "HU")));
+        assert!(!filter.matches(&event("purchase", 10, "HU")));
+        assert!(!filter.matches(&event("click", 9, "HU")));
+        assert!(!filter.matches(&event("click", 10, "DE")));
+        assert!(SubscriptionFilter::default().matches(&event("purchase", 0, "DE")));
+    }
+
+    #[test]
+    fn test_registry() {
+        let subscriptions = Subscriptions::default();
+        let first = subscriptions.register(SubscriptionFilter::default());
+        let second = subscriptions.register(SubscriptionFilter {
+            types: vec!["click".to_string()],
+            ..Default::default()
+        });
+        subscriptions.unregister(first);
+        let active = subscriptions.list();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, second);
+        assert_eq!(
+            serde_json::to_value(&active[0]).unwrap()["types"],
+            serde_json::json!(["click"])
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use rustls::{RootCertStore, ServerConfig as RustlsConfig, server::WebPkiClientVerifier};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use sha2::{Digest, Sha256};
+use std::{path::PathBuf, sync::Arc};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{
+    access_policy::ClientIdentity,
+    metrics,
+    receipts::hex,
+    server::{
+        AppState, app_error::AppError, producers::X_PRODUCER_ID, query_limits::API_KEY_HEADER,
+    },
+};
+
+/// Serves HTTPS, optionally requiring client certificates.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM file of the server's certificate chain.
+    pub cert_file: PathBuf,
+
+    /// PEM file of the server's private key.
+    pub key_file: PathBuf,
+
+    /// PEM file of the CAs client certificates must be signed by. If set, connections without
+    /// a valid client certificate are refused.
+    pub client_ca_file: Option<PathBuf>,
+
+    /// Port HTTPS is served on, on the same address. If set, plain HTTP is still served on the
+    /// main port, otherwise HTTPS replaces it.
+    pub port: Option<u16>,
+}
+
+/// The certificate a client authenticated with, put into the extensions of its requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCertificate {
+    /// Common name of the certificate's subject.
+    pub common_name: Option<String>,
+
+    /// SHA-256 of the certificate in hex.
+    pub fingerprint: String,
+}
+
+impl ClientCertificate {
+    pub fn from_der(certificate: &CertificateDer) -> Self {
+        let common_name =
+            X509Certificate::from_der(certificate)
+                .ok()
+                .and_then(|(_, certificate)| {
+                    let common_name = certificate.subject().iter_common_name().next()?;
+                    common_name.as_str().ok().map(str::to_string)
+                });
+        Self {
+            common_name,
+            fingerprint: hex(&Sha256::digest(certificate)),
+        }
+    }
+
+    /// Name of the certificate in logs and errors.
+    fn name(&self) -> &str {
+        self.common_name.as_deref().unwrap_or(&self.fingerprint)
+    }
+}
+
+/// Loads the certificates and key, and creates the acceptor of TLS connections.
+pub fn make_acceptor(config: &TlsConfig, http2: bool) -> Result<TlsAcceptor> {
+    let certificates = CertificateDer::pem_file_iter(&config.cert_file)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read {}", config.cert_file.display()))?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_file)
+        .with_context(|| format!("Failed to read {}", config.key_file.display()))?;
+    let builder = RustlsConfig::builder();
+    let builder = match &config.client_ca_file {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in CertificateDer::pem_file_iter(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?
+            {
+                let certificate =
+                    certificate.with_context(|| format!("Failed to read {}", path.display()))?;
+                roots
+                    .add(certificate)
+                    .with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .context("Failed to set up client certificate verification")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder
+        .with_single_cert(certificates, key)
+        .context("Invalid server certificate or key")?;
+    server_config.alpn_protocols = if http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Returns the identity the access policy maps a client certificate to, failing if there's
+/// none.
+pub fn client_identity<'a>(
+    state: &'a AppState,
+    certificate: &ClientCertificate,
+) -> Result<&'a ClientIdentity, AppError> {
+    state
+        .access_policy
+        .client_identity(certificate.common_name.as_deref(), &certificate.fingerprint)
+        .ok_or_else(|| {
+            metrics::increment("client_certificates_rejected_total", &[]);
+            AppError::UnknownClientCertificate(certificate.name().to_string())
+        })
+}
+
+/// Serves requests of clients with a certificate as the identity the access policy maps the
+/// certificate to. API keys and producer ids in their headers are replaced, so only the
+/// certificate identifies them. Certificates without an identity are refused.
+pub async fn apply_client_identity(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(certificate) = request.extensions().get::<ClientCertificate>().cloned() else {
+        return Ok(next.run(request).await);
+    };
+    let identity = client_identity(&state, &certificate)?;
+    let headers = request.headers_mut();
+    headers.remove(API_KEY_HEADER);
+    headers.remove(X_PRODUCER_ID);
+    let header_value =
+        |value: &str| HeaderValue::from_str(value).expect("Validated when loading the policy");
+    if let Some(api_key) = &identity.api_key {
+        headers.insert(API_KEY_HEADER, header_value(api_key));
+    }
+    if let Some(producer) = &identity.producer {
+        headers.insert(X_PRODUCER_ID, header_value(producer));
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, http::HeaderMap, middleware, routing::get};
+    use axum_test::TestServer;
+
+    use crate::{config::ServerConfig, server::make_app_state};
+
+    #[tokio::test]
+    async fn test_client_identity() {
+        let path = std::env::temp_dir().join(format!("certs-{}.toml", std::process::id()));
+        let policy = "[client_certificates.ingest-worker]\napi_key = \"secret\"\n";
+        std::fs::write(&path, policy).unwrap();
+        let config = ServerConfig {
+            access_policy_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let state = make_app_state(config).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let api_key =
+            |headers: HeaderMap| async move { format!("{:?}", headers.get(API_KEY_HEADER)) };
+        let certificate = |common_name: &str| ClientCertificate {
+            common_name: Some(common_name.to_string()),
+            fingerprint: "ab12".to_string(),
+        };
+        let app = |certificate: ClientCertificate| {
+            Router::new()
+                .route("/", get(api_key))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    apply_client_identity,
+                ))
+                .layer(axum::Extension(certificate))
+        };
+
+        let server = TestServer::new(app(certificate("ingest-worker"))).unwrap();
+        let response = server
+            .get("/")
+            .add_header(API_KEY_HEADER, HeaderValue::from_static("forged"))
+            .await;
+        assert_eq!(response.text(), r#"Some("secret")"#);
+        let server = TestServer::new(app(certificate("laptop"))).unwrap();
+        assert_eq!(server.get("/").await.status_code(), 403);
+    }
+}
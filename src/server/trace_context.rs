@@ -0,0 +1,105 @@
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{Instrument, field, info_span};
+
+/// W3C Trace Context header, `00-<trace id>-<parent id>-<flags>`.
+const TRACEPARENT_HEADER: HeaderName = HeaderName::from_static("traceparent");
+
+/// Header carrying only the trace id, for clients that don't speak W3C Trace Context.
+const TRACE_ID_HEADER: HeaderName = HeaderName::from_static("x-trace-id");
+
+/// Header carrying the id of a single request, set by the client or a proxy in front of us.
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request id taken from a client.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// Runs the request in a `request` span identified by a trace id, and returns the trace id in
+/// the `traceparent` and `X-Trace-Id` response headers, so a failed call can be found in the
+/// logs by its id.
+///
+/// The trace id of an incoming `traceparent` header is continued, otherwise a new one is
+/// generated. The request id is taken from the `X-Request-Id` header, or is the span id, and is
+/// returned in the same header. The span has empty `client_ip` and `tenant` fields for later
+/// middleware to fill in.
+pub async fn propagate_trace_context(request: Request, next: Next) -> Response {
+    let (trace_id, sampled) = parse_traceparent(request.headers())
+        .unwrap_or_else(|| (rand::random::<u128>().max(1), true));
+    let span_id = rand::random::<u64>().max(1);
+    let trace_id = format!("{trace_id:032x}");
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|request_id| request_id.to_str().ok())
+        .filter(|request_id| is_valid_request_id(request_id))
+        .map_or_else(|| format!("{span_id:016x}"), str::to_string);
+    let span = info_span!(
+        "request",
+        trace_id = %trace_id,
+        span_id = %format!("{span_id:016x}"),
+        request_id = %request_id,
+        client_ip = field::Empty,
+        tenant = field::Empty,
+    );
+
+    let mut response = next.run(request).instrument(span).await;
+    let traceparent = format!("00-{trace_id}-{span_id:016x}-{:02x}", u8::from(sampled));
+    let headers = response.headers_mut();
+    headers.insert(
+        TRACEPARENT_HEADER,
+        HeaderValue::from_str(&traceparent).expect("Hex digits are valid header values"),
+    );
+    headers.insert(
+        TRACE_ID_HEADER,
+        HeaderValue::from_str(&trace_id).expect("Hex digits are valid header values"),
+    );
+    headers.insert(
+        REQUEST_ID_HEADER,
+        HeaderValue::from_str(&request_id).expect("Validated or hex digits"),
+    );
+    response
+}
+
+/// Whether a client's request id is short and only has characters safe to log and index.
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LEN
+        && request_id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'))
+}
+
+/// Returns the trace id and the sampled flag of a valid `traceparent` header.
+fn parse_traceparent(headers: &HeaderMap) -> Option<(u128, bool)> {
+    let value = headers.get(TRACEPARENT_HEADER)?.to_str().ok()?;
+    let mut fields = value.trim().split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let parent_id = fields.next()?;
+    let flags = fields.next()?;
+    // Later versions may append fields, but version 00 has exactly four.
+    let valid_version = version.len() == 2 && version != "ff";
+    if !valid_version || (version == "00" && fields.next().is_some()) {
+        return None;
+    }
+    let is_hex = |field: &str, len| {
+        field.len() == len
+            && field
+                .bytes()
+                .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    if !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+    let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+    let parent_id = u64::from_str_radix(parent_id, 16).ok()?;
+    if trace_id == 0 || parent_id == 0 {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id, flags & 1 == 1))
+}
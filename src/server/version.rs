@@ -0,0 +1,61 @@
+use axum::{Json, extract::State};
+use serde::Serialize;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{event::Timestamp, server::AppState};
+
+/// What is deployed, and since when.
+#[derive(Serialize, Debug)]
+pub struct VersionInfo {
+    version: &'static str,
+
+    /// Commit the binary was built from, `unknown` if built outside a git checkout.
+    git_sha: &'static str,
+
+    /// When the binary was built, in milliseconds since the epoch.
+    build_timestamp: Timestamp,
+
+    /// Cargo features the binary was built with.
+    features: Vec<&'static str>,
+
+    /// Short name of the storage backend, eg. `postgres`.
+    storage_backend: &'static str,
+
+    /// When the server started, in milliseconds since the epoch.
+    started_at: Timestamp,
+
+    uptime_seconds: u64,
+}
+
+/// Returns the version and build of the binary, and the storage backend it runs with, so fleet
+/// tooling can verify what is deployed on each instance.
+#[axum::debug_handler]
+pub async fn get_version(State(state): State<Arc<AppState>>) -> Json<VersionInfo> {
+    let uptime = state.started.elapsed();
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        build_timestamp: env!("BUILD_TIMESTAMP").parse().unwrap_or_default(),
+        features: enabled_features(),
+        storage_backend: state.store.name(),
+        started_at: now_millis().saturating_sub(uptime.as_millis() as u64),
+        uptime_seconds: uptime.as_secs(),
+    })
+}
+
+fn enabled_features() -> Vec<&'static str> {
+    env!("ENABLED_FEATURES")
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .collect()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,96 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+use crate::{event::Event, metrics, storage::Storage};
+
+/// Configures shadow ingestion.
+#[derive(Debug, Clone)]
+pub struct ShadowConfig {
+    /// Percentage of ingested events that are also stored in the shadow backend.
+    pub percentage: u8,
+}
+
+/// Mirrors a share of the ingested events into an alternative storage backend and compares
+/// the outcomes and latencies with the primary backend.
+///
+/// Results of the shadow backend are never returned to clients, so it can be validated under
+/// real load before cutover.
+pub struct Shadow {
+    store: Arc<dyn Storage + Send + Sync + 'static>,
+    percentage: u64,
+    counter: AtomicU64,
+}
+
+impl Shadow {
+    pub fn new(store: Arc<dyn Storage + Send + Sync + 'static>, config: ShadowConfig) -> Self {
+        Self {
+            store,
+            percentage: config.percentage.min(100) as u64,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Decides whether the next ingest should be mirrored. Spreads the sampled ingests evenly.
+    pub fn sample(&self) -> bool {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        (n + 1) * self.percentage / 100 > n * self.percentage / 100
+    }
+
+    /// Stores the events in the shadow backend and records how it compares to the primary.
+    pub async fn run(&self, events: Vec<Event>, primary_ok: bool, primary_latency: Duration) {
+        let started = Instant::now();
+        let result = match <[Event; 1]>::try_from(events) {
+            Ok([event]) => self.store.store(event).await.map(|_| ()),
+            Err(events) => self.store.store_transaction(events).await.map(|_| ()),
+        };
+        let shadow_latency = started.elapsed();
+
+        let outcome = if result.is_ok() == primary_ok {
+            "match"
+        } else {
+            warn!(
+                "Shadow backend '{}' disagrees with primary: {:?}",
+                self.store.name(),
+                result
+            );
+            "mismatch"
+        };
+        debug!("Shadow ingest {outcome} in {shadow_latency:?} (primary {primary_latency:?})");
+
+        metrics::increment("shadow_ingests_total", &[("outcome", outcome)]);
+        for (backend, latency) in [("primary", primary_latency), ("shadow", shadow_latency)] {
+            metrics::add(
+                "shadow_store_seconds_sum",
+                &[("backend", backend)],
+                latency.as_secs_f64(),
+            );
+            metrics::increment("shadow_store_seconds_count", &[("backend", backend)]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryStorage;
+
+    #[tokio::test]
+    async fn test_shadow() {
+        let store = Arc::new(InMemoryStorage::new());
+        let shadow = Shadow::new(store.clone(), ShadowConfig { percentage: 50 });
+        assert_eq!((0..10).filter(|_| shadow.sample()).count(), 5);
+
+        let event = Event {
+            event_type: "test".to_string(),
+            ..Default::default()
+        };
+        shadow.run(vec![event], true, Duration::ZERO).await;
+        assert_eq!(store.count_events(None, None, None).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,418 @@
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, KeyInit},
+};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, fmt, path::Path, str::FromStr};
+use tracing::{info, warn};
+
+use crate::{metrics, receipts::hex};
+
+/// First word of the header line of snapshot files.
+const MAGIC: &str = "cside-snapshot";
+
+/// Current version of the snapshot file format. Version 1 files, without compression and
+/// encryption, and version 2 files, without key ids, are still loadable.
+const VERSION: u32 = 3;
+
+const ZSTD_LEVEL: i32 = 3;
+const NONCE_LENGTH: usize = 12;
+
+/// How snapshot bodies are stored on disk.
+#[derive(Debug, Clone)]
+pub struct SnapshotFormat {
+    /// Compress bodies with zstd.
+    pub compression: bool,
+
+    /// Encrypt bodies with AES-256-GCM. Also required to load encrypted snapshots.
+    pub encryption_key: Option<EncryptionKey>,
+
+    /// Keys replaced by the encryption key, still loading the snapshots they encrypted. Those
+    /// snapshots are re-encrypted with the current key when they are read.
+    pub retired_keys: Vec<EncryptionKey>,
+}
+
+impl Default for SnapshotFormat {
+    fn default() -> Self {
+        Self {
+            compression: true,
+            encryption_key: None,
+            retired_keys: Vec::new(),
+        }
+    }
+}
+
+impl SnapshotFormat {
+    /// Parses the encryption keys from comma separated keys, the current one first.
+    pub fn parse_encryption_keys(&mut self, keys: &str) -> Result<(), String> {
+        let mut keys = keys
+            .split(',')
+            .map(|key| key.trim().parse())
+            .collect::<Result<Vec<EncryptionKey>, _>>()?
+            .into_iter();
+        self.encryption_key = keys.next();
+        self.retired_keys = keys.collect();
+        Ok(())
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &EncryptionKey> {
+        self.encryption_key.iter().chain(&self.retired_keys)
+    }
+}
+
+/// 256-bit AES key, parsed from 64 hex digits.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl FromStr for EncryptionKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err("Encryption key must be 64 hex digits".to_string());
+        }
+        let mut key = [0; 32];
+        for (index, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[index * 2..index * 2 + 2], 16)
+                .map_err(|_| "Encryption key must be 64 hex digits".to_string())?;
+        }
+        Ok(EncryptionKey(key))
+    }
+}
+
+impl EncryptionKey {
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.0))
+    }
+
+    /// Identifies the key in the files it encrypted: the first 8 hex digits of its SHA-256.
+    pub fn id(&self) -> String {
+        hex(&Sha256::digest(self.0)[..4])
+    }
+}
+
+/// Error type for reading snapshot files.
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotFileError {
+    #[error("Invalid header")]
+    InvalidHeader,
+
+    #[error("Unsupported format version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("Truncated: expected {expected} bytes, found {actual}")]
+    Truncated { expected: usize, actual: usize },
+
+    #[error("Checksum mismatch")]
+    ChecksumMismatch,
+
+    #[error("Encrypted, but no encryption key is configured")]
+    MissingEncryptionKey,
+
+    #[error("Encrypted with unknown key '{0}'")]
+    UnknownEncryptionKey(String),
+
+    #[error("Decryption failed")]
+    Decryption,
+
+    #[error("Decompression failed: {0}")]
+    Decompression(std::io::Error),
+}
+
+/// Wraps a snapshot body into the snapshot file format.
+///
+/// The body is optionally compressed, then optionally encrypted with a random nonce prepended.
+/// It is preceded by a manifest line holding the format version, the length and CRC32 checksum
+/// of the stored bytes and how they were transformed, so torn writes and truncated files are
+/// detected when loading. Encrypted files name the id of their key:
+///
+/// ```text
+/// cside-snapshot 3 <length> <crc32 in hex> <zstd|none> <aes256gcm:<key id>|none>
+/// <stored bytes>
+/// ```
+pub fn encode(body: &[u8], format: &SnapshotFormat) -> Vec<u8> {
+    let mut stored = Cow::Borrowed(body);
+    if format.compression {
+        let compressed = zstd::encode_all(body, ZSTD_LEVEL).expect("Compressing to memory");
+        stored = Cow::Owned(compressed);
+    }
+    if let Some(key) = &format.encryption_key {
+        let nonce: [u8; NONCE_LENGTH] = rand::random();
+        let ciphertext = key
+            .cipher()
+            .encrypt(Nonce::from_slice(&nonce), stored.as_ref())
+            .expect("Snapshots are well below the AES-GCM size limit");
+        stored = Cow::Owned([nonce.as_slice(), ciphertext.as_slice()].concat());
+    }
+
+    let header = format!(
+        "{MAGIC} {VERSION} {} {:08x} {} {}\n",
+        stored.len(),
+        crc32fast::hash(&stored),
+        if format.compression { "zstd" } else { "none" },
+        match &format.encryption_key {
+            Some(key) => format!("aes256gcm:{}", key.id()),
+            None => "none".to_string(),
+        },
+    );
+    [header.as_bytes(), stored.as_ref()].concat()
+}
+
+/// Returns the body of a snapshot file after verifying it.
+///
+/// Files without a manifest line, written before the format had one, are returned as they are.
+pub fn decode<'a>(
+    data: &'a [u8],
+    format: &SnapshotFormat,
+) -> Result<Cow<'a, [u8]>, SnapshotFileError> {
+    decode_with_key(data, format).map(|(body, _)| body)
+}
+
+/// Like `decode`, also returning whether the file isn't encrypted with the current key, and so
+/// should be re-encrypted.
+fn decode_with_key<'a>(
+    data: &'a [u8],
+    format: &SnapshotFormat,
+) -> Result<(Cow<'a, [u8]>, bool), SnapshotFileError> {
+    let current_key = format.encryption_key.as_ref().map(EncryptionKey::id);
+    if !data.starts_with(MAGIC.as_bytes()) {
+        return Ok((Cow::Borrowed(data), current_key.is_some()));
+    }
+    let header_end = data
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .ok_or(SnapshotFileError::InvalidHeader)?;
+    let header =
+        std::str::from_utf8(&data[..header_end]).map_err(|_| SnapshotFileError::InvalidHeader)?;
+    let fields: Vec<_> = header.split(' ').collect();
+    let (version, length, checksum, compression, encryption) = match fields[..] {
+        [_, version @ "1", length, checksum] => (version, length, checksum, "none", "none"),
+        [_, version, length, checksum, compression, encryption] => {
+            (version, length, checksum, compression, encryption)
+        }
+        _ => return Err(SnapshotFileError::InvalidHeader),
+    };
+    let version: u32 = version
+        .parse()
+        .map_err(|_| SnapshotFileError::InvalidHeader)?;
+    if version > VERSION {
+        return Err(SnapshotFileError::UnsupportedVersion(version));
+    }
+    let length: usize = length
+        .parse()
+        .map_err(|_| SnapshotFileError::InvalidHeader)?;
+    let checksum =
+        u32::from_str_radix(checksum, 16).map_err(|_| SnapshotFileError::InvalidHeader)?;
+
+    let stored = &data[header_end + 1..];
+    if stored.len() != length {
+        return Err(SnapshotFileError::Truncated {
+            expected: length,
+            actual: stored.len(),
+        });
+    }
+    if crc32fast::hash(stored) != checksum {
+        return Err(SnapshotFileError::ChecksumMismatch);
+    }
+
+    let mut body = Cow::Borrowed(stored);
+    let key_id = match encryption {
+        "none" => None,
+        // Version 2 files don't name their key, so every key is tried.
+        "aes256gcm" => {
+            let (plaintext, key) = decrypt(&body, format.keys())?;
+            body = Cow::Owned(plaintext);
+            Some(key.id())
+        }
+        _ => {
+            let id = encryption
+                .strip_prefix("aes256gcm:")
+                .ok_or(SnapshotFileError::InvalidHeader)?;
+            let key = format.keys().find(|key| key.id() == id);
+            if key.is_none() && format.keys().next().is_some() {
+                return Err(SnapshotFileError::UnknownEncryptionKey(id.to_string()));
+            }
+            let (plaintext, _) = decrypt(&body, key)?;
+            body = Cow::Owned(plaintext);
+            Some(id.to_string())
+        }
+    };
+    match compression {
+        "none" => {}
+        "zstd" => {
+            let decompressed =
+                zstd::decode_all(body.as_ref()).map_err(SnapshotFileError::Decompression)?;
+            body = Cow::Owned(decompressed);
+        }
+        _ => return Err(SnapshotFileError::InvalidHeader),
+    }
+    let stale = current_key.is_some() && key_id != current_key;
+    Ok((body, stale))
+}
+
+/// Decrypts a nonce and ciphertext with the first of the keys that fits, returning the key too.
+fn decrypt<'k>(
+    stored: &[u8],
+    keys: impl IntoIterator<Item = &'k EncryptionKey>,
+) -> Result<(Vec<u8>, &'k EncryptionKey), SnapshotFileError> {
+    if stored.len() < NONCE_LENGTH {
+        return Err(SnapshotFileError::Decryption);
+    }
+    let (nonce, ciphertext) = stored.split_at(NONCE_LENGTH);
+    let mut keys = keys.into_iter().peekable();
+    if keys.peek().is_none() {
+        return Err(SnapshotFileError::MissingEncryptionKey);
+    }
+    keys.find_map(|key| {
+        let plaintext = key.cipher().decrypt(Nonce::from_slice(nonce), ciphertext);
+        plaintext.ok().map(|plaintext| (plaintext, key))
+    })
+    .ok_or(SnapshotFileError::Decryption)
+}
+
+/// Writes a snapshot file. Writes to a temporary file first so a crash can't leave a partial
+/// snapshot behind.
+pub async fn write(path: &Path, body: &[u8], format: &SnapshotFormat) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    tokio::fs::write(&temp_path, encode(body, format)).await?;
+    tokio::fs::rename(&temp_path, path).await
+}
+
+/// Reads and verifies a snapshot file. Returns `None` if it doesn't exist, and flags corrupt
+/// files in the logs and the `snapshot_corrupt_files_total` metric.
+///
+/// Files not encrypted with the current key are re-encrypted with it, so a retired key can be
+/// removed once every snapshot was read.
+pub fn read(path: &Path, format: &SnapshotFormat) -> Option<Vec<u8>> {
+    let data = std::fs::read(path).ok()?;
+    match decode_with_key(&data, format) {
+        Ok((body, stale)) => {
+            if stale {
+                reencrypt(path, &body, format);
+            }
+            Some(body.into_owned())
+        }
+        Err(error) => {
+            warn!("Ignoring corrupt snapshot {}: {error}", path.display());
+            metrics::increment("snapshot_corrupt_files_total", &[]);
+            None
+        }
+    }
+}
+
+fn reencrypt(path: &Path, body: &[u8], format: &SnapshotFormat) {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let result = std::fs::write(&temp_path, encode(body, format))
+        .and_then(|()| std::fs::rename(&temp_path, path));
+    match result {
+        Ok(()) => {
+            info!(
+                "Re-encrypted snapshot {} with the current key",
+                path.display()
+            );
+            metrics::increment("snapshot_reencrypted_files_total", &[]);
+        }
+        Err(error) => warn!("Failed to re-encrypt snapshot {}: {error}", path.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let body = br#"{"checkpoint":1,"state":{}}"#;
+        let plain = SnapshotFormat {
+            compression: false,
+            encryption_key: None,
+            retired_keys: Vec::new(),
+        };
+        let data = encode(body, &plain);
+        assert_eq!(decode(&data, &plain).unwrap().as_ref(), body);
+
+        // Files without a manifest are loaded as they are.
+        assert_eq!(decode(body, &plain).unwrap().as_ref(), body);
+
+        assert!(matches!(
+            decode(&data[..data.len() - 1], &plain),
+            Err(SnapshotFileError::Truncated { .. })
+        ));
+
+        let mut torn = data.clone();
+        *torn.last_mut().unwrap() = b']';
+        assert!(matches!(
+            decode(&torn, &plain),
+            Err(SnapshotFileError::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_compression_and_encryption() {
+        let body = br#"{"checkpoint":1,"state":{"customer":"secret"}}"#;
+        let format = SnapshotFormat {
+            compression: true,
+            encryption_key: Some("00".repeat(32).parse().unwrap()),
+            retired_keys: Vec::new(),
+        };
+        let data = encode(body, &format);
+        assert!(!data.windows(6).any(|window| window == b"secret"));
+        assert_eq!(decode(&data, &format).unwrap().as_ref(), body);
+
+        let without_key = SnapshotFormat::default();
+        assert!(matches!(
+            decode(&data, &without_key),
+            Err(SnapshotFileError::MissingEncryptionKey)
+        ));
+        let wrong_key = SnapshotFormat {
+            encryption_key: Some("01".repeat(32).parse().unwrap()),
+            ..format.clone()
+        };
+        assert!(matches!(
+            decode(&data, &wrong_key),
+            Err(SnapshotFileError::UnknownEncryptionKey(_))
+        ));
+
+        // Files of a retired key are loaded, and re-encrypted when read.
+        let mut rotated = SnapshotFormat::default();
+        let keys = format!("{},{}", "01".repeat(32), "00".repeat(32));
+        rotated.parse_encryption_keys(&keys).unwrap();
+        let path = std::env::temp_dir().join(format!("snapshot-{}.json", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(read(&path, &rotated).unwrap(), body);
+        let reencrypted = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(decode(&reencrypted, &wrong_key).unwrap().as_ref(), body);
+
+        // Version 2 files don't name their key.
+        let header_end = data.iter().position(|byte| *byte == b'\n').unwrap();
+        let header = std::str::from_utf8(&data[..header_end]).unwrap();
+        let (header, _) = header.rsplit_once(':').unwrap();
+        let v2 = [
+            header.replacen(" 3 ", " 2 ", 1).as_bytes(),
+            &data[header_end..],
+        ]
+        .concat();
+        assert_eq!(decode(&v2, &rotated).unwrap().as_ref(), body);
+
+        // Version 1 files are still loadable.
+        let v1 = format!(
+            "cside-snapshot 1 {} {:08x}\n",
+            body.len(),
+            crc32fast::hash(body)
+        );
+        let v1 = [v1.as_bytes(), body].concat();
+        assert_eq!(decode(&v1, &without_key).unwrap().as_ref(), body);
+    }
+}
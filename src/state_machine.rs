@@ -0,0 +1,254 @@
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::event::Event;
+
+/// A state machine driven by events.
+///
+/// Each transition is triggered by an event type and moves an entity from one of the `from`
+/// states into the `to` state. Entities are identified by a field in the event payload.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct StateMachineDefinition {
+    pub name: String,
+
+    /// Payload field holding the entity key, eg. `order_id`.
+    pub key_field: String,
+
+    /// State of entities that haven't seen any transitions yet.
+    pub initial_state: String,
+
+    pub transitions: Vec<Transition>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Transition {
+    pub event_type: String,
+
+    /// States the transition is allowed from. Empty means any state.
+    #[serde(default)]
+    pub from: Vec<String>,
+
+    pub to: String,
+}
+
+/// A transition that was validated but not yet applied.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedTransition {
+    pub machine: String,
+    pub key: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// Error returned when an event is not a legal transition for its entity.
+#[derive(Debug)]
+pub struct IllegalTransition {
+    pub machine: String,
+    pub key: String,
+    pub event_type: String,
+    pub state: String,
+}
+
+/// State machine definitions along with the current state of every tracked entity.
+#[derive(Default)]
+pub struct StateMachineSet {
+    definitions: Vec<StateMachineDefinition>,
+
+    /// Current state by entity key and state machine name.
+    states: AHashMap<String, AHashMap<String, String>>,
+}
+
+impl StateMachineSet {
+    pub fn definitions(&self) -> &[StateMachineDefinition] {
+        &self.definitions
+    }
+
+    /// Replaces the state machine definitions. States of entities are kept, so a machine
+    /// with the same name continues where it left off.
+    pub fn set_definitions(&mut self, definitions: Vec<StateMachineDefinition>) {
+        self.definitions = definitions;
+    }
+
+    /// Whether any state machine has a transition for the event type.
+    pub fn tracks(&self, event_type: &str) -> bool {
+        self.definitions.iter().any(|machine| {
+            machine
+                .transitions
+                .iter()
+                .any(|transition| transition.event_type == event_type)
+        })
+    }
+
+    /// Validates the event against all state machines and returns the transitions it triggers.
+    pub fn plan(&self, event: &Event) -> Result<Vec<PlannedTransition>, IllegalTransition> {
+        self.plan_batch(std::slice::from_ref(event))
+    }
+
+    /// Validates a sequence of events as if they were applied one after the other.
+    pub fn plan_batch(
+        &self,
+        events: &[Event],
+    ) -> Result<Vec<PlannedTransition>, IllegalTransition> {
+        let mut planned: Vec<PlannedTransition> = vec![];
+        for event in events {
+            for machine in &self.definitions {
+                if !machine
+                    .transitions
+                    .iter()
+                    .any(|t| t.event_type == event.event_type)
+                {
+                    continue;
+                }
+                // Events without an entity key are not part of the state machine.
+                let Some(key) = event.payload.get(&machine.key_field).map(entity_key) else {
+                    continue;
+                };
+                // Earlier events of the batch take precedence over the stored state.
+                let state = planned
+                    .iter()
+                    .rev()
+                    .find(|t| t.key == key && t.machine == machine.name)
+                    .map(|t| &t.to)
+                    .or_else(|| {
+                        self.states
+                            .get(&key)
+                            .and_then(|states| states.get(&machine.name))
+                    })
+                    .unwrap_or(&machine.initial_state)
+                    .clone();
+                let transition = machine.transitions.iter().find(|t| {
+                    t.event_type == event.event_type
+                        && (t.from.is_empty() || t.from.contains(&state))
+                });
+                match transition {
+                    Some(transition) => planned.push(PlannedTransition {
+                        machine: machine.name.clone(),
+                        key,
+                        from: state,
+                        to: transition.to.clone(),
+                    }),
+                    None => {
+                        return Err(IllegalTransition {
+                            machine: machine.name.clone(),
+                            key,
+                            event_type: event.event_type.clone(),
+                            state,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(planned)
+    }
+
+    /// Applies transitions previously returned by `plan`.
+    pub fn apply(&mut self, transitions: Vec<PlannedTransition>) {
+        for transition in transitions {
+            self.states
+                .entry(transition.key)
+                .or_default()
+                .insert(transition.machine, transition.to);
+        }
+    }
+
+    /// Returns the current state of an entity in every state machine that has seen it.
+    pub fn entity_states(&self, key: &str) -> Option<&AHashMap<String, String>> {
+        self.states.get(key)
+    }
+}
+
+/// Thread-safe wrapper around the state machine set.
+///
+/// Ingest holds the write lock while storing an event so that validation and the state update
+/// can't interleave with concurrent events of the same entity.
+pub struct StateMachines {
+    set: RwLock<StateMachineSet>,
+}
+
+impl StateMachines {
+    pub fn new(definitions: Vec<StateMachineDefinition>) -> Self {
+        Self {
+            set: RwLock::new(StateMachineSet {
+                definitions,
+                states: AHashMap::new(),
+            }),
+        }
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, StateMachineSet> {
+        self.set.read().await
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, StateMachineSet> {
+        self.set.write().await
+    }
+}
+
+/// Converts a payload value into an entity key. Strings are used as-is, other values are
+/// serialized as JSON.
+fn entity_key(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order_machine() -> StateMachineDefinition {
+        StateMachineDefinition {
+            name: "order".to_string(),
+            key_field: "order_id".to_string(),
+            initial_state: "new".to_string(),
+            transitions: vec![
+                Transition {
+                    event_type: "order_paid".to_string(),
+                    from: vec!["new".to_string()],
+                    to: "paid".to_string(),
+                },
+                Transition {
+                    event_type: "order_shipped".to_string(),
+                    from: vec!["paid".to_string()],
+                    to: "shipped".to_string(),
+                },
+            ],
+        }
+    }
+
+    fn order_event(event_type: &str, order_id: u64) -> Event {
+        Event {
+            event_type: event_type.to_string(),
+            timestamp: 1,
+            payload: serde_json::json!({ "order_id": order_id }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_transitions() {
+        let mut set = StateMachineSet {
+            definitions: vec![order_machine()],
+            states: AHashMap::new(),
+        };
+
+        // Shipping before paying is illegal.
+        assert!(set.plan(&order_event("order_shipped", 1)).is_err());
+
+        let plan = set.plan(&order_event("order_paid", 1)).unwrap();
+        set.apply(plan);
+        assert_eq!(set.entity_states("1").unwrap()["order"], "paid");
+
+        // Paying twice is illegal.
+        assert!(set.plan(&order_event("order_paid", 1)).is_err());
+
+        let plan = set.plan(&order_event("order_shipped", 1)).unwrap();
+        set.apply(plan);
+        assert_eq!(set.entity_states("1").unwrap()["order"], "shipped");
+
+        // Unrelated event types don't trigger transitions.
+        assert!(set.plan(&order_event("login", 1)).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+
+use crate::event::{Event, Timestamp};
+
+/// Describes a subset of events.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct EventFilter {
+    pub event_type: Option<String>,
+    pub start: Option<Timestamp>,
+    pub end: Option<Timestamp>,
+    pub correlation_id: Option<String>,
+
+    /// Top-level payload fields that must be equal to the given values.
+    #[serde(default)]
+    pub payload: serde_json::Map<String, serde_json::Value>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        if let Some(event_type) = &self.event_type
+            && event.event_type != *event_type
+        {
+            return false;
+        }
+        if let Some(correlation_id) = &self.correlation_id
+            && event.correlation_id.as_ref() != Some(correlation_id)
+        {
+            return false;
+        }
+        if self.start.is_some_and(|start| event.timestamp < start)
+            || self.end.is_some_and(|end| event.timestamp > end)
+        {
+            return false;
+        }
+        self.payload
+            .iter()
+            .all(|(field, value)| event.payload.get(field) == Some(value))
+    }
+}
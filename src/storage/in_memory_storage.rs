@@ -1,34 +1,33 @@
 use ahash::AHashMap;
-use std::{
-    collections::BTreeMap,
-    ops::Bound,
-    sync::atomic::{AtomicU64, Ordering},
-};
+use std::{collections::BTreeMap, ops::Bound, path::Path};
 use tokio::sync::RwLock;
-use tracing::{debug, instrument};
+use tracing::{debug, info, instrument};
 
 use crate::{
-    event::{Event, Timestamp},
-    storage::{RetrieveError, Storage, StoreError},
+    event::{Event, EventId, Timestamp},
+    id_generator::IdGenerator,
+    storage::{
+        EventFilter, EventPage, IntegrityReport, MAX_QUERIED_EVENTS, PageCursor, PoolStats,
+        PredicatePlan, QueryPlan, RetrieveError, Storage, StoreError, WriteCondition, page_size,
+        wal::{Wal, WalRecord},
+    },
 };
 
-// An internal identifier for events.
-type EventId = u64;
-static NEXT_EVENT_ID: AtomicU64 = AtomicU64::new(1);
-
-// Made-up restriction to demonstrate error handling.
-const MAX_QUERIED_EVENTS: usize = 4;
-
 /// Stores events in an indexed manner for efficient queries.
+#[derive(Default)]
 struct IndexedEvents {
-    /// Stores events by their internal identifier.
-    event_by_id: AHashMap<EventId, Event>,
+    /// Stores events by their internal identifier. Ids are assigned in insertion order, so
+    /// this allows for scanning events stored after a given event.
+    event_by_id: BTreeMap<EventId, Event>,
 
     /// Stores events by their timestamp. This allows for efficient range queries.
     events_by_timestamp: BTreeMap<Timestamp, Vec<EventId>>,
 
     /// Stores events by their type and timestamp. This allows for efficient range queries by type.
     events_by_type_by_timestamp: AHashMap<String, BTreeMap<Timestamp, Vec<EventId>>>,
+
+    /// Stores events by their correlation id. This allows for fast version checks of streams.
+    events_by_correlation_id: AHashMap<String, Vec<EventId>>,
 }
 
 pub struct InMemoryStorage {
@@ -36,49 +35,232 @@ pub struct InMemoryStorage {
     // and avoids data race issues of updating indexes separately. Faster alternatives
     // exist (eg. fences or eventual consistency) at the cost of complexity or consistency.
     events: RwLock<IndexedEvents>,
+
+    /// Generates ids while holding the write lock, so ids are assigned in insertion order.
+    id_generator: Box<dyn IdGenerator + Send + Sync>,
+
+    /// Logs the changes while holding the write lock, so they are replayed in the same order.
+    wal: Option<Wal>,
 }
 
 impl InMemoryStorage {
+    #[cfg(test)]
     pub fn new() -> Self {
+        use crate::id_generator::{IdStrategy, make_id_generator};
+
+        Self::with_id_generator(make_id_generator(IdStrategy::Sequential))
+    }
+
+    pub fn with_id_generator(id_generator: Box<dyn IdGenerator + Send + Sync>) -> Self {
         Self {
-            events: RwLock::new(IndexedEvents {
-                event_by_id: AHashMap::new(),
-                events_by_type_by_timestamp: AHashMap::new(),
-                events_by_timestamp: BTreeMap::new(),
-            }),
+            events: RwLock::new(IndexedEvents::default()),
+            id_generator,
+            wal: None,
+        }
+    }
+
+    /// Recovers the events from a write-ahead log, and logs every change to it from now on, so
+    /// events survive restarts.
+    pub fn with_wal(
+        path: &Path,
+        id_generator: Box<dyn IdGenerator + Send + Sync>,
+    ) -> std::io::Result<Self> {
+        let (mut wal, records) = Wal::open(path)?;
+        let replayed = records.len();
+        let mut events = IndexedEvents::default();
+        for record in records {
+            // Ids generated from now on must be larger than the logged ones, even deleted ones.
+            if let WalRecord::Insert { events: inserted }
+            | WalRecord::Replace {
+                events: inserted, ..
+            } = &record
+            {
+                for (event_id, _) in inserted {
+                    id_generator.observe(*event_id);
+                }
+            }
+            events.apply(record);
+        }
+        let recovered: Vec<_> = events
+            .event_by_id
+            .iter()
+            .map(|(event_id, event)| (*event_id, event.clone()))
+            .collect();
+        info!(
+            "Recovered {} events from {replayed} WAL records",
+            recovered.len()
+        );
+        wal.compact(recovered)?;
+        Ok(Self {
+            events: RwLock::new(events),
+            id_generator,
+            wal: Some(wal),
+        })
+    }
+
+    /// Logs the change if there is a write-ahead log, then applies it.
+    async fn commit(
+        &self,
+        events: &mut IndexedEvents,
+        record: WalRecord,
+    ) -> Result<(), StoreError> {
+        if let Some(wal) = &self.wal {
+            wal.append(&record).await.map_err(|error| {
+                StoreError::Backend(format!("Failed to write the WAL: {error}"))
+            })?;
         }
+        events.apply(record);
+        Ok(())
     }
 }
 
 #[async_trait::async_trait]
 impl Storage for InMemoryStorage {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn validate(&self, _event: &Event) -> Result<(), StoreError> {
+        // Every event fits in memory.
+        Ok(())
+    }
+
     #[instrument(skip_all)]
-    async fn store(&self, event: Event) -> Result<(), StoreError> {
+    async fn store(&self, event: Event) -> Result<EventId, StoreError> {
         debug!("Storing event");
-        if event.event_type == "winter wrap up" {
-            // In-memory storage doesn't support this event type.
-            // It's a made-up restriction to demonstrate error handling.
-            return Err(StoreError::InvalidEventType(event.event_type));
+        let mut events_guard = self.events.write().await;
+        let event_id = self.id_generator.generate();
+        let record = WalRecord::Insert {
+            events: vec![(event_id, event)],
+        };
+        self.commit(&mut events_guard, record).await?;
+        Ok(event_id)
+    }
+
+    #[instrument(skip_all)]
+    async fn store_transaction(&self, events: Vec<Event>) -> Result<Vec<EventId>, StoreError> {
+        debug!("Storing {} events in a transaction", events.len());
+
+        // A single lock acquisition makes the whole transaction visible at once.
+        let mut events_guard = self.events.write().await;
+        let events: Vec<_> = events
+            .into_iter()
+            .map(|event| (self.id_generator.generate(), event))
+            .collect();
+        let event_ids = events.iter().map(|(event_id, _)| *event_id).collect();
+        self.commit(&mut events_guard, WalRecord::Insert { events })
+            .await?;
+        Ok(event_ids)
+    }
+
+    #[instrument(skip_all)]
+    async fn store_if(
+        &self,
+        event: Event,
+        condition: &WriteCondition,
+    ) -> Result<EventId, StoreError> {
+        debug!("Storing event conditionally");
+
+        // Keep the write lock while checking so no other event can sneak in.
+        let mut events_guard = self.events.write().await;
+        let matched = events_guard.filtered(&condition.filter).count() as u64;
+        if matched != condition.matches {
+            return Err(StoreError::ConditionFailed {
+                expected: condition.matches,
+                matched,
+            });
         }
 
-        let event_id = NEXT_EVENT_ID.fetch_add(1, Ordering::Relaxed);
-        let event_type = event.event_type.clone();
+        let event_id = self.id_generator.generate();
+        let record = WalRecord::Insert {
+            events: vec![(event_id, event)],
+        };
+        self.commit(&mut events_guard, record).await?;
+        Ok(event_id)
+    }
 
+    #[instrument(skip_all)]
+    async fn increment_count(&self, event_id: EventId, by: u64) -> Result<(), StoreError> {
         let mut events_guard = self.events.write().await;
-        events_guard
-            .events_by_type_by_timestamp
-            .entry(event_type)
-            .or_default()
-            .entry(event.timestamp)
-            .or_default()
-            .push(event_id);
-        events_guard
-            .events_by_timestamp
-            .entry(event.timestamp)
-            .or_default()
-            .push(event_id);
-        events_guard.event_by_id.insert(event_id, event);
-        Ok(())
+        if !events_guard.event_by_id.contains_key(&event_id) {
+            return Err(StoreError::NotFound(event_id));
+        }
+        let record = WalRecord::IncrementCount { event_id, by };
+        self.commit(&mut events_guard, record).await
+    }
+
+    #[instrument(skip_all)]
+    async fn replace_events(
+        &self,
+        event_ids: &[EventId],
+        replacements: Vec<Event>,
+    ) -> Result<Vec<EventId>, StoreError> {
+        debug!(
+            "Replacing {} events with {} events",
+            event_ids.len(),
+            replacements.len()
+        );
+
+        let mut events_guard = self.events.write().await;
+        if let Some(missing) = event_ids
+            .iter()
+            .find(|event_id| !events_guard.event_by_id.contains_key(event_id))
+        {
+            return Err(StoreError::NotFound(*missing));
+        }
+        let events: Vec<_> = replacements
+            .into_iter()
+            .map(|event| (self.id_generator.generate(), event))
+            .collect();
+        let new_ids = events.iter().map(|(event_id, _)| *event_id).collect();
+        let record = WalRecord::Replace {
+            deleted: event_ids.to_vec(),
+            events,
+        };
+        self.commit(&mut events_guard, record).await?;
+        Ok(new_ids)
+    }
+
+    #[instrument(skip_all)]
+    async fn delete_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<u64, StoreError> {
+        debug!("Deleting events");
+        let mut events_guard = self.events.write().await;
+        let Some(events) = events_guard.timestamp_index(event_type) else {
+            return Ok(0);
+        };
+        if start.zip(end).is_some_and(|(start, end)| start > end) {
+            return Ok(0);
+        }
+        let count: usize = events
+            .range(timestamp_range(start, end))
+            .map(|(_, event_ids)| event_ids.len())
+            .sum();
+        if count > 0 {
+            let record = WalRecord::Purge {
+                event_type: event_type.map(str::to_string),
+                start,
+                end,
+            };
+            self.commit(&mut events_guard, record).await?;
+        }
+        debug!("Deleted {count} events");
+        Ok(count as u64)
+    }
+
+    #[instrument(skip_all)]
+    async fn import_events(&self, events: Vec<(EventId, Event)>) -> Result<(), StoreError> {
+        debug!("Importing {} events", events.len());
+        let mut events_guard = self.events.write().await;
+        for (event_id, _) in &events {
+            self.id_generator.observe(*event_id);
+        }
+        self.commit(&mut events_guard, WalRecord::Insert { events })
+            .await
     }
 
     #[instrument(skip_all)]
@@ -92,28 +274,16 @@ impl Storage for InMemoryStorage {
         let events_guard = self.events.read().await;
 
         // Filter by event type, if specified
-        let events = if let Some(event_type) = event_type {
-            match events_guard.events_by_type_by_timestamp.get(event_type) {
-                Some(events) => events,
-                None => return Ok(vec![]),
-            }
-        } else {
-            &events_guard.events_by_timestamp
-        };
-
-        // Filter by timestamp range, if specified
-        let start = match start {
-            Some(start) => Bound::Included(start),
-            _ => Bound::Unbounded,
-        };
-        let end = match end {
-            Some(end) => Bound::Included(end),
-            _ => Bound::Unbounded,
+        let Some(events) = events_guard.timestamp_index(event_type) else {
+            return Ok(vec![]);
         };
+        if start.zip(end).is_some_and(|(start, end)| start > end) {
+            return Ok(vec![]);
+        }
 
         // Get events in the specified range. Make sure not to return more than MAX_QUERIED_EVENTS.
         let result: Vec<_> = events
-            .range((start, end))
+            .range(timestamp_range(start, end))
             .flat_map(|(_, event_ids)| {
                 event_ids
                     .iter()
@@ -130,12 +300,525 @@ impl Storage for InMemoryStorage {
         debug!("Found {} events", result.len());
         Ok(result)
     }
+
+    #[instrument(skip_all)]
+    async fn get_events_page(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+        after: Option<PageCursor>,
+        limit: usize,
+    ) -> Result<EventPage, RetrieveError> {
+        debug!("Getting a page of events");
+        let limit = page_size(limit);
+        let events_guard = self.events.read().await;
+        let Some(events) = events_guard.timestamp_index(event_type) else {
+            return Ok(EventPage::default());
+        };
+
+        // Resume at the cursor's timestamp, skipping the events of it up to the cursor's id.
+        let start = match after {
+            Some(after) => Some(start.unwrap_or_default().max(after.timestamp)),
+            None => start,
+        };
+        if start.zip(end).is_some_and(|(start, end)| start > end) {
+            return Ok(EventPage::default());
+        }
+        let rows = events
+            .range(timestamp_range(start, end))
+            .flat_map(|(timestamp, event_ids)| {
+                let mut event_ids = event_ids.clone();
+                event_ids.sort_unstable();
+                event_ids.into_iter().map(|event_id| PageCursor {
+                    timestamp: *timestamp,
+                    event_id,
+                })
+            })
+            .filter(|position| after.is_none_or(|after| *position > after))
+            .take(limit + 1)
+            .flat_map(|position| {
+                let event = events_guard.event_by_id.get(&position.event_id)?;
+                Some((position.event_id, event.clone()))
+            })
+            .collect();
+        Ok(EventPage::from_rows(rows, limit))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_by_id(&self, event_id: EventId) -> Result<Option<Event>, RetrieveError> {
+        debug!("Getting event {event_id}");
+        let events_guard = self.events.read().await;
+        Ok(events_guard.event_by_id.get(&event_id).cloned())
+    }
+
+    #[instrument(skip_all)]
+    async fn explain(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> QueryPlan {
+        let estimated_rows = self
+            .count_events(event_type, start, end)
+            .await
+            .unwrap_or_default();
+
+        // The type is resolved by picking the per-type index, and the timestamp range is a
+        // range lookup in it, so every predicate is index-backed.
+        let given = [
+            ("event_type", event_type.is_some()),
+            ("start", start.is_some()),
+            ("end", end.is_some()),
+        ];
+        let predicates = given
+            .into_iter()
+            .filter(|(_, given)| *given)
+            .map(|(field, _)| PredicatePlan {
+                field,
+                index_backed: true,
+            })
+            .collect();
+
+        QueryPlan {
+            index: match event_type {
+                Some(_) => "events_by_type_by_timestamp",
+                None => "events_by_timestamp",
+            },
+            estimated_rows,
+            predicates,
+            limit: Some(MAX_QUERIED_EVENTS as u64),
+            exceeds_limit: estimated_rows > MAX_QUERIED_EVENTS as u64,
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn scan(
+        &self,
+        filter: &EventFilter,
+        after: Option<EventId>,
+    ) -> Result<Vec<(EventId, Event)>, RetrieveError> {
+        debug!("Scanning events");
+        let events_guard = self.events.read().await;
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        let result: Vec<_> = events_guard
+            .event_by_id
+            .range((start, Bound::Unbounded))
+            .filter(|(_, event)| filter.matches(event))
+            .map(|(event_id, event)| (*event_id, event.clone()))
+            .collect();
+        debug!("Scanned {} events", result.len());
+        Ok(result)
+    }
+
+    #[instrument(skip_all)]
+    async fn count_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<u64, RetrieveError> {
+        debug!("Counting events");
+        let events_guard = self.events.read().await;
+        let Some(events) = events_guard.timestamp_index(event_type) else {
+            return Ok(0);
+        };
+        if start.zip(end).is_some_and(|(start, end)| start > end) {
+            return Ok(0);
+        }
+        let count = events
+            .range(timestamp_range(start, end))
+            .map(|(_, event_ids)| event_ids.len() as u64)
+            .sum();
+        Ok(count)
+    }
+
+    #[instrument(skip_all)]
+    async fn event_type_counts(&self) -> Vec<(String, u64)> {
+        let events_guard = self.events.read().await;
+        let mut counts: Vec<_> = events_guard
+            .events_by_type_by_timestamp
+            .iter()
+            .map(|(event_type, events)| {
+                let count = events
+                    .values()
+                    .map(|event_ids| event_ids.len() as u64)
+                    .sum();
+                (event_type.clone(), count)
+            })
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    #[instrument(skip_all)]
+    async fn aggregate(
+        &self,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<Vec<(String, u64)>, RetrieveError> {
+        debug!("Counting events by type");
+        if start.zip(end).is_some_and(|(start, end)| start > end) {
+            return Ok(vec![]);
+        }
+        let events_guard = self.events.read().await;
+        let mut counts: Vec<_> = events_guard
+            .events_by_type_by_timestamp
+            .iter()
+            .map(|(event_type, events)| {
+                let count = events
+                    .range(timestamp_range(start, end))
+                    .flat_map(|(_, event_ids)| event_ids)
+                    .map(|event_id| {
+                        let event = events_guard.event_by_id.get(event_id);
+                        event.and_then(|event| event.count).unwrap_or(1)
+                    })
+                    .sum();
+                (event_type.clone(), count)
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        counts.sort();
+        Ok(counts)
+    }
+
+    #[instrument(skip_all)]
+    async fn verify_integrity(&self, repair: bool) -> IntegrityReport {
+        let mut events_guard = self.events.write().await;
+        let problems = events_guard.verify();
+        let repaired = repair && !problems.is_empty();
+        if repaired {
+            events_guard.rebuild_indexes();
+        }
+        IntegrityReport {
+            checked_events: events_guard.event_by_id.len() as u64,
+            problems,
+            repaired,
+        }
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        None
+    }
+
+    fn resize_pool(&self, _max_size: usize) -> Option<PoolStats> {
+        None
+    }
+
+    async fn close(&self) -> Result<(), StoreError> {
+        // Every change is synced when it is logged, this only syncs the file's metadata.
+        if let Some(wal) = &self.wal {
+            wal.sync()
+                .await
+                .map_err(|error| StoreError::Backend(format!("Failed to sync the WAL: {error}")))?;
+        }
+        Ok(())
+    }
+
+    async fn health_check(&self) -> Result<(), StoreError> {
+        // Everything is in memory, the WAL is checked when it's written.
+        Ok(())
+    }
+}
+
+impl IndexedEvents {
+    fn apply(&mut self, record: WalRecord) {
+        match record {
+            WalRecord::Insert { events } => {
+                for (event_id, event) in events {
+                    self.remove(event_id);
+                    self.insert(event_id, event);
+                }
+            }
+            WalRecord::IncrementCount { event_id, by } => {
+                if let Some(event) = self.event_by_id.get_mut(&event_id) {
+                    event.count = Some(event.count.unwrap_or(1) + by);
+                }
+            }
+            WalRecord::Replace { deleted, events } => {
+                for event_id in deleted {
+                    self.remove(event_id);
+                }
+                for (event_id, event) in events {
+                    self.insert(event_id, event);
+                }
+            }
+            WalRecord::Purge {
+                event_type,
+                start,
+                end,
+            } => self.purge(event_type.as_deref(), start, end),
+        }
+    }
+
+    fn insert(&mut self, event_id: EventId, event: Event) {
+        if let Some(correlation_id) = &event.correlation_id {
+            self.events_by_correlation_id
+                .entry(correlation_id.clone())
+                .or_default()
+                .push(event_id);
+        }
+        self.events_by_type_by_timestamp
+            .entry(event.event_type.clone())
+            .or_default()
+            .entry(event.timestamp)
+            .or_default()
+            .push(event_id);
+        self.events_by_timestamp
+            .entry(event.timestamp)
+            .or_default()
+            .push(event_id);
+        self.event_by_id.insert(event_id, event);
+    }
+
+    fn remove(&mut self, event_id: EventId) -> Option<Event> {
+        let event = self.event_by_id.remove(&event_id)?;
+        self.remove_correlation(event_id, &event);
+        self.remove_from_type_index(event_id, &event);
+        remove_from_timestamp_index(&mut self.events_by_timestamp, event.timestamp, event_id);
+        Some(event)
+    }
+
+    /// Removes the events in the inclusive timestamp range, of a type if given. The range is
+    /// split off the timestamp index it is looked up in at once, so expiring the oldest events
+    /// doesn't remove them from there one by one.
+    fn purge(
+        &mut self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) {
+        let index = match event_type {
+            Some(event_type) => match self.events_by_type_by_timestamp.get_mut(event_type) {
+                Some(index) => index,
+                None => return,
+            },
+            None => &mut self.events_by_timestamp,
+        };
+        let purged = split_range(index, start, end);
+        if let Some(event_type) = event_type
+            && index.is_empty()
+        {
+            self.events_by_type_by_timestamp.remove(event_type);
+        }
+        for (timestamp, event_ids) in purged {
+            for event_id in event_ids {
+                let Some(event) = self.event_by_id.remove(&event_id) else {
+                    continue;
+                };
+                self.remove_correlation(event_id, &event);
+                match event_type {
+                    Some(_) => remove_from_timestamp_index(
+                        &mut self.events_by_timestamp,
+                        timestamp,
+                        event_id,
+                    ),
+                    None => self.remove_from_type_index(event_id, &event),
+                }
+            }
+        }
+    }
+
+    fn remove_correlation(&mut self, event_id: EventId, event: &Event) {
+        if let Some(correlation_id) = &event.correlation_id
+            && let Some(event_ids) = self.events_by_correlation_id.get_mut(correlation_id)
+        {
+            event_ids.retain(|id| *id != event_id);
+            if event_ids.is_empty() {
+                self.events_by_correlation_id.remove(correlation_id);
+            }
+        }
+    }
+
+    fn remove_from_type_index(&mut self, event_id: EventId, event: &Event) {
+        if let Some(by_timestamp) = self.events_by_type_by_timestamp.get_mut(&event.event_type) {
+            remove_from_timestamp_index(by_timestamp, event.timestamp, event_id);
+            if by_timestamp.is_empty() {
+                self.events_by_type_by_timestamp.remove(&event.event_type);
+            }
+        }
+    }
+
+    /// Returns inconsistencies between the indexes and the stored events.
+    fn verify(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let mut check_timestamp_index =
+            |name: &str, index: &BTreeMap<Timestamp, Vec<EventId>>, event_type: Option<&str>| {
+                let mut indexed = 0;
+                for (timestamp, event_ids) in index {
+                    for event_id in event_ids {
+                        indexed += 1;
+                        match self.event_by_id.get(event_id) {
+                            None => problems.push(format!("{name}: missing event {event_id}")),
+                            Some(event)
+                                if event.timestamp != *timestamp
+                                    || event_type.is_some_and(|t| t != event.event_type) =>
+                            {
+                                problems.push(format!("{name}: event {event_id} misplaced"))
+                            }
+                            Some(_) => {}
+                        }
+                    }
+                }
+                indexed
+            };
+
+        let indexed = check_timestamp_index("events_by_timestamp", &self.events_by_timestamp, None);
+        let mut indexed_by_type = 0;
+        for (event_type, index) in &self.events_by_type_by_timestamp {
+            indexed_by_type +=
+                check_timestamp_index("events_by_type_by_timestamp", index, Some(event_type));
+        }
+        for (name, indexed) in [
+            ("events_by_timestamp", indexed),
+            ("events_by_type_by_timestamp", indexed_by_type),
+        ] {
+            if indexed != self.event_by_id.len() {
+                problems.push(format!(
+                    "{name}: {indexed} entries for {} events",
+                    self.event_by_id.len()
+                ));
+            }
+        }
+
+        let correlated = self
+            .event_by_id
+            .values()
+            .filter(|event| event.correlation_id.is_some())
+            .count();
+        let mut indexed_by_correlation_id = 0;
+        for (correlation_id, event_ids) in &self.events_by_correlation_id {
+            indexed_by_correlation_id += event_ids.len();
+            if !event_ids.windows(2).all(|pair| pair[0] < pair[1]) {
+                problems.push(format!(
+                    "events_by_correlation_id: ids of '{correlation_id}' not increasing"
+                ));
+            }
+            for event_id in event_ids {
+                let event = self.event_by_id.get(event_id);
+                if event.and_then(|event| event.correlation_id.as_ref()) != Some(correlation_id) {
+                    problems.push(format!(
+                        "events_by_correlation_id: event {event_id} misplaced"
+                    ));
+                }
+            }
+        }
+        if indexed_by_correlation_id != correlated {
+            problems.push(format!(
+                "events_by_correlation_id: {indexed_by_correlation_id} entries for {correlated} \
+                 events"
+            ));
+        }
+        problems
+    }
+
+    /// Rebuilds all indexes from the stored events.
+    fn rebuild_indexes(&mut self) {
+        let event_by_id = std::mem::take(&mut self.event_by_id);
+        self.events_by_timestamp.clear();
+        self.events_by_type_by_timestamp.clear();
+        self.events_by_correlation_id.clear();
+        for (event_id, event) in event_by_id {
+            self.insert(event_id, event);
+        }
+    }
+
+    /// Returns events matching the filter. Events are in timestamp order, except when filtering
+    /// by correlation id, in which case they are in insertion order.
+    fn filtered<'a>(&'a self, filter: &'a EventFilter) -> impl Iterator<Item = &'a Event> {
+        let range = timestamp_range(filter.start, filter.end);
+        let inverted = filter
+            .start
+            .zip(filter.end)
+            .is_some_and(|(start, end)| start > end);
+        let event_ids: Box<dyn Iterator<Item = &EventId> + '_> = match &filter.correlation_id {
+            Some(correlation_id) => Box::new(
+                self.events_by_correlation_id
+                    .get(correlation_id)
+                    .into_iter()
+                    .flatten(),
+            ),
+            None => Box::new(
+                self.timestamp_index(filter.event_type.as_deref())
+                    .filter(|_| !inverted)
+                    .into_iter()
+                    .flat_map(move |events| events.range(range))
+                    .flat_map(|(_, event_ids)| event_ids.iter()),
+            ),
+        };
+        event_ids
+            .filter_map(move |event_id| self.event_by_id.get(event_id))
+            .filter(move |event| filter.matches(event))
+    }
+
+    /// Returns the timestamp index of the given event type, or the index of all events.
+    fn timestamp_index(
+        &self,
+        event_type: Option<&str>,
+    ) -> Option<&BTreeMap<Timestamp, Vec<EventId>>> {
+        match event_type {
+            Some(event_type) => self.events_by_type_by_timestamp.get(event_type),
+            None => Some(&self.events_by_timestamp),
+        }
+    }
+}
+
+fn remove_from_timestamp_index(
+    index: &mut BTreeMap<Timestamp, Vec<EventId>>,
+    timestamp: Timestamp,
+    event_id: EventId,
+) {
+    if let Some(event_ids) = index.get_mut(&timestamp) {
+        event_ids.retain(|id| *id != event_id);
+        if event_ids.is_empty() {
+            index.remove(&timestamp);
+        }
+    }
+}
+
+/// Splits the entries in the inclusive timestamp range off the index.
+fn split_range(
+    index: &mut BTreeMap<Timestamp, Vec<EventId>>,
+    start: Option<Timestamp>,
+    end: Option<Timestamp>,
+) -> BTreeMap<Timestamp, Vec<EventId>> {
+    let mut purged = match start {
+        Some(start) => index.split_off(&start),
+        None => std::mem::take(index),
+    };
+    if let Some(after) = end.and_then(|end| end.checked_add(1)) {
+        // Appending to an empty index just moves the kept entries back.
+        index.append(&mut purged.split_off(&after));
+    }
+    purged
+}
+
+/// Converts optional inclusive timestamp limits into a range.
+fn timestamp_range(
+    start: Option<Timestamp>,
+    end: Option<Timestamp>,
+) -> (Bound<Timestamp>, Bound<Timestamp>) {
+    let start = match start {
+        Some(start) => Bound::Included(start),
+        _ => Bound::Unbounded,
+    };
+    let end = match end {
+        Some(end) => Bound::Included(end),
+        _ => Bound::Unbounded,
+    };
+    (start, end)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::event::Event;
+    use crate::{
+        event::Event,
+        id_generator::{IdStrategy, make_id_generator},
+    };
 
     #[tokio::test]
     async fn test_filtering() {
@@ -143,16 +826,19 @@ mod tests {
             event_type: "login".to_string(),
             timestamp: 4,
             payload: serde_json::json!({ "user_id": 123, "ip": "127.0.0.4" }),
+            ..Default::default()
         };
         let event_2 = Event {
             event_type: "login".to_string(),
             timestamp: 5,
             payload: serde_json::json!({ "user_id": 123, "ip": "127.0.0.5" }),
+            ..Default::default()
         };
         let event_3 = Event {
             event_type: "foo".to_string(),
             timestamp: 6,
             payload: serde_json::json!({ "user_id": 123, "ip": "127.0.0.6" }),
+            ..Default::default()
         };
         let store = InMemoryStorage::new();
 
@@ -160,6 +846,14 @@ mod tests {
         store.store(event_2.clone()).await.unwrap();
         store.store(event_3.clone()).await.unwrap();
 
+        assert_eq!(
+            store.event_type_counts().await,
+            vec![("foo".to_string(), 1), ("login".to_string(), 2)]
+        );
+        assert_eq!(
+            store.aggregate(Some(5), Some(5)).await.unwrap(),
+            vec![("login".to_string(), 1)]
+        );
         assert_eq!(
             store.get_events(None, None, None).await.unwrap(),
             vec![event_1.clone(), event_2.clone(), event_3.clone()]
@@ -198,4 +892,174 @@ mod tests {
             vec![event_2.clone()]
         );
     }
+
+    #[tokio::test]
+    async fn test_get_events_page() {
+        let store = InMemoryStorage::new();
+        let mut expected = vec![];
+        // More events than the result size limit, some sharing a timestamp.
+        for timestamp in [3, 1, 2, 2, 2, 5, 4, 2, 6, 7] {
+            let event = Event {
+                event_type: "click".to_string(),
+                timestamp,
+                ..Default::default()
+            };
+            store.store(event.clone()).await.unwrap();
+            expected.push(event);
+        }
+        expected.sort_by_key(|event| event.timestamp);
+
+        let mut walked = vec![];
+        let mut cursor = None;
+        loop {
+            let page = store
+                .get_events_page(Some("click"), None, None, cursor, 3)
+                .await
+                .unwrap();
+            assert!(page.events.len() <= 3);
+            walked.extend(page.events.into_iter().map(|(_, event)| event));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(walked, expected);
+
+        let page = store
+            .get_events_page(None, Some(2), Some(4), None, 100)
+            .await
+            .unwrap();
+        assert_eq!(page.events.len(), MAX_QUERIED_EVENTS);
+        let page = store
+            .get_events_page(None, Some(2), Some(4), page.next_cursor, 100)
+            .await
+            .unwrap();
+        let events: Vec<_> = page.events.into_iter().map(|(_, event)| event).collect();
+        assert_eq!(events, expected[5..7]);
+        assert_eq!(page.next_cursor, None);
+
+        let events = store.get_events(Some("click"), Some(4), Some(2)).await;
+        assert_eq!(events.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_delete_events() {
+        let store = InMemoryStorage::new();
+        for (event_type, timestamp) in [("click", 1), ("click", 2), ("view", 2), ("click", 3)] {
+            let event = Event {
+                event_type: event_type.to_string(),
+                timestamp,
+                ..Default::default()
+            };
+            store.store(event).await.unwrap();
+        }
+        let deleted = store.delete_events(Some("click"), None, Some(2)).await;
+        assert_eq!(deleted.unwrap(), 2);
+        assert_eq!(
+            store
+                .delete_events(Some("scroll"), None, None)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            store.delete_events(None, Some(3), Some(1)).await.unwrap(),
+            0
+        );
+
+        let remaining = store.get_events(None, None, None).await.unwrap();
+        let remaining: Vec<_> = remaining
+            .iter()
+            .map(|event| (event.event_type.as_str(), event.timestamp))
+            .collect();
+        assert_eq!(remaining, vec![("view", 2), ("click", 3)]);
+        assert!(store.verify_integrity(false).await.problems.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_verify_integrity() {
+        let store = InMemoryStorage::new();
+        for timestamp in [1, 2] {
+            let event = Event {
+                event_type: "login".to_string(),
+                timestamp,
+                correlation_id: Some("user-1".to_string()),
+                ..Default::default()
+            };
+            store.store(event).await.unwrap();
+        }
+        assert!(store.verify_integrity(false).await.problems.is_empty());
+
+        store.events.write().await.events_by_timestamp.remove(&2);
+        let report = store.verify_integrity(false).await;
+        assert_eq!(report.problems.len(), 1);
+        assert!(!report.repaired);
+
+        assert!(store.verify_integrity(true).await.repaired);
+        assert!(store.verify_integrity(false).await.problems.is_empty());
+        assert_eq!(store.count_events(None, Some(2), None).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_wal_recovery() {
+        let path = std::env::temp_dir().join(format!("events-{}.wal", std::process::id()));
+        let open =
+            || InMemoryStorage::with_wal(&path, make_id_generator(IdStrategy::Sequential)).unwrap();
+        let login = |timestamp| Event {
+            event_type: "login".to_string(),
+            timestamp,
+            correlation_id: Some("user-1".to_string()),
+            ..Default::default()
+        };
+        let store = open();
+        let first = store.store(login(1)).await.unwrap();
+        let batch = store
+            .store_transaction(vec![login(2), login(3)])
+            .await
+            .unwrap();
+        store.increment_count(first, 2).await.unwrap();
+        store.replace_events(&batch, vec![login(4)]).await.unwrap();
+        let expected = store.scan(&EventFilter::default(), None).await.unwrap();
+        drop(store);
+
+        let store = open();
+        assert_eq!(
+            store.scan(&EventFilter::default(), None).await.unwrap(),
+            expected
+        );
+        assert_eq!(expected[0].1.count, Some(3));
+        assert!(store.verify_integrity(false).await.problems.is_empty());
+        // The log was compacted, and ids continue after the recovered ones.
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert_eq!(store.store(login(5)).await.unwrap(), 5);
+        drop(store);
+
+        // A torn last record is skipped, but a log with an invalid record before it is kept.
+        let mut log = std::fs::read_to_string(&path).unwrap();
+        log.push_str("{\"insert\":");
+        std::fs::write(&path, &log).unwrap();
+        assert_eq!(
+            open()
+                .scan(&EventFilter::default(), None)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+        // So is a log with a record that doesn't match its checksum, even if it parses.
+        let log = std::fs::read_to_string(&path).unwrap();
+        let log = format!("{}{log}", log.replacen("login", "logon", 1));
+        std::fs::write(&path, &log).unwrap();
+        assert!(
+            InMemoryStorage::with_wal(&path, make_id_generator(IdStrategy::Sequential)).is_err()
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), log);
+        let log = format!("{{\"insert\":\n{}", std::fs::read_to_string(&path).unwrap());
+        std::fs::write(&path, &log).unwrap();
+        assert!(
+            InMemoryStorage::with_wal(&path, make_id_generator(IdStrategy::Sequential)).is_err()
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), log);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
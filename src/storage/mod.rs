@@ -1,25 +1,280 @@
+mod event_filter;
 mod in_memory_storage;
+mod postgres_storage;
+mod postgres_tls;
+mod replicated_storage;
+mod spans;
+mod sqlite_storage;
+mod wal;
+mod write_batcher;
+
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::PathBuf, str::FromStr};
 
 use crate::event::Event;
+use crate::event::EventId;
 use crate::event::Timestamp;
 
+pub use event_filter::EventFilter;
 pub use in_memory_storage::InMemoryStorage;
+pub use postgres_storage::{PostgresPoolConfig, PostgresStorage};
+pub use replicated_storage::{ReplicaConfig, ReplicatedStorage};
+pub use sqlite_storage::SqliteStorage;
+pub use write_batcher::WriteBatchConfig;
+
+// Made-up restriction to demonstrate error handling.
+const MAX_QUERIED_EVENTS: usize = 4;
+
+/// Clamps a requested page size to the result size limit. Pages hold at least one event, so
+/// walking them always makes progress.
+fn page_size(limit: usize) -> usize {
+    limit.clamp(1, MAX_QUERIED_EVENTS)
+}
+
+/// Storage backends selectable at startup.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum StorageBackend {
+    /// Keeps events in memory, they are lost on restart.
+    #[default]
+    Memory,
+
+    /// Keeps events in a SQLite database file.
+    Sqlite(PathBuf),
+
+    /// Keeps events in a PostgreSQL database, given by its URL.
+    Postgres(String),
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    /// Parses `memory`, `sqlite:<path>` or a `postgres://` URL.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            _ if s == "memory" => Ok(StorageBackend::Memory),
+            Some(("sqlite", path)) if !path.is_empty() => Ok(StorageBackend::Sqlite(path.into())),
+            Some(("postgres" | "postgresql", _)) => Ok(StorageBackend::Postgres(s.to_string())),
+            _ => Err(format!("Unknown storage backend: '{s}'")),
+        }
+    }
+}
 
 /// Error type for storage operations.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum StoreError {
-    InvalidEventType(String),
+    ConditionFailed {
+        expected: u64,
+        matched: u64,
+    },
+    NotFound(EventId),
+
+    /// The backend failed, eg. a database error.
+    Backend(String),
+}
+
+/// Condition of a conditional write: the event is only stored if exactly `matches` existing
+/// events match the filter.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WriteCondition {
+    pub filter: EventFilter,
+
+    #[serde(default)]
+    pub matches: u64,
+}
+
+/// How a storage backend would execute a `get_events` query.
+#[derive(Debug, Serialize)]
+pub struct QueryPlan {
+    /// Index the events are looked up in.
+    pub index: &'static str,
+
+    /// Number of events the index lookup yields, before post-filtering.
+    pub estimated_rows: u64,
+
+    /// How each given predicate is evaluated.
+    pub predicates: Vec<PredicatePlan>,
+
+    /// Maximum number of events the query may return, if limited.
+    pub limit: Option<u64>,
+
+    /// Whether the query would be rejected for exceeding the limit.
+    pub exceeds_limit: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PredicatePlan {
+    pub field: &'static str,
+
+    /// Whether the predicate narrows the index lookup, as opposed to filtering its results.
+    pub index_backed: bool,
+}
+
+/// Result of a storage integrity check.
+#[derive(Debug, Serialize)]
+pub struct IntegrityReport {
+    pub checked_events: u64,
+
+    /// Inconsistencies found, empty if the storage is intact.
+    pub problems: Vec<String>,
+
+    /// Whether the problems were repaired.
+    pub repaired: bool,
+}
+
+/// Statistics of a database connection pool.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct PoolStats {
+    /// Most connections open at a time.
+    pub max_size: usize,
+
+    /// Open connections.
+    pub size: usize,
+
+    /// Open connections not in use.
+    pub idle: usize,
+
+    /// Operations waiting for a connection.
+    pub waiting: usize,
+
+    /// Connections taken from the pool since startup.
+    pub acquired: u64,
+
+    /// Total time operations waited for a connection since startup.
+    pub wait_seconds: f64,
+}
+
+/// Position in the events ordered by timestamp, then id. Pages resume after the position of
+/// their previous page's last event, so events stored meanwhile don't shift the pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PageCursor {
+    pub timestamp: Timestamp,
+    pub event_id: EventId,
+}
+
+impl fmt::Display for PageCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:x}", self.timestamp, self.event_id)
+    }
+}
+
+impl FromStr for PageCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid cursor: '{s}'");
+        let (timestamp, event_id) = s.split_once('.').ok_or_else(invalid)?;
+        Ok(PageCursor {
+            timestamp: timestamp.parse().map_err(|_| invalid())?,
+            event_id: EventId::from_str_radix(event_id, 16).map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// A page of events, see `Storage::get_events_page`.
+#[derive(Debug, Default)]
+pub struct EventPage {
+    /// The events of the page with their ids.
+    pub events: Vec<(EventId, Event)>,
+
+    /// Where the next page starts, `None` if this is the last page.
+    pub next_cursor: Option<PageCursor>,
+}
+
+impl EventPage {
+    /// Makes a page of at most `limit` events from the rows of a query for one more event
+    /// than that. The extra event tells that there is a next page.
+    fn from_rows(mut rows: Vec<(EventId, Event)>, limit: usize) -> Self {
+        let next_cursor = if rows.len() > limit {
+            rows.truncate(limit);
+            rows.last().map(|(event_id, event)| PageCursor {
+                timestamp: event.timestamp,
+                event_id: *event_id,
+            })
+        } else {
+            None
+        };
+        EventPage {
+            events: rows,
+            next_cursor,
+        }
+    }
 }
 
 /// Error type for retrieval operations.
 #[derive(Debug)]
 pub enum RetrieveError {
     ResultTooLarge(u64),
+
+    /// The backend failed, eg. a database error.
+    Backend(String),
 }
+
 /// Storage trait for event storage.
 #[async_trait::async_trait]
 pub trait Storage {
-    async fn store(&self, event: Event) -> Result<(), StoreError>;
+    /// Short name of the storage backend, eg. `memory`.
+    fn name(&self) -> &'static str;
+
+    /// Checks whether the event could be stored, without storing it.
+    fn validate(&self, event: &Event) -> Result<(), StoreError>;
+
+    /// Stores the event and returns its id.
+    async fn store(&self, event: Event) -> Result<EventId, StoreError>;
+
+    /// Stores all events or none of them. No reader sees a partially stored transaction.
+    async fn store_transaction(&self, events: Vec<Event>) -> Result<Vec<EventId>, StoreError>;
+
+    /// Stores each event on its own, returning the id of each or why it wasn't stored, in the
+    /// order of the events. The valid events are stored with a single write of
+    /// `store_transaction`, so backends only need to optimize that for multi-row inserts.
+    async fn store_batch(&self, events: Vec<Event>) -> Vec<Result<EventId, StoreError>> {
+        let checks: Vec<_> = events.iter().map(|event| self.validate(event)).collect();
+        let valid: Vec<_> = events
+            .into_iter()
+            .zip(&checks)
+            .filter(|(_, check)| check.is_ok())
+            .map(|(event, _)| event)
+            .collect();
+        let stored = match self.store_transaction(valid).await {
+            Ok(event_ids) => event_ids.into_iter().map(Ok).collect(),
+            Err(error) => vec![Err(error); checks.iter().filter(|check| check.is_ok()).count()],
+        };
+        let mut stored = stored.into_iter();
+        checks
+            .into_iter()
+            .map(|check| match check {
+                Ok(()) => stored.next().expect("A result for each valid event"),
+                Err(error) => Err(error),
+            })
+            .collect()
+    }
+
+    /// Stores the event only if the condition holds. The check and the write are atomic.
+    async fn store_if(
+        &self,
+        event: Event,
+        condition: &WriteCondition,
+    ) -> Result<EventId, StoreError>;
+
+    /// Adds to the `count` of a stored event, see `Event::count`.
+    async fn increment_count(&self, event_id: EventId, by: u64) -> Result<(), StoreError>;
+
+    /// Deletes events and stores replacements for them atomically, eg. aggregates of raw
+    /// events. Fails without changes if any of the events doesn't exist.
+    async fn replace_events(
+        &self,
+        event_ids: &[EventId],
+        replacements: Vec<Event>,
+    ) -> Result<Vec<EventId>, StoreError>;
+
+    /// Deletes the events `get_events` would return, without the result size limit, and
+    /// returns how many were deleted.
+    async fn delete_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<u64, StoreError>;
 
     async fn get_events(
         &self,
@@ -27,4 +282,80 @@ pub trait Storage {
         start: Option<Timestamp>,
         end: Option<Timestamp>,
     ) -> Result<Vec<Event>, RetrieveError>;
+
+    /// Returns a page of the events `get_events` would, starting after the cursor. Pages hold
+    /// at most `limit` events, capped by the result size limit, so any number of events can be
+    /// walked through page by page.
+    async fn get_events_page(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+        after: Option<PageCursor>,
+        limit: usize,
+    ) -> Result<EventPage, RetrieveError>;
+
+    /// Returns the event with the given id, `None` if there is none.
+    async fn get_by_id(&self, event_id: EventId) -> Result<Option<Event>, RetrieveError>;
+
+    /// Stores events with their original ids, eg. when restoring a snapshot. Ids generated
+    /// later are larger than the imported ones.
+    async fn import_events(&self, events: Vec<(EventId, Event)>) -> Result<(), StoreError>;
+
+    /// Describes how `get_events` would execute the query, without running it.
+    async fn explain(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> QueryPlan;
+
+    /// Returns events matching the filter that were stored after the given event, in the order
+    /// they were stored. Unlike `get_events`, it isn't subject to result size limits.
+    async fn scan(
+        &self,
+        filter: &EventFilter,
+        after: Option<EventId>,
+    ) -> Result<Vec<(EventId, Event)>, RetrieveError>;
+
+    /// Counts events without retrieving them, so it isn't subject to result size limits.
+    async fn count_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<u64, RetrieveError>;
+
+    /// Returns the number of stored events of each type, ordered by event type.
+    async fn event_type_counts(&self) -> Vec<(String, u64)>;
+
+    /// Counts the events of each type in a time range, ordered by event type, with coalesced
+    /// events counted as the events they stand for. Counted on the per-type indexes, without
+    /// retrieving the events, so it isn't subject to result size limits. Types without events
+    /// in the range are left out.
+    async fn aggregate(
+        &self,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<Vec<(String, u64)>, RetrieveError>;
+
+    /// Verifies that the indexes are consistent with the stored events and that ids are
+    /// increasing in insertion order. With `repair`, rebuilds the indexes from the stored
+    /// events if problems are found.
+    async fn verify_integrity(&self, repair: bool) -> IntegrityReport;
+
+    /// Returns the statistics of the connection pool, `None` if the backend has none.
+    fn pool_stats(&self) -> Option<PoolStats>;
+
+    /// Changes the most connections open at a time, without a restart. Returns the new
+    /// statistics, `None` if the backend has no connection pool.
+    fn resize_pool(&self, max_size: usize) -> Option<PoolStats>;
+
+    /// Writes the buffered events and syncs them to disk, then releases the backend's
+    /// connections. Called once when the server shuts down; later writes fail.
+    async fn close(&self) -> Result<(), StoreError>;
+
+    /// Checks that the backend can serve requests, e.g. that the database is reachable, with
+    /// a cheap round trip that doesn't change any data.
+    async fn health_check(&self) -> Result<(), StoreError>;
 }
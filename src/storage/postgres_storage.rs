@@ -0,0 +1,984 @@
+use anyhow::{Context, bail};
+use deadpool_postgres::{
+    Manager, ManagerConfig, Object, Pool, PoolError, RecyclingMethod, Runtime,
+};
+use std::{
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+use tokio_postgres::{Client, NoTls, Row, Transaction, config::SslMode, types::ToSql};
+use tracing::{Instrument, debug, info, info_span, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    event::{Event, EventId, Timestamp},
+    id_generator::IdGenerator,
+    metrics,
+    storage::{
+        EventFilter, EventPage, IntegrityReport, MAX_QUERIED_EVENTS, PageCursor, PoolStats,
+        PredicatePlan, QueryPlan, RetrieveError, Storage, StoreError, WriteCondition, page_size,
+        postgres_tls::MakeRustlsConnect, spans::statement_span,
+    },
+};
+
+/// Schema changes, applied in order. Applied migrations are never changed, new ones are added.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE events (
+        id UUID PRIMARY KEY,
+        event_type TEXT NOT NULL,
+        timestamp BIGINT NOT NULL,
+        correlation_id TEXT,
+        count BIGINT,
+        payload JSONB NOT NULL
+    );
+    CREATE INDEX events_by_type_by_timestamp ON events (event_type, timestamp);
+    CREATE INDEX events_by_timestamp ON events (timestamp);
+    CREATE INDEX events_by_correlation_id ON events (correlation_id);
+"];
+
+/// Advisory lock held while migrating, so instances starting together don't race.
+const MIGRATION_LOCK: i64 = 0x6576_656e_7473;
+
+/// Advisory lock held by writes from generating ids until they commit, so events are committed
+/// in id order, and readers scanning after an id don't miss events committed late.
+const WRITE_LOCK: i64 = 0x7772_6974_6573;
+
+const COLUMNS: &str = "id, event_type, timestamp, correlation_id, count, payload";
+
+/// Inserts a new event. Fails if the id is taken, so a colliding id can't overwrite an event.
+const INSERT: &str = "INSERT INTO events \
+                      (id, event_type, timestamp, correlation_id, count, payload) \
+                      VALUES ($1, $2, $3, $4, $5, $6)";
+
+/// Inserts an imported event, replacing the event with the same id.
+const IMPORT: &str = "INSERT INTO events \
+                      (id, event_type, timestamp, correlation_id, count, payload) \
+                      VALUES ($1, $2, $3, $4, $5, $6) \
+                      ON CONFLICT (id) DO UPDATE SET event_type = $2, timestamp = $3, \
+                      correlation_id = $4, count = $5, payload = $6";
+
+/// Inserts new events from arrays of their columns, one row per element.
+const INSERT_ALL: &str = "INSERT INTO events \
+                          (id, event_type, timestamp, correlation_id, count, payload) \
+                          SELECT * FROM UNNEST($1::uuid[], $2::text[], $3::bigint[], \
+                          $4::text[], $5::bigint[], $6::jsonb[])";
+
+/// Configures the connection pool of the PostgreSQL storage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PostgresPoolConfig {
+    /// Most connections open at a time.
+    pub max_size: usize,
+
+    /// Longest time an operation waits for a free connection.
+    pub wait_timeout: Duration,
+}
+
+impl Default for PostgresPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 16,
+            wait_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Stores events in a PostgreSQL database, for production deployments.
+///
+/// Ids are stored as UUIDs, which sort like the ids, so in insertion order. They are generated
+/// by the process, so only one instance may write to a database.
+pub struct PostgresStorage {
+    pool: Pool,
+    id_generator: Box<dyn IdGenerator + Send + Sync>,
+
+    /// Set once the migrations are applied, on the first operation.
+    migrated: OnceCell<()>,
+
+    /// Connections taken from the pool.
+    acquired: AtomicU64,
+
+    /// Total time spent waiting for connections.
+    wait_micros: AtomicU64,
+}
+
+impl PostgresStorage {
+    /// Creates the connection pool. Connections are opened and the schema is migrated on first
+    /// use, so an unreachable database fails the operations instead of the startup.
+    ///
+    /// Connections use TLS as the `sslmode` of the URL asks, with the server's certificate
+    /// verified against the CAs in `ca_file`. Without it, connections are plaintext, and
+    /// `sslmode=require` is refused.
+    pub fn new(
+        url: &str,
+        id_generator: Box<dyn IdGenerator + Send + Sync>,
+        pool: PostgresPoolConfig,
+        ca_file: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let config: tokio_postgres::Config = url.parse().context("Invalid PostgreSQL URL")?;
+        let manager_config = ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        };
+        let manager = match ca_file {
+            Some(path) => {
+                let tls = MakeRustlsConnect::from_ca_file(path)?;
+                Manager::from_config(config, tls, manager_config)
+            }
+            None if config.get_ssl_mode() == SslMode::Require => {
+                bail!("sslmode=require needs POSTGRES_CA_FILE to verify the server")
+            }
+            None => Manager::from_config(config, NoTls, manager_config),
+        };
+        let pool = Pool::builder(manager)
+            .max_size(pool.max_size)
+            .wait_timeout(Some(pool.wait_timeout))
+            .runtime(Runtime::Tokio1)
+            .build()
+            .context("Failed to create the connection pool")?;
+        Ok(Self {
+            pool,
+            id_generator,
+            migrated: OnceCell::new(),
+            acquired: AtomicU64::new(0),
+            wait_micros: AtomicU64::new(0),
+        })
+    }
+
+    /// Takes a connection from the pool, migrating the schema first if needed.
+    async fn client(&self) -> Result<Object, PoolError> {
+        let started = Instant::now();
+        let client = self
+            .pool
+            .get()
+            .instrument(info_span!("db_connection", db.system = "postgresql"))
+            .await;
+        let waited = started.elapsed();
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        self.wait_micros
+            .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+        let labels = [("storage", "postgres")];
+        metrics::increment("storage_pool_acquisitions_total", &labels);
+        metrics::add(
+            "storage_pool_wait_seconds_total",
+            &labels,
+            waited.as_secs_f64(),
+        );
+        record_pool_metrics(&self.stats());
+
+        let mut client = client?;
+        self.migrated
+            .get_or_try_init(|| migrate(&mut client, self.id_generator.as_ref()))
+            .await?;
+        Ok(client)
+    }
+
+    fn stats(&self) -> PoolStats {
+        let status = self.pool.status();
+        PoolStats {
+            max_size: status.max_size,
+            size: status.size,
+            idle: status.available,
+            waiting: status.waiting,
+            acquired: self.acquired.load(Ordering::Relaxed),
+            wait_seconds: self.wait_micros.load(Ordering::Relaxed) as f64 / 1e6,
+        }
+    }
+
+    /// Stores the events with a single statement, returning their new ids.
+    async fn insert_all(&self, events: &[Event]) -> Result<Vec<EventId>, StoreError> {
+        if events.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        lock_writes(&transaction).await?;
+        let event_ids: Vec<_> = events
+            .iter()
+            .map(|_| self.id_generator.generate())
+            .collect();
+        let ids: Vec<_> = event_ids.iter().map(|id| Uuid::from_u128(*id)).collect();
+        let event_types: Vec<_> = events.iter().map(|event| &event.event_type).collect();
+        let timestamps: Vec<_> = events.iter().map(|event| event.timestamp as i64).collect();
+        let correlation_ids: Vec<_> = events.iter().map(|event| &event.correlation_id).collect();
+        let counts: Vec<_> = events
+            .iter()
+            .map(|event| event.count.map(|count| count as i64))
+            .collect();
+        let payloads: Vec<_> = events.iter().map(|event| &event.payload).collect();
+        let span = statement_span("postgresql", INSERT_ALL);
+        let inserted = transaction
+            .execute(
+                INSERT_ALL,
+                &[
+                    &ids,
+                    &event_types,
+                    &timestamps,
+                    &correlation_ids,
+                    &counts,
+                    &payloads,
+                ],
+            )
+            .instrument(span.clone())
+            .await?;
+        span.record("db.rows", inserted);
+        transaction.commit().await?;
+        Ok(event_ids)
+    }
+
+    async fn select(
+        &self,
+        filter: &EventFilter,
+        after: Option<EventId>,
+        order: &str,
+    ) -> Result<Vec<(EventId, Event)>, RetrieveError> {
+        let client = self.client().await?;
+        Ok(select(&**client, filter, after, order).await?)
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for PostgresStorage {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    fn validate(&self, event: &Event) -> Result<(), StoreError> {
+        // PostgreSQL integers are signed 64-bit.
+        let too_large = |value: u64| i64::try_from(value).is_err();
+        if too_large(event.timestamp) || event.count.is_some_and(too_large) {
+            return Err(StoreError::Backend("Integer out of range".to_string()));
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn store(&self, event: Event) -> Result<EventId, StoreError> {
+        debug!("Storing event");
+        self.validate(&event)?;
+        let event_ids = self.insert_all(std::slice::from_ref(&event)).await?;
+        Ok(event_ids[0])
+    }
+
+    #[instrument(skip_all)]
+    async fn store_transaction(&self, events: Vec<Event>) -> Result<Vec<EventId>, StoreError> {
+        debug!("Storing {} events in a transaction", events.len());
+        events.iter().try_for_each(|event| self.validate(event))?;
+        self.insert_all(&events).await
+    }
+
+    #[instrument(skip_all)]
+    async fn store_if(
+        &self,
+        event: Event,
+        condition: &WriteCondition,
+    ) -> Result<EventId, StoreError> {
+        debug!("Storing event conditionally");
+        self.validate(&event)?;
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        lock_writes(&transaction).await?;
+        // Blocks other writes until the transaction ends, so no other event can sneak in.
+        let query = "LOCK TABLE events IN SHARE ROW EXCLUSIVE MODE";
+        transaction
+            .batch_execute(query)
+            .instrument(statement_span("postgresql", query))
+            .await?;
+        let matched = select(&*transaction, &condition.filter, None, "id")
+            .await?
+            .len() as u64;
+        if matched != condition.matches {
+            return Err(StoreError::ConditionFailed {
+                expected: condition.matches,
+                matched,
+            });
+        }
+        let event_id = self.id_generator.generate();
+        insert(&transaction, INSERT, event_id, &event).await?;
+        transaction.commit().await?;
+        Ok(event_id)
+    }
+
+    #[instrument(skip_all)]
+    async fn increment_count(&self, event_id: EventId, by: u64) -> Result<(), StoreError> {
+        let by = i64::try_from(by).map_err(|_| StoreError::Backend("Count out of range".into()))?;
+        let client = self.client().await?;
+        let query = "UPDATE events SET count = coalesce(count, 1) + $1 WHERE id = $2";
+        let span = statement_span("postgresql", query);
+        let updated = client
+            .execute(query, &[&by, &Uuid::from_u128(event_id)])
+            .instrument(span.clone())
+            .await?;
+        span.record("db.rows", updated);
+        match updated {
+            0 => Err(StoreError::NotFound(event_id)),
+            _ => Ok(()),
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn replace_events(
+        &self,
+        event_ids: &[EventId],
+        replacements: Vec<Event>,
+    ) -> Result<Vec<EventId>, StoreError> {
+        debug!(
+            "Replacing {} events with {} events",
+            event_ids.len(),
+            replacements.len()
+        );
+        replacements
+            .iter()
+            .try_for_each(|event| self.validate(event))?;
+        let mut client = self.client().await?;
+        // Dropping the transaction on an error rolls it back.
+        let transaction = client.transaction().await?;
+        lock_writes(&transaction).await?;
+        let query = "DELETE FROM events WHERE id = $1";
+        for &event_id in event_ids {
+            let span = statement_span("postgresql", query);
+            let deleted = transaction
+                .execute(query, &[&Uuid::from_u128(event_id)])
+                .instrument(span.clone())
+                .await?;
+            span.record("db.rows", deleted);
+            if deleted == 0 {
+                return Err(StoreError::NotFound(event_id));
+            }
+        }
+        let mut new_ids = Vec::with_capacity(replacements.len());
+        for event in &replacements {
+            let event_id = self.id_generator.generate();
+            insert(&transaction, INSERT, event_id, event).await?;
+            new_ids.push(event_id);
+        }
+        transaction.commit().await?;
+        Ok(new_ids)
+    }
+
+    #[instrument(skip_all)]
+    async fn delete_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<u64, StoreError> {
+        debug!("Deleting events");
+        let filter = EventFilter {
+            event_type: event_type.map(str::to_string),
+            start,
+            end,
+            ..Default::default()
+        };
+        let (conditions, values) = conditions(&filter, None);
+        let query = format!("DELETE FROM events WHERE {conditions}");
+        let client = self.client().await?;
+        let span = statement_span("postgresql", &query);
+        let deleted = client
+            .execute(&query, &parameters(&values))
+            .instrument(span.clone())
+            .await?;
+        span.record("db.rows", deleted);
+        debug!("Deleted {deleted} events");
+        Ok(deleted)
+    }
+
+    #[instrument(skip_all)]
+    async fn import_events(&self, events: Vec<(EventId, Event)>) -> Result<(), StoreError> {
+        debug!("Importing {} events", events.len());
+        events
+            .iter()
+            .try_for_each(|(_, event)| self.validate(event))?;
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        lock_writes(&transaction).await?;
+        for (event_id, event) in &events {
+            self.id_generator.observe(*event_id);
+            insert(&transaction, IMPORT, *event_id, event).await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn get_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<Vec<Event>, RetrieveError> {
+        debug!("Getting events");
+        let filter = EventFilter {
+            event_type: event_type.map(str::to_string),
+            start,
+            end,
+            ..Default::default()
+        };
+        let order = format!("timestamp, id LIMIT {}", MAX_QUERIED_EVENTS + 1);
+        let events = self.select(&filter, None, &order).await?;
+        if events.len() > MAX_QUERIED_EVENTS {
+            return Err(RetrieveError::ResultTooLarge(MAX_QUERIED_EVENTS as u64));
+        }
+        debug!("Found {} events", events.len());
+        Ok(events.into_iter().map(|(_, event)| event).collect())
+    }
+
+    #[instrument(skip_all)]
+    async fn get_events_page(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+        after: Option<PageCursor>,
+        limit: usize,
+    ) -> Result<EventPage, RetrieveError> {
+        debug!("Getting a page of events");
+        let limit = page_size(limit);
+        let filter = EventFilter {
+            event_type: event_type.map(str::to_string),
+            start,
+            end,
+            ..Default::default()
+        };
+        let client = self.client().await?;
+        let rows = select_page(&**client, &filter, after, limit + 1).await?;
+        Ok(EventPage::from_rows(rows, limit))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_by_id(&self, event_id: EventId) -> Result<Option<Event>, RetrieveError> {
+        debug!("Getting event {event_id}");
+        let client = self.client().await?;
+        let query = format!("SELECT {COLUMNS} FROM events WHERE id = $1");
+        let span = statement_span("postgresql", &query);
+        let row = client
+            .query_opt(&query, &[&Uuid::from_u128(event_id)])
+            .instrument(span.clone())
+            .await?;
+        span.record("db.rows", usize::from(row.is_some()));
+        Ok(row
+            .as_ref()
+            .map(read_event)
+            .transpose()?
+            .map(|(_, event)| event))
+    }
+
+    #[instrument(skip_all)]
+    async fn explain(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> QueryPlan {
+        let estimated_rows = self
+            .count_events(event_type, start, end)
+            .await
+            .unwrap_or_default();
+
+        // The type and the timestamp range are the columns of the indexes.
+        let given = [
+            ("event_type", event_type.is_some()),
+            ("start", start.is_some()),
+            ("end", end.is_some()),
+        ];
+        let predicates = given
+            .into_iter()
+            .filter(|(_, given)| *given)
+            .map(|(field, _)| PredicatePlan {
+                field,
+                index_backed: true,
+            })
+            .collect();
+
+        QueryPlan {
+            index: match event_type {
+                Some(_) => "events_by_type_by_timestamp",
+                None => "events_by_timestamp",
+            },
+            estimated_rows,
+            predicates,
+            limit: Some(MAX_QUERIED_EVENTS as u64),
+            exceeds_limit: estimated_rows > MAX_QUERIED_EVENTS as u64,
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn scan(
+        &self,
+        filter: &EventFilter,
+        after: Option<EventId>,
+    ) -> Result<Vec<(EventId, Event)>, RetrieveError> {
+        debug!("Scanning events");
+        let events = self.select(filter, after, "id").await?;
+        debug!("Scanned {} events", events.len());
+        Ok(events)
+    }
+
+    #[instrument(skip_all)]
+    async fn count_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<u64, RetrieveError> {
+        debug!("Counting events");
+        let filter = EventFilter {
+            event_type: event_type.map(str::to_string),
+            start,
+            end,
+            ..Default::default()
+        };
+        let (conditions, values) = conditions(&filter, None);
+        let query = format!("SELECT count(*) FROM events WHERE {conditions}");
+        let client = self.client().await?;
+        let row = client
+            .query_one(&query, &parameters(&values))
+            .instrument(statement_span("postgresql", &query))
+            .await?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    #[instrument(skip_all)]
+    async fn event_type_counts(&self) -> Vec<(String, u64)> {
+        let counts = async {
+            let client = self.client().await?;
+            let query = "SELECT event_type, count(*) FROM events \
+                         GROUP BY event_type ORDER BY event_type";
+            let span = statement_span("postgresql", query);
+            let rows = client.query(query, &[]).instrument(span.clone()).await?;
+            span.record("db.rows", rows.len());
+            Ok::<_, PoolError>(
+                rows.iter()
+                    .map(|row| (row.get(0), row.get::<_, i64>(1) as u64))
+                    .collect(),
+            )
+        };
+        counts.await.unwrap_or_else(|error| {
+            warn!("Failed to count events by type: {error}");
+            vec![]
+        })
+    }
+
+    #[instrument(skip_all)]
+    async fn aggregate(
+        &self,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<Vec<(String, u64)>, RetrieveError> {
+        debug!("Counting events by type");
+        let filter = EventFilter {
+            start,
+            end,
+            ..Default::default()
+        };
+        let (conditions, values) = conditions(&filter, None);
+        // The sum of BIGINTs is NUMERIC, cast back so it reads as an integer.
+        let query = format!(
+            "SELECT event_type, sum(coalesce(count, 1))::BIGINT FROM events \
+             WHERE {conditions} GROUP BY event_type ORDER BY event_type"
+        );
+        let client = self.client().await?;
+        let span = statement_span("postgresql", &query);
+        let rows = client
+            .query(&query, &parameters(&values))
+            .instrument(span.clone())
+            .await?;
+        span.record("db.rows", rows.len());
+        Ok(rows
+            .iter()
+            .map(|row| (row.get(0), row.get::<_, i64>(1) as u64))
+            .collect())
+    }
+
+    /// PostgreSQL keeps its indexes consistent itself, so only the events are counted. With
+    /// `repair`, the indexes are rebuilt anyway.
+    #[instrument(skip_all)]
+    async fn verify_integrity(&self, repair: bool) -> IntegrityReport {
+        let report = async {
+            let client = self.client().await?;
+            let query = "SELECT count(*) FROM events";
+            let row = client
+                .query_one(query, &[])
+                .instrument(statement_span("postgresql", query))
+                .await?;
+            // Indexes left invalid, e.g. by an interrupted build, aren't used by queries.
+            let query = "SELECT indexrelid::regclass::text FROM pg_index \
+                WHERE indrelid = 'events'::regclass AND NOT (indisvalid AND indisready)";
+            let problems = client
+                .query(query, &[])
+                .instrument(statement_span("postgresql", query))
+                .await?
+                .iter()
+                .map(|row| format!("Index {} is invalid", row.get::<_, String>(0)))
+                .collect::<Vec<_>>();
+            let repaired = repair && !problems.is_empty();
+            if repaired {
+                let query = "REINDEX TABLE events";
+                client
+                    .batch_execute(query)
+                    .instrument(statement_span("postgresql", query))
+                    .await?;
+            }
+            Ok::<_, PoolError>(IntegrityReport {
+                checked_events: row.get::<_, i64>(0) as u64,
+                problems,
+                repaired,
+            })
+        };
+        report.await.unwrap_or_else(|error| IntegrityReport {
+            checked_events: 0,
+            problems: vec![format!("Integrity check failed: {error}")],
+            repaired: false,
+        })
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        Some(self.stats())
+    }
+
+    fn resize_pool(&self, max_size: usize) -> Option<PoolStats> {
+        // Connections beyond the new limit are closed as they are returned.
+        self.pool.resize(max_size);
+        let stats = self.stats();
+        record_pool_metrics(&stats);
+        Some(stats)
+    }
+
+    async fn close(&self) -> Result<(), StoreError> {
+        // Writes are committed before they return, so only the connections are left to close.
+        self.pool.close();
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn health_check(&self) -> Result<(), StoreError> {
+        let client = self.client().await?;
+        let query = "SELECT 1";
+        client
+            .batch_execute(query)
+            .instrument(statement_span("postgresql", query))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Converts database errors into storage errors.
+impl From<tokio_postgres::Error> for StoreError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        StoreError::Backend(error.to_string())
+    }
+}
+
+/// Converts connection pool errors into storage errors.
+impl From<PoolError> for StoreError {
+    fn from(error: PoolError) -> Self {
+        StoreError::Backend(error.to_string())
+    }
+}
+
+/// Converts database errors into retrieval errors.
+impl From<tokio_postgres::Error> for RetrieveError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        RetrieveError::Backend(error.to_string())
+    }
+}
+
+/// Converts connection pool errors into retrieval errors.
+impl From<PoolError> for RetrieveError {
+    fn from(error: PoolError) -> Self {
+        RetrieveError::Backend(error.to_string())
+    }
+}
+
+fn record_pool_metrics(stats: &PoolStats) {
+    let labels = [("storage", "postgres")];
+    metrics::set_gauge("storage_pool_max_size", &labels, stats.max_size as f64);
+    metrics::set_gauge("storage_pool_size", &labels, stats.size as f64);
+    metrics::set_gauge("storage_pool_idle", &labels, stats.idle as f64);
+    metrics::set_gauge("storage_pool_waiting", &labels, stats.waiting as f64);
+}
+
+/// Applies the migrations missing from the database, then makes sure ids generated from now on
+/// are larger than the stored ones.
+async fn migrate(
+    client: &mut Client,
+    id_generator: &(dyn IdGenerator + Send + Sync),
+) -> Result<(), PoolError> {
+    let transaction = client.transaction().await?;
+    transaction
+        .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+        .await?;
+    transaction
+        .batch_execute("CREATE TABLE IF NOT EXISTS schema_migrations (version INTEGER PRIMARY KEY)")
+        .await?;
+    let row = transaction
+        .query_one(
+            "SELECT coalesce(max(version), 0) FROM schema_migrations",
+            &[],
+        )
+        .await?;
+    let applied = row.get::<_, i32>(0) as usize;
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let version = version as i32 + 1;
+        info!("Applying PostgreSQL migration {version}");
+        transaction.batch_execute(migration).await?;
+        transaction
+            .execute(
+                "INSERT INTO schema_migrations (version) VALUES ($1)",
+                &[&version],
+            )
+            .await?;
+    }
+    let last = transaction
+        .query_opt("SELECT id FROM events ORDER BY id DESC LIMIT 1", &[])
+        .await?;
+    transaction.commit().await?;
+
+    if let Some(last) = last {
+        id_generator.observe(last.get::<_, Uuid>(0).as_u128());
+    }
+    Ok(())
+}
+
+/// Takes the write lock until the transaction ends. Writes take it before any other lock, so
+/// they can't deadlock on it.
+async fn lock_writes(transaction: &Transaction<'_>) -> Result<(), tokio_postgres::Error> {
+    let query = "SELECT pg_advisory_xact_lock($1)";
+    transaction
+        .execute(query, &[&WRITE_LOCK])
+        .instrument(statement_span("postgresql", query))
+        .await?;
+    Ok(())
+}
+
+/// Stores an event with the `INSERT` or `IMPORT` statement.
+async fn insert(
+    transaction: &Transaction<'_>,
+    query: &str,
+    event_id: EventId,
+    event: &Event,
+) -> Result<(), tokio_postgres::Error> {
+    let span = statement_span("postgresql", query);
+    let statement = transaction.prepare(query).instrument(span.clone()).await?;
+    let inserted = transaction
+        .execute(
+            &statement,
+            &[
+                &Uuid::from_u128(event_id),
+                &event.event_type,
+                &(event.timestamp as i64),
+                &event.correlation_id,
+                &event.count.map(|count| count as i64),
+                &event.payload,
+            ],
+        )
+        .instrument(span.clone())
+        .await?;
+    span.record("db.rows", inserted);
+    Ok(())
+}
+
+/// Returns the events matching the filter that were stored after the given event. The indexed
+/// fields are matched by the query, payload fields after reading the rows.
+async fn select(
+    client: &impl tokio_postgres::GenericClient,
+    filter: &EventFilter,
+    after: Option<EventId>,
+    order: &str,
+) -> Result<Vec<(EventId, Event)>, tokio_postgres::Error> {
+    let (conditions, values) = conditions(filter, after);
+    let query = format!("SELECT {COLUMNS} FROM events WHERE {conditions} ORDER BY {order}");
+    let span = statement_span("postgresql", &query);
+    let rows = client
+        .query(&query, &parameters(&values))
+        .instrument(span.clone())
+        .await?;
+    span.record("db.rows", rows.len());
+    let mut events = vec![];
+    for row in rows {
+        let (event_id, event) = read_event(&row)?;
+        if filter.matches(&event) {
+            events.push((event_id, event));
+        }
+    }
+    Ok(events)
+}
+
+/// Returns at most `limit` events matching the indexed filter fields after the cursor, ordered
+/// by timestamp, then id. UUIDs compare by their bytes, so ids compare as numbers.
+async fn select_page(
+    client: &impl tokio_postgres::GenericClient,
+    filter: &EventFilter,
+    after: Option<PageCursor>,
+    limit: usize,
+) -> Result<Vec<(EventId, Event)>, tokio_postgres::Error> {
+    let (mut conditions, mut values) = conditions(filter, None);
+    if let Some(after) = after {
+        values.push(Box::new(after.timestamp.min(i64::MAX as u64) as i64));
+        values.push(Box::new(Uuid::from_u128(after.event_id)));
+        let (timestamp, event_id) = (values.len() - 1, values.len());
+        conditions.push_str(&format!(
+            " AND (timestamp, id) > (${timestamp}, ${event_id})"
+        ));
+    }
+    let query = format!(
+        "SELECT {COLUMNS} FROM events WHERE {conditions} ORDER BY timestamp, id LIMIT {limit}"
+    );
+    let span = statement_span("postgresql", &query);
+    let rows = client
+        .query(&query, &parameters(&values))
+        .instrument(span.clone())
+        .await?;
+    span.record("db.rows", rows.len());
+    rows.iter().map(read_event).collect()
+}
+
+/// Parameters of the conditions.
+type Values = Vec<Box<dyn ToSql + Send + Sync>>;
+
+/// Builds the conditions of the indexed filter fields, and their parameters.
+fn conditions(filter: &EventFilter, after: Option<EventId>) -> (String, Values) {
+    let mut conditions = vec!["TRUE".to_string()];
+    let mut values: Values = vec![];
+    let mut condition = |condition: &str, value: Box<dyn ToSql + Send + Sync>| {
+        values.push(value);
+        conditions.push(format!("{condition} ${}", values.len()));
+    };
+    // Timestamps beyond the signed range can't be stored, so clamping keeps the meaning.
+    let timestamp = |timestamp: Timestamp| Box::new(timestamp.min(i64::MAX as u64) as i64);
+    if let Some(event_type) = &filter.event_type {
+        condition("event_type =", Box::new(event_type.clone()));
+    }
+    if let Some(start) = filter.start {
+        condition("timestamp >=", timestamp(start));
+    }
+    if let Some(end) = filter.end {
+        condition("timestamp <=", timestamp(end));
+    }
+    if let Some(correlation_id) = &filter.correlation_id {
+        condition("correlation_id =", Box::new(correlation_id.clone()));
+    }
+    if let Some(after) = after {
+        condition("id >", Box::new(Uuid::from_u128(after)));
+    }
+    (conditions.join(" AND "), values)
+}
+
+fn parameters(values: &Values) -> Vec<&(dyn ToSql + Sync)> {
+    values
+        .iter()
+        .map(|value| value.as_ref() as &(dyn ToSql + Sync))
+        .collect()
+}
+
+fn read_event(row: &Row) -> Result<(EventId, Event), tokio_postgres::Error> {
+    let event_id = row.try_get::<_, Uuid>(0)?.as_u128();
+    let event = Event {
+        event_type: row.try_get(1)?,
+        timestamp: row.try_get::<_, i64>(2)? as u64,
+        correlation_id: row.try_get(3)?,
+        count: row.try_get::<_, Option<i64>>(4)?.map(|count| count as u64),
+        payload: row.try_get(5)?,
+        dedup_id: None,
+    };
+    Ok((event_id, event))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id_generator::{IdStrategy, make_id_generator};
+
+    #[test]
+    fn test_ssl_mode() {
+        let open = |url| {
+            let id_generator = make_id_generator(IdStrategy::Sequential);
+            PostgresStorage::new(url, id_generator, PostgresPoolConfig::default(), None)
+        };
+        assert!(open("postgres://localhost/events?sslmode=prefer").is_ok());
+        // Without CAs to verify the server against, TLS can't be required.
+        assert!(open("postgres://localhost/events?sslmode=require").is_err());
+    }
+
+    /// Runs against the database at `POSTGRES_TEST_URL`, skipped if it isn't set.
+    #[tokio::test]
+    async fn test_postgres_storage() {
+        let Ok(url) = std::env::var("POSTGRES_TEST_URL") else {
+            return;
+        };
+        let open = || {
+            let id_generator = make_id_generator(IdStrategy::Sequential);
+            PostgresStorage::new(&url, id_generator, PostgresPoolConfig::default(), None).unwrap()
+        };
+        let store = open();
+        store
+            .client()
+            .await
+            .unwrap()
+            .batch_execute("TRUNCATE events")
+            .await
+            .unwrap();
+        let login = |timestamp, user_id| Event {
+            event_type: "login".to_string(),
+            timestamp,
+            payload: serde_json::json!({ "user_id": user_id }),
+            correlation_id: Some(format!("user-{user_id}")),
+            ..Default::default()
+        };
+        store.store(login(5, 1)).await.unwrap();
+        let second = store.store(login(4, 2)).await.unwrap();
+        let logout = Event {
+            event_type: "logout".to_string(),
+            timestamp: 6,
+            ..Default::default()
+        };
+        let logout_id = store.store(logout).await.unwrap();
+
+        assert_eq!(
+            store
+                .get_events(Some("login"), None, Some(5))
+                .await
+                .unwrap(),
+            vec![login(4, 2), login(5, 1)]
+        );
+        assert_eq!(store.count_events(None, Some(5), None).await.unwrap(), 2);
+        let filter = EventFilter {
+            payload: serde_json::json!({ "user_id": 2 })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ..Default::default()
+        };
+        assert_eq!(
+            store.scan(&filter, None).await.unwrap(),
+            vec![(second, login(4, 2))]
+        );
+
+        let condition = WriteCondition {
+            filter: EventFilter {
+                correlation_id: Some("user-1".to_string()),
+                ..Default::default()
+            },
+            matches: 0,
+        };
+        let result = store.store_if(login(7, 1), &condition).await;
+        assert!(matches!(
+            result,
+            Err(StoreError::ConditionFailed { matched: 1, .. })
+        ));
+        store.increment_count(logout_id, 2).await.unwrap();
+        assert!(store.replace_events(&[second, 0], vec![]).await.is_err());
+
+        // Ids continue after the stored ones.
+        let store = open();
+        assert_eq!(
+            store.event_type_counts().await,
+            vec![("login".to_string(), 2), ("logout".to_string(), 1)]
+        );
+        let events = store
+            .scan(&EventFilter::default(), Some(second))
+            .await
+            .unwrap();
+        assert_eq!(events[0].1.count, Some(3));
+        assert_eq!(store.store(login(8, 3)).await.unwrap(), logout_id + 1);
+    }
+}
@@ -0,0 +1,274 @@
+use std::{future::Future, sync::Arc, time::Duration};
+use tracing::{debug, instrument};
+
+use crate::{
+    event::{Event, EventId, Timestamp},
+    metrics,
+    storage::{
+        EventFilter, EventPage, IntegrityReport, PageCursor, PoolStats, QueryPlan, RetrieveError,
+        Storage, StorageBackend, StoreError, WriteCondition,
+    },
+};
+
+/// Configures a read replica of the storage.
+#[derive(Debug, Clone)]
+pub struct ReplicaConfig {
+    /// The replica, a database the primary backend's database is replicated to.
+    pub storage: StorageBackend,
+
+    /// How long reads wait for the primary before also asking the replica.
+    pub hedge_after: Duration,
+}
+
+/// Stores events in a primary backend and hedges reads with a read replica of it.
+///
+/// A read the primary doesn't answer within `hedge_after` is also sent to the replica, and
+/// whichever answers first is returned, so a slow primary doesn't hold up queries. The replica
+/// may lag behind, so a hedged read can miss the latest events.
+pub struct ReplicatedStorage {
+    primary: Arc<dyn Storage + Send + Sync>,
+    replica: Arc<dyn Storage + Send + Sync>,
+    hedge_after: Duration,
+}
+
+impl ReplicatedStorage {
+    pub fn new(
+        primary: Arc<dyn Storage + Send + Sync>,
+        replica: Arc<dyn Storage + Send + Sync>,
+        hedge_after: Duration,
+    ) -> Self {
+        Self {
+            primary,
+            replica,
+            hedge_after,
+        }
+    }
+
+    /// Runs the read on the primary, and also on the replica if the primary is slow.
+    async fn hedged<'a, T, F>(&'a self, read: impl Fn(&'a (dyn Storage + Send + Sync)) -> F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        hedge(
+            read(self.primary.as_ref()),
+            || read(self.replica.as_ref()),
+            self.hedge_after,
+        )
+        .await
+    }
+}
+
+/// Returns the result of the primary future, or of the secondary one if that's first. The
+/// secondary future is only started once the primary hasn't finished within `after`.
+async fn hedge<T, P, S>(primary: P, secondary: impl FnOnce() -> S, after: Duration) -> T
+where
+    P: Future<Output = T>,
+    S: Future<Output = T>,
+{
+    tokio::pin!(primary);
+    tokio::select! {
+        biased;
+        result = &mut primary => return result,
+        () = tokio::time::sleep(after) => {}
+    }
+    debug!("Primary didn't answer in {after:?}, asking the replica too");
+    tokio::select! {
+        result = primary => {
+            metrics::increment("hedged_reads_total", &[("winner", "primary")]);
+            result
+        }
+        result = secondary() => {
+            metrics::increment("hedged_reads_total", &[("winner", "replica")]);
+            result
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for ReplicatedStorage {
+    fn name(&self) -> &'static str {
+        self.primary.name()
+    }
+
+    fn validate(&self, event: &Event) -> Result<(), StoreError> {
+        self.primary.validate(event)
+    }
+
+    async fn store(&self, event: Event) -> Result<EventId, StoreError> {
+        self.primary.store(event).await
+    }
+
+    async fn store_transaction(&self, events: Vec<Event>) -> Result<Vec<EventId>, StoreError> {
+        self.primary.store_transaction(events).await
+    }
+
+    async fn store_batch(&self, events: Vec<Event>) -> Vec<Result<EventId, StoreError>> {
+        self.primary.store_batch(events).await
+    }
+
+    async fn store_if(
+        &self,
+        event: Event,
+        condition: &WriteCondition,
+    ) -> Result<EventId, StoreError> {
+        self.primary.store_if(event, condition).await
+    }
+
+    async fn increment_count(&self, event_id: EventId, by: u64) -> Result<(), StoreError> {
+        self.primary.increment_count(event_id, by).await
+    }
+
+    async fn replace_events(
+        &self,
+        event_ids: &[EventId],
+        replacements: Vec<Event>,
+    ) -> Result<Vec<EventId>, StoreError> {
+        self.primary.replace_events(event_ids, replacements).await
+    }
+
+    async fn delete_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<u64, StoreError> {
+        self.primary.delete_events(event_type, start, end).await
+    }
+
+    #[instrument(skip_all)]
+    async fn get_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<Vec<Event>, RetrieveError> {
+        self.hedged(|store| store.get_events(event_type, start, end))
+            .await
+    }
+
+    #[instrument(skip_all)]
+    async fn get_events_page(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+        after: Option<PageCursor>,
+        limit: usize,
+    ) -> Result<EventPage, RetrieveError> {
+        self.hedged(|store| store.get_events_page(event_type, start, end, after, limit))
+            .await
+    }
+
+    #[instrument(skip_all)]
+    async fn get_by_id(&self, event_id: EventId) -> Result<Option<Event>, RetrieveError> {
+        self.hedged(|store| store.get_by_id(event_id)).await
+    }
+
+    async fn import_events(&self, events: Vec<(EventId, Event)>) -> Result<(), StoreError> {
+        self.primary.import_events(events).await
+    }
+
+    async fn explain(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> QueryPlan {
+        self.primary.explain(event_type, start, end).await
+    }
+
+    #[instrument(skip_all)]
+    async fn scan(
+        &self,
+        filter: &EventFilter,
+        after: Option<EventId>,
+    ) -> Result<Vec<(EventId, Event)>, RetrieveError> {
+        self.hedged(|store| store.scan(filter, after)).await
+    }
+
+    #[instrument(skip_all)]
+    async fn count_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<u64, RetrieveError> {
+        self.hedged(|store| store.count_events(event_type, start, end))
+            .await
+    }
+
+    #[instrument(skip_all)]
+    async fn event_type_counts(&self) -> Vec<(String, u64)> {
+        self.hedged(|store| store.event_type_counts()).await
+    }
+
+    #[instrument(skip_all)]
+    async fn aggregate(
+        &self,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<Vec<(String, u64)>, RetrieveError> {
+        self.hedged(|store| store.aggregate(start, end)).await
+    }
+
+    async fn verify_integrity(&self, repair: bool) -> IntegrityReport {
+        self.primary.verify_integrity(repair).await
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        self.primary.pool_stats()
+    }
+
+    fn resize_pool(&self, max_size: usize) -> Option<PoolStats> {
+        self.primary.resize_pool(max_size)
+    }
+
+    async fn close(&self) -> Result<(), StoreError> {
+        let replica = self.replica.close().await;
+        self.primary.close().await.and(replica)
+    }
+
+    async fn health_check(&self) -> Result<(), StoreError> {
+        self.primary.health_check().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryStorage;
+
+    #[tokio::test]
+    async fn test_hedge() {
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            "primary"
+        };
+        let hedged = hedge(slow, || async { "replica" }, Duration::from_millis(10));
+        assert_eq!(hedged.await, "replica");
+
+        // A fast primary doesn't start the secondary read.
+        let fast = async { "primary" };
+        let hedged = hedge(
+            fast,
+            || -> std::future::Pending<_> { unreachable!() },
+            Duration::ZERO,
+        );
+        assert_eq!(hedged.await, "primary");
+    }
+
+    #[tokio::test]
+    async fn test_replicated_storage() {
+        let primary = Arc::new(InMemoryStorage::new());
+        let replica = Arc::new(InMemoryStorage::new());
+        let store = ReplicatedStorage::new(primary.clone(), replica, Duration::from_secs(1));
+        let event = Event {
+            event_type: "test".to_string(),
+            ..Default::default()
+        };
+
+        let event_id = store.store(event.clone()).await.unwrap();
+        assert_eq!(primary.count_events(None, None, None).await.unwrap(), 1);
+        assert_eq!(store.get_by_id(event_id).await.unwrap(), Some(event));
+    }
+}
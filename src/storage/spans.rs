@@ -0,0 +1,75 @@
+use sha2::{Digest, Sha256};
+use tracing::{Span, field, info_span};
+
+use crate::receipts::hex;
+
+/// Creates the span of a database statement, run in it so a slow request can be traced to the
+/// statement. The statement is named by its fingerprint, so the runs of a statement with
+/// different values can be grouped. Callers record the rows returned or changed in `db.rows`.
+pub fn statement_span(system: &'static str, statement: &str) -> Span {
+    let statement = normalize(statement);
+    info_span!(
+        "db_statement",
+        db.system = system,
+        db.statement = %statement,
+        db.fingerprint = %hex(&Sha256::digest(&statement)[..8]),
+        db.rows = field::Empty,
+    )
+}
+
+/// Collapses whitespace, and replaces literals and parameters like `$1` and `?1` with `?`.
+fn normalize(statement: &str) -> String {
+    let mut normalized = String::with_capacity(statement.len());
+    let mut characters = statement.chars().peekable();
+    while let Some(character) = characters.next() {
+        match character {
+            character if character.is_whitespace() => {
+                while characters.next_if(|next| next.is_whitespace()).is_some() {}
+                if !normalized.is_empty() && characters.peek().is_some() {
+                    normalized.push(' ');
+                }
+            }
+            '\'' => {
+                // Quotes in string literals are doubled.
+                loop {
+                    while characters.next().is_some_and(|next| next != '\'') {}
+                    if characters.next_if_eq(&'\'').is_none() {
+                        break;
+                    }
+                }
+                normalized.push('?');
+            }
+            '$' | '?' | '0'..='9'
+                if !normalized.ends_with(|last: char| last.is_alphanumeric() || last == '_') =>
+            {
+                while characters.next_if(|next| next.is_ascii_digit()).is_some() {}
+                normalized.push('?');
+            }
+            character => normalized.push(character),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize(
+                "SELECT id FROM events\n   WHERE event_type = ?1 AND timestamp >= ?2 LIMIT 5"
+            ),
+            "SELECT id FROM events WHERE event_type = ? AND timestamp >= ? LIMIT ?"
+        );
+        assert_eq!(
+            normalize("DELETE FROM events WHERE id = $1 AND event_type = 'it''s'"),
+            "DELETE FROM events WHERE id = ? AND event_type = ?"
+        );
+        assert_eq!(
+            normalize("SELECT count(*) FROM events2 WHERE 1"),
+            "SELECT count(*) FROM events2 WHERE ?"
+        );
+    }
+}
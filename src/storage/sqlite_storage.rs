@@ -0,0 +1,791 @@
+use rusqlite::{
+    Connection, OptionalExtension, Row, params, params_from_iter,
+    types::{Type, Value},
+};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tracing::{Span, debug, instrument, warn};
+
+use crate::{
+    event::{Event, EventId, Timestamp},
+    id_generator::IdGenerator,
+    storage::{
+        EventFilter, EventPage, IntegrityReport, MAX_QUERIED_EVENTS, PageCursor, PoolStats,
+        PredicatePlan, QueryPlan, RetrieveError, Storage, StoreError, WriteCondition, page_size,
+        spans::statement_span,
+        write_batcher::{WriteBatchConfig, WriteBatcher},
+    },
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        id BLOB PRIMARY KEY,
+        event_type TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        correlation_id TEXT,
+        count INTEGER,
+        payload TEXT NOT NULL
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS events_by_type_by_timestamp ON events (event_type, timestamp);
+    CREATE INDEX IF NOT EXISTS events_by_timestamp ON events (timestamp);
+    CREATE INDEX IF NOT EXISTS events_by_correlation_id ON events (correlation_id);
+";
+
+const COLUMNS: &str = "id, event_type, timestamp, correlation_id, count, payload";
+
+/// Inserts a new event. Fails if the id is taken, so a colliding id can't overwrite an event.
+const INSERT: &str = "INSERT INTO events \
+                      (id, event_type, timestamp, correlation_id, count, payload) \
+                      VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+
+/// Inserts an imported event, replacing the event with the same id.
+const IMPORT: &str = "INSERT OR REPLACE INTO events \
+                      (id, event_type, timestamp, correlation_id, count, payload) \
+                      VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+
+/// Stores events in a SQLite database file, so they survive restarts.
+///
+/// Ids are stored as 16 big-endian bytes, so they sort in insertion order.
+pub struct SqliteStorage {
+    // A single connection serializes the writes, so ids are generated in insertion order.
+    // SQLite only allows one writer at a time anyway.
+    connection: Arc<Mutex<Connection>>,
+
+    id_generator: Arc<dyn IdGenerator + Send + Sync>,
+
+    /// Groups concurrently stored events into one transaction.
+    batcher: WriteBatcher,
+}
+
+impl SqliteStorage {
+    /// Opens the database file, creating it and its tables if needed.
+    pub fn open(
+        path: &Path,
+        id_generator: Box<dyn IdGenerator + Send + Sync>,
+        batch: WriteBatchConfig,
+    ) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+        connection.execute_batch(SCHEMA)?;
+
+        // Ids generated from now on must be larger than the stored ones.
+        let last_id: Option<Vec<u8>> =
+            connection.query_row("SELECT max(id) FROM events", [], |row| row.get(0))?;
+        if let Some(last_id) = last_id {
+            id_generator.observe(decode_id(&last_id)?);
+        }
+        let connection = Arc::new(Mutex::new(connection));
+        let id_generator: Arc<dyn IdGenerator + Send + Sync> = Arc::from(id_generator);
+        let batcher = {
+            let connection = connection.clone();
+            let id_generator = id_generator.clone();
+            WriteBatcher::new("sqlite", batch, move |events| {
+                let mut connection = connection.lock().unwrap();
+                insert_all(&mut connection, id_generator.as_ref(), &events)
+            })
+        };
+        Ok(Self {
+            connection,
+            id_generator,
+            batcher,
+        })
+    }
+
+    /// Runs a database operation on a blocking thread, in the caller's span.
+    async fn run<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&mut Connection, &dyn IdGenerator) -> T + Send + 'static,
+    ) -> T {
+        let connection = self.connection.clone();
+        let id_generator = self.id_generator.clone();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let mut connection = connection.lock().unwrap();
+            operation(&mut connection, id_generator.as_ref())
+        })
+        .await
+        .expect("Database operations don't panic")
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for SqliteStorage {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn validate(&self, event: &Event) -> Result<(), StoreError> {
+        // SQLite integers are signed 64-bit.
+        let too_large = |value: u64| i64::try_from(value).is_err();
+        if too_large(event.timestamp) || event.count.is_some_and(too_large) {
+            return Err(StoreError::Backend("Integer out of range".to_string()));
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn store(&self, event: Event) -> Result<EventId, StoreError> {
+        debug!("Storing event");
+        self.validate(&event)?;
+        self.batcher.store(event).await
+    }
+
+    #[instrument(skip_all)]
+    async fn store_transaction(&self, events: Vec<Event>) -> Result<Vec<EventId>, StoreError> {
+        debug!("Storing {} events in a transaction", events.len());
+        events.iter().try_for_each(|event| self.validate(event))?;
+        self.run(move |connection, id_generator| insert_all(connection, id_generator, &events))
+            .await
+    }
+
+    #[instrument(skip_all)]
+    async fn store_if(
+        &self,
+        event: Event,
+        condition: &WriteCondition,
+    ) -> Result<EventId, StoreError> {
+        debug!("Storing event conditionally");
+        self.validate(&event)?;
+        let filter = condition.filter.clone();
+        let expected = condition.matches;
+        self.run(move |connection, id_generator| -> Result<_, StoreError> {
+            // The connection is held while checking, so no other event can sneak in.
+            let matched = select(connection, &filter, None, "id")?.len() as u64;
+            if matched != expected {
+                return Err(StoreError::ConditionFailed { expected, matched });
+            }
+            let event_id = id_generator.generate();
+            insert(connection, INSERT, event_id, &event)?;
+            Ok(event_id)
+        })
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn increment_count(&self, event_id: EventId, by: u64) -> Result<(), StoreError> {
+        self.run(move |connection, _| -> Result<_, StoreError> {
+            let query = "UPDATE events SET count = coalesce(count, 1) + ?1 WHERE id = ?2";
+            let span = statement_span("sqlite", query);
+            let _entered = span.enter();
+            let updated = connection.execute(query, params![by, &event_id.to_be_bytes()[..]])?;
+            span.record("db.rows", updated);
+            match updated {
+                0 => Err(StoreError::NotFound(event_id)),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn replace_events(
+        &self,
+        event_ids: &[EventId],
+        replacements: Vec<Event>,
+    ) -> Result<Vec<EventId>, StoreError> {
+        debug!(
+            "Replacing {} events with {} events",
+            event_ids.len(),
+            replacements.len()
+        );
+        replacements
+            .iter()
+            .try_for_each(|event| self.validate(event))?;
+        let event_ids = event_ids.to_vec();
+        self.run(move |connection, id_generator| -> Result<_, StoreError> {
+            // Dropping the transaction on an error rolls it back.
+            let transaction = connection.transaction()?;
+            let query = "DELETE FROM events WHERE id = ?1";
+            let span = statement_span("sqlite", query);
+            for event_id in &event_ids {
+                let deleted =
+                    span.in_scope(|| transaction.execute(query, [&event_id.to_be_bytes()[..]]))?;
+                if deleted == 0 {
+                    return Err(StoreError::NotFound(*event_id));
+                }
+            }
+            span.record("db.rows", event_ids.len());
+            let span = statement_span("sqlite", INSERT);
+            let mut new_ids = Vec::with_capacity(replacements.len());
+            for event in &replacements {
+                let event_id = id_generator.generate();
+                span.in_scope(|| insert(&transaction, INSERT, event_id, event))?;
+                new_ids.push(event_id);
+            }
+            span.record("db.rows", new_ids.len());
+            transaction.commit()?;
+            Ok(new_ids)
+        })
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn delete_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<u64, StoreError> {
+        debug!("Deleting events");
+        let filter = EventFilter {
+            event_type: event_type.map(str::to_string),
+            start,
+            end,
+            ..Default::default()
+        };
+        let deleted = self
+            .run(move |connection, _| {
+                let (conditions, values) = conditions(&filter, None);
+                let query = format!("DELETE FROM events WHERE {conditions}");
+                let span = statement_span("sqlite", &query);
+                let _entered = span.enter();
+                let deleted = connection.execute(&query, params_from_iter(values))?;
+                span.record("db.rows", deleted);
+                Ok::<_, rusqlite::Error>(deleted)
+            })
+            .await?;
+        debug!("Deleted {deleted} events");
+        Ok(deleted as u64)
+    }
+
+    #[instrument(skip_all)]
+    async fn import_events(&self, events: Vec<(EventId, Event)>) -> Result<(), StoreError> {
+        debug!("Importing {} events", events.len());
+        events
+            .iter()
+            .try_for_each(|(_, event)| self.validate(event))?;
+        self.run(move |connection, id_generator| -> Result<_, StoreError> {
+            let transaction = connection.transaction()?;
+            let span = statement_span("sqlite", IMPORT);
+            for (event_id, event) in &events {
+                id_generator.observe(*event_id);
+                span.in_scope(|| insert(&transaction, IMPORT, *event_id, event))?;
+            }
+            span.record("db.rows", events.len());
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn get_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<Vec<Event>, RetrieveError> {
+        debug!("Getting events");
+        let filter = EventFilter {
+            event_type: event_type.map(str::to_string),
+            start,
+            end,
+            ..Default::default()
+        };
+        let order = format!("timestamp, id LIMIT {}", MAX_QUERIED_EVENTS + 1);
+        let events = self
+            .run(move |connection, _| select(connection, &filter, None, &order))
+            .await?;
+        if events.len() > MAX_QUERIED_EVENTS {
+            return Err(RetrieveError::ResultTooLarge(MAX_QUERIED_EVENTS as u64));
+        }
+        debug!("Found {} events", events.len());
+        Ok(events.into_iter().map(|(_, event)| event).collect())
+    }
+
+    #[instrument(skip_all)]
+    async fn get_events_page(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+        after: Option<PageCursor>,
+        limit: usize,
+    ) -> Result<EventPage, RetrieveError> {
+        debug!("Getting a page of events");
+        let limit = page_size(limit);
+        let filter = EventFilter {
+            event_type: event_type.map(str::to_string),
+            start,
+            end,
+            ..Default::default()
+        };
+        let rows = self
+            .run(move |connection, _| select_page(connection, &filter, after, limit + 1))
+            .await?;
+        Ok(EventPage::from_rows(rows, limit))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_by_id(&self, event_id: EventId) -> Result<Option<Event>, RetrieveError> {
+        debug!("Getting event {event_id}");
+        let query = format!("SELECT {COLUMNS} FROM events WHERE id = ?1");
+        let row = self
+            .run(move |connection, _| {
+                let span = statement_span("sqlite", &query);
+                let _entered = span.enter();
+                let row = connection
+                    .query_row(&query, [&event_id.to_be_bytes()[..]], read_event)
+                    .optional()?;
+                span.record("db.rows", usize::from(row.is_some()));
+                Ok::<_, rusqlite::Error>(row)
+            })
+            .await?;
+        Ok(row.map(|(_, event)| event))
+    }
+
+    #[instrument(skip_all)]
+    async fn explain(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> QueryPlan {
+        let estimated_rows = self
+            .count_events(event_type, start, end)
+            .await
+            .unwrap_or_default();
+
+        // The type and the timestamp range are the columns of the indexes.
+        let given = [
+            ("event_type", event_type.is_some()),
+            ("start", start.is_some()),
+            ("end", end.is_some()),
+        ];
+        let predicates = given
+            .into_iter()
+            .filter(|(_, given)| *given)
+            .map(|(field, _)| PredicatePlan {
+                field,
+                index_backed: true,
+            })
+            .collect();
+
+        QueryPlan {
+            index: match event_type {
+                Some(_) => "events_by_type_by_timestamp",
+                None => "events_by_timestamp",
+            },
+            estimated_rows,
+            predicates,
+            limit: Some(MAX_QUERIED_EVENTS as u64),
+            exceeds_limit: estimated_rows > MAX_QUERIED_EVENTS as u64,
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn scan(
+        &self,
+        filter: &EventFilter,
+        after: Option<EventId>,
+    ) -> Result<Vec<(EventId, Event)>, RetrieveError> {
+        debug!("Scanning events");
+        let filter = filter.clone();
+        let events = self
+            .run(move |connection, _| select(connection, &filter, after, "id"))
+            .await?;
+        debug!("Scanned {} events", events.len());
+        Ok(events)
+    }
+
+    #[instrument(skip_all)]
+    async fn count_events(
+        &self,
+        event_type: Option<&str>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<u64, RetrieveError> {
+        debug!("Counting events");
+        let filter = EventFilter {
+            event_type: event_type.map(str::to_string),
+            start,
+            end,
+            ..Default::default()
+        };
+        let count = self
+            .run(move |connection, _| {
+                let (conditions, values) = conditions(&filter, None);
+                let query = format!("SELECT count(*) FROM events WHERE {conditions}");
+                let span = statement_span("sqlite", &query);
+                let _entered = span.enter();
+                span.record("db.rows", 1);
+                connection.query_row(&query, params_from_iter(values), |row| row.get::<_, u64>(0))
+            })
+            .await?;
+        Ok(count)
+    }
+
+    #[instrument(skip_all)]
+    async fn event_type_counts(&self) -> Vec<(String, u64)> {
+        let counts = self
+            .run(|connection, _| -> rusqlite::Result<_> {
+                let query = "SELECT event_type, count(*) FROM events \
+                             GROUP BY event_type ORDER BY event_type";
+                let span = statement_span("sqlite", query);
+                let _entered = span.enter();
+                let mut statement = connection.prepare_cached(query)?;
+                let counts = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                let counts = counts.collect::<rusqlite::Result<Vec<_>>>()?;
+                span.record("db.rows", counts.len());
+                Ok(counts)
+            })
+            .await;
+        counts.unwrap_or_else(|error| {
+            warn!("Failed to count events by type: {error}");
+            vec![]
+        })
+    }
+
+    #[instrument(skip_all)]
+    async fn aggregate(
+        &self,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    ) -> Result<Vec<(String, u64)>, RetrieveError> {
+        debug!("Counting events by type");
+        let filter = EventFilter {
+            start,
+            end,
+            ..Default::default()
+        };
+        let counts = self
+            .run(move |connection, _| -> rusqlite::Result<_> {
+                let (conditions, values) = conditions(&filter, None);
+                let query = format!(
+                    "SELECT event_type, sum(coalesce(count, 1)) FROM events WHERE {conditions} \
+                     GROUP BY event_type ORDER BY event_type"
+                );
+                let span = statement_span("sqlite", &query);
+                let _entered = span.enter();
+                let mut statement = connection.prepare_cached(&query)?;
+                let counts = statement.query_map(params_from_iter(values), |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?;
+                let counts = counts.collect::<rusqlite::Result<Vec<_>>>()?;
+                span.record("db.rows", counts.len());
+                Ok(counts)
+            })
+            .await?;
+        Ok(counts)
+    }
+
+    #[instrument(skip_all)]
+    async fn verify_integrity(&self, repair: bool) -> IntegrityReport {
+        let report = self
+            .run(move |connection, _| -> rusqlite::Result<_> {
+                let checked_events =
+                    connection.query_row("SELECT count(*) FROM events", [], |row| row.get(0))?;
+                let mut statement = connection.prepare("PRAGMA integrity_check")?;
+                let problems = statement
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .filter(|problem| !matches!(problem.as_deref(), Ok("ok")))
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                let repaired = repair && !problems.is_empty();
+                if repaired {
+                    connection.execute_batch("REINDEX")?;
+                }
+                Ok(IntegrityReport {
+                    checked_events,
+                    problems,
+                    repaired,
+                })
+            })
+            .await;
+        report.unwrap_or_else(|error| IntegrityReport {
+            checked_events: 0,
+            problems: vec![format!("Integrity check failed: {error}")],
+            repaired: false,
+        })
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        // A single connection is shared instead of a pool.
+        None
+    }
+
+    fn resize_pool(&self, _max_size: usize) -> Option<PoolStats> {
+        None
+    }
+
+    #[instrument(skip_all)]
+    async fn close(&self) -> Result<(), StoreError> {
+        self.batcher.close().await;
+        // Commits aren't synced with `synchronous = NORMAL`. Checkpointing moves them from the
+        // WAL into the database file and syncs it.
+        self.run(|connection, _| -> Result<_, StoreError> {
+            let query = "PRAGMA wal_checkpoint(TRUNCATE)";
+            let _entered = statement_span("sqlite", query).entered();
+            connection.execute_batch(query)?;
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn health_check(&self) -> Result<(), StoreError> {
+        self.run(|connection, _| -> Result<_, StoreError> {
+            let query = "SELECT 1";
+            let _entered = statement_span("sqlite", query).entered();
+            connection.query_row(query, [], |_| Ok(()))?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Converts database errors into storage errors.
+impl From<rusqlite::Error> for StoreError {
+    fn from(error: rusqlite::Error) -> Self {
+        StoreError::Backend(error.to_string())
+    }
+}
+
+/// Converts database errors into retrieval errors.
+impl From<rusqlite::Error> for RetrieveError {
+    fn from(error: rusqlite::Error) -> Self {
+        RetrieveError::Backend(error.to_string())
+    }
+}
+
+/// Stores an event with the `INSERT` or `IMPORT` statement.
+fn insert(
+    connection: &Connection,
+    query: &str,
+    event_id: EventId,
+    event: &Event,
+) -> rusqlite::Result<()> {
+    connection.prepare_cached(query)?.execute(params![
+        &event_id.to_be_bytes()[..],
+        event.event_type,
+        event.timestamp,
+        event.correlation_id,
+        event.count,
+        event.payload.to_string(),
+    ])?;
+    Ok(())
+}
+
+/// Stores the events in a transaction, returning their new ids.
+fn insert_all(
+    connection: &mut Connection,
+    id_generator: &dyn IdGenerator,
+    events: &[Event],
+) -> Result<Vec<EventId>, StoreError> {
+    let transaction = connection.transaction()?;
+    let span = statement_span("sqlite", INSERT);
+    let mut event_ids = Vec::with_capacity(events.len());
+    for event in events {
+        let event_id = id_generator.generate();
+        span.in_scope(|| insert(&transaction, INSERT, event_id, event))?;
+        event_ids.push(event_id);
+    }
+    span.record("db.rows", event_ids.len());
+    transaction.commit()?;
+    Ok(event_ids)
+}
+
+/// Returns the events matching the filter that were stored after the given event. The indexed
+/// fields are matched by the query, payload fields after reading the rows.
+fn select(
+    connection: &Connection,
+    filter: &EventFilter,
+    after: Option<EventId>,
+    order: &str,
+) -> rusqlite::Result<Vec<(EventId, Event)>> {
+    let (conditions, values) = conditions(filter, after);
+    let query = format!("SELECT {COLUMNS} FROM events WHERE {conditions} ORDER BY {order}");
+    let span = statement_span("sqlite", &query);
+    let _entered = span.enter();
+    let mut statement = connection.prepare_cached(&query)?;
+    let rows = statement.query_map(params_from_iter(values), read_event)?;
+    let mut events = vec![];
+    let mut read: usize = 0;
+    for row in rows {
+        let (event_id, event) = row?;
+        read += 1;
+        if filter.matches(&event) {
+            events.push((event_id, event));
+        }
+    }
+    span.record("db.rows", read);
+    Ok(events)
+}
+
+/// Returns at most `limit` events matching the indexed filter fields after the cursor, ordered
+/// by timestamp, then id. Ids are big-endian blobs, so they compare as numbers.
+fn select_page(
+    connection: &Connection,
+    filter: &EventFilter,
+    after: Option<PageCursor>,
+    limit: usize,
+) -> rusqlite::Result<Vec<(EventId, Event)>> {
+    let (mut conditions, mut values) = conditions(filter, None);
+    if let Some(after) = after {
+        values.push(Value::Integer(after.timestamp.min(i64::MAX as u64) as i64));
+        values.push(Value::Blob(after.event_id.to_be_bytes().to_vec()));
+        let (timestamp, event_id) = (values.len() - 1, values.len());
+        conditions.push_str(&format!(
+            " AND (timestamp, id) > (?{timestamp}, ?{event_id})"
+        ));
+    }
+    let query = format!(
+        "SELECT {COLUMNS} FROM events WHERE {conditions} ORDER BY timestamp, id LIMIT {limit}"
+    );
+    let span = statement_span("sqlite", &query);
+    let _entered = span.enter();
+    let mut statement = connection.prepare_cached(&query)?;
+    let rows = statement.query_map(params_from_iter(values), read_event)?;
+    let rows = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+    span.record("db.rows", rows.len());
+    Ok(rows)
+}
+
+/// Builds the conditions of the indexed filter fields, and their parameters.
+fn conditions(filter: &EventFilter, after: Option<EventId>) -> (String, Vec<Value>) {
+    let mut conditions = vec!["1".to_string()];
+    let mut values = vec![];
+    let mut condition = |condition: &str, value: Value| {
+        values.push(value);
+        conditions.push(format!("{condition} ?{}", values.len()));
+    };
+    // Timestamps beyond the signed range can't be stored, so clamping keeps the meaning.
+    let timestamp = |timestamp: Timestamp| Value::Integer(timestamp.min(i64::MAX as u64) as i64);
+    if let Some(event_type) = &filter.event_type {
+        condition("event_type =", Value::Text(event_type.clone()));
+    }
+    if let Some(start) = filter.start {
+        condition("timestamp >=", timestamp(start));
+    }
+    if let Some(end) = filter.end {
+        condition("timestamp <=", timestamp(end));
+    }
+    if let Some(correlation_id) = &filter.correlation_id {
+        condition("correlation_id =", Value::Text(correlation_id.clone()));
+    }
+    if let Some(after) = after {
+        condition("id >", Value::Blob(after.to_be_bytes().to_vec()));
+    }
+    (conditions.join(" AND "), values)
+}
+
+fn read_event(row: &Row) -> rusqlite::Result<(EventId, Event)> {
+    let event_id = decode_id(&row.get::<_, Vec<u8>>(0)?)?;
+    let payload: String = row.get(5)?;
+    let payload = serde_json::from_str(&payload)
+        .map_err(|error| rusqlite::Error::FromSqlConversionFailure(5, Type::Text, error.into()))?;
+    let event = Event {
+        event_type: row.get(1)?,
+        timestamp: row.get(2)?,
+        correlation_id: row.get(3)?,
+        count: row.get(4)?,
+        payload,
+        dedup_id: None,
+    };
+    Ok((event_id, event))
+}
+
+fn decode_id(bytes: &[u8]) -> rusqlite::Result<EventId> {
+    bytes
+        .try_into()
+        .map(EventId::from_be_bytes)
+        .map_err(|error| rusqlite::Error::FromSqlConversionFailure(0, Type::Blob, Box::new(error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id_generator::{IdStrategy, make_id_generator};
+
+    fn open(path: &Path) -> SqliteStorage {
+        let id_generator = make_id_generator(IdStrategy::Sequential);
+        SqliteStorage::open(path, id_generator, WriteBatchConfig::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage() {
+        let path = std::env::temp_dir().join(format!("events-{}.sqlite", std::process::id()));
+        let store = open(&path);
+        let login = |timestamp, user_id| Event {
+            event_type: "login".to_string(),
+            timestamp,
+            payload: serde_json::json!({ "user_id": user_id }),
+            correlation_id: Some(format!("user-{user_id}")),
+            ..Default::default()
+        };
+        store.store(login(5, 1)).await.unwrap();
+        store.store(login(4, 2)).await.unwrap();
+        let logout = Event {
+            event_type: "logout".to_string(),
+            timestamp: 6,
+            ..Default::default()
+        };
+        let logout_id = store.store(logout).await.unwrap();
+
+        assert_eq!(
+            store
+                .get_events(Some("login"), None, Some(5))
+                .await
+                .unwrap(),
+            vec![login(4, 2), login(5, 1)]
+        );
+        assert_eq!(store.count_events(None, Some(5), None).await.unwrap(), 2);
+        let filter = EventFilter {
+            payload: serde_json::json!({ "user_id": 2 })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ..Default::default()
+        };
+        assert_eq!(
+            store.scan(&filter, None).await.unwrap(),
+            vec![(2, login(4, 2))]
+        );
+        assert_eq!(store.get_by_id(2).await.unwrap(), Some(login(4, 2)));
+        assert_eq!(store.get_by_id(99).await.unwrap(), None);
+
+        let condition = WriteCondition {
+            filter: EventFilter {
+                correlation_id: Some("user-1".to_string()),
+                ..Default::default()
+            },
+            matches: 0,
+        };
+        let result = store.store_if(login(7, 1), &condition).await;
+        assert!(matches!(
+            result,
+            Err(StoreError::ConditionFailed { matched: 1, .. })
+        ));
+        store.increment_count(logout_id, 2).await.unwrap();
+        assert_eq!(
+            store.aggregate(Some(5), None).await.unwrap(),
+            vec![("login".to_string(), 1), ("logout".to_string(), 3)]
+        );
+        assert!(store.replace_events(&[1, 99], vec![]).await.is_err());
+        assert!(store.verify_integrity(false).await.problems.is_empty());
+
+        // Events and ids survive reopening.
+        drop(store);
+        let store = open(&path);
+        assert_eq!(
+            store.event_type_counts().await,
+            vec![("login".to_string(), 2), ("logout".to_string(), 1)]
+        );
+        let events = store.scan(&EventFilter::default(), Some(2)).await.unwrap();
+        assert_eq!(events[0].1.count, Some(3));
+        assert_eq!(store.store(login(8, 3)).await.unwrap(), 4);
+
+        // A colliding id, e.g. of a second writer, fails instead of overwriting the event.
+        let other = open(&path);
+        assert_eq!(store.store(login(9, 4)).await.unwrap(), 5);
+        let result = other.store(login(9, 5)).await;
+        assert!(matches!(result, Err(StoreError::Backend(_))));
+        assert_eq!(store.get_by_id(5).await.unwrap(), Some(login(9, 4)));
+        drop((store, other));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use tracing::warn;
+
+use crate::event::{Event, EventId, Timestamp};
+
+/// A change of the stored events, logged before it is applied.
+///
+/// Records are externally tagged, since serde can't read the `u128` event ids inside an
+/// internally tagged enum.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WalRecord {
+    /// Stores events, replacing the events with the same ids.
+    Insert {
+        events: Vec<(EventId, Event)>,
+    },
+
+    IncrementCount {
+        event_id: EventId,
+        by: u64,
+    },
+
+    /// Deletes events and stores their replacements.
+    Replace {
+        deleted: Vec<EventId>,
+        events: Vec<(EventId, Event)>,
+    },
+
+    /// Deletes the events in an inclusive timestamp range, of a type if given.
+    Purge {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        event_type: Option<String>,
+        start: Option<Timestamp>,
+        end: Option<Timestamp>,
+    },
+}
+
+/// Append-only JSON lines file of the changes of an in-memory storage, replayed on startup.
+///
+/// Each line starts with the CRC32 checksum of its record in hex, like `1a2b3c4d {...}`, so a
+/// corrupted record that still parses isn't replayed.
+pub struct Wal {
+    path: PathBuf,
+    file: Mutex<tokio::fs::File>,
+}
+
+impl Wal {
+    /// Opens the log, returning it and the records in it to replay. Fails at the first invalid
+    /// record or checksum mismatch but the last line, so a log that can't be replayed isn't
+    /// compacted away.
+    pub fn open(path: &Path) -> std::io::Result<(Self, Vec<WalRecord>)> {
+        let mut records = vec![];
+        if path.exists() {
+            let content = std::fs::read_to_string(path)?;
+            let mut lines = content.lines().filter(|line| !line.is_empty()).peekable();
+            while let Some(line) = lines.next() {
+                match decode(line) {
+                    Ok(record) => records.push(record),
+                    // A torn write can only affect the last line.
+                    Err(error) if lines.peek().is_none() => {
+                        warn!("Ignoring invalid last WAL record: {error}")
+                    }
+                    Err(error) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("Invalid WAL record: {error}"),
+                        ));
+                    }
+                }
+            }
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let wal = Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(tokio::fs::File::from_std(file)),
+        };
+        Ok((wal, records))
+    }
+
+    /// Appends a record, and makes it durable before returning.
+    pub async fn append(&self, record: &WalRecord) -> std::io::Result<()> {
+        let line = encode(record);
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.sync_data().await
+    }
+
+    /// Syncs the log's data and metadata to disk.
+    pub async fn sync(&self) -> std::io::Result<()> {
+        self.file.lock().await.sync_all().await
+    }
+
+    /// Replaces the log with a single record of the current events, so replaying it doesn't
+    /// take longer with every change. The new log is written next to the old one and renamed
+    /// over it, so a crash leaves one of them intact.
+    pub fn compact(&mut self, events: Vec<(EventId, Event)>) -> std::io::Result<()> {
+        let compacted = self.path.with_extension("compacting");
+        let mut file = std::fs::File::create(&compacted)?;
+        if !events.is_empty() {
+            file.write_all(&encode(&WalRecord::Insert { events }))?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&compacted, &self.path)?;
+        let file = std::fs::OpenOptions::new().append(true).open(&self.path)?;
+        self.file = Mutex::new(tokio::fs::File::from_std(file));
+        Ok(())
+    }
+}
+
+/// Returns the line of a record, with its checksum and the newline.
+fn encode(record: &WalRecord) -> Vec<u8> {
+    let json = serde_json::to_vec(record).expect("Events always serialize");
+    let mut line = format!("{:08x} ", crc32fast::hash(&json)).into_bytes();
+    line.extend(json);
+    line.push(b'\n');
+    line
+}
+
+/// Reads the record of a line, checking its checksum. Lines of logs written before checksums
+/// were added start with the record itself, and are read without checking.
+fn decode(line: &str) -> Result<WalRecord, String> {
+    if line.starts_with('{') {
+        return serde_json::from_str(line).map_err(|error| error.to_string());
+    }
+    let (checksum, json) = line.split_once(' ').ok_or("Missing checksum")?;
+    let checksum = u32::from_str_radix(checksum, 16).map_err(|_| "Invalid checksum")?;
+    if crc32fast::hash(json.as_bytes()) != checksum {
+        return Err("Checksum mismatch".to_string());
+    }
+    serde_json::from_str(json).map_err(|error| error.to_string())
+}
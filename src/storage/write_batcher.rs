@@ -0,0 +1,226 @@
+use std::{
+    sync::{
+        Mutex,
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+use tracing::debug;
+
+use crate::{
+    event::{Event, EventId},
+    metrics,
+    storage::StoreError,
+};
+
+/// Configures how concurrent writes are grouped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WriteBatchConfig {
+    /// Longest time an event waits for others to share its batch.
+    pub max_delay: Duration,
+
+    /// Most events written in one batch.
+    pub max_batch: usize,
+}
+
+impl Default for WriteBatchConfig {
+    fn default() -> Self {
+        Self {
+            max_delay: Duration::from_millis(5),
+            max_batch: 1000,
+        }
+    }
+}
+
+/// An event waiting for its batch to be written.
+struct PendingWrite {
+    event: Event,
+    reply: oneshot::Sender<Result<EventId, StoreError>>,
+}
+
+/// Groups concurrent writes into batches written by a dedicated thread, so a database commits
+/// once per batch instead of once per event.
+///
+/// Batches only wait for more events while writes are observed to queue up. The target size
+/// doubles while events pile up during writes, shrinks to what arrived when the delay runs out,
+/// and is capped so a batch is written in about the max delay. A lone event is written at once.
+pub struct WriteBatcher {
+    /// Queues the events for the writer thread, `None` once closed.
+    sender: Mutex<Option<Sender<PendingWrite>>>,
+
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl WriteBatcher {
+    /// Starts the writer thread, which writes each batch with `write`, returning the ids of the
+    /// events in order. The thread stops when the batcher is closed or dropped.
+    pub fn new<F>(name: &'static str, config: WriteBatchConfig, write: F) -> Self
+    where
+        F: FnMut(Vec<Event>) -> Result<Vec<EventId>, StoreError> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let writer = std::thread::Builder::new()
+            .name(format!("{name}-writer"))
+            .spawn(move || run(name, config, receiver, write))
+            .expect("Failed to start writer thread");
+        Self {
+            sender: Mutex::new(Some(sender)),
+            writer: Mutex::new(Some(writer)),
+        }
+    }
+
+    /// Stores the event in the next batch.
+    pub async fn store(&self, event: Event) -> Result<EventId, StoreError> {
+        let (reply, receiver) = oneshot::channel();
+        self.sender
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or_else(|| StoreError::Backend("Writer stopped".to_string()))?
+            .send(PendingWrite { event, reply })
+            .map_err(|_| StoreError::Backend("Writer stopped".to_string()))?;
+        receiver
+            .await
+            .map_err(|_| StoreError::Backend("Writer stopped".to_string()))?
+    }
+
+    /// Writes the queued events and stops the writer thread. Events stored later are rejected.
+    pub async fn close(&self) {
+        self.sender.lock().unwrap().take();
+        let Some(writer) = self.writer.lock().unwrap().take() else {
+            return;
+        };
+        let _ = tokio::task::spawn_blocking(move || writer.join()).await;
+    }
+}
+
+fn run<F>(
+    name: &'static str,
+    config: WriteBatchConfig,
+    receiver: Receiver<PendingWrite>,
+    mut write: F,
+) where
+    F: FnMut(Vec<Event>) -> Result<Vec<EventId>, StoreError>,
+{
+    let max_batch = config.max_batch.max(1);
+    let mut target = 1;
+    while let Ok(first) = receiver.recv() {
+        let deadline = Instant::now() + config.max_delay;
+        let mut batch = vec![first];
+
+        // Events queued during the previous write are taken without waiting.
+        while batch.len() < max_batch {
+            match receiver.try_recv() {
+                Ok(pending) => batch.push(pending),
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+            }
+        }
+        let queued = batch.len() > 1;
+        let mut timed_out = false;
+        while batch.len() < target {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(pending) => batch.push(pending),
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => {
+                    timed_out = true;
+                    break;
+                }
+            }
+        }
+
+        let size = batch.len();
+        let (events, replies): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .map(|pending| (pending.event, pending.reply))
+            .unzip();
+        let started = Instant::now();
+        let outcome = write(events);
+        let elapsed = started.elapsed();
+        debug!("Wrote a batch of {size} events in {elapsed:?}");
+        match outcome {
+            Ok(event_ids) => {
+                for (reply, event_id) in replies.into_iter().zip(event_ids) {
+                    let _ = reply.send(Ok(event_id));
+                }
+            }
+            Err(error) => {
+                for reply in replies {
+                    let _ = reply.send(Err(error.clone()));
+                }
+            }
+        }
+
+        target = match (timed_out, queued) {
+            (true, _) => size,
+            (false, true) => (target.max(size) * 2).min(max_batch),
+            (false, false) => target,
+        };
+        // Bigger batches would hold the queued events longer than the max delay.
+        if elapsed > config.max_delay {
+            let affordable = size as f64 * config.max_delay.as_secs_f64() / elapsed.as_secs_f64();
+            target = target.min(affordable as usize);
+        }
+        target = target.max(1);
+
+        let labels = [("storage", name)];
+        metrics::increment("write_batches_total", &labels);
+        metrics::add("batched_writes_total", &labels, size as f64);
+        metrics::set_gauge("write_batch_target_size", &labels, target as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_write_batcher() {
+        let batches = Arc::new(Mutex::new(vec![]));
+        let written = batches.clone();
+        let mut last_id = 0;
+        let config = WriteBatchConfig {
+            max_delay: Duration::from_millis(50),
+            max_batch: 100,
+        };
+        let batcher = Arc::new(WriteBatcher::new("test", config, move |events| {
+            written.lock().unwrap().push(events.len());
+            // Slow writes let the concurrent events queue up.
+            std::thread::sleep(Duration::from_millis(10));
+            let event_ids = (last_id + 1..=last_id + events.len() as EventId).collect();
+            last_id += events.len() as EventId;
+            Ok(event_ids)
+        }));
+
+        // A lone event is written at once.
+        assert_eq!(batcher.store(Event::default()).await.unwrap(), 1);
+        assert_eq!(*batches.lock().unwrap(), vec![1]);
+
+        let writes: Vec<_> = (0..50)
+            .map(|_| {
+                let batcher = batcher.clone();
+                tokio::spawn(async move { batcher.store(Event::default()).await })
+            })
+            .collect();
+        let mut event_ids = vec![];
+        for write in writes {
+            event_ids.push(write.await.unwrap().unwrap());
+        }
+        event_ids.sort();
+        assert_eq!(event_ids, (2..=51).collect::<Vec<_>>());
+        {
+            let batches = batches.lock().unwrap();
+            assert_eq!(batches.iter().sum::<usize>(), 51);
+            assert!(batches.len() < 51);
+        }
+
+        // Events queued before closing are written, later ones are rejected.
+        let mut queued = Box::pin(batcher.store(Event::default()));
+        let _ = futures_util::poll!(&mut queued);
+        batcher.close().await;
+        assert!(queued.await.is_ok());
+        assert!(batcher.store(Event::default()).await.is_err());
+    }
+}